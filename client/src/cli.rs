@@ -1,9 +1,11 @@
-use std::{path::PathBuf, str::FromStr};
+use std::{path::PathBuf, str::FromStr, time::Duration};
 
 use anyhow::{anyhow, Result};
 use chrono::{DateTime, FixedOffset, Local, NaiveDateTime, TimeZone};
 use clap::{Parser, Subcommand};
 use derive_more::{From, Into};
+use globset::Glob;
+use humantime_serde::re::humantime::parse_duration;
 use rammingen_protocol::{ArchivePath, DateTimeUtc};
 
 use crate::{info::DATE_TIME_FORMAT, path::SanitizedLocalPath};
@@ -23,6 +25,23 @@ pub struct Cli {
     /// - %APPDATA%\rammingen.conf on Windows
     #[clap(long)]
     pub config: Option<PathBuf>,
+    /// If another instance of rammingen is running, wait for it to finish
+    /// instead of exiting with an error.
+    #[clap(long)]
+    pub wait: bool,
+    /// Show how much time was spent in each stage (scanning, hashing, uploading, etc.)
+    /// at the end.
+    #[clap(long)]
+    pub timings: bool,
+    /// If the local database is corrupted, move it aside and start with an empty one
+    /// instead of exiting with an error. All files are hashed again on the next sync.
+    #[clap(long)]
+    pub repair_db: bool,
+    /// Don't use the local database. An empty temporary database is used instead,
+    /// so all files are hashed again and no state is left behind. Commands that
+    /// depend on the local state (e.g. `sync`) can't be used with this option.
+    #[clap(long)]
+    pub no_local_db: bool,
     #[clap(subcommand)]
     pub command: Command,
 }
@@ -30,21 +49,97 @@ pub struct Cli {
 #[derive(Debug, Subcommand, PartialEq, Eq)]
 pub enum Command {
     /// Sync all mount point with the server.
-    Sync,
+    Sync {
+        /// Only sync this path inside of a mount point, leaving the rest
+        /// of the mount point untouched.
+        #[arg(long)]
+        path: Option<SanitizedLocalPath>,
+    },
+    /// Records the state of a mount point from the local database on the server,
+    /// without scanning local files.
+    ///
+    /// Use it to repair the server state after an upload was interrupted.
+    /// Entries that differ on the server are recorded again, unless they
+    /// were changed on the server after they were last synced by this client.
+    ResyncMount { path: SanitizedLocalPath },
     /// Upload a file or directory to the server.
     Upload {
         local_path: SanitizedLocalPath,
         archive_path: ArchivePath,
+        /// Only show what would be uploaded. File content is hashed, but not uploaded,
+        /// and nothing is recorded on the server.
+        #[arg(long)]
+        dry_run: bool,
+        /// Send the planned versions to the server to check that it would accept them
+        /// (e.g. that limits are not exceeded). Nothing is recorded.
+        #[arg(long, requires = "dry_run")]
+        validate_server: bool,
     },
     /// Download a file or directory from the server.
     Download {
         archive_path: ArchivePath,
-        local_path: SanitizedLocalPath,
+        /// Local path to download to. Must be omitted if `--into` is used.
+        #[arg(required_unless_present = "into")]
+        local_path: Option<SanitizedLocalPath>,
         /// Timestamp of the version to be downloaded (in local time zone).
         /// If omitted, the latest version is downloaded.
         /// Accepted timestamp format: %Y-%m-%d_%H:%M:%S
         version: Option<DateTimeArg>,
+        /// Same as the positional `version` argument.
+        #[arg(long = "version", value_name = "VERSION", conflicts_with = "version")]
+        version_option: Option<DateTimeArg>,
+        /// Restore a past version into this directory instead of downloading it
+        /// to `local_path`. Requires a version.
+        ///
+        /// The content is placed into `<into>/<name>`, where `<name>` is the last component
+        /// of the archive path, and `<into>/manifest.json` records what was restored.
+        /// `<into>` must be empty or not exist, and must not be inside a mount point.
+        #[arg(
+            long,
+            conflicts_with_all = ["local_path", "checksum", "metadata_only", "repair", "pattern"],
+        )]
+        into: Option<SanitizedLocalPath>,
+        /// Compare content of existing local files with the archive instead of trusting
+        /// the local database. Files that differ are downloaded again.
+        #[arg(long, visible_alias = "verify-all")]
+        checksum: bool,
+        /// Create the directory structure and empty placeholder files
+        /// without downloading file content.
+        #[arg(long, conflicts_with = "checksum")]
+        metadata_only: bool,
+        /// Interpret the version timestamp relative to the server's clock
+        /// instead of the local clock, compensating for clock skew between them.
+        #[arg(long, requires = "version")]
+        server_time: bool,
+        /// Repair a mount point (or a path inside of it): compare content of local files
+        /// with the archive and download only missing and mismatched files.
+        ///
+        /// Local changes that are not yet uploaded to the server are overwritten.
+        #[arg(long, conflicts_with_all = ["version", "version_option", "metadata_only"])]
+        repair: bool,
+        /// Only download files matching this glob pattern (e.g. `*.raw`).
+        /// The pattern is matched against the path relative to the downloaded path,
+        /// and `*` also matches `/`. Directories are only created if they contain
+        /// matching files.
+        #[arg(long, conflicts_with = "repair")]
+        pattern: Option<Glob>,
     },
+    /// Shows how many files would be downloaded by restoring a past version
+    /// of a file or directory, and their total size.
+    RestoreEstimate {
+        archive_path: ArchivePath,
+        /// Timestamp of the version (in local time zone).
+        /// Accepted timestamp format: %Y-%m-%d_%H:%M:%S
+        version: DateTimeArg,
+    },
+    /// Shows configured mount points and their exclude rules.
+    Mounts,
+    /// Shows the effective configuration, including default values and implicit
+    /// exclude rules. Secrets are redacted.
+    ShowConfig,
+    /// Shows number and size of files that would be uploaded from a local path,
+    /// without reading or uploading their content.
+    Estimate { path: SanitizedLocalPath },
     /// Shows information about a local path.
     LocalStatus { path: SanitizedLocalPath },
     /// Shows information about an archive path.
@@ -53,6 +148,23 @@ pub enum Command {
         /// Also shows deleted entries.
         #[arg(short, long)]
         deleted: bool,
+        /// Request the list of sources from the server instead of using the cached one.
+        #[arg(long)]
+        refresh_sources: bool,
+    },
+    /// Prints archive paths starting with a partially typed path (e.g. `ar:/foo/ba`),
+    /// one per line, for use in shell completion. Directories end with `/`.
+    Complete { partial: String },
+    /// Shows all archive paths of files that have the same content as a local file.
+    FindByContent { local_path: SanitizedLocalPath },
+    /// Shows groups of files with identical content within an archive path.
+    Duplicates { path: ArchivePath },
+    /// Shows paths with the largest number of stored versions within an archive path.
+    Hotspots {
+        path: ArchivePath,
+        /// Maximal number of paths to show.
+        #[arg(short, long, default_value = "20")]
+        limit: u32,
     },
     /// Shows the list of available versions for an archive path.
     History {
@@ -60,26 +172,120 @@ pub enum Command {
         /// Also shows versions of all nested paths.
         #[arg(short, long)]
         recursive: bool,
+        /// Request the list of sources from the server instead of using the cached one.
+        #[arg(long)]
+        refresh_sources: bool,
     },
     /// Set the specified version as the current version of an archive path.
     Reset {
         archive_path: ArchivePath,
         /// Accepted timestamp format: %Y-%m-%d_%H:%M:%S
         version: DateTime<FixedOffset>,
+        /// Interpret the timestamp relative to the server's clock
+        /// instead of the local clock, compensating for clock skew between them.
+        #[arg(long)]
+        server_time: bool,
+    },
+    /// Permanently delete all versions of an archive path recorded after the specified time,
+    /// restoring the state it had at that time.
+    ///
+    /// Unlike `reset`, the newer versions are removed from history and can't be restored.
+    /// Without `--confirm`, only shows the number of paths that would be affected.
+    Rollback {
+        archive_path: ArchivePath,
+        /// Accepted timestamp format: %Y-%m-%d_%H:%M:%S
+        #[arg(long)]
+        to: DateTimeArg,
+        /// Actually perform the rollback.
+        #[arg(long)]
+        confirm: bool,
+    },
+    /// Permanently remove all data recorded by a source that was disabled on the server.
+    /// Requires `admin_token` in config.
+    ///
+    /// Entries last recorded by the source are deleted along with their history,
+    /// except for versions that belong to snapshots.
+    PurgeSource {
+        name: String,
+        /// Minimal time since the source was disabled.
+        #[arg(long, default_value = "30days", value_parser = parse_duration)]
+        grace_period: Duration,
     },
     /// Move (rename) data from one archive path to another.
     Move {
         old_path: ArchivePath,
         new_path: ArchivePath,
+        /// Only check that the server would accept the move and show the number
+        /// of paths that would be affected.
+        #[arg(long)]
+        dry_run: bool,
     },
     /// Remove an archive path.
     Remove { archive_path: ArchivePath },
+    /// Pin an archive path, so that its versions are never removed from history.
+    Pin {
+        archive_path: ArchivePath,
+        /// Also pin all nested paths.
+        #[arg(short, long)]
+        recursive: bool,
+    },
+    /// Remove the pin from an archive path.
+    Unpin { archive_path: ArchivePath },
+    /// Shows the list of pinned archive paths.
+    Pinned,
+    /// Runs commands listed in a file, one per line, sharing the same local database
+    /// and server connection.
+    ///
+    /// Arguments are split like in a shell. Empty lines and lines starting with `#`
+    /// are ignored. Execution stops at the first failed command.
+    RunScript { path: PathBuf },
+    /// Removes local database entries that are not related to any of
+    /// the configured mount points.
+    PruneDb,
+    /// Rewrites the local database to reclaim unused disk space.
+    CompactDb,
     /// Shows server status.
     Status,
-    /// Initiates an integrity check on the server.
-    CheckIntegrity,
+    /// Checks that file storage on the server is consistent with its database.
+    ///
+    /// By default, digests of content hashes are compared, so only the parts of
+    /// the storage that differ from the database are listed. The server keeps digests
+    /// of the storage in memory, so files changed in the storage directory by other
+    /// means may only be detected with `--full`.
+    CheckIntegrity {
+        /// Make the server list the whole storage and compare it with the database.
+        #[arg(long)]
+        full: bool,
+    },
     /// Generates a new encryption key.
     GenerateEncryptionKey,
+    /// Replaces the access token in the config file.
+    ///
+    /// The new token is read from stdin and checked against the server before saving.
+    SetToken,
+}
+
+impl Command {
+    /// Returns true if the command depends on the state stored in the local database,
+    /// so it can't be used with `--no-local-db`.
+    pub fn requires_local_db(&self) -> bool {
+        matches!(
+            self,
+            Command::Sync { .. }
+                | Command::ResyncMount { .. }
+                | Command::LocalStatus { .. }
+                | Command::PruneDb
+                | Command::CompactDb
+        )
+    }
+}
+
+/// A single command of a script executed by `run-script`.
+#[derive(Debug, Parser)]
+#[command(no_binary_name = true)]
+pub struct ScriptCommand {
+    #[clap(subcommand)]
+    pub command: Command,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, From, Into)]
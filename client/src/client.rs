@@ -1,28 +1,41 @@
 use aes_siv::Aes256SivAead;
 use anyhow::{anyhow, bail, Result};
 use byteorder::{ByteOrder, LE};
+use bytes::Bytes;
 use derivative::Derivative;
-use fs_err::File;
-use futures::{Stream, StreamExt};
-use reqwest::{header::CONTENT_LENGTH, Body, Method, Url};
-use serde::{de::DeserializeOwned, Serialize};
+use fs_err::{create_dir_all, remove_file, File, OpenOptions};
+use futures::{stream, Stream, StreamExt};
+use reqwest::{
+    header::{
+        HeaderMap, HeaderName, HeaderValue, ACCEPT_ENCODING, CONTENT_ENCODING, CONTENT_LENGTH,
+        RANGE,
+    },
+    Body, Method, StatusCode, Url,
+};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use std::{
     io::{self, Read, Seek, SeekFrom, Write},
     path::Path,
     time::Duration,
 };
 use stream_generator::generate_try_stream;
-use tokio::task::block_in_place;
+use tokio::{
+    sync::mpsc,
+    task::{block_in_place, spawn_blocking},
+};
+use tracing::debug;
 
 use rammingen_protocol::{
-    endpoints::{RequestToResponse, RequestToStreamingResponse},
+    compression::{self, Decoder, Encoding},
+    endpoints::{ErrorResponse, RequestToResponse, RequestToStreamingResponse},
     util::stream_file,
-    EncryptedContentHash,
+    ContentHash, EncryptedContentHash, ADMIN_TOKEN_HEADER, CLIENT_VERSION_HEADER,
+    REQUEST_ID_HEADER,
 };
 
 use crate::{
     data::DecryptedFileContent,
-    encryption::{encrypt_content_hash, Decryptor},
+    encryption::{encrypt_content_hash, encrypt_file_to, Decryptor},
 };
 
 #[derive(Derivative, Clone)]
@@ -40,6 +53,10 @@ impl Client {
             token: token.into(),
             reqwest: reqwest::Client::builder()
                 .timeout(Duration::from_secs(10))
+                .default_headers(HeaderMap::from_iter([(
+                    HeaderName::from_static(CLIENT_VERSION_HEADER),
+                    HeaderValue::from_static(env!("CARGO_PKG_VERSION")),
+                )]))
                 .build()
                 .unwrap(),
         }
@@ -50,19 +67,45 @@ impl Client {
         R: RequestToResponse + Serialize,
         R::Response: DeserializeOwned,
     {
-        let response = self
+        self.request_inner(request, None).await
+    }
+
+    /// Sends a request that requires the server's admin token.
+    pub async fn admin_request<R>(&self, request: &R, admin_token: &str) -> Result<R::Response>
+    where
+        R: RequestToResponse + Serialize,
+        R::Response: DeserializeOwned,
+    {
+        self.request_inner(request, Some(admin_token)).await
+    }
+
+    async fn request_inner<R>(&self, request: &R, admin_token: Option<&str>) -> Result<R::Response>
+    where
+        R: RequestToResponse + Serialize,
+        R::Response: DeserializeOwned,
+    {
+        let mut builder = self
             .reqwest
             .request(Method::POST, self.server_url.join(R::PATH)?)
-            .bearer_auth(&self.token)
-            .body(bincode::serialize(&request)?)
-            .send()
-            .await?
-            .error_for_status()?
-            .bytes()
-            .await?;
+            .bearer_auth(&self.token);
+        if let Some(admin_token) = admin_token {
+            builder = builder.header(ADMIN_TOKEN_HEADER, admin_token);
+        }
+        if R::COMPRESS_RESPONSE {
+            builder = builder.header(ACCEPT_ENCODING, compression::ACCEPT_ENCODING);
+        }
+        let response =
+            error_for_status(builder.body(bincode::serialize(&request)?).send().await?).await?;
+        let mut decoder = response_decoder(&response)?;
+        let mut response = response.bytes().await?.to_vec();
+        if let Some(decoder) = &mut decoder {
+            let mut decoded = Vec::new();
+            block_in_place(|| decoder.decode(&response, &mut decoded))?;
+            response = decoded;
+        }
 
-        bincode::deserialize::<Result<R::Response, String>>(&response)?
-            .map_err(|msg| anyhow!("server error: {msg}"))
+        bincode::deserialize::<Result<R::Response, ErrorResponse>>(&response)?
+            .map_err(ErrorResponse::into_error)
     }
 
     pub fn stream<R>(&self, request: &R) -> impl Stream<Item = Result<R::ResponseItem>>
@@ -73,23 +116,28 @@ impl Client {
         let this = self.clone();
         let request = bincode::serialize(&request);
         generate_try_stream(|mut y| async move {
-            let mut response = this
+            let response = this
                 .reqwest
                 .request(Method::POST, this.server_url.join(R::PATH)?)
                 .bearer_auth(&this.token)
+                .header(ACCEPT_ENCODING, compression::ACCEPT_ENCODING)
                 .body(request?)
                 .send()
-                .await?
-                .error_for_status()?;
+                .await?;
+            let mut response = error_for_status(response).await?;
+            let mut decoder = response_decoder(&response)?;
             let mut buf = Vec::new();
             while let Some(chunk) = response.chunk().await? {
-                buf.extend_from_slice(&chunk);
+                if let Some(decoder) = &mut decoder {
+                    block_in_place(|| decoder.decode(&chunk, &mut buf))?;
+                } else {
+                    buf.extend_from_slice(&chunk);
+                }
                 while let Some((chunk, index)) = take_chunk(&buf) {
-                    let data =
-                        bincode::deserialize::<Result<Option<Vec<R::ResponseItem>>, String>>(
-                            chunk,
-                        )?
-                        .map_err(|msg| anyhow!("server error: {msg}"))?;
+                    let data = bincode::deserialize::<
+                        Result<Option<Vec<R::ResponseItem>>, ErrorResponse>,
+                    >(chunk)?
+                    .map_err(ErrorResponse::into_error)?;
 
                     buf.drain(..index);
                     if let Some(data) = data {
@@ -113,7 +161,8 @@ impl Client {
     ) -> Result<()> {
         let size = encrypted_file.seek(SeekFrom::End(0))?;
         encrypted_file.rewind()?;
-        self.reqwest
+        let response = self
+            .reqwest
             .put(format!("{}content/{}", self.server_url, hash.to_url_safe()))
             .bearer_auth(&self.token)
             .header(CONTENT_LENGTH, size)
@@ -121,64 +170,295 @@ impl Client {
                 stream_file(encrypted_file).map(io::Result::Ok),
             ))
             .send()
-            .await?
-            .error_for_status()?;
+            .await?;
+        if response.status() == StatusCode::PAYLOAD_TOO_LARGE {
+            bail!("server rejected content: file size ({size} bytes) exceeds server limit");
+        }
+        if response.status() == StatusCode::INSUFFICIENT_STORAGE {
+            bail!("server rejected content: not enough free space in server storage");
+        }
+        error_for_status(response).await?;
         Ok(())
     }
 
-    pub async fn download_and_decrypt(
+    /// Encrypts a local file and uploads the encrypted content at the same time,
+    /// without storing it locally. Returns the encrypted size.
+    ///
+    /// `hash` must be the hash of the file content calculated in advance. If the content
+    /// turns out to be different, the upload is aborted and the server discards it.
+    pub async fn upload_stream(
         &self,
-        content: &DecryptedFileContent,
-        path: impl AsRef<Path>,
+        hash: &ContentHash,
+        path: &Path,
         cipher: &Aes256SivAead,
-    ) -> Result<()> {
-        let encrypted_hash = encrypt_content_hash(&content.hash, cipher)?;
-        let mut response = self
+    ) -> Result<u64> {
+        let encrypted_hash = encrypt_content_hash(hash, cipher)?;
+        let (sender, receiver) = mpsc::channel(UPLOAD_STREAM_CHANNEL_CAPACITY);
+        let path = path.to_path_buf();
+        let cipher = cipher.clone();
+        let hash = hash.clone();
+        let encrypt_task = spawn_blocking(move || {
+            let result =
+                encrypt_file_to(&path, &cipher, ChannelWriter(sender.clone())).and_then(|data| {
+                    if data.hash != hash {
+                        bail!("file {:?} was updated while it was being uploaded", path);
+                    }
+                    Ok(data.encrypted_size)
+                });
+            if let Err(err) = &result {
+                // Abort the request, so that the server doesn't store incomplete content.
+                let _ = sender
+                    .blocking_send(Err(io::Error::new(io::ErrorKind::Other, err.to_string())));
+            }
+            result
+        });
+        let body = stream::unfold(receiver, |mut receiver| async move {
+            receiver.recv().await.map(|item| (item, receiver))
+        });
+        let response = self
             .reqwest
-            .get(format!(
+            .put(format!(
                 "{}content/{}",
                 self.server_url,
                 encrypted_hash.to_url_safe()
             ))
             .bearer_auth(&self.token)
-            .send()
-            .await?
-            .error_for_status()?;
-
-        let header_len: u64 = response
-            .headers()
-            .get(CONTENT_LENGTH)
-            .ok_or_else(|| anyhow!("missing content length header"))?
-            .to_str()?
-            .parse()?;
-
-        if content.encrypted_size != header_len {
-            bail!("encrypted size mismatch");
+            .body(Body::wrap_stream(body))
+            .send();
+        let (response, encrypted_size) = tokio::join!(response, encrypt_task);
+        match (response, encrypted_size?) {
+            (Ok(response), Ok(encrypted_size)) => {
+                if response.status() == StatusCode::PAYLOAD_TOO_LARGE {
+                    bail!("server rejected content: file size exceeds server limit");
+                }
+                if response.status() == StatusCode::INSUFFICIENT_STORAGE {
+                    bail!("server rejected content: not enough free space in server storage");
+                }
+                error_for_status(response).await?;
+                Ok(encrypted_size)
+            }
+            (Ok(_), Err(err)) => Err(err),
+            (Err(err), Ok(_)) => Err(err.into()),
+            (Err(request_err), Err(err)) => Err(err.context(request_err)),
         }
+    }
+
+    /// Downloads content and decrypts it to `path`.
+    ///
+    /// Received encrypted data is also saved to `resume_dir`. If the download is
+    /// interrupted, the next attempt to download the same content decrypts the saved data
+    /// and requests only the rest of the content from the server.
+    pub async fn download_and_decrypt(
+        &self,
+        content: &DecryptedFileContent,
+        path: impl AsRef<Path>,
+        resume_dir: &Path,
+        cipher: &Aes256SivAead,
+    ) -> Result<()> {
+        let encrypted_hash = encrypt_content_hash(&content.hash, cipher)?;
+        create_dir_all(resume_dir)?;
+        let name = encrypted_hash.to_url_safe();
+        let encrypted_path = resume_dir.join(&name);
+        let info_path = resume_dir.join(format!("{name}.info"));
 
         let file = File::create(path.as_ref())?;
         let mut decryptor = Decryptor::new(cipher, file);
-        let mut actual_encrypted_size = 0;
+        self.download_encrypted(
+            &encrypted_hash,
+            content.encrypted_size,
+            &encrypted_path,
+            &info_path,
+            &mut decryptor,
+        )
+        .await?;
+        let result = block_in_place(|| {
+            let (_, actual_hash, actual_original_size) = decryptor.finish()?;
+            if content.hash != actual_hash {
+                bail!("content hash mismatch");
+            }
+            if content.original_size != actual_original_size {
+                bail!("original size mismatch");
+            }
+            anyhow::Ok(())
+        });
+        // The downloaded data is not useful anymore, even if it turned out to be invalid.
+        remove_file(&encrypted_path)?;
+        remove_file(&info_path)?;
+        result
+    }
+
+    /// Downloads encrypted content to `output`, saving it to `path` as well.
+    /// Data saved to `path` by a previous attempt is reused.
+    async fn download_encrypted(
+        &self,
+        hash: &EncryptedContentHash,
+        encrypted_size: u64,
+        path: &Path,
+        info_path: &Path,
+        output: &mut impl Write,
+    ) -> Result<()> {
+        let mut offset = read_partial_download_info(info_path)
+            .filter(|info| &info.hash == hash && info.valid_len <= encrypted_size)
+            .map_or(0, |info| info.valid_len);
+        let mut file = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .write(true)
+            .open(path)?;
+        if file.metadata()?.len() < offset {
+            offset = 0;
+        }
 
-        while let Some(chunk) = response.chunk().await? {
-            actual_encrypted_size += chunk.len() as u64;
-            block_in_place(|| decryptor.write_all(&chunk))?;
+        let mut request = self
+            .reqwest
+            .get(format!("{}content/{}", self.server_url, hash.to_url_safe()))
+            .bearer_auth(&self.token);
+        if offset > 0 && offset < encrypted_size {
+            debug!(
+                "resuming download of {} from {}",
+                hash.to_url_safe(),
+                offset
+            );
+            request = request.header(RANGE, format!("bytes={offset}-"));
         }
-        let (_, actual_hash, actual_original_size) = block_in_place(|| decryptor.finish())?;
-        if actual_encrypted_size != header_len {
-            bail!("content length mismatch");
+        let response = if offset < encrypted_size {
+            let response = error_for_status(request.send().await?).await?;
+            if offset > 0 && response.status() != StatusCode::PARTIAL_CONTENT {
+                // The server ignored the range, so the download starts from the beginning.
+                offset = 0;
+            }
+            let header_len: u64 = response
+                .headers()
+                .get(CONTENT_LENGTH)
+                .ok_or_else(|| anyhow!("missing content length header"))?
+                .to_str()?
+                .parse()?;
+            if encrypted_size != offset + header_len {
+                bail!("encrypted size mismatch");
+            }
+            Some(response)
+        } else {
+            None
+        };
+
+        let replayed = block_in_place(|| {
+            file.set_len(offset)?;
+            file.rewind()?;
+            io::copy(&mut Read::by_ref(&mut file).take(offset), output)?;
+            anyhow::Ok(())
+        });
+        if let Err(err) = replayed {
+            // Saved data is unusable, so the next attempt starts from the beginning.
+            drop(file);
+            remove_file(path)?;
+            remove_file(info_path)?;
+            return Err(err);
         }
-        if content.hash != actual_hash {
-            bail!("content hash mismatch");
+        let mut info = PartialDownloadInfo {
+            hash: hash.clone(),
+            valid_len: offset,
+        };
+        if let Some(mut response) = response {
+            let mut unsaved_len = 0;
+            while let Some(chunk) = response.chunk().await? {
+                block_in_place(|| {
+                    file.write_all(&chunk)?;
+                    output.write_all(&chunk)
+                })?;
+                info.valid_len += chunk.len() as u64;
+                unsaved_len += chunk.len() as u64;
+                if unsaved_len >= PARTIAL_DOWNLOAD_SAVE_INTERVAL {
+                    block_in_place(|| save_partial_download_info(&mut file, info_path, &info))?;
+                    unsaved_len = 0;
+                }
+            }
+            block_in_place(|| save_partial_download_info(&mut file, info_path, &info))?;
         }
-        if content.original_size != actual_original_size {
-            bail!("original size mismatch");
+        if info.valid_len != encrypted_size {
+            bail!("content length mismatch");
         }
+        Ok(())
+    }
+}
 
+/// Max number of encrypted chunks waiting to be sent in `Client::upload_stream`.
+const UPLOAD_STREAM_CHANNEL_CAPACITY: usize = 16;
+
+/// Sends all written data to a channel.
+struct ChannelWriter(mpsc::Sender<io::Result<Bytes>>);
+
+impl Write for ChannelWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0
+            .blocking_send(Ok(Bytes::copy_from_slice(buf)))
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "upload request was aborted"))?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
         Ok(())
     }
 }
 
+/// Number of downloaded bytes after which the download progress is saved.
+const PARTIAL_DOWNLOAD_SAVE_INTERVAL: u64 = 8 * 1024 * 1024;
+
+/// Same as `reqwest::Response::error_for_status`, but includes the server's message
+/// if the server rejected the client because of its version, and the request ID
+/// assigned by the server.
+async fn error_for_status(response: reqwest::Response) -> Result<reqwest::Response> {
+    if response.status() == StatusCode::UPGRADE_REQUIRED {
+        bail!("server rejected request: {}", response.text().await?);
+    }
+    let request_id = response
+        .headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .map(ToOwned::to_owned);
+    response.error_for_status().map_err(|err| match request_id {
+        Some(request_id) => {
+            anyhow::Error::from(err).context(format!("request {request_id} failed"))
+        }
+        None => err.into(),
+    })
+}
+
+/// Creates a decoder for the transport compression chosen by the server, if any.
+fn response_decoder(response: &reqwest::Response) -> Result<Option<Decoder>> {
+    if let Some(value) = response.headers().get(CONTENT_ENCODING) {
+        let encoding = Encoding::from_name(value.to_str()?)
+            .ok_or_else(|| anyhow!("unsupported content encoding: {:?}", value))?;
+        Ok(Some(Decoder::new(encoding)?))
+    } else {
+        Ok(None)
+    }
+}
+
+/// Progress of a content download, stored alongside the partially downloaded data.
+#[derive(Debug, Serialize, Deserialize)]
+struct PartialDownloadInfo {
+    hash: EncryptedContentHash,
+    /// Number of bytes at the start of the file that are known to be written.
+    valid_len: u64,
+}
+
+fn read_partial_download_info(info_path: &Path) -> Option<PartialDownloadInfo> {
+    let data = fs_err::read(info_path).ok()?;
+    bincode::deserialize(&data).ok()
+}
+
+/// Flushes downloaded data to disk and records that it's valid.
+fn save_partial_download_info(
+    file: &mut File,
+    info_path: &Path,
+    info: &PartialDownloadInfo,
+) -> Result<()> {
+    file.flush()?;
+    file.sync_data()?;
+    fs_err::write(info_path, bincode::serialize(info)?)?;
+    Ok(())
+}
+
 fn take_chunk(buf: &[u8]) -> Option<(&[u8], usize)> {
     if buf.len() < 4 {
         return None;
@@ -1,24 +1,78 @@
 use aes_siv::aead::OsRng;
 use aes_siv::{Aes256SivAead, KeyInit};
+use anyhow::{anyhow, bail};
+use argon2::{Algorithm, Argon2, Params, Version};
 use base64::{prelude::BASE64_URL_SAFE_NO_PAD, Engine};
+use chrono::SubsecRound;
 use core::fmt;
 use derivative::Derivative;
 use generic_array::GenericArray;
-use rammingen_protocol::ArchivePath;
+use rammingen_protocol::{ArchivePath, DateTimeUtc, RecordTrigger};
 use reqwest::Url;
 use serde::de::Error;
 use serde::{Deserialize, Serialize};
-use std::path::PathBuf;
+use std::{path::PathBuf, time::Duration};
 use typenum::U64;
 
 use crate::path::SanitizedLocalPath;
-use crate::rules::Rule;
+use crate::rules::{Rule, Rules};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct MountPoint {
     pub local_path: SanitizedLocalPath,
     pub archive_path: ArchivePath,
+    /// Paths excluded from sync. Additional rules in the same format can be placed
+    /// into a `.rammingenignore` file in any directory of the mount point,
+    /// applying to the contents of that directory.
     pub exclude: Vec<Rule>,
+    /// Exclude files and directories with names starting with `.`.
+    #[serde(default)]
+    pub exclude_hidden: bool,
+    /// If archive paths of multiple mount points overlap, the mount point with the highest
+    /// priority wins: changes in the overlapping part of other mount points are not uploaded,
+    /// and their local files are replaced when the archive content changes.
+    #[serde(default)]
+    pub priority: i32,
+    /// If true, all changes of this mount point found during a sync are recorded
+    /// on the server in a single transaction, after all the content is uploaded,
+    /// so that other clients never see a partially synced state. Syncs producing
+    /// more than 16384 changes are split into multiple transactions.
+    #[serde(default)]
+    pub transactional: bool,
+    /// Don't descend into directories on other filesystems than the mount point's
+    /// local path (like `rsync -x`).
+    #[serde(default)]
+    pub one_file_system: bool,
+    /// Don't descend into directories on filesystems of these types
+    /// (e.g. `["proc", "sysfs", "tmpfs"]`). Only supported on Linux.
+    #[serde(default)]
+    pub exclude_filesystems: Vec<String>,
+    /// Changes recorded on the server by these triggers (e.g. `["Reset"]`) are not
+    /// downloaded to this mount point during sync. Local files affected by such changes
+    /// keep their content until they're changed again by another trigger.
+    #[serde(default)]
+    pub download_trigger_filter: Vec<RecordTrigger>,
+    /// Only record metadata (hash, size, modification time) of files on the server,
+    /// without uploading their content. Useful for keeping track of large files
+    /// that are backed up by other means. Content of such files can't be downloaded,
+    /// so the archive path shouldn't be used by other mount points or clients.
+    ///
+    /// The server only accepts such versions from sources that were allowed to
+    /// record them with `rammingen-admin allow-metadata-only`.
+    #[serde(default)]
+    pub metadata_only: bool,
+}
+
+impl MountPoint {
+    /// Returns rules for excluding paths from this mount point.
+    pub fn rules(&self, always_exclude: &[Rule], extra: &[Rule]) -> Rules {
+        Rules::new(
+            &[always_exclude, &self.exclude, extra],
+            self.local_path.clone(),
+        )
+        .exclude_hidden(self.exclude_hidden)
+        .exclude_filesystems(self.one_file_system, &self.exclude_filesystems)
+    }
 }
 
 #[derive(Clone)]
@@ -29,6 +83,18 @@ impl EncryptionKey {
         Self(Aes256SivAead::generate_key(&mut OsRng))
     }
 
+    /// Derives a key from a passphrase with Argon2id. The parameters are fixed,
+    /// because changing them would change the key.
+    pub fn derive(passphrase: &str, salt: &str) -> anyhow::Result<Self> {
+        let params = Params::new(19 * 1024, 2, 1, Some(64))
+            .map_err(|err| anyhow!("invalid key derivation parameters: {err}"))?;
+        let mut key = GenericArray::default();
+        Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+            .hash_password_into(passphrase.as_bytes(), salt.as_bytes(), &mut key)
+            .map_err(|err| anyhow!("failed to derive encryption key: {err}"))?;
+        Ok(Self(key))
+    }
+
     pub fn get(&self) -> &GenericArray<u8, U64> {
         &self.0
     }
@@ -71,20 +137,251 @@ impl Serialize for EncryptionKey {
 #[derive(Derivative, Clone, Serialize, Deserialize)]
 #[derivative(Debug)]
 pub struct Config {
+    /// Rules applied to all mount points and uploads. The local database, the log file
+    /// and the config file are always excluded implicitly.
     pub always_exclude: Vec<Rule>,
     pub mount_points: Vec<MountPoint>,
-    pub encryption_key: EncryptionKey,
+    /// Key used to encrypt paths and content. Generate it with `generate-encryption-key`.
+    /// Either this or `encryption_passphrase` must be set.
+    #[serde(default)]
+    pub encryption_key: Option<EncryptionKey>,
+    /// Passphrase from which the encryption key is derived with Argon2id, as an alternative
+    /// to `encryption_key`. Requires `encryption_salt`.
+    ///
+    /// The key depends on both the passphrase and the salt, so changing either of them
+    /// is the same as switching to a different encryption key: the archive recorded
+    /// with the old key becomes inaccessible, and all files are uploaded again.
+    #[derivative(Debug = "ignore")]
+    #[serde(default)]
+    pub encryption_passphrase: Option<String>,
+    /// Salt used to derive the key from `encryption_passphrase`, at least 8 bytes long.
+    /// It doesn't have to be secret, but it should be unique (e.g. a random string).
+    #[serde(default)]
+    pub encryption_salt: Option<String>,
     pub server_url: Url,
     #[derivative(Debug = "ignore")]
     pub access_token: String,
+    /// Admin token of the server. Only required for `rollback`.
+    #[serde(default)]
+    #[derivative(Debug = "ignore")]
+    pub admin_token: Option<String>,
+    /// If set, all archive paths used by this client are relative to this path,
+    /// e.g. `ar:/a` refers to `ar:/prefix/a`. Paths outside of the prefix are not visible.
+    #[serde(default)]
+    pub archive_prefix: Option<ArchivePath>,
+    /// Precision used when comparing file modification times with the local database.
+    /// Use a lower precision if the file system doesn't preserve sub-second
+    /// modification times, so that unchanged files are not considered modified.
+    #[serde(default)]
+    pub mtime_precision: MtimePrecision,
+    /// If true, file content is encrypted and uploaded at the same time, instead of
+    /// storing the encrypted content in memory or in a temporary file first.
+    /// Changed files are read twice: first to calculate the hash, then to upload the content.
+    #[serde(default)]
+    pub stream_uploads: bool,
+    /// If true, server requests related to a path are logged along with the decrypted
+    /// archive path, and the path is added to the error message if the request fails.
+    /// Disabled by default because the log file then contains unencrypted paths.
+    #[serde(default)]
+    pub log_decrypted_paths: bool,
+    /// If true, files and directories that can't be read (e.g. because of insufficient
+    /// permissions) are skipped during upload instead of failing the whole upload.
+    /// Skipped paths are listed at the end of the sync. Their previous state
+    /// on the server is kept.
+    #[serde(default)]
+    pub skip_unreadable: bool,
+    /// If set, the upload is aborted when more than this number of paths are skipped
+    /// with `skip_unreadable` or `too_recent_policy: "skip"`, since it usually indicates
+    /// a systemic problem (e.g. a failing disk) rather than isolated bad files.
+    #[serde(default)]
+    pub max_errors: Option<u64>,
+    /// Same as `max_errors`, but relative to the number of scanned entries
+    /// (e.g. `0.1` for 10%). Checked when scanning is finished and before deletions
+    /// are recorded, so that a few errors at the start don't abort the upload.
+    #[serde(default)]
+    pub max_error_rate: Option<f64>,
+    /// What to do with a file that is still being modified when it's uploaded.
+    /// Uploading always waits for a short time for the file to settle first.
+    #[serde(default)]
+    pub too_recent_policy: TooRecentPolicy,
+    /// Maximal number of versions recorded on the server in a single request during upload.
+    /// Larger batches reduce overhead when uploading many small files.
+    ///
+    /// Local database is updated only after a batch is recorded, so changes of an interrupted
+    /// batch are detected again on the next run. If the server rejects one version of a batch,
+    /// the whole batch (and the sync) fails.
+    #[serde(default = "default_add_versions_batch_size")]
+    pub add_versions_batch_size: usize,
+    /// Pending versions are recorded on the server before the batch is full if content
+    /// uploaded for them reaches this size (in bytes), so that progress of uploading
+    /// large files is saved sooner. Doesn't apply to transactional mount points.
+    #[serde(default = "default_add_versions_batch_bytes")]
+    pub add_versions_batch_bytes: u64,
+    /// Maximal number of local files hashed in parallel when their content is verified
+    /// (e.g. in `download --checksum`) or checked for changes before uploading.
+    /// Defaults to the number of CPU cores.
+    /// Set it to 1 if the files are on a spinning disk.
+    #[serde(default = "default_hash_threads")]
+    pub hash_threads: usize,
+    /// Maximal total size (in bytes) of local files that are hashed, uploaded or downloaded
+    /// at the same time. Many small files are still processed in parallel, but large files
+    /// are throttled, which avoids memory and bandwidth spikes. A file larger than the limit
+    /// is processed when no other files are in flight. Not limited by default.
+    #[serde(default)]
+    pub max_bytes_in_flight: Option<u64>,
+    /// Command executed when `sync` starts, e.g. `["curl", "-fsS", "https://example.com/start"]`.
+    ///
+    /// Hook commands receive sync statistics in environment variables
+    /// (`RAMMINGEN_SCANNED_ENTRIES`, `RAMMINGEN_MODIFIED_FILES`, `RAMMINGEN_SENT_TO_SERVER`,
+    /// `RAMMINGEN_UPDATED_ON_SERVER`, `RAMMINGEN_KIND_CHANGES`, `RAMMINGEN_UNREADABLE`,
+    /// `RAMMINGEN_BUSY`, `RAMMINGEN_OVERSIZED`). If a hook fails,
+    /// the error is logged, but the result of the sync is not affected.
+    #[serde(default)]
+    pub on_start: Option<Vec<String>>,
+    /// Command executed when `sync` completes successfully.
+    #[serde(default)]
+    pub on_success: Option<Vec<String>>,
+    /// Command executed when `sync` fails. The error message is passed
+    /// in `RAMMINGEN_ERROR` environment variable.
+    #[serde(default)]
+    pub on_failure: Option<Vec<String>>,
     #[serde(default)]
     pub local_db_path: Option<PathBuf>,
+    /// Compress entries written to the local database with zstd. Reduces the size
+    /// of the database with many entries at the cost of some CPU time. Entries that
+    /// don't get smaller are stored uncompressed.
+    /// Existing entries are still readable after this option is changed.
+    #[serde(default)]
+    pub compress_local_db: bool,
+    /// If set, a JSON manifest listing all files in mount points (with their archive paths,
+    /// content hashes, sizes and version times) is written to this path after each
+    /// successful `sync`.
+    #[serde(default)]
+    pub manifest_path: Option<PathBuf>,
+    /// If set, the local database is compacted after `sync` when this time has passed
+    /// since the last compaction (e.g. "30days"). See `compact-db` command.
+    #[serde(with = "humantime_serde", default)]
+    pub compact_db_interval: Option<Duration>,
+    /// If set, files that are removed locally because they were deleted in the archive
+    /// are kept next to the local database for this time (e.g. "7days").
+    /// If such a file is restored in the archive within that time, its content
+    /// is moved back instead of being downloaded again.
+    #[serde(with = "humantime_serde", default)]
+    pub retain_deleted_files: Option<Duration>,
+    /// How long the list of sources (used to show which client recorded a version)
+    /// is cached in the local database before it's requested from the server again.
+    /// Use `--refresh-sources` to refresh it immediately.
+    #[serde(with = "humantime_serde", default = "default_sources_cache_ttl")]
+    pub sources_cache_ttl: Duration,
     #[serde(default)]
     pub log_file: Option<PathBuf>,
     #[serde(default = "default_log_filter")]
     pub log_filter: String,
 }
 
+impl Config {
+    /// Returns the encryption key, deriving it from `encryption_passphrase` if it's used.
+    pub fn encryption_key(&self) -> anyhow::Result<EncryptionKey> {
+        match (&self.encryption_key, &self.encryption_passphrase) {
+            (Some(key), None) => Ok(key.clone()),
+            (None, Some(passphrase)) => {
+                let Some(salt) = &self.encryption_salt else {
+                    bail!("encryption_salt must be set if encryption_passphrase is used");
+                };
+                EncryptionKey::derive(passphrase, salt)
+            }
+            (Some(_), Some(_)) => {
+                bail!("encryption_key and encryption_passphrase can't be used together")
+            }
+            (None, None) => bail!("either encryption_key or encryption_passphrase must be set"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MtimePrecision {
+    Seconds,
+    Milliseconds,
+    Microseconds,
+    #[default]
+    Nanoseconds,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TooRecentPolicy {
+    /// Wait, then fail the upload (the behavior before this option was added).
+    #[default]
+    Wait,
+    /// Leave the previous version of the file on the server, so that the file
+    /// is uploaded on a later sync.
+    Skip,
+    /// Upload the content that was read, even though it may be inconsistent.
+    /// With `stream_uploads`, the upload may still fail if the file changes
+    /// while it's being uploaded.
+    Snapshot,
+}
+
+impl MtimePrecision {
+    /// Truncates `time` to this precision.
+    pub fn truncate(self, time: DateTimeUtc) -> DateTimeUtc {
+        let digits = match self {
+            MtimePrecision::Seconds => 0,
+            MtimePrecision::Milliseconds => 3,
+            MtimePrecision::Microseconds => 6,
+            MtimePrecision::Nanoseconds => 9,
+        };
+        time.trunc_subsecs(digits)
+    }
+
+    /// Checks whether two modification times are equal at this precision.
+    pub fn same_time(self, a: DateTimeUtc, b: DateTimeUtc) -> bool {
+        self.truncate(a) == self.truncate(b)
+    }
+}
+
 fn default_log_filter() -> String {
     "info".into()
 }
+
+fn default_add_versions_batch_size() -> usize {
+    128
+}
+
+fn default_add_versions_batch_bytes() -> u64 {
+    64 * 1024 * 1024
+}
+
+fn default_hash_threads() -> usize {
+    std::thread::available_parallelism().map_or(1, Into::into)
+}
+
+fn default_sources_cache_ttl() -> Duration {
+    Duration::from_secs(24 * 3600)
+}
+
+#[test]
+fn mtime_precision() {
+    use chrono::{TimeZone, Utc};
+
+    let a = Utc.timestamp_opt(1_000_000, 123_456_789).unwrap();
+    let b = Utc.timestamp_opt(1_000_000, 0).unwrap();
+    assert!(!MtimePrecision::Nanoseconds.same_time(a, b));
+    assert!(!MtimePrecision::Milliseconds.same_time(a, b));
+    assert!(MtimePrecision::Seconds.same_time(a, b));
+    assert!(MtimePrecision::Milliseconds
+        .same_time(a, Utc.timestamp_opt(1_000_000, 123_000_000).unwrap()));
+}
+
+#[test]
+fn passphrase_key_derivation() {
+    let key = EncryptionKey::derive("correct horse battery staple", "rammingen salt").unwrap();
+    assert_eq!(
+        BASE64_URL_SAFE_NO_PAD.encode(key.get()),
+        "PnsUR_1yGJrXuPryDAUAeq8VX9ybFKx7VEfOvhvS62p73mKMPbMS84CXbcakRMtFxVl2oKWWWh9lYahKXQa3cg"
+    );
+    let other_salt = EncryptionKey::derive("correct horse battery staple", "other salt").unwrap();
+    assert_ne!(key.get(), other_salt.get());
+    assert!(EncryptionKey::derive("passphrase", "short").is_err());
+}
@@ -1,6 +1,13 @@
-use std::sync::atomic::{AtomicU64, Ordering};
+use std::{
+    fmt::Display,
+    future::Future,
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, Instant},
+};
 
-use tracing::info;
+use parking_lot::Mutex;
+use rammingen_protocol::EntryKind;
+use tracing::{info, warn};
 
 #[derive(Debug, Default)]
 pub struct Counters {
@@ -8,6 +15,12 @@ pub struct Counters {
     pub modified_files: AtomicU64,
     pub sent_to_server: AtomicU64,
     pub updated_on_server: AtomicU64,
+    pub kind_changes: AtomicU64,
+    pub unreadable_paths: Mutex<Vec<String>>,
+    pub busy_paths: Mutex<Vec<String>>,
+    pub oversized_paths: Mutex<Vec<String>>,
+    /// Accumulated over the whole run, not affected by `reset`.
+    pub timings: Timings,
 }
 
 impl Counters {
@@ -16,6 +29,7 @@ impl Counters {
         let modified_files = self.modified_files.load(Ordering::Relaxed);
         let sent_to_server = self.sent_to_server.load(Ordering::Relaxed);
         let updated_on_server = self.updated_on_server.load(Ordering::Relaxed);
+        let kind_changes = self.kind_changes.load(Ordering::Relaxed);
         info!("scanned {} entries", scanned_entries);
         if modified_files > 0 {
             info!("found {} modified files", modified_files);
@@ -26,5 +40,168 @@ impl Counters {
         if updated_on_server > 0 {
             info!("updated {} entries on server", updated_on_server);
         }
+        if kind_changes > 0 {
+            info!(
+                "{} entries changed from file to directory or vice versa",
+                kind_changes
+            );
+        }
+        let unreadable_paths = self.unreadable_paths.lock();
+        if !unreadable_paths.is_empty() {
+            warn!("skipped {} unreadable paths:", unreadable_paths.len());
+            for path in &*unreadable_paths {
+                warn!("    {}", path);
+            }
+        }
+        let busy_paths = self.busy_paths.lock();
+        if !busy_paths.is_empty() {
+            warn!(
+                "skipped {} files that were being modified:",
+                busy_paths.len()
+            );
+            for path in &*busy_paths {
+                warn!("    {}", path);
+            }
+        }
+        let oversized_paths = self.oversized_paths.lock();
+        if !oversized_paths.is_empty() {
+            warn!(
+                "skipped {} files exceeding the server's max file size:",
+                oversized_paths.len()
+            );
+            for path in &*oversized_paths {
+                warn!("    {}", path);
+            }
+        }
+    }
+
+    /// Returns names and current values of all counters.
+    pub fn values(&self) -> [(&'static str, u64); 8] {
+        let value = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        [
+            ("scanned_entries", value(&self.scanned_entries)),
+            ("modified_files", value(&self.modified_files)),
+            ("sent_to_server", value(&self.sent_to_server)),
+            ("updated_on_server", value(&self.updated_on_server)),
+            ("kind_changes", value(&self.kind_changes)),
+            ("unreadable", self.unreadable_paths.lock().len() as u64),
+            ("busy", self.busy_paths.lock().len() as u64),
+            ("oversized", self.oversized_paths.lock().len() as u64),
+        ]
+    }
+
+    /// Sets all counters to zero.
+    pub fn reset(&self) {
+        for counter in [
+            &self.scanned_entries,
+            &self.modified_files,
+            &self.sent_to_server,
+            &self.updated_on_server,
+            &self.kind_changes,
+        ] {
+            counter.store(0, Ordering::Relaxed);
+        }
+        self.unreadable_paths.lock().clear();
+        self.busy_paths.lock().clear();
+        self.oversized_paths.lock().clear();
+    }
+
+    /// Records that `path` was skipped because it couldn't be read.
+    pub fn unreadable(&self, path: impl Display) {
+        self.unreadable_paths.lock().push(path.to_string());
+    }
+
+    /// Records that the file at `path` was skipped because it was being modified.
+    pub fn busy(&self, path: impl Display) {
+        self.busy_paths.lock().push(path.to_string());
+    }
+
+    /// Records that the file at `path` was skipped because it exceeds
+    /// the server's max file size.
+    pub fn oversized(&self, path: impl Display) {
+        self.oversized_paths.lock().push(path.to_string());
+    }
+
+    /// Records and logs that the entry at `path` changed its kind.
+    pub fn kind_changed(&self, path: impl Display, old: EntryKind, new: EntryKind) {
+        self.kind_changes.fetch_add(1, Ordering::Relaxed);
+        info!(
+            "{} changed from {} to {}",
+            path,
+            kind_name(old),
+            kind_name(new)
+        );
+    }
+}
+
+fn kind_name(kind: EntryKind) -> &'static str {
+    match kind {
+        EntryKind::File => "file",
+        EntryKind::Directory => "directory",
+    }
+}
+
+/// Total duration and number of operations of one kind.
+#[derive(Debug, Default)]
+pub struct Timing {
+    nanos: AtomicU64,
+    count: AtomicU64,
+}
+
+impl Timing {
+    /// Runs `f` and adds the elapsed time to this timing.
+    pub fn measure<T>(&self, f: impl FnOnce() -> T) -> T {
+        let started = Instant::now();
+        let output = f();
+        self.add(started.elapsed());
+        output
+    }
+
+    /// Awaits `future` and adds the elapsed time to this timing.
+    pub async fn measure_async<T>(&self, future: impl Future<Output = T>) -> T {
+        let started = Instant::now();
+        let output = future.await;
+        self.add(started.elapsed());
+        output
+    }
+
+    fn add(&self, duration: Duration) {
+        self.nanos.fetch_add(
+            duration.as_nanos().try_into().unwrap_or(u64::MAX),
+            Ordering::Relaxed,
+        );
+        self.count.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Time spent in different stages of the client's work.
+#[derive(Debug, Default)]
+pub struct Timings {
+    /// Scanning local files, including hashing and uploading content.
+    pub scan: Timing,
+    pub hash: Timing,
+    pub upload: Timing,
+    pub record: Timing,
+    pub pull_updates: Timing,
+    pub download: Timing,
+}
+
+impl Timings {
+    pub fn report(&self) {
+        for (name, timing) in [
+            ("scanning local files (total)", &self.scan),
+            ("hashing and encrypting files", &self.hash),
+            ("uploading content", &self.upload),
+            ("recording versions on server", &self.record),
+            ("pulling updates from server", &self.pull_updates),
+            ("downloading content", &self.download),
+        ] {
+            let count = timing.count.load(Ordering::Relaxed);
+            if count == 0 {
+                continue;
+            }
+            let duration = Duration::from_nanos(timing.nanos.load(Ordering::Relaxed));
+            info!("{name}: {duration:.2?} ({count} times)");
+        }
     }
 }
@@ -2,12 +2,14 @@ use std::path::Path;
 
 use anyhow::{anyhow, Result};
 use rammingen_protocol::{
-    ArchivePath, ContentHash, DateTimeUtc, EntryKind, EntryVersionData, RecordTrigger, SourceId,
+    endpoints::SourceInfo, ArchivePath, ContentHash, DateTimeUtc, EncryptedArchivePath, EntryKind,
+    EntryUpdateNumber, EntryVersionData, RecordTrigger, SourceId,
 };
 use serde::{Deserialize, Serialize};
 
 use crate::{
-    encryption::{decrypt_content_hash, decrypt_path, decrypt_size},
+    config::MtimePrecision,
+    encryption::{decrypt_content_hash, decrypt_size},
     Ctx,
 };
 
@@ -20,6 +22,31 @@ pub struct DecryptedFileContent {
     pub unix_mode: Option<u32>,
 }
 
+/// Sources received from the server, cached in the local database.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CachedSources {
+    pub fetched_at: DateTimeUtc,
+    pub sources: Vec<SourceInfo>,
+}
+
+/// Progress of initializing the local database with `GetBootstrapEntries`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BootstrapProgress {
+    /// Updates after this number are requested with `GetNewEntries` when
+    /// bootstrapping is finished.
+    pub update_number: EntryUpdateNumber,
+    /// Path of the last received entry.
+    pub last_path: Option<EncryptedArchivePath>,
+}
+
+/// Local file that was removed because it was deleted in the archive.
+/// Its content is kept for `retain_deleted_files` in case it's restored.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DeletedLocalFile {
+    pub deleted_at: DateTimeUtc,
+    pub content: DecryptedFileContent,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct LocalEntryInfo {
     pub kind: EntryKind,
@@ -49,7 +76,11 @@ impl LocalEntryInfo {
         }
     }
 
-    pub fn matches_real(&self, path: impl AsRef<Path>) -> Result<bool> {
+    pub fn matches_real(
+        &self,
+        path: impl AsRef<Path>,
+        mtime_precision: MtimePrecision,
+    ) -> Result<bool> {
         let metadata = fs_err::metadata(path)?;
         if metadata.is_symlink() {
             return Ok(false);
@@ -62,7 +93,7 @@ impl LocalEntryInfo {
                 .content
                 .as_ref()
                 .ok_or_else(|| anyhow!("missing content for file"))?;
-            if DateTimeUtc::from(metadata.modified()?) != content.modified_at {
+            if !mtime_precision.same_time(metadata.modified()?.into(), content.modified_at) {
                 return Ok(false);
             }
         }
@@ -83,7 +114,7 @@ pub struct DecryptedEntryVersionData {
 impl DecryptedEntryVersionData {
     pub fn new(ctx: &Ctx, data: EntryVersionData) -> Result<Self> {
         Ok(Self {
-            path: decrypt_path(&data.path, &ctx.cipher)?,
+            path: ctx.decrypt_path(&data.path)?,
             recorded_at: data.recorded_at,
             source_id: data.source_id,
             record_trigger: data.record_trigger,
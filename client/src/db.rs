@@ -1,31 +1,211 @@
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, bail, Result};
 use byteorder::{ByteOrder, LE};
-use rammingen_protocol::{ArchivePath, EntryKind, EntryUpdateNumber};
+use chrono::{TimeZone, Utc};
+use fs2::FileExt;
+use fs_err::{remove_dir_all, rename, File, OpenOptions};
+use rammingen_protocol::{
+    util::try_exists, ArchivePath, ContentHash, DateTimeUtc, EntryKind, EntryUpdateNumber,
+};
+use serde::{de::DeserializeOwned, Serialize};
 use sled::{transaction::ConflictableTransactionError, Transactional};
-use std::{fmt::Debug, io, iter, path::Path, str};
+use std::{
+    fmt::{self, Debug},
+    io, iter, panic,
+    path::{Path, PathBuf, MAIN_SEPARATOR},
+    str, thread,
+    time::{Duration, Instant},
+};
+use tokio::task::block_in_place;
+use tracing::warn;
 
 use crate::{
-    data::{DecryptedEntryVersionData, LocalEntryInfo},
+    data::{
+        BootstrapProgress, CachedSources, DecryptedEntryVersionData, DeletedLocalFile,
+        LocalEntryInfo,
+    },
     path::SanitizedLocalPath,
+    term::set_status,
 };
 
 const KEY_LAST_ENTRY_UPDATE_NUMBER: [u8; 4] = [0, 0, 0, 1];
+const KEY_ARCHIVE_PREFIX: [u8; 4] = [0, 0, 0, 2];
+const KEY_LAST_COMPACTED_AT: [u8; 4] = [0, 0, 0, 3];
+const KEY_CACHED_SOURCES: [u8; 4] = [0, 0, 0, 4];
+const KEY_BOOTSTRAP_PROGRESS: [u8; 4] = [0, 0, 0, 5];
+const KEY_ENTRY_FORMAT: [u8; 4] = [0, 0, 0, 6];
+
+/// Version of the format of archive and local entries. Older databases store
+/// untagged bincode values and are converted when opened.
+const ENTRY_FORMAT_VERSION: u8 = 1;
+
+/// The first byte of each entry tells how the rest of it is encoded,
+/// so compressed and uncompressed entries can be stored in the same database.
+const TAG_UNCOMPRESSED: u8 = 0;
+const TAG_ZSTD: u8 = 1;
 
 pub struct Db {
     #[allow(dead_code)]
     db: sled::Db,
     archive_entries: sled::Tree,
+    /// Update numbers of archive entries.
+    archive_update_numbers: sled::Tree,
     local_entries: sled::Tree,
+    /// Last update number pulled from the server when each local entry was saved.
+    local_update_numbers: sled::Tree,
+    /// Retained local files deleted in the archive, by content hash.
+    deleted_local_files: sled::Tree,
+    /// Whether new entries are compressed.
+    compress: bool,
+    // Held until the database is closed.
+    _lock: Option<File>,
 }
 
 impl Db {
-    pub fn open(path: &Path) -> Result<Db> {
-        let db = sled::open(path)?;
-        Ok(Self {
+    /// Opens the database, holding a lock on it until the returned value is dropped.
+    ///
+    /// If another process holds the lock, this function returns an error or,
+    /// if `wait_for_lock` is true, waits until the lock is released.
+    ///
+    /// If the database is corrupted and `repair` is true, it's moved to `<path>.corrupted`
+    /// and an empty database is created instead.
+    pub fn open(path: &Path, wait_for_lock: bool, repair: bool) -> Result<Db> {
+        let lock = lock(path, wait_for_lock)?;
+        recover_compaction(path)?;
+        let db = match open_sled(path) {
+            Err(err) if err.is::<CorruptedDb>() => {
+                if !repair {
+                    bail!(
+                        "{:#} (use --repair-db to replace it with an empty database)",
+                        err
+                    );
+                }
+                let corrupted_path = with_suffix(path, ".corrupted");
+                if try_exists(&corrupted_path)? {
+                    remove_dir_all(&corrupted_path)?;
+                }
+                rename(path, &corrupted_path)?;
+                warn!(
+                    "{:#}. It was moved to {} and replaced with an empty database. \
+                    All local files will be hashed again and compared with the server \
+                    on the next sync.",
+                    err,
+                    corrupted_path.display()
+                );
+                open_sled(path)?
+            }
+            result => result?,
+        };
+        Self::new(db, Some(lock))
+    }
+
+    /// Opens an empty database that is removed when the returned value is dropped.
+    pub fn open_temporary() -> Result<Db> {
+        Self::new(sled::Config::new().temporary(true).open()?, None)
+    }
+
+    /// Rewrites the database at `path` into a new database, reclaiming space
+    /// that sled doesn't release on its own. Returns sizes of the database
+    /// before and after compaction.
+    pub fn compact(path: &Path, wait_for_lock: bool) -> Result<(u64, u64)> {
+        let _lock = lock(path, wait_for_lock)?;
+        recover_compaction(path)?;
+        if !try_exists(path)? {
+            bail!("local database {} doesn't exist", path.display());
+        }
+        let new_path = with_suffix(path, ".compacting");
+        let old_path = with_suffix(path, ".old");
+        let sizes = {
+            let old = open_sled(path)?;
+            let new = open_sled(&new_path)?;
+            new.import(old.export());
+            new.insert(KEY_LAST_COMPACTED_AT, &Utc::now().timestamp().to_le_bytes())?;
+            new.flush()?;
+            (old.size_on_disk()?, new.size_on_disk()?)
+        };
+        // If the process is interrupted between these steps,
+        // `recover_compaction` restores a consistent state on the next run.
+        rename(path, &old_path)?;
+        rename(&new_path, path)?;
+        remove_dir_all(&old_path)?;
+        Ok(sizes)
+    }
+
+    /// Returns the time of the last compaction, if any.
+    pub fn last_compacted_at(&self) -> Result<Option<DateTimeUtc>> {
+        self.db
+            .get(KEY_LAST_COMPACTED_AT)?
+            .map(|value| {
+                Utc.timestamp_opt(LE::read_i64(&value), 0)
+                    .single()
+                    .ok_or_else(|| anyhow!("invalid compaction time"))
+            })
+            .transpose()
+    }
+
+    fn new(db: sled::Db, lock: Option<File>) -> Result<Db> {
+        let this = Self {
             archive_entries: db.open_tree("archive_entries")?,
+            archive_update_numbers: db.open_tree("archive_update_numbers")?,
             local_entries: db.open_tree("local_entries")?,
+            local_update_numbers: db.open_tree("local_update_numbers")?,
+            deleted_local_files: db.open_tree("deleted_local_files")?,
             db,
-        })
+            compress: false,
+            _lock: lock,
+        };
+        this.upgrade_entry_format()?;
+        Ok(this)
+    }
+
+    /// Adds format tags to entries written by versions that stored them untagged.
+    fn upgrade_entry_format(&self) -> Result<()> {
+        match self.db.get(KEY_ENTRY_FORMAT)?.as_deref() {
+            Some([ENTRY_FORMAT_VERSION]) => return Ok(()),
+            Some(other) => bail!("unsupported local database entry format: {:?}", other),
+            None => {}
+        }
+        let tagged = |tree: &sled::Tree| {
+            tree.iter()
+                .map(|pair| {
+                    let (key, value) = pair?;
+                    Ok((key, [&[TAG_UNCOMPRESSED], &*value].concat()))
+                })
+                .collect::<Result<Vec<_>>>()
+        };
+        let archive_entries = tagged(&self.archive_entries)?;
+        let local_entries = tagged(&self.local_entries)?;
+        (&*self.db, &self.archive_entries, &self.local_entries).transaction(
+            |(db, archive_entries_tree, local_entries_tree)| {
+                for (key, value) in &archive_entries {
+                    archive_entries_tree.insert(key, value.as_slice())?;
+                }
+                for (key, value) in &local_entries {
+                    local_entries_tree.insert(key, value.as_slice())?;
+                }
+                db.insert(&KEY_ENTRY_FORMAT, &[ENTRY_FORMAT_VERSION])?;
+                Ok::<_, ConflictableTransactionError>(())
+            },
+        )?;
+        Ok(())
+    }
+
+    /// Enables or disables compression of entries written from now on.
+    pub fn with_compression(mut self, compress: bool) -> Self {
+        self.compress = compress;
+        self
+    }
+
+    /// Encodes an entry. If compression is enabled, the value is stored uncompressed
+    /// when compression doesn't make it smaller.
+    fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>> {
+        let data = bincode::serialize(value)?;
+        if self.compress {
+            let compressed = zstd::encode_all(data.as_slice(), 0)?;
+            if compressed.len() < data.len() {
+                return Ok([&[TAG_ZSTD], compressed.as_slice()].concat());
+            }
+        }
+        Ok([&[TAG_UNCOMPRESSED], data.as_slice()].concat())
     }
 
     pub fn get_all_archive_entries(
@@ -33,7 +213,7 @@ impl Db {
     ) -> impl Iterator<Item = Result<DecryptedEntryVersionData>> + DoubleEndedIterator {
         self.archive_entries
             .iter()
-            .map(|pair| Ok(bincode::deserialize::<DecryptedEntryVersionData>(&pair?.1)?))
+            .map(|pair| decode::<DecryptedEntryVersionData>(&pair?.1))
     }
 
     pub fn get_archive_entry(
@@ -44,9 +224,7 @@ impl Db {
             .archive_entries
             .get(path.to_str_without_prefix().as_bytes())?
         {
-            Ok(Some(bincode::deserialize::<DecryptedEntryVersionData>(
-                &value,
-            )?))
+            Ok(Some(decode::<DecryptedEntryVersionData>(&value)?))
         } else {
             Ok(None)
         }
@@ -61,7 +239,7 @@ impl Db {
                 .archive_entries
                 .get(path.to_str_without_prefix().as_bytes())?
                 .ok_or_else(|| anyhow!("no such archive path: {}", path))?;
-            anyhow::Ok(bincode::deserialize::<DecryptedEntryVersionData>(&value)?)
+            decode::<DecryptedEntryVersionData>(&value)
         })();
         let children = if root_entry
             .as_ref()
@@ -72,7 +250,7 @@ impl Db {
             Some(
                 self.archive_entries
                     .scan_prefix(prefix)
-                    .map(|pair| Ok(bincode::deserialize::<DecryptedEntryVersionData>(&pair?.1)?)),
+                    .map(|pair| decode::<DecryptedEntryVersionData>(&pair?.1)),
             )
         } else {
             None
@@ -80,6 +258,25 @@ impl Db {
         iter::once(root_entry).chain(children.into_iter().flatten())
     }
 
+    /// Returns the update number of the archive entry. It's unknown for entries
+    /// received by older versions of the client.
+    pub fn get_archive_update_number(
+        &self,
+        path: &ArchivePath,
+    ) -> Result<Option<EntryUpdateNumber>> {
+        Ok(self
+            .archive_update_numbers
+            .get(path.to_str_without_prefix().as_bytes())?
+            .map(|value| LE::read_i64(&value).into()))
+    }
+
+    pub fn remove_archive_entry(&self, path: &ArchivePath) -> Result<()> {
+        let key = path.to_str_without_prefix().as_bytes();
+        self.archive_entries.remove(key)?;
+        self.archive_update_numbers.remove(key)?;
+        Ok(())
+    }
+
     pub fn last_entry_update_number(&self) -> Result<EntryUpdateNumber> {
         Ok(self
             .db
@@ -89,27 +286,114 @@ impl Db {
             .into())
     }
 
+    /// Checks that the database was populated using the same archive prefix.
+    /// Archive entries are stored relative to the prefix, so they can't be reused
+    /// if the prefix changes.
+    pub fn check_archive_prefix(&self, prefix: Option<&ArchivePath>) -> Result<()> {
+        let prefix = prefix.map_or("/", |prefix| prefix.to_str_without_prefix());
+        if let Some(stored) = self.db.get(KEY_ARCHIVE_PREFIX)? {
+            if stored != prefix.as_bytes() {
+                bail!(
+                    "local database was created with archive prefix {:?}, \
+                    but archive prefix {:?} is configured; \
+                    use a different local database path for each archive prefix",
+                    String::from_utf8_lossy(&stored),
+                    prefix,
+                );
+            }
+        } else {
+            if prefix != "/" && !self.archive_entries.is_empty() {
+                bail!(
+                    "local database was created without archive prefix, \
+                    but archive prefix {:?} is configured; \
+                    use a different local database path for each archive prefix",
+                    prefix,
+                );
+            }
+            self.db.insert(KEY_ARCHIVE_PREFIX, prefix.as_bytes())?;
+        }
+        Ok(())
+    }
+
+    pub fn cached_sources(&self) -> Result<Option<CachedSources>> {
+        if let Some(value) = self.db.get(KEY_CACHED_SOURCES)? {
+            Ok(Some(bincode::deserialize::<CachedSources>(&value)?))
+        } else {
+            Ok(None)
+        }
+    }
+
+    pub fn set_cached_sources(&self, sources: &CachedSources) -> Result<()> {
+        self.db
+            .insert(KEY_CACHED_SOURCES, bincode::serialize(sources)?)?;
+        Ok(())
+    }
+
+    /// Saves received archive entries along with their update numbers.
     pub fn update_archive_entries(
         &self,
-        updates: &[DecryptedEntryVersionData],
+        updates: &[(DecryptedEntryVersionData, EntryUpdateNumber)],
         update_number: EntryUpdateNumber,
     ) -> Result<()> {
-        if updates.is_empty() {
-            return Ok(());
-        }
-        (&*self.db, &self.archive_entries).transaction(|(db, archive_entries)| {
-            for update in updates {
-                archive_entries.insert(
-                    update.path.to_str_without_prefix().as_bytes(),
-                    bincode::serialize(update).map_err(into_abort_err)?,
+        (
+            &*self.db,
+            &self.archive_entries,
+            &self.archive_update_numbers,
+        )
+            .transaction(|(db, archive_entries, archive_update_numbers)| {
+                for (update, update_number) in updates {
+                    let key = update.path.to_str_without_prefix().as_bytes();
+                    archive_entries.insert(key, self.encode(update).map_err(into_abort_err)?)?;
+                    archive_update_numbers.insert(key, &i64::from(*update_number).to_le_bytes())?;
+                }
+                db.insert(
+                    &KEY_LAST_ENTRY_UPDATE_NUMBER,
+                    &i64::from(update_number).to_le_bytes(),
                 )?;
-            }
-            db.insert(
-                &KEY_LAST_ENTRY_UPDATE_NUMBER,
-                &i64::from(update_number).to_le_bytes(),
-            )?;
-            Ok(())
-        })?;
+                Ok(())
+            })?;
+        Ok(())
+    }
+
+    /// Returns progress of bootstrapping if it was started but not finished.
+    pub fn bootstrap_progress(&self) -> Result<Option<BootstrapProgress>> {
+        if let Some(value) = self.db.get(KEY_BOOTSTRAP_PROGRESS)? {
+            Ok(Some(bincode::deserialize::<BootstrapProgress>(&value)?))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Saves entries received while bootstrapping the database along with the progress.
+    /// If `finished` is true, `progress.update_number` is saved as the last update number
+    /// instead.
+    pub fn save_bootstrap_entries(
+        &self,
+        entries: &[(DecryptedEntryVersionData, EntryUpdateNumber)],
+        progress: &BootstrapProgress,
+        finished: bool,
+    ) -> Result<()> {
+        let update_number = i64::from(progress.update_number).to_le_bytes();
+        let progress = bincode::serialize(progress)?;
+        (
+            &*self.db,
+            &self.archive_entries,
+            &self.archive_update_numbers,
+        )
+            .transaction(|(db, archive_entries, archive_update_numbers)| {
+                for (entry, update_number) in entries {
+                    let key = entry.path.to_str_without_prefix().as_bytes();
+                    archive_entries.insert(key, self.encode(entry).map_err(into_abort_err)?)?;
+                    archive_update_numbers.insert(key, &i64::from(*update_number).to_le_bytes())?;
+                }
+                if finished {
+                    db.remove(&KEY_BOOTSTRAP_PROGRESS)?;
+                    db.insert(&KEY_LAST_ENTRY_UPDATE_NUMBER, &update_number)?;
+                } else {
+                    db.insert(&KEY_BOOTSTRAP_PROGRESS, progress.as_slice())?;
+                }
+                Ok(())
+            })?;
         Ok(())
     }
 
@@ -120,30 +404,418 @@ impl Db {
         self.local_entries.iter().map(|pair| {
             let (key, value) = pair?;
             let path = SanitizedLocalPath::new(str::from_utf8(&key)?)?;
-            let data = bincode::deserialize::<LocalEntryInfo>(&value)?;
+            let data = decode::<LocalEntryInfo>(&value)?;
             Ok((path, data))
         })
     }
 
+    /// Returns paths of all local entries nested in `path`.
+    pub fn get_local_entry_paths_under(
+        &self,
+        path: &SanitizedLocalPath,
+    ) -> impl Iterator<Item = Result<SanitizedLocalPath>> + DoubleEndedIterator {
+        let mut prefix = path.as_str().to_owned();
+        if !prefix.ends_with(MAIN_SEPARATOR) {
+            prefix.push(MAIN_SEPARATOR);
+        }
+        self.local_entries.scan_prefix(prefix).map(|pair| {
+            let (key, _) = pair?;
+            SanitizedLocalPath::new(str::from_utf8(&key)?)
+        })
+    }
+
     pub fn get_local_entry(&self, path: &SanitizedLocalPath) -> Result<Option<LocalEntryInfo>> {
         if let Some(value) = self.local_entries.get(path)? {
-            Ok(Some(bincode::deserialize::<LocalEntryInfo>(&value)?))
+            Ok(Some(decode::<LocalEntryInfo>(&value)?))
         } else {
             Ok(None)
         }
     }
 
+    /// Returns the last update number that was pulled from the server when
+    /// the local entry was saved. It's unknown for entries saved by older versions
+    /// of the client.
+    pub fn get_local_update_number(
+        &self,
+        path: &SanitizedLocalPath,
+    ) -> Result<Option<EntryUpdateNumber>> {
+        Ok(self
+            .local_update_numbers
+            .get(path)?
+            .map(|value| LE::read_i64(&value).into()))
+    }
+
     pub fn set_local_entry(&self, path: &SanitizedLocalPath, data: &LocalEntryInfo) -> Result<()> {
-        self.local_entries.insert(path, bincode::serialize(data)?)?;
+        let value = self.encode(data)?;
+        let update_number = i64::from(self.last_entry_update_number()?).to_le_bytes();
+        (&self.local_entries, &self.local_update_numbers).transaction(
+            |(local_entries, local_update_numbers)| {
+                local_entries.insert(path.as_str().as_bytes(), value.as_slice())?;
+                local_update_numbers.insert(path.as_str().as_bytes(), &update_number)?;
+                Ok::<_, ConflictableTransactionError>(())
+            },
+        )?;
         Ok(())
     }
 
     pub fn remove_local_entry(&self, path: &SanitizedLocalPath) -> Result<()> {
         self.local_entries.remove(path)?;
+        self.local_update_numbers.remove(path)?;
         Ok(())
     }
+
+    pub fn get_all_deleted_local_files(
+        &self,
+    ) -> impl Iterator<Item = Result<(ContentHash, DeletedLocalFile)>> {
+        self.deleted_local_files.iter().map(|pair| {
+            let (key, value) = pair?;
+            Ok((key.to_vec().try_into()?, decode(&value)?))
+        })
+    }
+
+    pub fn get_deleted_local_file(&self, hash: &ContentHash) -> Result<Option<DeletedLocalFile>> {
+        self.deleted_local_files
+            .get(hash.as_slice())?
+            .map(|value| decode(&value))
+            .transpose()
+    }
+
+    pub fn set_deleted_local_file(
+        &self,
+        hash: &ContentHash,
+        data: &DeletedLocalFile,
+    ) -> Result<()> {
+        self.deleted_local_files
+            .insert(hash.as_slice(), self.encode(data)?)?;
+        Ok(())
+    }
+
+    pub fn remove_deleted_local_file(&self, hash: &ContentHash) -> Result<()> {
+        self.deleted_local_files.remove(hash.as_slice())?;
+        Ok(())
+    }
+}
+
+/// Decodes an entry, decompressing it first if it was compressed.
+fn decode<T: DeserializeOwned>(value: &[u8]) -> Result<T> {
+    match value.split_first() {
+        Some((&TAG_UNCOMPRESSED, data)) => Ok(bincode::deserialize(data)?),
+        Some((&TAG_ZSTD, data)) => Ok(bincode::deserialize(&zstd::decode_all(data)?)?),
+        Some((tag, _)) => bail!("unknown local database entry tag: {}", tag),
+        None => bail!("empty local database entry"),
+    }
+}
+
+/// Cleans up after a compaction that was interrupted. The caller must hold the lock.
+///
+/// If the database was already moved to `<path>.old`, but the new database wasn't
+/// moved in its place yet, the old database is moved back. Leftovers of the new
+/// database are removed, and so is the old database if the new one is in place.
+fn recover_compaction(path: &Path) -> Result<()> {
+    let new_path = with_suffix(path, ".compacting");
+    let old_path = with_suffix(path, ".old");
+    if try_exists(&old_path)? {
+        if try_exists(path)? {
+            remove_dir_all(&old_path)?;
+        } else {
+            warn!(
+                "compaction of local database {} was interrupted, restoring the database",
+                path.display()
+            );
+            rename(&old_path, path)?;
+        }
+    }
+    if try_exists(&new_path)? {
+        remove_dir_all(&new_path)?;
+    }
+    Ok(())
+}
+
+pub fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(suffix);
+    path.into()
+}
+
+/// Error returned when files of the local database are corrupted.
+#[derive(Debug)]
+struct CorruptedDb {
+    path: PathBuf,
+    message: String,
+}
+
+impl fmt::Display for CorruptedDb {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "local database {} is corrupted: {}",
+            self.path.display(),
+            self.message
+        )
+    }
+}
+
+impl std::error::Error for CorruptedDb {}
+
+/// Opens a sled database.
+///
+/// sled may panic instead of returning an error if its files are corrupted. Such panics
+/// and corruption errors are reported as `CorruptedDb`, other panics as regular errors.
+fn open_sled(path: &Path) -> Result<sled::Db> {
+    wait_for_sled_lock(path)?;
+    let corrupted = |message| CorruptedDb {
+        path: path.to_path_buf(),
+        message,
+    };
+    match panic::catch_unwind(|| sled::open(path)) {
+        Ok(Err(err @ sled::Error::Corruption { .. })) => Err(corrupted(err.to_string()).into()),
+        Ok(result) => Ok(result?),
+        Err(payload) => {
+            let message = payload
+                .downcast_ref::<&str>()
+                .map(|s| s.to_string())
+                .or_else(|| payload.downcast_ref::<String>().cloned())
+                .unwrap_or_default();
+            if SLED_CORRUPTION_PANICS
+                .iter()
+                .any(|prefix| message.starts_with(prefix))
+            {
+                Err(corrupted(message).into())
+            } else {
+                bail!(
+                    "failed to open local database {}: sled panicked: {}",
+                    path.display(),
+                    message
+                )
+            }
+        }
+    }
+}
+
+/// Messages of panics that sled raises when it reads invalid data from its files.
+const SLED_CORRUPTION_PANICS: &[&str] = &["failed to deserialize data", "unexpected pull"];
+
+/// Waits until the file lock that sled takes on `<path>/db` is released.
+///
+/// sled may release its lock in a background thread shortly after the database
+/// is closed, so opening it again right away (e.g. after compaction) can fail.
+/// The caller must hold our advisory lock, so the sled lock can only be held by
+/// this process, and it's safe to wait for it. If the lock is still held after
+/// a timeout, sled reports the error when opening the database.
+fn wait_for_sled_lock(path: &Path) -> Result<()> {
+    let db_file = path.join("db");
+    if !try_exists(&db_file)? {
+        return Ok(());
+    }
+    let file = OpenOptions::new().read(true).write(true).open(db_file)?;
+    let started = Instant::now();
+    loop {
+        match file.file().try_lock_exclusive() {
+            Ok(()) => {
+                file.file().unlock()?;
+                return Ok(());
+            }
+            Err(err) if err.kind() != fs2::lock_contended_error().kind() => {
+                return Err(err.into());
+            }
+            Err(_) if started.elapsed() >= SLED_LOCK_TIMEOUT => return Ok(()),
+            Err(_) => block_in_place(|| thread::sleep(Duration::from_millis(50))),
+        }
+    }
+}
+
+const SLED_LOCK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Acquires an advisory lock on `<path>.lock`.
+///
+/// The lock is released automatically by the OS when the process exits,
+/// so a lock file left behind by a crashed process doesn't block new runs.
+fn lock(path: &Path, wait: bool) -> Result<File> {
+    if let Some(parent) = path.parent() {
+        fs_err::create_dir_all(parent)?;
+    }
+    let file = OpenOptions::new()
+        .create(true)
+        .write(true)
+        .open(with_suffix(path, ".lock"))?;
+    if let Err(err) = file.file().try_lock_exclusive() {
+        if err.kind() != fs2::lock_contended_error().kind() {
+            return Err(err.into());
+        }
+        if !wait {
+            bail!(
+                "local database {} is used by another instance of rammingen \
+                (use --wait to wait for it to finish)",
+                path.display()
+            );
+        }
+        let _status = set_status("Waiting for another instance of rammingen to finish");
+        block_in_place(|| file.file().lock_exclusive())?;
+    }
+    Ok(file)
 }
 
 fn into_abort_err(e: impl Debug) -> ConflictableTransactionError<io::Error> {
     ConflictableTransactionError::Abort(io::Error::new(io::ErrorKind::Other, format!("{e:?}")))
 }
+
+#[test]
+pub fn interrupted_compaction_is_recovered() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("db");
+    let old_path = with_suffix(&path, ".old");
+    let new_path = with_suffix(&path, ".compacting");
+
+    // Interrupted after the old database was moved away.
+    fs_err::create_dir(&old_path).unwrap();
+    fs_err::write(old_path.join("data"), "old").unwrap();
+    fs_err::create_dir(&new_path).unwrap();
+    recover_compaction(&path).unwrap();
+    assert_eq!(fs_err::read_to_string(path.join("data")).unwrap(), "old");
+    assert!(!old_path.exists());
+    assert!(!new_path.exists());
+
+    // Interrupted after the new database was moved in place.
+    fs_err::create_dir(&old_path).unwrap();
+    recover_compaction(&path).unwrap();
+    assert!(path.exists());
+    assert!(!old_path.exists());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+pub async fn corrupted_db_is_repaired() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("db");
+    drop(Db::open(&path, false, false).unwrap());
+    fs_err::write(path.join("conf"), "not a valid sled config").unwrap();
+
+    let err = Db::open(&path, false, false).err().unwrap();
+    assert!(err.to_string().contains("--repair-db"), "{err:#}");
+    drop(Db::open(&path, false, true).unwrap());
+    assert!(with_suffix(&path, ".corrupted").exists());
+    drop(Db::open(&path, false, false).unwrap());
+}
+
+#[cfg(test)]
+fn test_entries() -> (LocalEntryInfo, DecryptedEntryVersionData) {
+    use crate::data::DecryptedFileContent;
+    use rammingen_protocol::{ContentHash, RecordTrigger};
+
+    let content = DecryptedFileContent {
+        modified_at: Utc::now(),
+        original_size: 12345,
+        encrypted_size: 12400,
+        hash: ContentHash::new(rand::random()),
+        unix_mode: Some(0o644),
+    };
+    let local = LocalEntryInfo {
+        kind: EntryKind::File,
+        content: Some(content.clone()),
+    };
+    let archive = DecryptedEntryVersionData {
+        path: "ar:/my_files/documents/projects/report.txt"
+            .parse()
+            .unwrap(),
+        recorded_at: Utc::now(),
+        source_id: 1.into(),
+        record_trigger: RecordTrigger::Sync,
+        kind: Some(EntryKind::File),
+        content: Some(content),
+    };
+    (local, archive)
+}
+
+#[tokio::test(flavor = "multi_thread")]
+pub async fn compression_can_be_toggled() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("db");
+    let (local, archive) = test_entries();
+    fn encoded(value: &impl Serialize) -> Vec<u8> {
+        bincode::serialize(value).unwrap()
+    }
+    // Entries written before and after each change must stay readable.
+    let mut written = Vec::new();
+    for (i, compress) in [false, true, false].into_iter().enumerate() {
+        let db = Db::open(&path, false, false)
+            .unwrap()
+            .with_compression(compress);
+        let local_path = SanitizedLocalPath::new(dir.path().join(format!("file{i}"))).unwrap();
+        let archive_path = archive.path.join_one(&format!("file{i}")).unwrap();
+        db.set_local_entry(&local_path, &local).unwrap();
+        let entry = DecryptedEntryVersionData {
+            path: archive_path.clone(),
+            content: archive.content.clone(),
+            ..archive
+        };
+        db.update_archive_entries(&[(entry, (i as i64).into())], (i as i64).into())
+            .unwrap();
+        written.push((local_path, archive_path));
+        for (local_path, archive_path) in &written {
+            let read_local = db.get_local_entry(local_path).unwrap().unwrap();
+            assert_eq!(encoded(&read_local), encoded(&local));
+            let read_archive = db.get_archive_entry(archive_path).unwrap().unwrap();
+            assert_eq!(&read_archive.path, archive_path);
+            assert_eq!(encoded(&read_archive.content), encoded(&archive.content));
+        }
+    }
+}
+
+#[test]
+fn compression_shrinks_entries() {
+    let (local, archive) = test_entries();
+    let compressed = Db::open_temporary().unwrap().with_compression(true);
+    let raw_db = Db::open_temporary().unwrap();
+    let archive_raw = raw_db.encode(&archive).unwrap();
+    let archive_compressed = compressed.encode(&archive).unwrap();
+    assert_eq!(archive_raw[0], TAG_UNCOMPRESSED);
+    assert_eq!(archive_compressed[0], TAG_ZSTD);
+    assert!(archive_compressed.len() < archive_raw.len());
+    let decoded = decode::<DecryptedEntryVersionData>(&archive_compressed).unwrap();
+    assert_eq!(
+        bincode::serialize(&decoded).unwrap(),
+        bincode::serialize(&archive).unwrap()
+    );
+    // Entries that compression doesn't shrink are stored uncompressed.
+    let local_raw = raw_db.encode(&local).unwrap();
+    let local_compressed = compressed.encode(&local).unwrap();
+    assert!(local_compressed.len() <= local_raw.len());
+    let decoded = decode::<LocalEntryInfo>(&local_compressed).unwrap();
+    assert_eq!(
+        bincode::serialize(&decoded).unwrap(),
+        bincode::serialize(&local).unwrap()
+    );
+}
+
+#[test]
+fn untagged_entries_are_upgraded() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("db");
+    let (local, archive) = test_entries();
+    let local_path = SanitizedLocalPath::new(dir.path().join("file")).unwrap();
+    let archive_key = archive.path.to_str_without_prefix().as_bytes().to_vec();
+    {
+        // Entries as written before the format was tagged.
+        let db = open_sled(&path).unwrap();
+        db.open_tree("local_entries")
+            .unwrap()
+            .insert(local_path.as_str(), bincode::serialize(&local).unwrap())
+            .unwrap();
+        db.open_tree("archive_entries")
+            .unwrap()
+            .insert(&archive_key, bincode::serialize(&archive).unwrap())
+            .unwrap();
+    }
+    for _ in 0..2 {
+        let db = Db::open(&path, false, false).unwrap();
+        let read_local = db.get_local_entry(&local_path).unwrap().unwrap();
+        assert_eq!(
+            bincode::serialize(&read_local).unwrap(),
+            bincode::serialize(&local).unwrap()
+        );
+        let read_archive = db.get_archive_entry(&archive.path).unwrap().unwrap();
+        assert_eq!(
+            bincode::serialize(&read_archive).unwrap(),
+            bincode::serialize(&archive).unwrap()
+        );
+    }
+}
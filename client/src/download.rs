@@ -1,22 +1,33 @@
-use std::path::Path;
+use std::{
+    env,
+    path::{Path, PathBuf},
+};
 
 use anyhow::{anyhow, bail, Result};
-use fs_err::{create_dir, remove_dir, remove_file, rename};
-use futures::{stream, Stream, TryStreamExt};
+use chrono::Utc;
+use fs_err::{create_dir, create_dir_all, remove_dir, remove_file, rename, File};
+use futures::{stream, Stream, StreamExt, TryStreamExt};
+use globset::GlobMatcher;
+use itertools::Itertools;
 use rammingen_protocol::{
     endpoints::GetEntryVersionsAtTime,
     util::{archive_to_native_relative_path, try_exists},
-    ArchivePath, DateTimeUtc, EntryKind,
+    ArchivePath, ContentHash, DateTimeUtc, EntryKind, RecordTrigger,
 };
+use serde::Serialize;
 use stream_generator::generate_try_stream;
+use tokio::task::block_in_place;
 use tracing::{info, warn};
 
 use crate::{
-    data::{DecryptedEntryVersionData, LocalEntryInfo},
-    encryption::encrypt_path,
+    data::{DecryptedEntryVersionData, DecryptedFileContent, DeletedLocalFile, LocalEntryInfo},
+    db::with_suffix,
+    encryption::hash_file,
     path::SanitizedLocalPath,
+    pull_updates::pull_updates,
     rules::Rules,
     term::set_status,
+    upload::to_archive_path,
     Ctx,
 };
 
@@ -53,10 +64,11 @@ pub async fn download_version(
     root_archive_path: &ArchivePath,
     root_local_path: &SanitizedLocalPath,
     version: DateTimeUtc,
+    options: DownloadOptions<'_>,
 ) -> Result<bool> {
     let stream = generate_try_stream(move |mut y| async move {
         let mut response_stream = ctx.client.stream(&GetEntryVersionsAtTime {
-            path: encrypt_path(root_archive_path, &ctx.cipher)?,
+            path: ctx.encrypt_path(root_archive_path)?,
             recorded_at: version,
         });
         let mut any = false;
@@ -76,6 +88,7 @@ pub async fn download_version(
         root_local_path,
         &mut Rules::new(&[&ctx.config.always_exclude], root_local_path.clone()),
         false,
+        options,
         stream,
     )
     .await
@@ -87,6 +100,7 @@ pub async fn download_latest(
     root_local_path: &SanitizedLocalPath,
     rules: &mut Rules,
     is_mount: bool,
+    options: DownloadOptions<'_>,
 ) -> Result<bool> {
     let data = stream::iter(ctx.db.get_archive_entries(root_archive_path));
     download(
@@ -95,25 +109,390 @@ pub async fn download_latest(
         root_local_path,
         rules,
         is_mount,
+        options,
         data,
     )
     .await
 }
 
+/// Record of a restore written to `manifest.json`.
+#[derive(Debug, Serialize)]
+struct RestoreManifest<'a> {
+    archive_path: &'a ArchivePath,
+    version: DateTimeUtc,
+    restored_at: DateTimeUtc,
+    local_path: &'a SanitizedLocalPath,
+    files: u64,
+    directories: u64,
+    total_size: u64,
+}
+
+impl RestoreManifest<'_> {
+    fn count(&mut self, path: &Path) -> Result<()> {
+        let metadata = fs_err::symlink_metadata(path)?;
+        if metadata.is_dir() {
+            self.directories += 1;
+            for entry in fs_err::read_dir(path)? {
+                self.count(&entry?.path())?;
+            }
+        } else {
+            self.files += 1;
+            self.total_size += metadata.len();
+        }
+        Ok(())
+    }
+}
+
+/// Downloads the version of `archive_path` at `version` into an empty directory `into`
+/// and writes a manifest describing the restore.
+pub async fn restore(
+    ctx: &Ctx,
+    archive_path: &ArchivePath,
+    version: DateTimeUtc,
+    into: &SanitizedLocalPath,
+) -> Result<()> {
+    if let Some(mount_point) = ctx.config.mount_points.iter().find(|mount_point| {
+        into.as_path().starts_with(&mount_point.local_path)
+            || mount_point.local_path.as_path().starts_with(into)
+    }) {
+        bail!(
+            "cannot restore into {}: it overlaps with mount point {}",
+            into,
+            mount_point.local_path
+        );
+    }
+    if try_exists(into)? {
+        if fs_err::read_dir(into)?.next().is_some() {
+            bail!("cannot restore into {}: directory is not empty", into);
+        }
+    } else {
+        create_dir_all(into)?;
+    }
+    let local_path = into.join(archive_path.last_name().unwrap_or("root"))?;
+    download_version(
+        ctx,
+        archive_path,
+        &local_path,
+        version,
+        DownloadOptions::default(),
+    )
+    .await?;
+
+    let mut manifest = RestoreManifest {
+        archive_path,
+        version,
+        restored_at: Utc::now(),
+        local_path: &local_path,
+        files: 0,
+        directories: 0,
+        total_size: 0,
+    };
+    manifest.count(local_path.as_path())?;
+    fs_err::write(
+        into.join("manifest.json")?,
+        serde_json::to_string_pretty(&manifest)?,
+    )?;
+    info!(
+        "Restored {} files and {} directories into {}",
+        manifest.files, manifest.directories, local_path
+    );
+    Ok(())
+}
+
+/// Downloads missing and mismatched files of a mount point (or a path inside of it),
+/// comparing content of local files with the archive.
+pub async fn repair_mount(
+    ctx: &Ctx,
+    archive_path: &ArchivePath,
+    local_path: &SanitizedLocalPath,
+) -> Result<bool> {
+    let mut mount_points = ctx
+        .config
+        .mount_points
+        .iter()
+        .map(|mount_point| {
+            let rules = mount_point.rules(&ctx.config.always_exclude, &[]);
+            (mount_point, rules)
+        })
+        .collect_vec();
+    let Some((mounted_archive_path, rules)) = to_archive_path(local_path, &mut mount_points)?
+    else {
+        bail!("{} is not inside of a mount point", local_path);
+    };
+    if &mounted_archive_path != archive_path {
+        bail!(
+            "{} corresponds to {}, not {}",
+            local_path,
+            mounted_archive_path,
+            archive_path
+        );
+    }
+    pull_updates(ctx).await?;
+    download_latest(
+        ctx,
+        archive_path,
+        local_path,
+        rules,
+        true,
+        DownloadOptions {
+            checksum: true,
+            ..DownloadOptions::default()
+        },
+    )
+    .await
+}
+
+/// Returns the local file that `download` will need to compare with `entry`
+/// when verifying content, so that it can be hashed in advance.
+/// Conditions must match the ones used by `download`.
+fn file_to_verify(
+    ctx: &Ctx,
+    root_archive_path: &ArchivePath,
+    root_local_path: &SanitizedLocalPath,
+    rules: &mut Rules,
+    is_mount: bool,
+    options: &DownloadOptions<'_>,
+    entry: &DecryptedEntryVersionData,
+) -> Result<Option<SanitizedLocalPath>> {
+    if !options.checksum
+        || entry.kind != Some(EntryKind::File)
+        || options.excluded_triggers.contains(&entry.record_trigger)
+    {
+        return Ok(None);
+    }
+    let local_path = archive_to_local_path(&entry.path, root_archive_path, root_local_path)?;
+    if rules.matches(&local_path)? {
+        return Ok(None);
+    }
+    if let Some(pattern) = options.pattern {
+        if !matches_pattern(pattern, &entry.path, root_archive_path) {
+            return Ok(None);
+        }
+    }
+    let db_data = if is_mount {
+        ctx.db.get_local_entry(&local_path)?
+    } else {
+        None
+    };
+    if let Some(db_data) = db_data {
+        if !db_data.is_same_as_entry(entry) {
+            return Ok(None);
+        }
+    } else if options.overwrite_local_changes {
+        return Ok(None);
+    }
+    if !try_exists(local_path.as_path())? || !fs_err::symlink_metadata(&local_path)?.is_file() {
+        return Ok(None);
+    }
+    Ok(Some(local_path))
+}
+
+/// Returns the directory where partially downloaded content is kept,
+/// so that interrupted downloads can be resumed.
+fn resume_dir(ctx: &Ctx) -> PathBuf {
+    match &ctx.config.local_db_path {
+        Some(path) => with_suffix(path, ".downloads"),
+        None => env::temp_dir().join("rammingen-downloads"),
+    }
+}
+
+/// Returns the directory where files deleted in the archive are kept
+/// (see `retain_deleted_files` in config).
+fn deleted_files_dir(ctx: &Ctx) -> PathBuf {
+    match &ctx.config.local_db_path {
+        Some(path) => with_suffix(path, ".deleted"),
+        None => env::temp_dir().join("rammingen-deleted"),
+    }
+}
+
+/// Removes a local file that was deleted in the archive. If `retain_deleted_files`
+/// is set and the file wasn't changed locally, it's moved to `deleted_files_dir` instead.
+fn remove_deleted_file(
+    ctx: &Ctx,
+    path: &SanitizedLocalPath,
+    db_data: &LocalEntryInfo,
+) -> Result<()> {
+    if let (Some(_), Some(content)) = (ctx.config.retain_deleted_files, &db_data.content) {
+        if db_data.matches_real(path, ctx.config.mtime_precision)? {
+            let dir = deleted_files_dir(ctx);
+            create_dir_all(&dir)?;
+            match rename(path, dir.join(content.hash.to_string())) {
+                Ok(()) => {
+                    ctx.db.set_deleted_local_file(
+                        &content.hash,
+                        &DeletedLocalFile {
+                            deleted_at: Utc::now(),
+                            content: content.clone(),
+                        },
+                    )?;
+                    return Ok(());
+                }
+                Err(err) => warn!("Cannot retain deleted file {}: {}", path, err),
+            }
+        }
+    }
+    remove_file(path)?;
+    Ok(())
+}
+
+/// Moves a retained deleted file with the same content as `content` to `path`.
+/// Returns false if there is no such file, so the content has to be downloaded.
+fn restore_deleted_file(
+    ctx: &Ctx,
+    content: &DecryptedFileContent,
+    path: &SanitizedLocalPath,
+) -> Result<bool> {
+    if ctx.config.retain_deleted_files.is_none()
+        || ctx.db.get_deleted_local_file(&content.hash)?.is_none()
+    {
+        return Ok(false);
+    }
+    ctx.db.remove_deleted_local_file(&content.hash)?;
+    let retained_path = deleted_files_dir(ctx).join(content.hash.to_string());
+    if !try_exists(&retained_path)? {
+        return Ok(false);
+    }
+    // The retained file could have been modified in the meantime.
+    if block_in_place(|| hash_file(&retained_path))?.0 != content.hash {
+        remove_file(&retained_path)?;
+        return Ok(false);
+    }
+    rename(&retained_path, path)?;
+    Ok(true)
+}
+
+/// Removes retained deleted files that are older than `retain_deleted_files`,
+/// or all of them if it's not set.
+fn remove_expired_deleted_files(ctx: &Ctx) -> Result<()> {
+    let retain_for = ctx
+        .config
+        .retain_deleted_files
+        .map(chrono::Duration::from_std)
+        .transpose()?;
+    for item in ctx.db.get_all_deleted_local_files() {
+        let (hash, file) = item?;
+        if retain_for.is_some_and(|retain_for| file.deleted_at + retain_for > Utc::now()) {
+            continue;
+        }
+        let retained_path = deleted_files_dir(ctx).join(hash.to_string());
+        if try_exists(&retained_path)? {
+            remove_file(&retained_path)?;
+        }
+        ctx.db.remove_deleted_local_file(&hash)?;
+    }
+    Ok(())
+}
+
+/// Returns true if the local file system entry at `path` has the same kind and content
+/// as `entry`. `hash` is the hash of the local file, if it was computed in advance.
+fn content_matches(
+    path: &SanitizedLocalPath,
+    entry: &DecryptedEntryVersionData,
+    hash: Option<&ContentHash>,
+) -> Result<bool> {
+    if !try_exists(path)? {
+        return Ok(false);
+    }
+    let metadata = fs_err::symlink_metadata(path)?;
+    match entry.kind {
+        Some(EntryKind::Directory) => Ok(metadata.is_dir()),
+        Some(EntryKind::File) => {
+            if !metadata.is_file() {
+                return Ok(false);
+            }
+            let content = entry
+                .content
+                .as_ref()
+                .ok_or_else(|| anyhow!("missing content info for existing file"))?;
+            if let Some(hash) = hash {
+                return Ok(hash == &content.hash);
+            }
+            let _status = set_status(format!("Verifying local file: {}", path));
+            Ok(block_in_place(|| hash_file(path))?.0 == content.hash)
+        }
+        None => Ok(false),
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DownloadOptions<'a> {
+    /// Compare content of existing local files with the archive
+    /// instead of trusting the local database. Files that differ are overwritten.
+    pub checksum: bool,
+    /// Create empty placeholder files instead of downloading file content.
+    pub metadata_only: bool,
+    /// Replace local files even if they were changed since they were last synced.
+    pub overwrite_local_changes: bool,
+    /// Skip entries with the latest version recorded by one of these triggers.
+    pub excluded_triggers: &'a [RecordTrigger],
+    /// Only download files matching this pattern. Directories are created
+    /// only as parents of matching files.
+    pub pattern: Option<&'a GlobMatcher>,
+}
+
+/// Creates missing parent directories of `path` within `root_local_path`.
+/// If `is_mount` is true, they're also recorded in the local database, as
+/// the archive already contains them.
+fn create_parents(
+    ctx: &Ctx,
+    path: &SanitizedLocalPath,
+    root_local_path: &SanitizedLocalPath,
+    is_mount: bool,
+) -> Result<()> {
+    let mut missing = Vec::new();
+    let mut current = path.parent()?;
+    while let Some(parent) = current {
+        if !parent.as_path().starts_with(root_local_path) || try_exists(parent.as_path())? {
+            break;
+        }
+        current = parent.parent()?;
+        missing.push(parent);
+    }
+    for parent in missing.into_iter().rev() {
+        create_dir(&parent)?;
+        if is_mount {
+            ctx.db.set_local_entry(
+                &parent,
+                &LocalEntryInfo {
+                    kind: EntryKind::Directory,
+                    content: None,
+                },
+            )?;
+        }
+    }
+    Ok(())
+}
+
+/// Checks whether `path` matches `pattern`. The pattern is matched against the path
+/// relative to `root_archive_path`, or against the file name if `path` is the root itself.
+fn matches_pattern(
+    pattern: &GlobMatcher,
+    path: &ArchivePath,
+    root_archive_path: &ArchivePath,
+) -> bool {
+    path.strip_prefix(root_archive_path)
+        .filter(|relative| !relative.is_empty())
+        .or_else(|| path.last_name())
+        .is_some_and(|relative| pattern.is_match(relative))
+}
+
+/// Downloads `versions` to `root_local_path`.
 pub async fn download(
     ctx: &Ctx,
     root_archive_path: &ArchivePath,
     root_local_path: &SanitizedLocalPath,
     rules: &mut Rules,
     is_mount: bool,
+    options: DownloadOptions<'_>,
     versions: impl Stream<Item = Result<DecryptedEntryVersionData>>,
 ) -> Result<bool> {
     tokio::pin!(versions);
     if is_mount {
         let _status = set_status("Checking for files deleted remotely");
+        remove_expired_deleted_files(ctx)?;
         for entry in ctx.db.get_archive_entries(root_archive_path).rev() {
             let entry = entry?;
-            if entry.kind.is_some() {
+            if entry.kind.is_some() || options.excluded_triggers.contains(&entry.record_trigger) {
                 continue;
             }
             let entry_local_path =
@@ -127,7 +506,7 @@ pub async fn download(
             if try_exists(entry_local_path.as_path())? {
                 match db_data.kind {
                     EntryKind::File => {
-                        remove_file(&entry_local_path)?;
+                        remove_deleted_file(ctx, &entry_local_path, &db_data)?;
                     }
                     EntryKind::Directory => {
                         if let Err(err) = remove_dir(&entry_local_path) {
@@ -141,17 +520,67 @@ pub async fn download(
             info!("Removed {}", entry_local_path);
         }
     }
+    // Local files are hashed in parallel ahead of the entries being processed.
+    let mut prefetch_rules = rules.clone();
+    let versions = versions
+        .map(|entry| {
+            let job = entry.and_then(|entry| {
+                let path = file_to_verify(
+                    ctx,
+                    root_archive_path,
+                    root_local_path,
+                    &mut prefetch_rules,
+                    is_mount,
+                    &options,
+                    &entry,
+                )?;
+                let size = entry
+                    .content
+                    .as_ref()
+                    .map_or(0, |content| content.original_size);
+                let job = path.map(|path| ctx.hash_pool.spawn(size, move || hash_file(path)));
+                Ok((entry, job))
+            });
+            async move {
+                let (entry, job) = job?;
+                let hash = if let Some(job) = job {
+                    Some(job.await?.0)
+                } else {
+                    None
+                };
+                anyhow::Ok((entry, hash))
+            }
+        })
+        .buffered(ctx.config.hash_threads.max(1));
+    tokio::pin!(versions);
     let mut found_any = false;
-    while let Some(entry) = versions.try_next().await? {
+    while let Some((entry, hash)) = versions.try_next().await? {
         let Some(kind) = entry.kind else {
             continue;
         };
+        if options.excluded_triggers.contains(&entry.record_trigger) {
+            continue;
+        }
         let entry_local_path =
             archive_to_local_path(&entry.path, root_archive_path, root_local_path)?;
         if rules.matches(&entry_local_path)? {
             continue;
         }
+        if let Some(pattern) = options.pattern {
+            if kind == EntryKind::Directory
+                || !matches_pattern(pattern, &entry.path, root_archive_path)
+            {
+                continue;
+            }
+            if let Some(parent) = entry_local_path.parent()? {
+                create_dir_all(&parent)?;
+            }
+        } else if !options.excluded_triggers.is_empty() {
+            // Parent directories may have been skipped because of their trigger.
+            create_parents(ctx, &entry_local_path, root_local_path, is_mount)?;
+        }
         let _status = set_status(format!("Scanning remote files: {}", root_local_path));
+        found_any = true;
 
         let mut must_delete = false;
         let db_data = if is_mount {
@@ -160,17 +589,42 @@ pub async fn download(
             None
         };
         if let Some(db_data) = &db_data {
-            if db_data.is_same_as_entry(&entry) {
-                continue;
+            if options.checksum {
+                if db_data.is_same_as_entry(&entry)
+                    && content_matches(&entry_local_path, &entry, hash.as_ref())?
+                {
+                    continue;
+                }
+                must_delete = try_exists(entry_local_path.as_path())?;
+            } else {
+                if db_data.is_same_as_entry(&entry) {
+                    continue;
+                }
+                if !options.overwrite_local_changes
+                    && !db_data.matches_real(&entry_local_path, ctx.config.mtime_precision)?
+                {
+                    bail!(
+                        "local db data doesn't match local file at {:?}",
+                        entry_local_path
+                    );
+                }
+                must_delete =
+                    !options.overwrite_local_changes || try_exists(entry_local_path.as_path())?;
             }
-            if !db_data.matches_real(&entry_local_path)? {
-                bail!(
-                    "local db data doesn't match local file at {:?}",
-                    entry_local_path
-                );
+        } else if options.overwrite_local_changes {
+            must_delete = try_exists(entry_local_path.as_path())?;
+        } else if options.checksum && try_exists(entry_local_path.as_path())? {
+            if content_matches(&entry_local_path, &entry, hash.as_ref())? {
+                continue;
             }
             must_delete = true;
         }
+        if let Some(db_data) = &db_data {
+            if db_data.kind != kind {
+                ctx.counters
+                    .kind_changed(&entry_local_path, db_data.kind, kind);
+            }
+        }
         if !must_delete && try_exists(entry_local_path.as_path())? {
             bail!(
                 "local entry already exists at {:?} (while processing entry: {:?}",
@@ -181,12 +635,16 @@ pub async fn download(
 
         match kind {
             EntryKind::Directory => {
-                if must_delete {
+                let is_existing_dir =
+                    must_delete && fs_err::symlink_metadata(&entry_local_path)?.is_dir();
+                if must_delete && !is_existing_dir {
                     if !remove_dir_or_file(&entry_local_path)? {
                         continue;
                     }
                 }
-                create_dir(&entry_local_path)?;
+                if !is_existing_dir {
+                    create_dir(&entry_local_path)?;
+                }
                 ctx.db.set_local_entry(
                     &entry_local_path,
                     &LocalEntryInfo {
@@ -200,6 +658,18 @@ pub async fn download(
                     .content
                     .ok_or_else(|| anyhow!("missing content info for existing file"))?;
 
+                if options.metadata_only {
+                    if must_delete {
+                        if !remove_dir_or_file(&entry_local_path)? {
+                            continue;
+                        }
+                    }
+                    File::create(&entry_local_path)?;
+                    set_unix_mode(&entry_local_path, content.unix_mode)?;
+                    info!("Created placeholder for {}", entry_local_path);
+                    continue;
+                }
+
                 let file_name = entry_local_path
                     .file_name()
                     .ok_or_else(|| anyhow!("failed to get file name for local file path"))?;
@@ -211,12 +681,27 @@ pub async fn download(
                 if try_exists(&tmp_path)? {
                     remove_file(&tmp_path)?;
                 }
-                ctx.client
-                    .download_and_decrypt(&content, &tmp_path, &ctx.cipher)
-                    .await?;
+                if restore_deleted_file(ctx, &content, &tmp_path)? {
+                    info!("Reused retained deleted file for {}", entry_local_path);
+                } else {
+                    let _bytes_permit = ctx.hash_pool.reserve_bytes(content.original_size).await?;
+                    ctx.counters
+                        .timings
+                        .download
+                        .measure_async(ctx.client.download_and_decrypt(
+                            &content,
+                            &tmp_path,
+                            &resume_dir(ctx),
+                            &ctx.cipher,
+                        ))
+                        .await?;
+                }
                 if let Some(db_data) = &db_data {
                     // Check again just in case.
-                    if !db_data.matches_real(&entry_local_path)? {
+                    if !options.checksum
+                        && !options.overwrite_local_changes
+                        && !db_data.matches_real(&entry_local_path, ctx.config.mtime_precision)?
+                    {
                         bail!(
                             "local db data doesn't match local file at {:?}",
                             entry_local_path
@@ -229,16 +714,7 @@ pub async fn download(
                     }
                 }
                 rename(&tmp_path, &entry_local_path)?;
-
-                #[cfg(target_family = "unix")]
-                {
-                    use std::fs::Permissions;
-                    use std::os::unix::prelude::PermissionsExt;
-
-                    if let Some(mode) = content.unix_mode {
-                        fs_err::set_permissions(&entry_local_path, Permissions::from_mode(mode))?;
-                    }
-                }
+                set_unix_mode(&entry_local_path, content.unix_mode)?;
 
                 content.modified_at = fs_err::metadata(&entry_local_path)?.modified()?.into();
                 ctx.db.set_local_entry(
@@ -250,12 +726,25 @@ pub async fn download(
                 )?;
             }
         }
-        found_any = true;
         info!("Downloaded {}", entry_local_path);
     }
     Ok(found_any)
 }
 
+#[allow(unused_variables)]
+fn set_unix_mode(path: &SanitizedLocalPath, unix_mode: Option<u32>) -> Result<()> {
+    #[cfg(target_family = "unix")]
+    {
+        use std::fs::Permissions;
+        use std::os::unix::prelude::PermissionsExt;
+
+        if let Some(mode) = unix_mode {
+            fs_err::set_permissions(path, Permissions::from_mode(mode))?;
+        }
+    }
+    Ok(())
+}
+
 struct TmpGuard(SanitizedLocalPath);
 
 impl TmpGuard {
@@ -274,3 +763,17 @@ impl Drop for TmpGuard {
         }
     }
 }
+
+#[test]
+fn pattern_matching() {
+    let pattern = globset::Glob::new("*.raw").unwrap().compile_matcher();
+    let root: ArchivePath = "ar:/photos".parse().unwrap();
+    let matches = |path: &str| matches_pattern(&pattern, &path.parse().unwrap(), &root);
+    assert!(matches("ar:/photos/a.raw"));
+    assert!(matches("ar:/photos/2023/shoot/b.raw"));
+    assert!(!matches("ar:/photos/a.jpg"));
+    assert!(!matches("ar:/photos"));
+
+    let root: ArchivePath = "ar:/photos/a.raw".parse().unwrap();
+    assert!(matches_pattern(&pattern, &root, &root));
+}
@@ -61,6 +61,10 @@ fn nonce_size() -> usize {
     <Aes256SivAead as AeadCore>::NonceSize::to_int()
 }
 
+fn tag_size() -> usize {
+    <Aes256SivAead as AeadCore>::TagSize::to_int()
+}
+
 /// Passes through any writes and calculates Sha256 hash and size of the written data.
 struct HashingWriter<W> {
     hasher: Sha256,
@@ -166,16 +170,24 @@ impl<'a, W: Write> Write for EncryptingWriter<'a, W> {
     }
 }
 
-pub struct EncryptedFileData {
-    pub file: SpooledTempFile,
+pub struct EncryptedFileData<W = SpooledTempFile> {
+    pub file: W,
     pub hash: ContentHash,
     pub original_size: u64,
     pub encrypted_size: u64,
 }
 
 pub fn encrypt_file(path: impl AsRef<Path>, cipher: &Aes256SivAead) -> Result<EncryptedFileData> {
+    encrypt_file_to(path, cipher, SpooledTempFile::new(MAX_IN_MEMORY))
+}
+
+/// Encrypts content of a local file, writing encrypted content to `output`.
+pub fn encrypt_file_to<W: Write>(
+    path: impl AsRef<Path>,
+    cipher: &Aes256SivAead,
+    output: W,
+) -> Result<EncryptedFileData<W>> {
     let mut input_file = File::open(path.as_ref())?;
-    let output = SpooledTempFile::new(MAX_IN_MEMORY);
     let encryptor = EncryptingWriter::new(output, cipher)?;
     let encoder = DeflateEncoder::new(encryptor, CompressionOptions::high());
     let mut hasher = HashingWriter::new(encoder);
@@ -191,6 +203,56 @@ pub fn encrypt_file(path: impl AsRef<Path>, cipher: &Aes256SivAead) -> Result<En
     })
 }
 
+/// Returns size of encrypted content for compressed content of the specified size.
+fn encrypted_size(compressed_size: u64) -> u64 {
+    let block_size = BLOCK_SIZE as u64;
+    let num_blocks = compressed_size.div_ceil(block_size);
+    let block_overhead = (4 + nonce_size() + tag_size()) as u64;
+    // magic number + blocks
+    4 + compressed_size + num_blocks * block_overhead
+}
+
+/// Returns approximate size of encrypted content for a file of the specified size,
+/// assuming that the content doesn't compress.
+pub fn estimate_encrypted_size(original_size: u64) -> u64 {
+    encrypted_size(original_size)
+}
+
+/// Counts bytes written to it.
+struct CountingWriter(u64);
+
+impl Write for CountingWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0 += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Calculates size of encrypted content of a local file without encrypting it.
+/// Content is compressed before encryption, so it still has to be compressed.
+pub fn encrypted_file_size(path: impl AsRef<Path>) -> Result<u64> {
+    let mut input_file = File::open(path.as_ref())?;
+    let mut encoder = DeflateEncoder::new(CountingWriter(0), CompressionOptions::high());
+    io::copy(&mut input_file, &mut encoder)?;
+    // `encrypt_file_to` flushes the encoder before finishing it, which adds a sync block.
+    encoder.flush()?;
+    let CountingWriter(compressed_size) = encoder.finish()?;
+    Ok(encrypted_size(compressed_size))
+}
+
+/// Calculates hash and size of the content of a local file.
+pub fn hash_file(path: impl AsRef<Path>) -> Result<(ContentHash, u64)> {
+    let mut input_file = File::open(path.as_ref())?;
+    let mut hasher = HashingWriter::new(io::sink());
+    io::copy(&mut input_file, &mut hasher)?;
+    let (_, hash, size) = hasher.finish()?;
+    Ok((hash, size))
+}
+
 // Decrypts encrypted files.
 pub struct Decryptor<'a, W: Write> {
     // Whether the magic number has been read.
@@ -435,3 +497,30 @@ pub fn file_roundtrip() {
         }
     }
 }
+
+#[test]
+pub fn encrypted_file_size_matches_encryption() {
+    use aes_siv::KeyInit;
+    use tempfile::NamedTempFile;
+
+    let key = Aes256SivAead::generate_key(&mut OsRng);
+    let cipher = Aes256SivAead::new(&key);
+
+    let compressible = "some text ".repeat(10_000).into_bytes();
+    // Doesn't compress and takes several blocks.
+    let random: Vec<u8> = (0..BLOCK_SIZE * 5 / 2)
+        .map(|_| rand::random::<u8>())
+        .collect();
+    for content in [Vec::new(), compressible, random] {
+        let mut file = NamedTempFile::new().unwrap();
+        file.write_all(&content).unwrap();
+        file.flush().unwrap();
+
+        let encrypted = encrypt_file_to(file.path(), &cipher, Vec::new()).unwrap();
+        assert_eq!(encrypted.encrypted_size, encrypted.file.len() as u64);
+        assert_eq!(
+            encrypted_file_size(file.path()).unwrap(),
+            encrypted.encrypted_size
+        );
+    }
+}
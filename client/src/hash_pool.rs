@@ -0,0 +1,187 @@
+use std::{future::Future, sync::Arc};
+
+use anyhow::Result;
+use tokio::{
+    sync::{OwnedSemaphorePermit, Semaphore},
+    task::spawn_blocking,
+};
+
+/// Sizes are counted in units of this many bytes, so that limits above 4 GiB
+/// fit into the number of semaphore permits.
+const BYTES_PER_PERMIT: u64 = 1024;
+
+/// Runs blocking jobs that read local files (e.g. hashing them) with bounded concurrency.
+/// Shared by all operations of the client, so that nested scans don't multiply the number
+/// of files read at the same time.
+///
+/// Also limits the total size of files that are read, uploaded or downloaded at the same time
+/// (see `max_bytes_in_flight` in config).
+#[derive(Debug)]
+pub struct HashPool {
+    semaphore: Arc<Semaphore>,
+    bytes: Option<BytesLimit>,
+}
+
+#[derive(Debug, Clone)]
+struct BytesLimit {
+    semaphore: Arc<Semaphore>,
+    max_permits: u32,
+}
+
+impl BytesLimit {
+    async fn reserve(&self, size: u64) -> Result<OwnedSemaphorePermit> {
+        let permits = size
+            .div_ceil(BYTES_PER_PERMIT)
+            .clamp(1, self.max_permits.into()) as u32;
+        Ok(self.semaphore.clone().acquire_many_owned(permits).await?)
+    }
+}
+
+/// Reservation of a part of `max_bytes_in_flight`. Released when dropped.
+#[derive(Debug)]
+pub struct BytesPermit {
+    _permit: Option<OwnedSemaphorePermit>,
+}
+
+impl HashPool {
+    pub fn new(threads: usize, max_bytes_in_flight: Option<u64>) -> Self {
+        Self {
+            semaphore: Arc::new(Semaphore::new(threads.max(1))),
+            bytes: max_bytes_in_flight.map(|max_bytes| {
+                let max_permits = (max_bytes / BYTES_PER_PERMIT)
+                    .clamp(1, Semaphore::MAX_PERMITS.min(u32::MAX as usize) as u64)
+                    as u32;
+                BytesLimit {
+                    semaphore: Arc::new(Semaphore::new(max_permits as usize)),
+                    max_permits,
+                }
+            }),
+        }
+    }
+
+    /// Waits until a file of `size` bytes fits into `max_bytes_in_flight`.
+    /// A file larger than the limit is admitted when no other files are in flight.
+    pub async fn reserve_bytes(&self, size: u64) -> Result<BytesPermit> {
+        let permit = match &self.bytes {
+            Some(bytes) => Some(bytes.reserve(size).await?),
+            None => None,
+        };
+        Ok(BytesPermit { _permit: permit })
+    }
+
+    /// Starts `job` immediately if the pool has a free slot and `size` bytes fit
+    /// into `max_bytes_in_flight`, or once that is the case.
+    /// The job runs in the background even if the returned future is not polled,
+    /// so waiting for it never blocks other jobs.
+    pub fn spawn<T, F>(&self, size: u64, job: F) -> impl Future<Output = Result<T>>
+    where
+        T: Send + 'static,
+        F: FnOnce() -> Result<T> + Send + 'static,
+    {
+        let semaphore = self.semaphore.clone();
+        let bytes = self.bytes.clone();
+        let handle = tokio::spawn(async move {
+            let _bytes_permit = match &bytes {
+                Some(bytes) => Some(bytes.reserve(size).await?),
+                None => None,
+            };
+            let _permit = semaphore.acquire_owned().await?;
+            spawn_blocking(job).await?
+        });
+        async move { handle.await? }
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn concurrency_is_bounded() {
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc, Mutex,
+    };
+
+    let hash_threads = 3;
+    let pool = HashPool::new(hash_threads, None);
+    let running = Arc::new(AtomicUsize::new(0));
+    let peak = Arc::new(AtomicUsize::new(0));
+    // Jobs are held until the test releases them.
+    let (release, held) = mpsc::channel::<()>();
+    let held = Arc::new(Mutex::new(held));
+    let jobs = (0..12)
+        .map(|i| {
+            let running = running.clone();
+            let peak = peak.clone();
+            let held = held.clone();
+            pool.spawn(1, move || {
+                let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+                peak.fetch_max(now, Ordering::SeqCst);
+                held.lock().unwrap().recv()?;
+                running.fetch_sub(1, Ordering::SeqCst);
+                Ok(i)
+            })
+        })
+        .collect::<Vec<_>>();
+    // Jobs start without being polled.
+    while running.load(Ordering::SeqCst) < hash_threads {
+        tokio::task::yield_now().await;
+    }
+    for _ in 0..12 {
+        release.send(()).unwrap();
+    }
+    let results = futures::future::try_join_all(jobs).await.unwrap();
+    assert_eq!(results, (0..12).collect::<Vec<_>>());
+    assert_eq!(peak.load(Ordering::SeqCst), hash_threads);
+    assert_eq!(running.load(Ordering::SeqCst), 0);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn bytes_in_flight_are_bounded() {
+    use futures::FutureExt;
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc, Mutex,
+    };
+
+    // Only two 4 KiB files fit into the limit at once.
+    let pool = HashPool::new(8, Some(10 * 1024));
+    let running = Arc::new(AtomicUsize::new(0));
+    let peak = Arc::new(AtomicUsize::new(0));
+    let (release, held) = mpsc::channel::<()>();
+    let held = Arc::new(Mutex::new(held));
+    let jobs = (0..6)
+        .map(|i| {
+            let running = running.clone();
+            let peak = peak.clone();
+            let held = held.clone();
+            pool.spawn(4096, move || {
+                let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+                peak.fetch_max(now, Ordering::SeqCst);
+                held.lock().unwrap().recv()?;
+                running.fetch_sub(1, Ordering::SeqCst);
+                Ok(i)
+            })
+        })
+        .collect::<Vec<_>>();
+    while running.load(Ordering::SeqCst) < 2 {
+        tokio::task::yield_now().await;
+    }
+    for _ in 0..6 {
+        release.send(()).unwrap();
+    }
+    let results = futures::future::try_join_all(jobs).await.unwrap();
+    assert_eq!(results, (0..6).collect::<Vec<_>>());
+    assert_eq!(peak.load(Ordering::SeqCst), 2);
+
+    // A file larger than the limit is admitted alone.
+    let large = pool.reserve_bytes(1 << 20).await.unwrap();
+    assert!(pool.reserve_bytes(1).now_or_never().is_none());
+    drop(large);
+    pool.reserve_bytes(1).now_or_never().unwrap().unwrap();
+
+    let unlimited = HashPool::new(1, None);
+    let _large = unlimited.reserve_bytes(1 << 40).await.unwrap();
+    unlimited
+        .reserve_bytes(1 << 40)
+        .now_or_never()
+        .unwrap()
+        .unwrap();
+}
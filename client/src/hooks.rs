@@ -0,0 +1,51 @@
+use anyhow::{bail, Result};
+use tokio::process::Command;
+use tracing::{debug, warn};
+
+use crate::counters::Counters;
+
+/// Runs a hook command configured for a sync event.
+///
+/// Counters are passed to the command in `RAMMINGEN_*` environment variables,
+/// and the error message (if any) in `RAMMINGEN_ERROR`. Failure of the hook is logged,
+/// but doesn't affect the result of the sync.
+pub async fn run_hook(
+    name: &str,
+    command: Option<&Vec<String>>,
+    counters: &Counters,
+    error: Option<&anyhow::Error>,
+) {
+    let Some(command) = command else {
+        return;
+    };
+    if let Err(err) = run_command(command, counters, error).await {
+        warn!("{name} hook failed: {err:?}");
+    }
+}
+
+async fn run_command(
+    command: &[String],
+    counters: &Counters,
+    error: Option<&anyhow::Error>,
+) -> Result<()> {
+    let Some((program, args)) = command.split_first() else {
+        bail!("empty command");
+    };
+    let mut command = Command::new(program);
+    command.args(args);
+    for (name, value) in counters.values() {
+        command.env(
+            format!("RAMMINGEN_{}", name.to_uppercase()),
+            value.to_string(),
+        );
+    }
+    if let Some(error) = error {
+        command.env("RAMMINGEN_ERROR", format!("{error:#}"));
+    }
+    debug!("Running hook: {:?}", command);
+    let status = command.status().await?;
+    if !status.success() {
+        bail!("command exited with {status}");
+    }
+    Ok(())
+}
@@ -1,20 +1,38 @@
-use std::fmt::Display;
+use std::{
+    cmp::Reverse,
+    collections::{HashMap, HashSet},
+    fmt::Display,
+    str::FromStr,
+};
 
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, bail, Result};
 use byte_unit::Byte;
-use chrono::{DateTime, Local, SubsecRound, Timelike};
+use chrono::{DateTime, Local, SubsecRound, Timelike, Utc};
 use futures::TryStreamExt;
 use itertools::Itertools;
 use prettytable::{cell, format::FormatBuilder, row, Table};
 use rammingen_protocol::{
-    endpoints::{GetAllEntryVersions, GetDirectChildEntries, GetSources, SourceInfo},
-    ArchivePath, DateTimeUtc, EntryKind, SourceId,
+    endpoints::{
+        CheckIntegrity, ContentDigest, DirectChildEntriesCursor, GetAllEntryVersions,
+        GetContentDigests, GetDirectChildEntries, GetEntriesByHash, GetPinnedPaths, GetSources,
+        GetVersionHotspots, GetVersionStats, SourceInfo, CONTENT_DIGEST_DEPTH,
+    },
+    util::try_exists,
+    ArchivePath, ContentHash, DateTimeUtc, EncryptedArchivePath, Entry, EntryKind, SourceId,
 };
-use tracing::{error, info};
+use tokio::task::block_in_place;
+use tracing::{debug, error, info};
 
 use crate::{
-    data::DecryptedEntryVersionData, encryption::encrypt_path, path::SanitizedLocalPath,
-    pull_updates::pull_updates, rules::Rules, upload::to_archive_path, Ctx,
+    config::Config,
+    data::{CachedSources, DecryptedEntryVersionData},
+    encryption::{decrypt_path, encrypt_content_hash, hash_file},
+    path::SanitizedLocalPath,
+    pull_updates::pull_updates,
+    rules::Rules,
+    term::set_status,
+    upload::{to_archive_path, upload, VersionBatch},
+    Ctx,
 };
 
 struct Sources(Vec<SourceInfo>);
@@ -29,8 +47,105 @@ impl Sources {
     }
 }
 
-async fn get_sources(ctx: &Ctx) -> Result<Sources> {
-    ctx.client.request(&GetSources).await.map(Sources)
+/// Max number of direct child entries requested from the server at once.
+const CHILD_ENTRIES_PAGE_SIZE: u32 = 1000;
+
+/// Requests direct children of a path from the server page by page,
+/// so that large directories are never loaded at once.
+struct ChildEntryPages {
+    path: EncryptedArchivePath,
+    after: Option<DirectChildEntriesCursor>,
+    done: bool,
+}
+
+impl ChildEntryPages {
+    fn new(ctx: &Ctx, path: &ArchivePath) -> Result<Self> {
+        Ok(Self {
+            path: ctx.encrypt_path(path)?,
+            after: None,
+            done: false,
+        })
+    }
+
+    /// Returns the next page of entries, or `None` if all entries have been returned.
+    async fn next(&mut self, ctx: &Ctx) -> Result<Option<Vec<Entry>>> {
+        if self.done {
+            return Ok(None);
+        }
+        let page: Vec<Entry> = ctx
+            .client
+            .stream(&GetDirectChildEntries {
+                path: self.path.clone(),
+                after: self.after.take(),
+                limit: CHILD_ENTRIES_PAGE_SIZE,
+            })
+            .try_collect()
+            .await?;
+        self.done = page.len() < CHILD_ENTRIES_PAGE_SIZE as usize;
+        let Some(last) = page.last() else {
+            return Ok(None);
+        };
+        self.after = Some(DirectChildEntriesCursor {
+            kind: last.data.kind,
+            path: last.data.path.clone(),
+        });
+        Ok(Some(page))
+    }
+}
+
+/// Prints archive paths that start with `partial`. Only direct children of the last
+/// complete path component are considered, so the server is asked for a single directory.
+pub async fn complete(ctx: &Ctx, partial: &str) -> Result<()> {
+    let (parent, prefix) = partial
+        .rsplit_once('/')
+        .ok_or_else(|| anyhow!("archive path must start with 'ar:/'"))?;
+    let parent = if parent == "ar:" {
+        ArchivePath::from_str("ar:/")?
+    } else {
+        ArchivePath::from_str(parent)?
+    };
+    let mut pages = ChildEntryPages::new(ctx, &parent)?;
+    while let Some(page) = pages.next(ctx).await? {
+        for entry in page {
+            let entry = DecryptedEntryVersionData::new(ctx, entry.data)?;
+            let Some(kind) = entry.kind else {
+                continue;
+            };
+            let name = entry.path.last_name().ok_or_else(|| {
+                anyhow!("any child entry must have last name (path: {}", entry.path)
+            })?;
+            if !name.starts_with(prefix) {
+                continue;
+            }
+            if kind == EntryKind::Directory {
+                info!("{}/", entry.path);
+            } else {
+                info!("{}", entry.path);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Returns sources cached in the local database, requesting them from the server
+/// if the cache is missing or expired, or if `refresh` is true.
+async fn get_sources(ctx: &Ctx, refresh: bool) -> Result<Sources> {
+    if !refresh {
+        if let Some(cached) = ctx.db.cached_sources()? {
+            let age = Utc::now() - cached.fetched_at;
+            if age >= chrono::Duration::zero()
+                && age < chrono::Duration::from_std(ctx.config.sources_cache_ttl)?
+            {
+                return Ok(Sources(cached.sources));
+            }
+        }
+    }
+    let sources = ctx.client.request(&GetSources).await?;
+    ctx.db.set_cached_sources(&CachedSources {
+        fetched_at: Utc::now(),
+        sources: sources.clone(),
+    })?;
+    Ok(Sources(sources))
 }
 
 pub async fn local_status(ctx: &Ctx, path: &SanitizedLocalPath) -> Result<()> {
@@ -40,10 +155,7 @@ pub async fn local_status(ctx: &Ctx, path: &SanitizedLocalPath) -> Result<()> {
         .mount_points
         .iter()
         .map(|mount_point| {
-            let rules = Rules::new(
-                &[&ctx.config.always_exclude, &mount_point.exclude],
-                mount_point.local_path.clone(),
-            );
+            let rules = mount_point.rules(&ctx.config.always_exclude, &[]);
             (mount_point, rules)
         })
         .collect_vec();
@@ -51,11 +163,12 @@ pub async fn local_status(ctx: &Ctx, path: &SanitizedLocalPath) -> Result<()> {
     info!("normalized local path: {}", path);
 
     if let Some((archive_path, rules)) = to_archive_path(path, &mut mount_points)? {
+        rules.load_parent_ignore_files(path)?;
         if rules.matches(path)? {
-            info!("this path is ignored according to the configured exclude rules");
+            info!("this path is ignored according to the exclude rules");
         } else {
             info!("archive path: {}", archive_path);
-            let encrypted = encrypt_path(&archive_path, &ctx.cipher)?;
+            let encrypted = ctx.encrypt_path(&archive_path)?;
             info!("encrypted archive path: {}", encrypted);
             info!(
                 "archive entry in local db: {:?}",
@@ -73,9 +186,14 @@ pub async fn local_status(ctx: &Ctx, path: &SanitizedLocalPath) -> Result<()> {
     Ok(())
 }
 
-pub async fn ls(ctx: &Ctx, path: &ArchivePath, show_deleted: bool) -> Result<()> {
+pub async fn ls(
+    ctx: &Ctx,
+    path: &ArchivePath,
+    show_deleted: bool,
+    refresh_sources: bool,
+) -> Result<()> {
     pull_updates(ctx).await?;
-    let sources = get_sources(ctx).await?;
+    let sources = get_sources(ctx, refresh_sources).await?;
 
     let Some(main_entry) = ctx.db.get_archive_entry(path)? else {
         error!("no such path");
@@ -83,7 +201,7 @@ pub async fn ls(ctx: &Ctx, path: &ArchivePath, show_deleted: bool) -> Result<()>
     };
 
     info!("path: {}", main_entry.path);
-    let encrypted = encrypt_path(path, &ctx.cipher)?;
+    let encrypted = ctx.encrypt_path(path)?;
     info!("encrypted archive path: {}", encrypted);
     info!("recorded at: {}", pretty_time(main_entry.recorded_at));
     info!("source id: {}", sources.format(main_entry.source_id));
@@ -121,41 +239,35 @@ pub async fn ls(ctx: &Ctx, path: &ArchivePath, show_deleted: bool) -> Result<()>
         info!("current status: deleted");
     }
 
-    let mut entries = Vec::new();
-    let mut stream = ctx
-        .client
-        .stream(&GetDirectChildEntries(encrypt_path(path, &ctx.cipher)?));
-
-    while let Some(entry) = stream.try_next().await? {
-        entries.push(DecryptedEntryVersionData::new(ctx, entry.data)?);
-    }
-    // already sorted by path, so we use stable sort
-    entries.sort_by_key(|entry| match &entry.kind {
-        Some(EntryKind::Directory) => 0,
-        Some(EntryKind::File) => 1,
-        None => 2,
-    });
-
-    if !entries.is_empty() {
-        info!("");
-    }
-    let mut table = Table::new();
-    table.set_format(FormatBuilder::new().column_separator(' ').build());
+    // Entries are sorted by kind (directories first, then files, then deleted entries)
+    // and then by path, so we can print each page as it arrives.
+    let mut pages = ChildEntryPages::new(ctx, path)?;
     let mut num_hidden_deleted = 0;
-    for entry in entries {
-        let name = entry
-            .path
-            .last_name()
-            .ok_or_else(|| anyhow!("any child entry must have last name (path: {}", entry.path))?;
-        let recorded_at = pretty_time(entry.recorded_at);
-        if entry.kind.is_none() && !show_deleted {
-            num_hidden_deleted += 1;
-            continue;
+    let mut any_entries = false;
+    while let Some(page) = pages.next(ctx).await? {
+        if !any_entries {
+            info!("");
+            any_entries = true;
+        }
+        let mut table = Table::new();
+        table.set_format(FormatBuilder::new().column_separator(' ').build());
+        for entry in page {
+            let entry = DecryptedEntryVersionData::new(ctx, entry.data)?;
+            let name = entry.path.last_name().ok_or_else(|| {
+                anyhow!("any child entry must have last name (path: {}", entry.path)
+            })?;
+            let recorded_at = pretty_time(entry.recorded_at);
+            if entry.kind.is_none() && !show_deleted {
+                num_hidden_deleted += 1;
+                continue;
+            }
+            let status = pretty_status(&entry)?;
+            table.add_row(row![recorded_at, status, name]);
+        }
+        if !table.is_empty() {
+            info!("{table}");
         }
-        let status = pretty_status(&entry)?;
-        table.add_row(row![recorded_at, status, name]);
     }
-    info!("{table}");
 
     if num_hidden_deleted > 0 {
         info!(
@@ -167,9 +279,299 @@ pub async fn ls(ctx: &Ctx, path: &ArchivePath, show_deleted: bool) -> Result<()>
     Ok(())
 }
 
+pub fn mounts(config: &Config) -> Result<()> {
+    if config.mount_points.is_empty() {
+        info!("no mount points configured");
+    }
+    for mount_point in &config.mount_points {
+        info!("{} -> {}", mount_point.local_path, mount_point.archive_path);
+        if mount_point.priority != 0 {
+            info!("    priority: {}", mount_point.priority);
+        }
+        if !try_exists(&mount_point.local_path)? {
+            info!("    local path doesn't exist");
+        }
+        for rule in config.always_exclude.iter().chain(&mount_point.exclude) {
+            info!("    exclude: {}", rule);
+        }
+        if mount_point.exclude_hidden {
+            info!("    exclude: hidden files");
+        }
+        if mount_point.one_file_system {
+            info!("    exclude: other filesystems");
+        }
+        for fs_type in &mount_point.exclude_filesystems {
+            info!("    exclude: filesystem type {fs_type:?}");
+        }
+        for trigger in &mount_point.download_trigger_filter {
+            info!("    don't download changes recorded by: {trigger:?}");
+        }
+    }
+    Ok(())
+}
+
+/// Prints the effective configuration with secrets redacted.
+pub fn show_config(config: &Config) -> Result<()> {
+    let mut value = serde_json::to_value(config)?;
+    for key in [
+        "encryption_key",
+        "encryption_passphrase",
+        "access_token",
+        "admin_token",
+    ] {
+        value[key] = "<redacted>".into();
+    }
+    info!("{}", serde_json::to_string_pretty(&value)?);
+    Ok(())
+}
+
+pub async fn pinned_paths(ctx: &Ctx) -> Result<()> {
+    let pinned_paths = ctx.client.request(&GetPinnedPaths).await?;
+    let mut any = false;
+    for pinned in pinned_paths {
+        let mut path = decrypt_path(&pinned.path, &ctx.cipher)?;
+        if let Some(prefix) = &ctx.config.archive_prefix {
+            // Paths outside of the archive prefix are not visible to this client.
+            let Some(unnested) = path.unnest(prefix) else {
+                continue;
+            };
+            path = unnested;
+        }
+        any = true;
+        info!(
+            "{}{} (pinned at {})",
+            path,
+            if pinned.recursive { " (recursive)" } else { "" },
+            pretty_time(pinned.pinned_at)
+        );
+    }
+    if !any {
+        info!("no pinned paths");
+    }
+    Ok(())
+}
+
+pub async fn hotspots(ctx: &Ctx, path: &ArchivePath, limit: u32) -> Result<()> {
+    let hotspots = ctx
+        .client
+        .request(&GetVersionHotspots {
+            under: ctx.encrypt_path(path)?,
+            limit,
+        })
+        .await?;
+    if hotspots.is_empty() {
+        info!("no versions found");
+    }
+    for hotspot in hotspots {
+        info!(
+            "{} versions: {}",
+            hotspot.versions,
+            ctx.decrypt_path(&hotspot.path)?
+        );
+    }
+    Ok(())
+}
+
+/// Checks that file storage is consistent with database on the server.
+///
+/// Unless `full` is set, the server's digests of content hashes are compared instead
+/// of all hashes, and only prefixes with differing digests are inspected further.
+pub async fn check_integrity(ctx: &Ctx, full: bool) -> Result<()> {
+    if full {
+        ctx.client.request(&CheckIntegrity).await?;
+        info!("It's fine.");
+        return Ok(());
+    }
+    let mut num_requests = 0;
+    let mut num_errors = 0;
+    let mut prefixes = vec![String::new()];
+    while let Some(prefix) = prefixes.pop() {
+        let digests = ctx
+            .client
+            .request(&GetContentDigests {
+                prefix: prefix.clone(),
+            })
+            .await?;
+        num_requests += 1;
+        if digests.database == digests.storage {
+            continue;
+        }
+        for child in digests.children {
+            if child.database == child.storage {
+                continue;
+            }
+            if prefix.len() < CONTENT_DIGEST_DEPTH {
+                prefixes.push(child.prefix);
+                continue;
+            }
+            num_errors += 1;
+            if child.storage == ContentDigest::default() {
+                error!("hash not found in storage: {}", child.prefix);
+            } else if child.database == ContentDigest::default() {
+                error!("hash not found in db: {}", child.prefix);
+            } else {
+                error!("size mismatch for hash {}", child.prefix);
+            }
+        }
+    }
+    debug!("compared content digests in {} requests", num_requests);
+    if num_errors > 0 {
+        bail!(
+            "found {} inconsistencies between db and storage",
+            num_errors
+        );
+    }
+    info!("It's fine.");
+    Ok(())
+}
+
+pub async fn restore_estimate(
+    ctx: &Ctx,
+    path: &ArchivePath,
+    recorded_at: DateTimeUtc,
+) -> Result<()> {
+    let stats = ctx
+        .client
+        .request(&GetVersionStats {
+            recorded_at,
+            path: ctx.encrypt_path(path)?,
+        })
+        .await?;
+    if stats.files == 0 && stats.directories == 0 {
+        info!("no matching entries found");
+        return Ok(());
+    }
+    info!(
+        "restoring {} at {} would download {} files ({} directories), {} (encrypted size)",
+        path,
+        pretty_time(recorded_at),
+        stats.files,
+        stats.directories,
+        pretty_size(stats.encrypted_size)
+    );
+    Ok(())
+}
+
+pub async fn find_by_content(ctx: &Ctx, local_path: &SanitizedLocalPath) -> Result<()> {
+    let hash = {
+        let _status = set_status(format!("Hashing local file: {}", local_path));
+        block_in_place(|| hash_file(local_path))?.0
+    };
+    let mut stream = ctx
+        .client
+        .stream(&GetEntriesByHash(encrypt_content_hash(&hash, &ctx.cipher)?));
+    let mut any = false;
+    while let Some(entry) = stream.try_next().await? {
+        let mut path = decrypt_path(&entry.data.path, &ctx.cipher)?;
+        if let Some(prefix) = &ctx.config.archive_prefix {
+            // Paths outside of the archive prefix are not visible to this client.
+            let Some(unnested) = path.unnest(prefix) else {
+                continue;
+            };
+            path = unnested;
+        }
+        any = true;
+        info!("{}", path);
+    }
+    if !any {
+        info!("no files with the same content found in archive");
+    }
+    Ok(())
+}
+
+pub async fn duplicates(ctx: &Ctx, path: &ArchivePath) -> Result<()> {
+    pull_updates(ctx).await?;
+    let mut files_by_hash = HashMap::<ContentHash, (u64, Vec<ArchivePath>)>::new();
+    for entry in ctx.db.get_archive_entries(path) {
+        let entry = entry?;
+        if entry.kind != Some(EntryKind::File) {
+            continue;
+        }
+        let content = entry
+            .content
+            .ok_or_else(|| anyhow!("missing content for file entry"))?;
+        files_by_hash
+            .entry(content.hash)
+            .or_insert_with(|| (content.original_size, Vec::new()))
+            .1
+            .push(entry.path);
+    }
+    let mut groups = files_by_hash
+        .into_values()
+        .filter(|(_, paths)| paths.len() > 1)
+        .map(|(size, paths)| (size * (paths.len() as u64 - 1), paths))
+        .collect_vec();
+    groups.sort_by_key(|(wasted, paths)| {
+        (
+            Reverse(*wasted),
+            paths[0].to_str_without_prefix().to_owned(),
+        )
+    });
+
+    let mut total_wasted = 0;
+    for (wasted, paths) in &groups {
+        info!("{} copies, {} wasted:", paths.len(), pretty_size(*wasted));
+        for path in paths {
+            info!("    {}", path);
+        }
+        total_wasted += wasted;
+    }
+    info!(
+        "Found {} groups of duplicates, {} wasted in total",
+        groups.len(),
+        pretty_size(total_wasted)
+    );
+    Ok(())
+}
+
+pub async fn estimate(ctx: &Ctx, path: &SanitizedLocalPath) -> Result<()> {
+    let mut mount_points = ctx
+        .config
+        .mount_points
+        .iter()
+        .map(|mount_point| {
+            let rules = mount_point.rules(&ctx.config.always_exclude, &[]);
+            (mount_point, rules)
+        })
+        .collect_vec();
+    let mut default_rules = Rules::new(&[&ctx.config.always_exclude], path.clone());
+    // Files outside of mount points are estimated as if they were uploaded to the root.
+    let (archive_path, rules, is_mount) =
+        if let Some((archive_path, rules)) = to_archive_path(path, &mut mount_points)? {
+            rules.load_parent_ignore_files(path)?;
+            (archive_path, rules, true)
+        } else {
+            (ArchivePath::from_str("ar:/")?, &mut default_rules, false)
+        };
+    let mut batch = VersionBatch::estimate();
+    upload(
+        ctx,
+        path,
+        &archive_path,
+        rules,
+        is_mount,
+        &mut HashSet::new(),
+        &mut batch,
+    )
+    .await?;
+    let estimate = batch
+        .take_estimate()
+        .ok_or_else(|| anyhow!("missing upload estimate"))?;
+    info!(
+        "{} new or modified files ({} unchanged), {} new directories",
+        estimate.changed_files, estimate.unchanged_files, estimate.new_directories
+    );
+    info!(
+        "Total size: {}, estimated upload size: {} (before compression)",
+        pretty_size(estimate.original_size),
+        pretty_size(estimate.encrypted_size)
+    );
+    Ok(())
+}
+
 pub const DATE_TIME_FORMAT: &str = "%Y-%m-%d_%H:%M:%S";
 
-fn pretty_time(value: DateTimeUtc) -> impl Display {
+pub fn pretty_time(value: DateTimeUtc) -> impl Display {
     let mut local = DateTime::<Local>::from(value);
     if local.nanosecond() != 0 {
         local = local.trunc_subsecs(0) + chrono::Duration::seconds(1);
@@ -207,10 +609,15 @@ pub fn pretty_size(size: u64) -> impl Display {
         .to_string()
 }
 
-pub async fn list_versions(ctx: &Ctx, path: &ArchivePath, recursive: bool) -> Result<()> {
-    let sources = get_sources(ctx).await?;
+pub async fn list_versions(
+    ctx: &Ctx,
+    path: &ArchivePath,
+    recursive: bool,
+    refresh_sources: bool,
+) -> Result<()> {
+    let sources = get_sources(ctx, refresh_sources).await?;
     let mut stream = ctx.client.stream(&GetAllEntryVersions {
-        path: encrypt_path(path, &ctx.cipher)?,
+        path: ctx.encrypt_path(path)?,
         recursive,
     });
     let mut table = Table::new();
@@ -8,8 +8,11 @@ mod data;
 mod db;
 mod download;
 mod encryption;
+mod hash_pool;
+mod hooks;
 mod info;
 pub mod path;
+mod prune;
 mod pull_updates;
 pub mod rules;
 mod sync;
@@ -17,35 +20,51 @@ pub mod term;
 mod upload;
 
 use crate::{
-    info::{local_status, ls},
+    info::{
+        check_integrity, complete, duplicates, estimate, find_by_content, hotspots, local_status,
+        ls, mounts, pinned_paths, restore_estimate, show_config,
+    },
+    prune::{compact_db, prune_db},
     pull_updates::pull_updates,
-    upload::upload,
+    upload::{check_error_thresholds, upload, VersionBatch},
 };
 use aes_siv::{Aes256SivAead, KeyInit};
-use anyhow::{anyhow, bail, Result};
-use cli::Cli;
+use anyhow::{anyhow, bail, Context, Result};
+use chrono::Utc;
+use clap::Parser;
+use cli::{Cli, ScriptCommand};
 use client::Client;
 use config::Config;
 use counters::Counters;
+use db::with_suffix;
 use derivative::Derivative;
-use download::{download_latest, download_version};
-use encryption::encrypt_path;
-use info::{list_versions, pretty_size};
+use download::{download_latest, download_version, repair_mount, restore, DownloadOptions};
+use encryption::{decrypt_path, encrypt_path};
+use hash_pool::HashPool;
+use hooks::run_hook;
+use info::{list_versions, pretty_size, pretty_time};
 use path::SanitizedLocalPath;
 use rammingen_protocol::{
-    endpoints::{CheckIntegrity, GetServerStatus, MovePath, RemovePath, ResetVersion},
+    endpoints::{
+        GetServerLimits, GetServerStatus, GetServerTime, MovePath, PinPath, PurgeSource,
+        RemovePath, ResetVersion, Rollback, ServerLimits, UnpinPath,
+    },
     util::log_writer,
+    ArchivePath, DateTimeUtc, EncryptedArchivePath,
 };
-use rules::Rules;
+use rules::{Rule, Rules};
 use std::fs::Metadata;
 use std::{
     collections::HashSet,
-    path::PathBuf,
+    future::Future,
+    io::{self, Write},
+    path::{Path, PathBuf},
     sync::{Arc, Mutex},
 };
-use sync::sync;
+use sync::{resync_mount, sync, write_manifest};
 use term::TermLayer;
-use tracing::{error, info};
+use tokio::task::block_in_place;
+use tracing::{debug, error, info, warn};
 use tracing_subscriber::{
     prelude::__tracing_subscriber_SubscriberExt, util::SubscriberInitExt, EnvFilter,
 };
@@ -58,104 +77,383 @@ pub struct Ctx {
     pub cipher: Aes256SivAead,
     pub db: crate::db::Db,
     pub counters: Counters,
+    pub hash_pool: HashPool,
+    /// Limits of the server, requested on first use.
+    server_limits: tokio::sync::OnceCell<ServerLimits>,
 }
 
-pub async fn run(cli: Cli, config: Config) -> Result<()> {
+pub async fn run(cli: Cli, mut config: Config) -> Result<()> {
     let local_db_path = if let Some(v) = &config.local_db_path {
         v.clone()
     } else {
         let data_dir = dirs::data_dir().ok_or_else(|| anyhow!("cannot find config dir"))?;
         data_dir.join("rammingen.db")
     };
+    // Without the local database, nothing is stored next to it
+    // (e.g. partially downloaded content).
+    config.local_db_path = (!cli.no_local_db).then(|| local_db_path.clone());
+    let config_path = cli.config.clone().or_else(|| default_config_path().ok());
+    let own_files_rules = own_files_exclude_rules(config_path.as_deref(), &config, &local_db_path);
+    config.always_exclude.splice(0..0, own_files_rules);
+    if cli.command == cli::Command::Mounts {
+        return mounts(&config);
+    }
+    if cli.command == cli::Command::ShowConfig {
+        return show_config(&config);
+    }
+    if cli.no_local_db && cli.command.requires_local_db() {
+        bail!("this command can't be used with --no-local-db");
+    }
+    if cli.command == cli::Command::CompactDb {
+        return compact_db(&local_db_path, cli.wait);
+    }
+    let script = if let cli::Command::RunScript { path } = &cli.command {
+        let script = parse_script(path)?;
+        if cli.no_local_db {
+            if let Some((line, _)) = script
+                .iter()
+                .find(|(_, command)| command.requires_local_db())
+            {
+                bail!("{line:?} can't be used with --no-local-db");
+            }
+        }
+        Some(script)
+    } else {
+        None
+    };
+    let compress_local_db = config.compress_local_db;
+    let hash_threads = config.hash_threads;
+    let max_bytes_in_flight = config.max_bytes_in_flight;
+    let encryption_key = config.encryption_key()?;
     let ctx = Arc::new(Ctx {
         client: Client::new(config.server_url.clone(), &config.access_token),
-        cipher: Aes256SivAead::new(config.encryption_key.get()),
+        cipher: Aes256SivAead::new(encryption_key.get()),
         config,
-        db: crate::db::Db::open(&local_db_path)?,
+        db: if cli.no_local_db {
+            crate::db::Db::open_temporary()?
+        } else {
+            crate::db::Db::open(&local_db_path, cli.wait, cli.repair_db)?
+                .with_compression(compress_local_db)
+        },
         counters: Counters::default(),
+        hash_pool: HashPool::new(hash_threads, max_bytes_in_flight),
+        server_limits: Default::default(),
     });
+    ctx.db
+        .check_archive_prefix(ctx.config.archive_prefix.as_ref())?;
+    if let Some(script) = script {
+        let result = async {
+            for (line, command) in script {
+                info!("Running: {line}");
+                ctx.counters.reset();
+                handle_command(&ctx, command).await?;
+            }
+            anyhow::Ok(())
+        }
+        .await;
+        if cli.timings {
+            ctx.counters.timings.report();
+        }
+        result
+    } else {
+        let is_sync = matches!(cli.command, cli::Command::Sync { .. });
+        let result = handle_command(&ctx, cli.command).await;
+        if cli.timings {
+            ctx.counters.timings.report();
+        }
+        result?;
+        if is_sync && compaction_due(&ctx)? {
+            // The database must be closed before compaction.
+            drop(ctx);
+            compact_db(&local_db_path, cli.wait)?;
+        }
+        Ok(())
+    }
+}
+
+/// Checks whether the local database should be compacted according to `compact_db_interval`.
+fn compaction_due(ctx: &Ctx) -> Result<bool> {
+    let Some(interval) = ctx.config.compact_db_interval else {
+        return Ok(false);
+    };
+    Ok(match ctx.db.last_compacted_at()? {
+        Some(time) => Utc::now() - time >= chrono::Duration::from_std(interval)?,
+        None => true,
+    })
+}
+
+async fn handle_command(ctx: &Ctx, command: cli::Command) -> Result<()> {
     #[allow(unused_variables)]
-    match cli.command {
-        cli::Command::Sync => {
-            sync(&ctx).await?;
+    match command {
+        cli::Command::Sync { path } => {
+            let config = &ctx.config;
+            run_hook("on_start", config.on_start.as_ref(), &ctx.counters, None).await;
+            let result = async {
+                sync(ctx, path.as_ref()).await?;
+                if let Some(manifest_path) = &config.manifest_path {
+                    write_manifest(ctx, manifest_path)?;
+                }
+                Ok(())
+            }
+            .await;
+            match &result {
+                Ok(()) => {
+                    ctx.counters.report();
+                    run_hook(
+                        "on_success",
+                        config.on_success.as_ref(),
+                        &ctx.counters,
+                        None,
+                    )
+                    .await;
+                }
+                Err(err) => {
+                    run_hook(
+                        "on_failure",
+                        config.on_failure.as_ref(),
+                        &ctx.counters,
+                        Some(err),
+                    )
+                    .await;
+                }
+            }
+            result?;
         }
         cli::Command::Upload {
             local_path,
             archive_path,
+            dry_run,
+            validate_server,
         } => {
             let local_path = SanitizedLocalPath::new(&local_path)?;
-            if let Err(err) = upload(
-                &ctx,
-                &local_path,
-                &archive_path,
-                &mut Rules::new(&[&ctx.config.always_exclude], local_path.clone()),
-                false,
-                &mut HashSet::new(),
-            )
-            .await
-            {
+            let mut batch = if dry_run {
+                VersionBatch::dry_run(validate_server)
+            } else {
+                VersionBatch::from_config(ctx)
+            };
+            let result = ctx
+                .counters
+                .timings
+                .scan
+                .measure_async(upload(
+                    ctx,
+                    &local_path,
+                    &archive_path,
+                    &mut Rules::new(&[&ctx.config.always_exclude], local_path.clone()),
+                    false,
+                    &mut HashSet::new(),
+                    &mut batch,
+                ))
+                .await;
+            if let Err(err) = result {
                 error!("Failed to process {:?}: {:?}", local_path, err);
             }
+            // Changes found before the error are still recorded.
+            batch.flush(ctx).await?;
+            check_error_thresholds(ctx, true)?;
             ctx.counters.report();
         }
         cli::Command::Download {
             archive_path,
             local_path,
             version,
+            version_option,
+            into,
+            checksum,
+            metadata_only,
+            server_time,
+            repair,
+            pattern,
         } => {
-            let found_any = if let Some(version) = version {
-                download_version(&ctx, &archive_path, &local_path, version.0).await?
-            } else {
-                pull_updates(&ctx).await?;
-                download_latest(
-                    &ctx,
-                    &archive_path,
-                    &local_path,
-                    &mut Rules::new(&[&ctx.config.always_exclude], local_path.clone()),
-                    false,
-                )
-                .await?
+            let version = match version.or(version_option) {
+                Some(version) if server_time => Some(to_server_time(ctx, version.0).await?),
+                version => version.map(|version| version.0),
             };
-            if !found_any {
-                bail!("no matching entries found");
+            if let Some(into) = into {
+                let Some(version) = version else {
+                    bail!("--into requires a version");
+                };
+                restore(ctx, &archive_path, version, &into).await?;
+            } else {
+                let local_path = local_path.ok_or_else(|| anyhow!("local path is required"))?;
+                let pattern = pattern.map(|pattern| pattern.compile_matcher());
+                let options = DownloadOptions {
+                    checksum,
+                    metadata_only,
+                    pattern: pattern.as_ref(),
+                    ..DownloadOptions::default()
+                };
+                let found_any = if repair {
+                    repair_mount(ctx, &archive_path, &local_path).await?
+                } else if let Some(version) = version {
+                    download_version(ctx, &archive_path, &local_path, version, options).await?
+                } else {
+                    pull_updates(ctx).await?;
+                    download_latest(
+                        ctx,
+                        &archive_path,
+                        &local_path,
+                        &mut Rules::new(&[&ctx.config.always_exclude], local_path.clone()),
+                        false,
+                        options,
+                    )
+                    .await?
+                };
+                if !found_any {
+                    bail!("no matching entries found");
+                }
             }
         }
-        cli::Command::LocalStatus { path } => local_status(&ctx, &path).await?,
-        cli::Command::Ls { path, deleted } => ls(&ctx, &path, deleted).await?,
+        cli::Command::ResyncMount { path } => resync_mount(ctx, &path).await?,
+        cli::Command::Estimate { path } => estimate(ctx, &path).await?,
+        cli::Command::LocalStatus { path } => local_status(ctx, &path).await?,
+        cli::Command::Ls {
+            path,
+            deleted,
+            refresh_sources,
+        } => ls(ctx, &path, deleted, refresh_sources).await?,
+        cli::Command::Complete { partial } => complete(ctx, &partial).await?,
+        cli::Command::Duplicates { path } => duplicates(ctx, &path).await?,
+        cli::Command::FindByContent { local_path } => find_by_content(ctx, &local_path).await?,
+        cli::Command::PruneDb => block_in_place(|| prune_db(ctx))?,
         cli::Command::Reset {
             archive_path,
             version,
+            server_time,
         } => {
+            let version = if server_time {
+                to_server_time(ctx, version.into()).await?
+            } else {
+                version.into()
+            };
             let stats = ctx
                 .client
                 .request(&ResetVersion {
-                    path: encrypt_path(&archive_path, &ctx.cipher)?,
-                    recorded_at: version.into(),
+                    path: ctx.encrypt_path(&archive_path)?,
+                    recorded_at: version,
                 })
                 .await?;
             info!("{:?}", stats);
         }
-        cli::Command::Move { old_path, new_path } => {
+        cli::Command::Rollback {
+            archive_path,
+            to,
+            confirm,
+        } => {
+            let admin_token = ctx
+                .config
+                .admin_token
+                .as_deref()
+                .ok_or_else(|| anyhow!("admin_token must be set in config to use rollback"))?;
+            let stats = ctx
+                .client
+                .admin_request(
+                    &Rollback {
+                        path: ctx.encrypt_path(&archive_path)?,
+                        recorded_at: to.0,
+                        dry_run: !confirm,
+                    },
+                    admin_token,
+                )
+                .await?;
+            if confirm {
+                info!("{stats:?}");
+            } else {
+                info!(
+                    "{} paths would be rolled back; use --confirm to permanently \
+                    delete newer versions",
+                    stats.affected_paths
+                );
+            }
+        }
+        cli::Command::PurgeSource { name, grace_period } => {
+            let admin_token =
+                ctx.config.admin_token.as_deref().ok_or_else(|| {
+                    anyhow!("admin_token must be set in config to use purge-source")
+                })?;
+            let stats = ctx
+                .client
+                .admin_request(&PurgeSource { name, grace_period }, admin_token)
+                .await?;
+            info!(
+                "Purged source: deleted {} entries and {} versions, removed {} files",
+                stats.deleted_entries, stats.deleted_versions, stats.removed_files
+            );
+        }
+        cli::Command::Move {
+            old_path,
+            new_path,
+            dry_run,
+        } => {
             let stats = ctx
                 .client
                 .request(&MovePath {
-                    old_path: encrypt_path(&old_path, &ctx.cipher)?,
-                    new_path: encrypt_path(&new_path, &ctx.cipher)?,
+                    old_path: ctx.encrypt_path(&old_path)?,
+                    new_path: ctx.encrypt_path(&new_path)?,
+                    dry_run,
                 })
                 .await?;
-            info!("{stats:?}");
+            if dry_run {
+                info!(
+                    "server would accept the move, {} paths would be affected",
+                    stats.affected_paths
+                );
+            } else {
+                info!("{stats:?}");
+            }
         }
         cli::Command::Remove { archive_path } => {
             let stats = ctx
                 .client
                 .request(&RemovePath {
-                    path: encrypt_path(&archive_path, &ctx.cipher)?,
+                    path: ctx.encrypt_path(&archive_path)?,
                 })
                 .await?;
             info!("{:?}", stats);
         }
-        cli::Command::History { path, recursive } => {
-            list_versions(&ctx, &path, recursive).await?;
+        cli::Command::Pin {
+            archive_path,
+            recursive,
+        } => {
+            ctx.client
+                .request(&PinPath {
+                    path: ctx.encrypt_path(&archive_path)?,
+                    recursive,
+                })
+                .await?;
+            info!("Pinned {}", archive_path);
+        }
+        cli::Command::Unpin { archive_path } => {
+            let removed = ctx
+                .client
+                .request(&UnpinPath {
+                    path: ctx.encrypt_path(&archive_path)?,
+                })
+                .await?;
+            if removed {
+                info!("Unpinned {}", archive_path);
+            } else {
+                bail!("{} is not pinned", archive_path);
+            }
+        }
+        cli::Command::Pinned => {
+            pinned_paths(ctx).await?;
+        }
+        cli::Command::RestoreEstimate {
+            archive_path,
+            version,
+        } => {
+            restore_estimate(ctx, &archive_path, version.into()).await?;
+        }
+        cli::Command::Hotspots { path, limit } => {
+            hotspots(ctx, &path, limit).await?;
+        }
+        cli::Command::History {
+            path,
+            recursive,
+            refresh_sources,
+        } => {
+            list_versions(ctx, &path, recursive, refresh_sources).await?;
         }
         cli::Command::Status => {
             let status = ctx.client.request(&GetServerStatus).await?;
@@ -163,18 +461,172 @@ pub async fn run(cli: Cli, config: Config) -> Result<()> {
                 "Available space on server: {}",
                 pretty_size(status.available_space)
             );
+            info!("Snapshots: {}", status.snapshots);
+            if let Some(time) = status.last_snapshot_at {
+                info!("Last snapshot: {}", pretty_time(time));
+            }
+            if let Some(time) = status.next_snapshot_at {
+                info!("Next snapshot: {}", pretty_time(time));
+            }
+            let limits = ctx.server_limits().await;
+            if let Some(size) = limits.max_file_size {
+                info!("Max file size: {}", pretty_size(size));
+            }
+            if let Some(size) = limits.max_daily_growth_per_source {
+                info!("Max daily growth per source: {}", pretty_size(size));
+            }
+            if let Some(count) = limits.max_entries {
+                info!("Max entries: {}", count);
+            }
+            if let Some(version) = &limits.min_client_version {
+                info!("Min client version: {}", version);
+            }
         }
-        cli::Command::CheckIntegrity => {
-            ctx.client.request(&CheckIntegrity).await?;
-            info!("It's fine.");
+        cli::Command::CheckIntegrity { full } => check_integrity(ctx, full).await?,
+        cli::Command::Mounts => mounts(&ctx.config)?,
+        cli::Command::ShowConfig => show_config(&ctx.config)?,
+        cli::Command::GenerateEncryptionKey
+        | cli::Command::SetToken
+        | cli::Command::RunScript { .. }
+        | cli::Command::CompactDb => {
+            unreachable!()
         }
-        cli::Command::GenerateEncryptionKey => unreachable!(),
     }
 
     #[allow(unreachable_code)]
     Ok(())
 }
 
+/// Reads commands from a script file. Returns each command along with its source line.
+fn parse_script(path: &Path) -> Result<Vec<(String, cli::Command)>> {
+    let text = fs_err::read_to_string(path)?;
+    let mut commands = Vec::new();
+    for (index, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let location = format!("{}:{}", path.display(), index + 1);
+        let words = shell_words::split(line).map_err(|err| anyhow!("{location}: {err}"))?;
+        let command = ScriptCommand::try_parse_from(words)
+            .map_err(|err| anyhow!("{location}: {err}"))?
+            .command;
+        if matches!(
+            command,
+            cli::Command::GenerateEncryptionKey
+                | cli::Command::SetToken
+                | cli::Command::RunScript { .. }
+                | cli::Command::CompactDb
+        ) {
+            bail!("{location}: this command is not supported in scripts");
+        }
+        commands.push((line.to_owned(), command));
+    }
+    Ok(commands)
+}
+
+pub fn default_config_path() -> Result<PathBuf> {
+    let config_dir = dirs::config_dir().ok_or_else(|| anyhow!("cannot find config dir"))?;
+    Ok(config_dir.join("rammingen.conf"))
+}
+
+/// Asks for a new access token, checks that the server accepts it and
+/// replaces the old token in the config file.
+pub async fn set_token(config_path: &Path, config: &Config) -> Result<()> {
+    print!("New access token: ");
+    io::stdout().flush()?;
+    let mut token = String::new();
+    io::stdin().read_line(&mut token)?;
+    let token = token.trim();
+    if token.is_empty() {
+        bail!("access token must not be empty");
+    }
+
+    Client::new(config.server_url.clone(), token)
+        .request(&GetServerStatus)
+        .await
+        .map_err(|err| anyhow!("server rejected the new access token: {err}"))?;
+
+    // Replace the token in place to preserve formatting and comments of the config file.
+    let text = fs_err::read_to_string(config_path)?;
+    if config.access_token.is_empty() || text.matches(&config.access_token).count() != 1 {
+        bail!(
+            "cannot locate the current access token in {}, please update it manually",
+            config_path.display()
+        );
+    }
+    fs_err::write(config_path, text.replacen(&config.access_token, token, 1))?;
+    info!("Access token updated in {}", config_path.display());
+    Ok(())
+}
+
+/// Converts a time read from the local clock to the corresponding time
+/// on the server's clock, compensating for clock skew between them.
+async fn to_server_time(ctx: &Ctx, time: DateTimeUtc) -> Result<DateTimeUtc> {
+    let before = Utc::now();
+    let server_now = ctx.client.request(&GetServerTime).await?;
+    let after = Utc::now();
+    // Assume the server read its clock halfway through the request.
+    let skew = server_now - (before + (after - before) / 2);
+    debug!("Server clock skew: {} ms", skew.num_milliseconds());
+    Ok(time + skew)
+}
+
+impl Ctx {
+    /// Encrypts an archive path, applying the configured archive prefix.
+    pub fn encrypt_path(&self, path: &ArchivePath) -> Result<EncryptedArchivePath> {
+        if let Some(prefix) = &self.config.archive_prefix {
+            encrypt_path(&path.nest_under(prefix), &self.cipher)
+        } else {
+            encrypt_path(path, &self.cipher)
+        }
+    }
+
+    /// Returns limits enforced by the server. They are requested once per run.
+    /// If the request fails (e.g. the server is too old to support it), no limits are assumed.
+    pub async fn server_limits(&self) -> &ServerLimits {
+        self.server_limits
+            .get_or_init(|| async {
+                self.client
+                    .request(&GetServerLimits)
+                    .await
+                    .unwrap_or_else(|err| {
+                        warn!("failed to get server limits, assuming none: {:?}", err);
+                        ServerLimits::default()
+                    })
+            })
+            .await
+    }
+
+    /// Decrypts an archive path and strips the configured archive prefix from it.
+    pub fn decrypt_path(&self, path: &EncryptedArchivePath) -> Result<ArchivePath> {
+        let path = decrypt_path(path, &self.cipher)?;
+        if let Some(prefix) = &self.config.archive_prefix {
+            path.unnest(prefix)
+                .ok_or_else(|| anyhow!("{} is outside of archive prefix {}", path, prefix))
+        } else {
+            Ok(path)
+        }
+    }
+
+    /// Awaits a server request related to `path`. If `log_decrypted_paths` is enabled,
+    /// the request is logged with the decrypted path, and the path is added to the error.
+    pub async fn with_path_context<T>(
+        &self,
+        action: &str,
+        path: &ArchivePath,
+        request: impl Future<Output = Result<T>>,
+    ) -> Result<T> {
+        if !self.config.log_decrypted_paths {
+            return request.await;
+        }
+        info!("{action}: {path}");
+        request
+            .await
+            .with_context(|| format!("{action} failed for {path}"))
+    }
+}
+
 #[cfg(target_family = "unix")]
 pub fn unix_mode(metadata: &Metadata) -> Option<u32> {
     use std::os::unix::prelude::PermissionsExt;
@@ -187,9 +639,55 @@ pub fn unix_mode(_metadata: &Metadata) -> Option<u32> {
     None
 }
 
+fn default_log_file() -> Option<PathBuf> {
+    dirs::data_dir().map(|dir| dir.join("rammingen.log"))
+}
+
+/// Returns rules that exclude rammingen's own files (local database, its lock file,
+/// log file and config file), so that they are never uploaded as part of a mount point.
+///
+/// Prints a warning for each of these files that is located inside a mount point.
+fn own_files_exclude_rules(
+    config_path: Option<&Path>,
+    config: &Config,
+    local_db_path: &Path,
+) -> Vec<Rule> {
+    let db_paths = [
+        Some(local_db_path.to_path_buf()),
+        Some(with_suffix(local_db_path, ".lock")),
+        Some(with_suffix(local_db_path, ".corrupted")),
+        Some(with_suffix(local_db_path, ".compacting")),
+        Some(with_suffix(local_db_path, ".old")),
+        Some(with_suffix(local_db_path, ".downloads")),
+    ];
+    let paths = db_paths.into_iter().chain([
+        config.log_file.clone().or_else(default_log_file),
+        config.manifest_path.clone(),
+        config_path.map(Path::to_path_buf),
+    ]);
+    let mut rules = Vec::new();
+    for path in paths.flatten() {
+        let Ok(path) = SanitizedLocalPath::new(&path) else {
+            continue;
+        };
+        if let Some(mount_point) = config
+            .mount_points
+            .iter()
+            .find(|mount_point| path.as_path().starts_with(&mount_point.local_path))
+        {
+            warn!(
+                "{} is inside mount point {}, it will be excluded automatically",
+                path, mount_point.local_path
+            );
+        }
+        rules.push(Rule::PathEquals(path));
+    }
+    rules
+}
+
 pub fn setup_logger(log_file: Option<PathBuf>, log_filter: String) -> Result<()> {
     // Defaults to stdout if `data_dir()` fails.
-    let log_file = log_file.or_else(|| dirs::data_dir().map(|dir| dir.join("rammingen.log")));
+    let log_file = log_file.or_else(default_log_file);
     let fmt_layer =
         tracing_subscriber::fmt::layer().with_writer(Mutex::new(log_writer(log_file.as_deref())?));
     tracing_subscriber::registry()
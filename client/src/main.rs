@@ -1,10 +1,10 @@
-use anyhow::{anyhow, Result};
+use anyhow::Result;
 use base64::{prelude::BASE64_URL_SAFE_NO_PAD, Engine};
 use clap::Parser;
 use rammingen::{
     cli::{Cli, Command},
     config::{Config, EncryptionKey},
-    setup_logger,
+    default_config_path, set_token, setup_logger,
 };
 use tracing::error;
 
@@ -26,11 +26,13 @@ async fn try_main() -> Result<()> {
     let config_path = if let Some(config) = &cli.config {
         config.clone()
     } else {
-        let config_dir = dirs::config_dir().ok_or_else(|| anyhow!("cannot find config dir"))?;
-        config_dir.join("rammingen.conf")
+        default_config_path()?
     };
-    let config: Config = json5::from_str(&fs_err::read_to_string(config_path)?)?;
+    let config: Config = json5::from_str(&fs_err::read_to_string(&config_path)?)?;
     setup_logger(config.log_file.clone(), config.log_filter.clone())?;
+    if cli.command == Command::SetToken {
+        return set_token(&config_path, &config).await;
+    }
     rammingen::run(cli, config).await?;
     Ok(())
 }
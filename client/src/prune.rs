@@ -0,0 +1,68 @@
+use std::path::Path;
+
+use anyhow::Result;
+use rammingen_protocol::ArchivePath;
+use tokio::task::block_in_place;
+use tracing::info;
+
+use crate::{db::Db, info::pretty_size, term::set_status, Ctx};
+
+fn is_related_archive_path(path: &ArchivePath, mount_path: &ArchivePath) -> bool {
+    path == mount_path || path.strip_prefix(mount_path).is_some()
+        // Keep parents of mount points as well.
+        || mount_path.strip_prefix(path).is_some()
+}
+
+/// Removes local DB data that is no longer used by any of the configured mount points.
+///
+/// Local entries are removed if they are not inside any mount point.
+/// Archive entries are removed only if they are deleted and not related to any mount point,
+/// because existing entries are still used by commands like `ls` and `download`
+/// and can't be restored without pulling all updates from the server again.
+pub fn prune_db(ctx: &Ctx) -> Result<()> {
+    let _status = set_status("Pruning local database");
+    let mut removed_local = 0;
+    for entry in ctx.db.get_all_local_entries() {
+        let (path, _) = entry?;
+        if !ctx
+            .config
+            .mount_points
+            .iter()
+            .any(|mount_point| path.as_path().starts_with(&mount_point.local_path))
+        {
+            ctx.db.remove_local_entry(&path)?;
+            removed_local += 1;
+        }
+    }
+    let mut removed_archive = 0;
+    for entry in ctx.db.get_all_archive_entries() {
+        let entry = entry?;
+        if entry.kind.is_none()
+            && !ctx
+                .config
+                .mount_points
+                .iter()
+                .any(|mount_point| is_related_archive_path(&entry.path, &mount_point.archive_path))
+        {
+            ctx.db.remove_archive_entry(&entry.path)?;
+            removed_archive += 1;
+        }
+    }
+    info!(
+        "Removed {} local entries and {} archive entries",
+        removed_local, removed_archive
+    );
+    Ok(())
+}
+
+/// Compacts the local database at `path`, reporting its size before and after.
+pub fn compact_db(path: &Path, wait_for_lock: bool) -> Result<()> {
+    let _status = set_status("Compacting local database");
+    let (old_size, new_size) = block_in_place(|| Db::compact(path, wait_for_lock))?;
+    info!(
+        "Compacted local database from {} to {}",
+        pretty_size(old_size),
+        pretty_size(new_size)
+    );
+    Ok(())
+}
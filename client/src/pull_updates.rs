@@ -2,20 +2,124 @@ use std::cmp::max;
 
 use anyhow::Result;
 use futures::TryStreamExt;
-use rammingen_protocol::endpoints::GetNewEntries;
+use rammingen_protocol::{
+    endpoints::{GetBootstrapEntries, GetLastUpdateNumber, GetNewEntries},
+    EncryptedArchivePath, EntryUpdateNumber,
+};
 
-use crate::{data::DecryptedEntryVersionData, term::set_status, Ctx};
+use crate::{
+    data::{BootstrapProgress, DecryptedEntryVersionData},
+    encryption::encrypt_path,
+    term::set_status,
+    Ctx,
+};
+
+/// Max number of received updates that are saved to the local database at once.
+const BATCH_SIZE: usize = 10_000;
 
 pub async fn pull_updates(ctx: &Ctx) -> Result<()> {
+    ctx.counters
+        .timings
+        .pull_updates
+        .measure_async(pull_updates_inner(ctx))
+        .await
+}
+
+async fn pull_updates_inner(ctx: &Ctx) -> Result<()> {
+    let prefix = ctx
+        .config
+        .archive_prefix
+        .as_ref()
+        .map(|prefix| encrypt_path(prefix, &ctx.cipher))
+        .transpose()?;
+    bootstrap(ctx, prefix.as_ref()).await?;
+
     let _status = set_status("Pulling updates from server");
     let mut last_update_number = ctx.db.last_entry_update_number()?;
+    let mut committed_update_number = last_update_number;
     let mut stream = ctx.client.stream(&GetNewEntries { last_update_number });
     let mut decrypted = Vec::new();
+    let mut num_pending = 0;
     while let Some(update) = stream.try_next().await? {
-        decrypted.push(DecryptedEntryVersionData::new(ctx, update.data)?);
         last_update_number = max(last_update_number, update.update_number);
+        num_pending += 1;
+        if in_prefix(&update.data.path, prefix.as_ref()) {
+            decrypted.push((
+                DecryptedEntryVersionData::new(ctx, update.data)?,
+                update.update_number,
+            ));
+        }
+        // Updates are ordered by update number, so all updates up to `last_update_number`
+        // have been received. Saving them in batches allows an interrupted pull
+        // to resume from the last saved batch.
+        if num_pending >= BATCH_SIZE {
+            ctx.db
+                .update_archive_entries(&decrypted, last_update_number)?;
+            committed_update_number = last_update_number;
+            decrypted.clear();
+            num_pending = 0;
+        }
+    }
+    if last_update_number != committed_update_number {
+        ctx.db
+            .update_archive_entries(&decrypted, last_update_number)?;
+    }
+    Ok(())
+}
+
+/// Initializes an empty local database with the current state of all entries,
+/// which is much faster than pulling all updates if the archive has a long history.
+///
+/// Entries are saved in batches, so an interrupted bootstrap resumes after the last
+/// saved entry.
+async fn bootstrap(ctx: &Ctx, prefix: Option<&EncryptedArchivePath>) -> Result<()> {
+    let mut progress = if let Some(progress) = ctx.db.bootstrap_progress()? {
+        progress
+    } else if ctx.db.last_entry_update_number()? == EntryUpdateNumber::from(0) {
+        let progress = BootstrapProgress {
+            update_number: ctx.client.request(&GetLastUpdateNumber).await?,
+            last_path: None,
+        };
+        if progress.update_number == EntryUpdateNumber::from(0) {
+            // The archive is empty.
+            return Ok(());
+        }
+        progress
+    } else {
+        return Ok(());
+    };
+
+    let _status = set_status("Downloading current state of the archive");
+    let mut stream = ctx.client.stream(&GetBootstrapEntries {
+        after_path: progress.last_path.clone(),
+    });
+    let mut decrypted = Vec::new();
+    let mut num_pending = 0;
+    while let Some(entry) = stream.try_next().await? {
+        num_pending += 1;
+        let path = entry.data.path.clone();
+        if in_prefix(&path, prefix) {
+            decrypted.push((
+                DecryptedEntryVersionData::new(ctx, entry.data)?,
+                entry.update_number,
+            ));
+        }
+        progress.last_path = Some(path);
+        if num_pending >= BATCH_SIZE {
+            ctx.db
+                .save_bootstrap_entries(&decrypted, &progress, false)?;
+            decrypted.clear();
+            num_pending = 0;
+        }
     }
-    ctx.db
-        .update_archive_entries(&decrypted, last_update_number)?;
+    ctx.db.save_bootstrap_entries(&decrypted, &progress, true)?;
     Ok(())
 }
+
+fn in_prefix(path: &EncryptedArchivePath, prefix: Option<&EncryptedArchivePath>) -> bool {
+    if let Some(prefix) = prefix {
+        path == prefix || path.strip_prefix(prefix).is_some()
+    } else {
+        true
+    }
+}
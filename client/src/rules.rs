@@ -1,17 +1,33 @@
-use anyhow::Result;
+use anyhow::{Context, Result};
 use regex::Regex;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::{collections::HashMap, fmt, fs::Metadata, io, path::PathBuf};
 
 use crate::path::SanitizedLocalPath;
 
+/// Name of the file with exclude rules for the directory containing it.
+pub const IGNORE_FILE_NAME: &str = ".rammingenignore";
+
 #[derive(Debug, Clone)]
 pub struct Rules {
     rules: Vec<Rule>,
     root: SanitizedLocalPath,
+    // Rules loaded from ignore files, by directory.
+    local_rules: HashMap<SanitizedLocalPath, Vec<Rule>>,
+    exclude_hidden: bool,
+    filesystems: FilesystemRules,
     cache: HashMap<SanitizedLocalPath, bool>,
 }
 
+#[derive(Debug, Clone, Default)]
+struct FilesystemRules {
+    one_file_system: bool,
+    excluded_types: Vec<String>,
+    root_device: Option<u64>,
+    // Mount points and their filesystem types, loaded on first use.
+    mounts: Option<Vec<(PathBuf, String)>>,
+}
+
 impl Rules {
     pub fn new(rules: &[&[Rule]], root: SanitizedLocalPath) -> Self {
         let mut vec = Vec::new();
@@ -21,10 +37,109 @@ impl Rules {
         Self {
             rules: vec,
             root,
+            local_rules: HashMap::new(),
+            exclude_hidden: false,
+            filesystems: FilesystemRules::default(),
             cache: HashMap::new(),
         }
     }
 
+    /// Sets whether files and directories with names starting with `.` are excluded.
+    /// The root path is never excluded.
+    pub fn exclude_hidden(mut self, value: bool) -> Self {
+        self.exclude_hidden = value;
+        self.cache.clear();
+        self
+    }
+
+    /// Sets whether directories on other filesystems than the root path, or on
+    /// filesystems of the specified types, are excluded. These rules are only checked
+    /// by `matches_filesystem`.
+    pub fn exclude_filesystems(mut self, one_file_system: bool, types: &[String]) -> Self {
+        self.filesystems.one_file_system = one_file_system;
+        self.filesystems.excluded_types = types.to_vec();
+        self
+    }
+
+    /// Checks whether the existing directory at `path` with the specified metadata
+    /// should be excluded because of the filesystem it's on. The root path is never excluded.
+    pub fn matches_filesystem(
+        &mut self,
+        path: &SanitizedLocalPath,
+        metadata: &Metadata,
+    ) -> Result<bool> {
+        let filesystems = &mut self.filesystems;
+        if (!filesystems.one_file_system && filesystems.excluded_types.is_empty())
+            || path == &self.root
+            || !metadata.is_dir()
+        {
+            return Ok(false);
+        }
+        let Some(device) = metadata_device(metadata) else {
+            return Ok(false);
+        };
+        let root_device = if let Some(root_device) = filesystems.root_device {
+            root_device
+        } else {
+            let root_device = metadata_device(&fs_err::metadata(&self.root)?).unwrap_or_default();
+            filesystems.root_device = Some(root_device);
+            root_device
+        };
+        if device == root_device {
+            return Ok(false);
+        }
+        if filesystems.one_file_system {
+            return Ok(true);
+        }
+        if filesystems.mounts.is_none() {
+            filesystems.mounts = Some(load_mounts()?);
+        }
+        let mounts = filesystems.mounts.as_ref().expect("loaded above");
+        // The longest mount point containing the path determines its filesystem.
+        let fs_type = mounts
+            .iter()
+            .filter(|(mount_point, _)| path.as_path().starts_with(mount_point))
+            .max_by_key(|(mount_point, _)| mount_point.as_os_str().len())
+            .map(|(_, fs_type)| fs_type);
+        Ok(fs_type.map_or(false, |fs_type| {
+            filesystems.excluded_types.contains(fs_type)
+        }))
+    }
+
+    /// Loads rules from the ignore file in `dir`, if it exists. These rules apply
+    /// to all paths nested in `dir`, in addition to the other rules.
+    pub fn load_ignore_file(&mut self, dir: &SanitizedLocalPath) -> Result<()> {
+        let path = dir.join(IGNORE_FILE_NAME)?;
+        let text = match fs_err::read_to_string(&path) {
+            Ok(text) => text,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(err) => return Err(err.into()),
+        };
+        let rules = json5::from_str::<Vec<Rule>>(&text)
+            .with_context(|| format!("failed to parse {path}"))?;
+        self.local_rules.insert(dir.clone(), rules);
+        self.cache
+            .retain(|cached, _| cached == dir || !cached.as_path().starts_with(dir));
+        Ok(())
+    }
+
+    /// Loads ignore files of all directories from the root to the parent of `path`.
+    pub fn load_parent_ignore_files(&mut self, path: &SanitizedLocalPath) -> Result<()> {
+        let mut dirs = Vec::new();
+        let mut dir = path.parent()?;
+        while let Some(current) = dir {
+            if !current.as_path().starts_with(&self.root) {
+                break;
+            }
+            dir = current.parent()?;
+            dirs.push(current);
+        }
+        for dir in dirs.iter().rev() {
+            self.load_ignore_file(dir)?;
+        }
+        Ok(())
+    }
+
     pub fn matches(&mut self, path: &SanitizedLocalPath) -> Result<bool> {
         if let Some(value) = self.cache.get(path) {
             Ok(*value)
@@ -41,11 +156,10 @@ impl Rules {
         if path == &self.root {
             return Ok(false);
         }
-        if path
-            .file_name()
-            .map_or(false, |name| name.ends_with(".rammingen.part"))
-        {
-            return Ok(true);
+        if let Some(name) = path.file_name() {
+            if name.ends_with(".rammingen.part") || (self.exclude_hidden && name.starts_with('.')) {
+                return Ok(true);
+            }
         }
         if let Some(parent) = path.parent()? {
             if self.matches(&parent)? {
@@ -58,10 +172,69 @@ impl Rules {
                 return Ok(true);
             }
         }
+        if !self.local_rules.is_empty() {
+            let mut dir = path.parent()?;
+            while let Some(current) = dir {
+                if let Some(rules) = self.local_rules.get(&current) {
+                    for rule in rules {
+                        if rule.matches(path)? {
+                            return Ok(true);
+                        }
+                    }
+                }
+                if current == self.root {
+                    break;
+                }
+                dir = current.parent()?;
+            }
+        }
         Ok(false)
     }
 }
 
+#[cfg(target_family = "unix")]
+fn metadata_device(metadata: &Metadata) -> Option<u64> {
+    use std::os::unix::fs::MetadataExt;
+
+    Some(metadata.dev())
+}
+
+#[cfg(not(target_family = "unix"))]
+fn metadata_device(_metadata: &Metadata) -> Option<u64> {
+    None
+}
+
+/// Returns all mount points and their filesystem types.
+#[cfg(target_os = "linux")]
+fn load_mounts() -> Result<Vec<(PathBuf, String)>> {
+    Ok(parse_mounts(&fs_err::read_to_string("/proc/self/mounts")?))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn load_mounts() -> Result<Vec<(PathBuf, String)>> {
+    anyhow::bail!("exclude_filesystems is only supported on Linux")
+}
+
+/// Parses the content of `/proc/self/mounts`.
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+fn parse_mounts(text: &str) -> Vec<(PathBuf, String)> {
+    text.lines()
+        .filter_map(|line| {
+            let mut fields = line.split(' ');
+            let _device = fields.next()?;
+            let mount_point = fields.next()?;
+            let fs_type = fields.next()?;
+            // Spaces and some other characters are escaped as octal codes.
+            let mount_point = mount_point
+                .replace("\\040", " ")
+                .replace("\\011", "\t")
+                .replace("\\012", "\n")
+                .replace("\\134", "\\");
+            Some((PathBuf::from(mount_point), fs_type.to_string()))
+        })
+        .collect()
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "snake_case")]
 pub enum Rule {
@@ -98,6 +271,24 @@ impl Rule {
     }
 }
 
+impl fmt::Display for Rule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Rule::NameEquals(name) => write!(f, "name equals {name:?}"),
+            Rule::NameMatches(regex) => write!(f, "name matches {:?}", regex.as_str()),
+            Rule::PathEquals(path) => write!(f, "path equals {path}"),
+            Rule::PathMatches(regex) => write!(f, "path matches {:?}", regex.as_str()),
+            Rule::SubdirsOf { path, except } => {
+                write!(f, "subdirs of {path}")?;
+                if !except.is_empty() {
+                    write!(f, " except {}", except.join(", "))?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -146,6 +337,32 @@ mod tests {
         i(&mut rules, "/tmp/1/abd/other");
     }
 
+    #[test]
+    fn hidden() {
+        let mut rules = Rules::new(&[], p("/tmp/.1")).exclude_hidden(true);
+        i(&mut rules, "/tmp/.1");
+        i(&mut rules, "/tmp/.1/abc");
+        e(&mut rules, "/tmp/.1/.git");
+        e(&mut rules, "/tmp/.1/.git/config");
+        e(&mut rules, "/tmp/.1/abc/.cache");
+        i(&mut rules, "/tmp/.1/abc/a.b");
+    }
+
+    #[test]
+    fn mounts() {
+        let mounts = parse_mounts(
+            "sysfs /sys sysfs rw,nosuid 0 0\n\
+            /dev/sda1 /mnt/my\\040disk ext4 rw,relatime 0 0\n",
+        );
+        assert_eq!(
+            mounts,
+            vec![
+                ("/sys".into(), "sysfs".into()),
+                ("/mnt/my disk".into(), "ext4".into())
+            ]
+        );
+    }
+
     #[test]
     fn with_final() {
         let mut rules = rules(
@@ -160,6 +377,34 @@ mod tests {
         e(&mut rules, "/tmp/1/target/2/a");
     }
 
+    #[test]
+    fn ignore_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = SanitizedLocalPath::new(dir.path()).unwrap();
+        let project = root.join("project").unwrap();
+        fs_err::create_dir(&project).unwrap();
+        fs_err::write(
+            project.join(IGNORE_FILE_NAME).unwrap(),
+            r#"[{ name_equals: "target" }, { name_matches: "\\.log$" }]"#,
+        )
+        .unwrap();
+        let mut rules = Rules::new(&[], root.clone());
+        let path = |s: &str| root.join(s).unwrap();
+        rules
+            .load_parent_ignore_files(&path("project/src"))
+            .unwrap();
+        assert!(!rules.matches(&path("target")).unwrap());
+        assert!(!rules.matches(&path("a.log")).unwrap());
+        assert!(!rules.matches(&path("project")).unwrap());
+        assert!(!rules.matches(&path("project/src")).unwrap());
+        assert!(rules.matches(&path("project/target")).unwrap());
+        assert!(rules.matches(&path("project/target/a")).unwrap());
+        assert!(rules.matches(&path("project/src/a.log")).unwrap());
+
+        fs_err::write(project.join(IGNORE_FILE_NAME).unwrap(), "[").unwrap();
+        assert!(rules.load_ignore_file(&project).is_err());
+    }
+
     #[test]
     fn with_subdirs() {
         let mut rules = rules(
@@ -1,55 +1,375 @@
-use std::collections::HashSet;
+use std::{cmp::Reverse, collections::HashSet, path::Path};
 
 use crate::{
-    download::download_latest,
+    config::MountPoint,
+    download::{download_latest, DownloadOptions},
+    encryption::encrypt_content_hash,
+    path::SanitizedLocalPath,
     pull_updates::pull_updates,
-    rules::Rules,
-    upload::{find_local_deletions, upload},
+    rules::{Rule, Rules},
+    term::set_status,
+    upload::{
+        check_error_thresholds, find_local_deletions, mount_point_index, to_archive_path, upload,
+        VersionBatch, MAX_TRANSACTION_SIZE,
+    },
     Ctx,
 };
-use anyhow::Result;
+use anyhow::{bail, Result};
+use chrono::Utc;
 use itertools::Itertools;
+use rammingen_protocol::{
+    endpoints::ContentHashExists, util::archive_to_native_relative_path, ArchivePath, DateTimeUtc,
+};
+use serde::Serialize;
+use tracing::{info, warn};
 
-pub async fn sync(ctx: &Ctx) -> Result<()> {
-    let mut existing_paths = HashSet::new();
-    let mut mount_points = ctx
-        .config
+/// Returns mount points ordered by priority, highest first.
+/// Mount points with equal priority keep their order from the config.
+fn mount_points_by_priority(ctx: &Ctx) -> Vec<&MountPoint> {
+    ctx.config
         .mount_points
         .iter()
-        .map(|mount_point| {
-            let rules = Rules::new(
-                &[&ctx.config.always_exclude, &mount_point.exclude],
+        .sorted_by_key(|mount_point| Reverse(mount_point.priority))
+        .collect()
+}
+
+/// Returns parts of `mount_point` that correspond to archive paths of mount points
+/// with a higher priority, as pairs of local and archive paths.
+fn overridden_paths(
+    ctx: &Ctx,
+    mount_point: &MountPoint,
+) -> Result<Vec<(SanitizedLocalPath, ArchivePath)>> {
+    let mut paths = Vec::new();
+    for other in &ctx.config.mount_points {
+        if other.priority <= mount_point.priority {
+            continue;
+        }
+        let path = if mount_point.archive_path == other.archive_path
+            || mount_point
+                .archive_path
+                .strip_prefix(&other.archive_path)
+                .is_some()
+        {
+            (
                 mount_point.local_path.clone(),
-            );
-            (mount_point, rules)
-        })
+                mount_point.archive_path.clone(),
+            )
+        } else if let Some(relative) = other.archive_path.strip_prefix(&mount_point.archive_path) {
+            (
+                mount_point
+                    .local_path
+                    .join(&*archive_to_native_relative_path(relative))?,
+                other.archive_path.clone(),
+            )
+        } else {
+            continue;
+        };
+        paths.push(path);
+    }
+    Ok(paths)
+}
+
+/// Records the state of local entries of the mount point at `local_path` on the server
+/// if it differs from the local database, without scanning local files.
+///
+/// Used to repair the server state after an interrupted upload. An entry is only
+/// recorded if the server's current version of it was received before the local entry
+/// was saved, so changes made by other clients since then are left alone.
+pub async fn resync_mount(ctx: &Ctx, local_path: &SanitizedLocalPath) -> Result<()> {
+    let Some(mount_point) = ctx
+        .config
+        .mount_points
+        .iter()
+        .find(|mount_point| &mount_point.local_path == local_path)
+    else {
+        bail!("{} is not a mount point", local_path);
+    };
+    pull_updates(ctx).await?;
+    let overridden = overridden_paths(ctx, mount_point)?;
+    let mut mount_points = vec![(
+        mount_point,
+        mount_point.rules(&ctx.config.always_exclude, &[]),
+    )];
+    let mut batch = if mount_point.transactional {
+        VersionBatch::new(MAX_TRANSACTION_SIZE)
+    } else {
+        VersionBatch::from_config(ctx)
+    };
+    batch.set_metadata_only(mount_point.metadata_only);
+    let _status = set_status("Comparing local database with the server");
+    let root_entry = ctx
+        .db
+        .get_local_entry(local_path)?
+        .map(|_| Ok(local_path.clone()));
+    // Parents go before their children.
+    for path in root_entry
+        .into_iter()
+        .chain(ctx.db.get_local_entry_paths_under(local_path))
+    {
+        let path = path?;
+        if overridden
+            .iter()
+            .any(|(overridden_path, _)| path.as_path().starts_with(overridden_path))
+        {
+            continue;
+        }
+        let Some(local_entry) = ctx.db.get_local_entry(&path)? else {
+            continue;
+        };
+        let Some((archive_path, rules)) = to_archive_path(&path, &mut mount_points)? else {
+            continue;
+        };
+        if rules.matches(&path)? {
+            continue;
+        }
+        if let Some(entry) = ctx.db.get_archive_entry(&archive_path)? {
+            if local_entry.is_same_as_entry(&entry) {
+                continue;
+            }
+            let server_update_number = ctx.db.get_archive_update_number(&archive_path)?;
+            let local_update_number = ctx.db.get_local_update_number(&path)?;
+            let synced = server_update_number
+                .zip(local_update_number)
+                .is_some_and(|(server, local)| server <= local);
+            if !synced {
+                warn!(
+                    "{} was changed on the server after it was last synced, skipping",
+                    path
+                );
+                continue;
+            }
+        }
+        if let Some(content) = local_entry
+            .content
+            .as_ref()
+            .filter(|_| !mount_point.metadata_only)
+        {
+            let hash = encrypt_content_hash(&content.hash, &ctx.cipher)?;
+            if !ctx.client.request(&ContentHashExists(hash)).await? {
+                warn!(
+                    "content of {} is missing on the server, it will be uploaded on the next sync",
+                    path
+                );
+                // The file will be considered changed by the next sync.
+                ctx.db.remove_local_entry(&path)?;
+                continue;
+            }
+        }
+        batch
+            .add_local_entry(ctx, path, archive_path, local_entry)
+            .await?;
+    }
+    batch.flush(ctx).await?;
+    ctx.counters.report();
+    Ok(())
+}
+
+/// A file listed in the manifest written after sync.
+#[derive(Debug, Serialize)]
+struct ManifestFile {
+    local_path: SanitizedLocalPath,
+    archive_path: ArchivePath,
+    hash: String,
+    size: u64,
+    modified_at: DateTimeUtc,
+    /// Time when the current version was recorded in the archive.
+    recorded_at: Option<DateTimeUtc>,
+}
+
+/// Manifest of the files in all mount points, written after sync.
+#[derive(Debug, Serialize)]
+struct SyncManifest {
+    written_at: DateTimeUtc,
+    files: Vec<ManifestFile>,
+}
+
+/// Writes a manifest of all files in mount points according to the local database.
+pub fn write_manifest(ctx: &Ctx, path: &Path) -> Result<()> {
+    // Paths are mapped to archive paths the same way as during sync.
+    let mut mount_points = mount_points_by_priority(ctx)
+        .into_iter()
+        .map(|mount_point| (mount_point, Rules::new(&[], mount_point.local_path.clone())))
         .collect_vec();
+    let mut files = Vec::new();
+    for entry in ctx.db.get_all_local_entries() {
+        let (local_path, data) = entry?;
+        let Some(content) = data.content else {
+            continue;
+        };
+        let Some((archive_path, _)) = to_archive_path(&local_path, &mut mount_points)? else {
+            continue;
+        };
+        let recorded_at = ctx
+            .db
+            .get_archive_entry(&archive_path)?
+            .map(|entry| entry.recorded_at);
+        files.push(ManifestFile {
+            local_path,
+            archive_path,
+            hash: content.hash.to_string(),
+            size: content.original_size,
+            modified_at: content.modified_at,
+            recorded_at,
+        });
+    }
+    let manifest = SyncManifest {
+        written_at: Utc::now(),
+        files,
+    };
+    // Written to a temporary file first, so that the previous manifest
+    // is not lost if writing fails.
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+    fs_err::write(&tmp_path, serde_json::to_string_pretty(&manifest)?)?;
+    fs_err::rename(&tmp_path, path)?;
+    info!(
+        "Wrote manifest of {} files to {}",
+        manifest.files.len(),
+        path.display()
+    );
+    Ok(())
+}
 
-    for (mount_point, rules) in &mut mount_points {
-        upload(
+/// Syncs all mount points with the server. If `path` is specified, only this path
+/// (which must be inside of a mount point) is synced.
+pub async fn sync(ctx: &Ctx, path: Option<&SanitizedLocalPath>) -> Result<()> {
+    let mut existing_paths = HashSet::new();
+    let mut overridden = Vec::new();
+    for mount_point in mount_points_by_priority(ctx) {
+        let paths = overridden_paths(ctx, mount_point)?;
+        let rules = paths
+            .iter()
+            .map(|(local_path, _)| Rule::PathEquals(local_path.clone()))
+            .collect_vec();
+        overridden.push((mount_point, paths, rules));
+    }
+    let mount_rules = |mount_point: &MountPoint, overridden_rules: &[Rule]| {
+        mount_point.rules(&ctx.config.always_exclude, overridden_rules)
+    };
+    let mut mount_points = overridden
+        .iter()
+        .map(|(mount_point, _, rules)| (*mount_point, mount_rules(mount_point, rules)))
+        .collect_vec();
+
+    // Index of the mount point containing `path`, local path and archive path
+    // of the synced subtree.
+    let scope = if let Some(path) = path {
+        let index = mount_point_index(path, &mount_points);
+        let archive_path = to_archive_path(path, &mut mount_points)?;
+        let (Some(index), Some((archive_path, _))) = (index, archive_path) else {
+            bail!("{} is not inside of a mount point", path);
+        };
+        Some((index, path, archive_path))
+    } else {
+        None
+    };
+    // Returns local and archive paths that should be synced in a mount point.
+    // Mount points nested in the synced path are synced entirely.
+    let roots = |index: usize, mount_point: &MountPoint| match &scope {
+        Some((scope_index, path, archive_path)) if index == *scope_index => {
+            Some(((*path).clone(), archive_path.clone()))
+        }
+        Some((_, path, _)) if !mount_point.local_path.as_path().starts_with(path) => None,
+        _ => Some((
+            mount_point.local_path.clone(),
+            mount_point.archive_path.clone(),
+        )),
+    };
+
+    let mut batch = VersionBatch::from_config(ctx);
+    // Changes of transactional mount points are recorded together at the end.
+    let mut transaction = VersionBatch::new(MAX_TRANSACTION_SIZE);
+    let timings = &ctx.counters.timings;
+    for (index, (mount_point, rules)) in mount_points.iter_mut().enumerate() {
+        let Some((local_path, archive_path)) = roots(index, mount_point) else {
+            continue;
+        };
+        if scope.is_some() {
+            if local_path.as_path().symlink_metadata().is_err() {
+                // The path was removed, so it will be recorded as deleted.
+                continue;
+            }
+            rules.load_parent_ignore_files(&local_path)?;
+        }
+        let target = if mount_point.transactional {
+            &mut transaction
+        } else {
+            &mut batch
+        };
+        target.set_metadata_only(mount_point.metadata_only);
+        timings
+            .scan
+            .measure_async(upload(
+                ctx,
+                &local_path,
+                &archive_path,
+                rules,
+                true,
+                &mut existing_paths,
+                target,
+            ))
+            .await?;
+    }
+    check_error_thresholds(ctx, true)?;
+    timings
+        .scan
+        .measure_async(find_local_deletions(
             ctx,
-            &mount_point.local_path,
-            &mount_point.archive_path,
-            rules,
-            true,
-            &mut existing_paths,
-        )
+            &mut mount_points,
+            &existing_paths,
+            path,
+            &mut batch,
+            &mut transaction,
+        ))
         .await?;
-    }
-    find_local_deletions(ctx, &mut mount_points, &existing_paths).await?;
+    batch.flush(ctx).await?;
+    transaction.flush(ctx).await?;
     pull_updates(ctx).await?;
-    for mount_point in &ctx.config.mount_points {
+    for (index, (mount_point, paths, _)) in overridden.iter().enumerate() {
+        let Some((root_local_path, root_archive_path)) = roots(index, mount_point) else {
+            continue;
+        };
         download_latest(
             ctx,
-            &mount_point.archive_path,
-            &mount_point.local_path,
-            &mut Rules::new(
-                &[&ctx.config.always_exclude, &mount_point.exclude],
-                mount_point.local_path.clone(),
-            ),
+            &root_archive_path,
+            &root_local_path,
+            // Rules loaded from ignore files while uploading also apply here.
+            &mut mount_points[index].1,
             true,
+            DownloadOptions {
+                excluded_triggers: &mount_point.download_trigger_filter,
+                ..DownloadOptions::default()
+            },
         )
         .await?;
+        // Local changes in overridden parts are never uploaded, so they are
+        // replaced with the archive content.
+        for (local_path, archive_path) in paths {
+            // Only overridden parts within the synced path are replaced.
+            let (local_path, archive_path) = if local_path.as_path().starts_with(&root_local_path) {
+                (local_path, archive_path)
+            } else if root_local_path.as_path().starts_with(local_path) {
+                (&root_local_path, &root_archive_path)
+            } else {
+                continue;
+            };
+            if ctx.db.get_archive_entry(archive_path)?.is_none() {
+                continue;
+            }
+            download_latest(
+                ctx,
+                archive_path,
+                local_path,
+                &mut mount_rules(mount_point, &[]),
+                true,
+                DownloadOptions {
+                    overwrite_local_changes: true,
+                    excluded_triggers: &mount_point.download_trigger_filter,
+                    ..DownloadOptions::default()
+                },
+            )
+            .await?;
+        }
     }
     Ok(())
 }
@@ -1,19 +1,29 @@
-use anyhow::{anyhow, bail, Result};
+use anyhow::{anyhow, bail, Context as _, Result};
 use fs_err as fs;
-use futures::future::BoxFuture;
+use futures::{future::BoxFuture, stream, StreamExt};
+use itertools::{Either, Itertools};
 use rammingen_protocol::{
-    endpoints::{AddVersion, ContentHashExists},
+    endpoints::{AddVersion, AddVersions, ContentHashExists, LimitExceeded},
     util::native_to_archive_relative_path,
-    ArchivePath, DateTimeUtc, EntryKind, FileContent, RecordTrigger,
+    ArchivePath, ContentHash, DateTimeUtc, EntryKind, FileContent, RecordTrigger,
+};
+use std::{
+    cmp::Reverse,
+    collections::HashSet,
+    error::Error,
+    fmt,
+    fs::Metadata,
+    io, mem,
+    sync::atomic::Ordering,
+    time::{Duration, SystemTime},
 };
-use std::{collections::HashSet, sync::atomic::Ordering, time::Duration};
 use tokio::{task::block_in_place, time::sleep};
 use tracing::{debug, info, warn};
 
 use crate::{
-    config::MountPoint,
+    config::{MountPoint, MtimePrecision, TooRecentPolicy},
     data::{DecryptedFileContent, LocalEntryInfo},
-    encryption::{self, encrypt_content_hash, encrypt_path, encrypt_size},
+    encryption::{self, encrypt_content_hash, encrypt_size},
     path::SanitizedLocalPath,
     rules::Rules,
     term::set_status,
@@ -22,22 +32,79 @@ use crate::{
 
 const TOO_RECENT_INTERVAL: Duration = Duration::from_millis(100);
 
+/// Error of reading a local path that is being uploaded: the path was removed
+/// during the scan, access to it was denied, or it's not a regular file or directory.
+/// Such paths are skipped if `skip_unreadable` is set.
+#[derive(Debug)]
+struct UnreadablePath {
+    path: SanitizedLocalPath,
+    source: anyhow::Error,
+}
+
+impl fmt::Display for UnreadablePath {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "cannot read {}", self.path)
+    }
+}
+
+impl Error for UnreadablePath {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        Some(&*self.source)
+    }
+}
+
+trait ReadResultExt<T> {
+    /// Converts errors meaning that `path` was removed or access to it was denied
+    /// into `UnreadablePath`. Other errors (e.g. no space left for temporary files)
+    /// are returned as is.
+    fn unreadable_path(self, path: &SanitizedLocalPath) -> Result<T>;
+}
+
+impl<T, E: Into<anyhow::Error>> ReadResultExt<T> for Result<T, E> {
+    fn unreadable_path(self, path: &SanitizedLocalPath) -> Result<T> {
+        self.map_err(|err| {
+            let err = err.into();
+            let unreadable = err.chain().any(|err| {
+                err.downcast_ref::<io::Error>().is_some_and(|err| {
+                    matches!(
+                        err.kind(),
+                        io::ErrorKind::PermissionDenied | io::ErrorKind::NotFound
+                    )
+                })
+            });
+            if unreadable {
+                UnreadablePath {
+                    path: path.clone(),
+                    source: err,
+                }
+                .into()
+            } else {
+                err
+            }
+        })
+    }
+}
+
+fn is_unreadable_path(err: &anyhow::Error) -> bool {
+    err.chain().any(|err| err.is::<UnreadablePath>())
+}
+
 pub fn to_archive_path<'a>(
     local_path: &SanitizedLocalPath,
     mount_points: &'a mut [(&MountPoint, Rules)],
 ) -> Result<Option<(ArchivePath, &'a mut Rules)>> {
-    for (mount_point, rules) in mount_points {
-        if local_path == &mount_point.local_path {
-            return Ok(Some((mount_point.archive_path.clone(), rules)));
-        }
-        if let Ok(relative) = local_path.as_path().strip_prefix(&mount_point.local_path) {
-            let archive = mount_point
-                .archive_path
-                .join_multiple(&native_to_archive_relative_path(relative)?)?;
-            return Ok(Some((archive, rules)));
-        }
+    let Some(index) = mount_point_index(local_path, mount_points) else {
+        return Ok(None);
+    };
+    let (mount_point, rules) = &mut mount_points[index];
+    if local_path == &mount_point.local_path {
+        return Ok(Some((mount_point.archive_path.clone(), rules)));
     }
-    Ok(None)
+    let relative = local_path.as_path().strip_prefix(&mount_point.local_path)?;
+    let archive = mount_point
+        .archive_path
+        .join_multiple(&native_to_archive_relative_path(relative)?)?;
+    Ok(Some((archive, rules)))
 
     // if let Some(value) = cache.get(local_path) {
     //     return value.clone();
@@ -69,42 +136,502 @@ pub fn to_archive_path<'a>(
     // output
 }
 
+/// Returns the index of the mount point containing `local_path`. If multiple mount points
+/// contain it, the one with the highest priority is chosen. If priorities are equal,
+/// the innermost mount point is chosen.
+pub fn mount_point_index(
+    local_path: &SanitizedLocalPath,
+    mount_points: &[(&MountPoint, Rules)],
+) -> Option<usize> {
+    mount_points
+        .iter()
+        .enumerate()
+        .filter(|(_, (mount_point, _))| local_path.as_path().starts_with(&mount_point.local_path))
+        .min_by_key(|(_, (mount_point, _))| {
+            Reverse((
+                mount_point.priority,
+                mount_point.local_path.as_path().components().count(),
+            ))
+        })
+        .map(|(index, _)| index)
+}
+
+/// Maximal number of versions of transactional mount points recorded in a single
+/// server transaction. If a sync produces more versions, they are recorded in several
+/// transactions, and the archive may be observed in a partially updated state
+/// between them.
+pub const MAX_TRANSACTION_SIZE: usize = 16384;
+
+/// Versions waiting to be recorded on the server.
+///
+/// Local database is updated only after the versions are recorded, so if the upload is
+/// interrupted, pending changes are detected again on the next run. The server records
+/// a batch in a single transaction, so if it rejects one of the versions, none of them
+/// are recorded.
+pub struct VersionBatch {
+    max_size: usize,
+    max_uploaded_size: Option<u64>,
+    uploaded_size: u64,
+    /// If true, versions are only reported, and content is not uploaded.
+    dry_run: bool,
+    /// If true, versions of a dry run are checked by the server.
+    validate_server: bool,
+    /// If true, content of files added to the batch is not uploaded.
+    metadata_only: bool,
+    /// True if some of the pending versions refer to content that was not uploaded.
+    has_metadata_only_versions: bool,
+    versions: Vec<AddVersion>,
+    pending: Vec<PendingVersion>,
+    /// Set if the batch only collects an estimate of the upload.
+    estimate: Option<UploadEstimate>,
+}
+
+struct PendingVersion {
+    local_path: SanitizedLocalPath,
+    archive_path: ArchivePath,
+    change: PendingChange,
+}
+
+enum PendingChange {
+    Upload {
+        old_kind: Option<EntryKind>,
+        local_entry: LocalEntryInfo,
+        is_mount: bool,
+    },
+    Deletion,
+}
+
+impl VersionBatch {
+    /// Creates a batch that is sent to the server automatically
+    /// when it reaches `max_size` versions.
+    pub fn new(max_size: usize) -> Self {
+        Self {
+            max_size,
+            max_uploaded_size: None,
+            uploaded_size: 0,
+            dry_run: false,
+            validate_server: false,
+            metadata_only: false,
+            has_metadata_only_versions: false,
+            versions: Vec::new(),
+            pending: Vec::new(),
+            estimate: None,
+        }
+    }
+
+    /// Creates a batch with limits specified in the config.
+    pub fn from_config(ctx: &Ctx) -> Self {
+        Self {
+            max_uploaded_size: Some(ctx.config.add_versions_batch_bytes),
+            ..Self::new(ctx.config.add_versions_batch_size)
+        }
+    }
+
+    /// Creates a batch that only reports versions that would be recorded.
+    /// If `validate_server` is true, all versions are sent to the server in a single
+    /// dry run request, so that the server checks them without recording anything.
+    pub fn dry_run(validate_server: bool) -> Self {
+        Self {
+            dry_run: true,
+            validate_server,
+            ..Self::new(usize::MAX)
+        }
+    }
+
+    /// Creates a batch that only collects an estimate of the upload. File content
+    /// is not read, and nothing is sent to the server.
+    pub fn estimate() -> Self {
+        Self {
+            estimate: Some(UploadEstimate::default()),
+            ..Self::new(usize::MAX)
+        }
+    }
+
+    /// Returns the estimate collected by a batch created with `estimate`.
+    pub fn take_estimate(&mut self) -> Option<UploadEstimate> {
+        self.estimate.take()
+    }
+
+    /// Sets whether content of files added to the batch from now on is uploaded.
+    /// Used for metadata-only mount points.
+    pub fn set_metadata_only(&mut self, metadata_only: bool) {
+        self.metadata_only = metadata_only;
+    }
+
+    /// Returns true if content of new file versions should be uploaded.
+    fn uploads_content(&self) -> bool {
+        !self.dry_run && !self.metadata_only
+    }
+
+    /// Adds a version to the batch. `uploaded_size` is the size of content uploaded
+    /// for this version.
+    async fn add(
+        &mut self,
+        ctx: &Ctx,
+        version: AddVersion,
+        pending: PendingVersion,
+        uploaded_size: u64,
+    ) -> Result<()> {
+        if self.metadata_only && version.content.is_some() {
+            self.has_metadata_only_versions = true;
+        }
+        self.versions.push(version);
+        self.pending.push(pending);
+        self.uploaded_size += uploaded_size;
+        if self.versions.len() >= self.max_size
+            || self
+                .max_uploaded_size
+                .is_some_and(|max| self.uploaded_size >= max)
+        {
+            self.flush(ctx).await?;
+        }
+        Ok(())
+    }
+
+    /// Adds a version that records `local_entry` from the local database
+    /// as the current state of `archive_path`. File content must already be
+    /// present on the server.
+    pub async fn add_local_entry(
+        &mut self,
+        ctx: &Ctx,
+        local_path: SanitizedLocalPath,
+        archive_path: ArchivePath,
+        local_entry: LocalEntryInfo,
+    ) -> Result<()> {
+        let version = AddVersion {
+            path: ctx.encrypt_path(&archive_path)?,
+            record_trigger: RecordTrigger::Upload,
+            kind: Some(local_entry.kind),
+            content: local_entry
+                .content
+                .as_ref()
+                .map(|content| file_content(ctx, content))
+                .transpose()?,
+        };
+        ctx.counters.sent_to_server.fetch_add(1, Ordering::Relaxed);
+        let pending = PendingVersion {
+            local_path,
+            archive_path,
+            change: PendingChange::Upload {
+                old_kind: None,
+                local_entry,
+                is_mount: true,
+            },
+        };
+        self.add(ctx, version, pending, 0).await
+    }
+
+    /// Records all pending versions on the server in a single transaction.
+    pub async fn flush(&mut self, ctx: &Ctx) -> Result<()> {
+        if self.versions.is_empty() {
+            return Ok(());
+        }
+        let versions = AddVersions {
+            versions: mem::take(&mut self.versions),
+            dry_run: self.dry_run,
+            metadata_only: mem::take(&mut self.has_metadata_only_versions),
+        };
+        let pending = mem::take(&mut self.pending);
+        self.uploaded_size = 0;
+        if self.dry_run {
+            return self.report_dry_run(ctx, versions, pending).await;
+        }
+        let _status = set_status("Recording versions on server");
+        if ctx.config.log_decrypted_paths {
+            for item in &pending {
+                info!("AddVersion: {}", item.archive_path);
+            }
+        }
+        let responses = ctx
+            .counters
+            .timings
+            .record
+            .measure_async(ctx.client.request(&versions))
+            .await
+            .map_err(|err| {
+                if err.downcast_ref::<LimitExceeded>().is_some() {
+                    err.context(format!(
+                        "server limit exceeded, {} changes were not recorded",
+                        pending.len()
+                    ))
+                } else {
+                    err
+                }
+            })?;
+        if responses.len() != pending.len() {
+            bail!(
+                "invalid number of responses: expected {}, got {}",
+                pending.len(),
+                responses.len()
+            );
+        }
+        for (item, response) in pending.into_iter().zip(responses) {
+            if response.added {
+                ctx.counters
+                    .updated_on_server
+                    .fetch_add(1, Ordering::Relaxed);
+            }
+            match item.change {
+                PendingChange::Upload {
+                    old_kind,
+                    local_entry,
+                    is_mount,
+                } => {
+                    if response.added {
+                        info!("Uploaded {}", item.local_path);
+                    }
+                    if let Some(old_kind) = old_kind {
+                        if old_kind != local_entry.kind {
+                            ctx.counters
+                                .kind_changed(&item.local_path, old_kind, local_entry.kind);
+                        }
+                    }
+                    if is_mount {
+                        ctx.db.set_local_entry(&item.local_path, &local_entry)?;
+                    }
+                }
+                PendingChange::Deletion => {
+                    if response.added {
+                        info!("Recorded deletion of {}", item.local_path);
+                    }
+                    ctx.db.remove_local_entry(&item.local_path)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Logs versions of a dry run, checking them on the server first if requested.
+    async fn report_dry_run(
+        &self,
+        ctx: &Ctx,
+        versions: AddVersions,
+        pending: Vec<PendingVersion>,
+    ) -> Result<()> {
+        let responses = if self.validate_server {
+            let _status = set_status("Validating versions on server");
+            let responses = ctx
+                .client
+                .request(&versions)
+                .await
+                .map_err(|err| err.context("server rejected the changes"))?;
+            if responses.len() != pending.len() {
+                bail!(
+                    "invalid number of responses: expected {}, got {}",
+                    pending.len(),
+                    responses.len()
+                );
+            }
+            Some(responses)
+        } else {
+            None
+        };
+        let mut count = 0;
+        for (index, item) in pending.iter().enumerate() {
+            if responses
+                .as_ref()
+                .is_some_and(|responses| !responses[index].added)
+            {
+                continue;
+            }
+            count += 1;
+            match item.change {
+                PendingChange::Upload { .. } => info!("Would upload {}", item.local_path),
+                PendingChange::Deletion => info!("Would record deletion of {}", item.local_path),
+            }
+        }
+        if self.validate_server {
+            info!(
+                "server would accept the changes, {} paths would be updated",
+                count
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Encrypts content info for a new version.
+fn file_content(ctx: &Ctx, content: &DecryptedFileContent) -> Result<FileContent> {
+    Ok(FileContent {
+        modified_at: content.modified_at,
+        original_size: encrypt_size(content.original_size, &ctx.cipher)?,
+        encrypted_size: content.encrypted_size,
+        hash: encrypt_content_hash(&content.hash, &ctx.cipher)?,
+        unix_mode: content.unix_mode,
+    })
+}
+
+/// Records deletions of paths that are present in the local database but were not found
+/// during the upload. Deletions in transactional mount points are added to `transaction`,
+/// and other deletions are added to `batch`.
+/// If `root` is specified, only entries within it are checked.
 pub async fn find_local_deletions<'a>(
     ctx: &'a Ctx,
     mount_points: &'a mut [(&MountPoint, Rules)],
     existing_paths: &'a HashSet<SanitizedLocalPath>,
+    root: Option<&SanitizedLocalPath>,
+    batch: &mut VersionBatch,
+    transaction: &mut VersionBatch,
 ) -> Result<()> {
     let _status = set_status("Checking for files deleted locally");
-    for entry in ctx.db.get_all_local_entries().rev() {
-        let (local_path, _data) = entry?;
+    // Nested paths must be deleted before their parents.
+    let local_paths = if let Some(root) = root {
+        let root_entry = ctx.db.get_local_entry(root)?.map(|_| Ok(root.clone()));
+        Either::Left(
+            ctx.db
+                .get_local_entry_paths_under(root)
+                .rev()
+                .chain(root_entry),
+        )
+    } else {
+        Either::Right(
+            ctx.db
+                .get_all_local_entries()
+                .rev()
+                .map_ok(|(path, _data)| path),
+        )
+    };
+    for local_path in local_paths {
+        let local_path = local_path?;
         if existing_paths.contains(&local_path) {
             continue;
         }
 
-        let Some((archive_path, rules)) =
-            to_archive_path(&local_path, mount_points)?
-            else {
-                continue;
-            };
+        let transactional = mount_point_index(&local_path, mount_points)
+            .is_some_and(|index| mount_points[index].0.transactional);
+        let Some((archive_path, rules)) = to_archive_path(&local_path, mount_points)? else {
+            continue;
+        };
         if rules.matches(&local_path)? {
             continue;
         }
-        let response = ctx
-            .client
-            .request(&AddVersion {
-                path: encrypt_path(&archive_path, &ctx.cipher)?,
-                record_trigger: RecordTrigger::Sync,
-                kind: None,
-                content: None,
+        let request = AddVersion {
+            path: ctx.encrypt_path(&archive_path)?,
+            record_trigger: RecordTrigger::Sync,
+            kind: None,
+            content: None,
+        };
+        let pending = PendingVersion {
+            local_path,
+            archive_path,
+            change: PendingChange::Deletion,
+        };
+        if transactional {
+            transaction.add(ctx, request, pending, 0).await?;
+        } else {
+            batch.add(ctx, request, pending, 0).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Checks whether a file may have changed since it was recorded in the local database.
+/// Only metadata is compared, so the file may still turn out to be unchanged.
+fn file_maybe_changed(
+    db_data: Option<&LocalEntryInfo>,
+    modified: DateTimeUtc,
+    unix_mode: Option<u32>,
+    mtime_precision: MtimePrecision,
+) -> bool {
+    db_data.map_or(true, |db_data| {
+        db_data.kind != EntryKind::File || {
+            db_data.content.as_ref().map_or(true, |content| {
+                !mtime_precision.same_time(content.modified_at, modified)
+                    || content.unix_mode != unix_mode
             })
-            .await?;
-        if response.added {
-            ctx.counters
-                .updated_on_server
-                .fetch_add(1, Ordering::Relaxed);
-            info!("Recorded deletion of {}", local_path);
         }
-        ctx.db.remove_local_entry(&local_path)?;
+    })
+}
+
+/// Estimate of an upload, collected by walking the local tree in the same way
+/// as `upload` with a batch created by `VersionBatch::estimate`.
+#[derive(Debug, Default)]
+pub struct UploadEstimate {
+    pub new_directories: u64,
+    pub changed_files: u64,
+    pub unchanged_files: u64,
+    pub original_size: u64,
+    pub encrypted_size: u64,
+}
+
+impl UploadEstimate {
+    /// Counts a file. It's considered changed based on its metadata only.
+    fn add_file(
+        &mut self,
+        ctx: &Ctx,
+        db_data: Option<&LocalEntryInfo>,
+        metadata: &Metadata,
+    ) -> Result<()> {
+        if file_maybe_changed(
+            db_data,
+            DateTimeUtc::from(metadata.modified()?),
+            unix_mode(metadata),
+            ctx.config.mtime_precision,
+        ) {
+            self.changed_files += 1;
+            self.original_size += metadata.len();
+            self.encrypted_size += encryption::estimate_encrypted_size(metadata.len());
+        } else {
+            self.unchanged_files += 1;
+        }
+        Ok(())
+    }
+}
+
+/// Checks whether a file of `encrypted_size` would be rejected by the server
+/// because of its `max_file_size` limit, and records the skip if so.
+async fn exceeds_max_file_size(
+    ctx: &Ctx,
+    local_path: &SanitizedLocalPath,
+    encrypted_size: u64,
+) -> bool {
+    let Some(max_file_size) = ctx.server_limits().await.max_file_size else {
+        return false;
+    };
+    if encrypted_size <= max_file_size {
+        return false;
+    }
+    warn!(
+        "skipping file {} because its size ({} bytes) exceeds the server limit ({} bytes)",
+        local_path, encrypted_size, max_file_size
+    );
+    ctx.counters.oversized(local_path);
+    true
+}
+
+/// Returns an error if too many paths were skipped because they couldn't be read.
+/// `max_error_rate` is only checked if `scan_finished` is true.
+pub fn check_error_thresholds(ctx: &Ctx, scan_finished: bool) -> Result<()> {
+    let errors = ctx.counters.unreadable_paths.lock().len() + ctx.counters.busy_paths.lock().len();
+    thresholds_exceeded(
+        ctx.config.max_errors,
+        ctx.config.max_error_rate,
+        errors as u64,
+        ctx.counters.scanned_entries.load(Ordering::Relaxed),
+        scan_finished,
+    )
+}
+
+fn thresholds_exceeded(
+    max_errors: Option<u64>,
+    max_error_rate: Option<f64>,
+    errors: u64,
+    scanned: u64,
+    scan_finished: bool,
+) -> Result<()> {
+    if let Some(max_errors) = max_errors {
+        if errors > max_errors {
+            bail!("aborting because {errors} paths were skipped (max_errors is {max_errors})");
+        }
+    }
+    if let Some(max_error_rate) = max_error_rate.filter(|_| scan_finished) {
+        let scanned = scanned + errors;
+        if errors > 0 && errors as f64 > max_error_rate * scanned as f64 {
+            bail!(
+                "aborting because {errors} of {scanned} paths were skipped \
+                (max_error_rate is {max_error_rate})"
+            );
+        }
     }
     Ok(())
 }
@@ -116,11 +643,137 @@ pub fn upload<'a>(
     rules: &'a mut Rules,
     is_mount: bool,
     existing_paths: &'a mut HashSet<SanitizedLocalPath>,
+    batch: &'a mut VersionBatch,
+) -> BoxFuture<'a, Result<()>> {
+    Box::pin(upload_entry(
+        ctx,
+        local_path,
+        archive_path,
+        rules,
+        is_mount,
+        existing_paths,
+        batch,
+        None,
+    ))
+}
+
+/// Hash of a local file computed ahead of `upload_inner` processing it.
+struct PrefetchedHash {
+    /// Modification time of the file before it was hashed.
+    modified: SystemTime,
+    hash: ContentHash,
+    original_size: u64,
+}
+
+/// Returns the modification time and the size of `local_path` if it's a file
+/// that `upload_inner` is going to hash. Only metadata is read.
+fn prefetch_candidate(
+    ctx: &Ctx,
+    local_path: &SanitizedLocalPath,
+    rules: &mut Rules,
+) -> Option<(SystemTime, u64)> {
+    let metadata = fs::symlink_metadata(local_path).ok()?;
+    if !metadata.is_file()
+        || rules.matches(local_path).unwrap_or(true)
+        || rules
+            .matches_filesystem(local_path, &metadata)
+            .unwrap_or(true)
+    {
+        return None;
+    }
+    let modified = metadata.modified().ok()?;
+    if modified
+        .elapsed()
+        .map_or(true, |elapsed| elapsed < TOO_RECENT_INTERVAL)
+    {
+        return None;
+    }
+    let db_data = ctx.db.get_local_entry(local_path).ok()?;
+    file_maybe_changed(
+        db_data.as_ref(),
+        DateTimeUtc::from(modified),
+        unix_mode(&metadata),
+        ctx.config.mtime_precision,
+    )
+    .then_some((modified, metadata.len()))
+}
+
+/// Hashes `local_path` in the hash pool. Errors are left for `upload_inner` to report.
+async fn prefetch_hash(
+    ctx: &Ctx,
+    local_path: SanitizedLocalPath,
+    modified: SystemTime,
+    size: u64,
+) -> Option<PrefetchedHash> {
+    ctx.hash_pool
+        .spawn(size, move || {
+            let (hash, original_size) = encryption::hash_file(&local_path)?;
+            if fs::symlink_metadata(&local_path)?.modified()? != modified {
+                bail!("file was modified while it was being hashed");
+            }
+            Ok(PrefetchedHash {
+                modified,
+                hash,
+                original_size,
+            })
+        })
+        .await
+        .ok()
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn upload_entry(
+    ctx: &Ctx,
+    local_path: &SanitizedLocalPath,
+    archive_path: &ArchivePath,
+    rules: &mut Rules,
+    is_mount: bool,
+    existing_paths: &mut HashSet<SanitizedLocalPath>,
+    batch: &mut VersionBatch,
+    prefetched: Option<PrefetchedHash>,
+) -> Result<()> {
+    let result = upload_inner(
+        ctx,
+        local_path,
+        archive_path,
+        rules,
+        is_mount,
+        existing_paths,
+        batch,
+        prefetched,
+    )
+    .await;
+    match result {
+        Err(err) if ctx.config.skip_unreadable && is_unreadable_path(&err) => {
+            warn!("skipping unreadable path {}: {:#}", local_path, err);
+            ctx.counters.unreadable(local_path);
+            check_error_thresholds(ctx, false)?;
+            // Entries that couldn't be scanned must not be recorded as deleted.
+            existing_paths.insert(local_path.clone());
+            for path in ctx.db.get_local_entry_paths_under(local_path) {
+                existing_paths.insert(path?);
+            }
+            Ok(())
+        }
+        result => result,
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn upload_inner<'a>(
+    ctx: &'a Ctx,
+    local_path: &'a SanitizedLocalPath,
+    archive_path: &'a ArchivePath,
+    rules: &'a mut Rules,
+    is_mount: bool,
+    existing_paths: &'a mut HashSet<SanitizedLocalPath>,
+    batch: &'a mut VersionBatch,
+    prefetched: Option<PrefetchedHash>,
 ) -> BoxFuture<'a, Result<()>> {
     Box::pin(async move {
         let _status = set_status(format!("Scanning local files: {}", local_path));
         existing_paths.insert(local_path.clone());
-        let mut metadata = fs::symlink_metadata(local_path)?;
+        let mut metadata = fs::symlink_metadata(local_path).unreadable_path(local_path)?;
         if metadata.is_symlink() {
             warn!("skipping symlink: {}", local_path);
             return Ok(());
@@ -129,8 +782,24 @@ pub fn upload<'a>(
             debug!("ignored: {}", local_path);
             return Ok(());
         }
+        if rules.matches_filesystem(local_path, &metadata)? {
+            debug!("ignored (excluded filesystem): {}", local_path);
+            // Entries that were uploaded before are left as they are.
+            for path in ctx.db.get_local_entry_paths_under(local_path) {
+                existing_paths.insert(path?);
+            }
+            return Ok(());
+        }
         ctx.counters.scanned_entries.fetch_add(1, Ordering::Relaxed);
         let is_dir = metadata.is_dir();
+        if !is_dir && !metadata.is_file() {
+            // Opening sockets or device files fails, and opening FIFOs blocks.
+            return Err(UnreadablePath {
+                path: local_path.clone(),
+                source: anyhow!("not a regular file or directory"),
+            }
+            .into());
+        }
         let kind = if is_dir {
             EntryKind::Directory
         } else {
@@ -140,6 +809,7 @@ pub fn upload<'a>(
 
         let changed;
         let content;
+        let mut uploaded_size = 0;
 
         if is_dir {
             changed = db_data
@@ -147,9 +817,12 @@ pub fn upload<'a>(
                 .map_or(true, |db_data| db_data.kind != kind);
             content = None;
         } else {
+            if let Some(estimate) = &mut batch.estimate {
+                return estimate.add_file(ctx, db_data.as_ref(), &metadata);
+            }
             let mut modified = None;
             for _ in 0..5 {
-                metadata = fs::symlink_metadata(local_path)?;
+                metadata = fs::symlink_metadata(local_path).unreadable_path(local_path)?;
                 let new_modified = metadata.modified()?;
                 if new_modified.elapsed()? < TOO_RECENT_INTERVAL {
                     info!("file {} was modified recently, waiting...", local_path);
@@ -159,58 +832,194 @@ pub fn upload<'a>(
                     break;
                 }
             }
-            let modified =
-                modified.ok_or_else(|| anyhow!("file {:?} keeps updating", local_path))?;
+            let modified = match (modified, ctx.config.too_recent_policy) {
+                (Some(modified), _) => modified,
+                (None, TooRecentPolicy::Wait) => bail!("file {:?} keeps updating", local_path),
+                (None, TooRecentPolicy::Skip) => {
+                    warn!("skipping file {} because it keeps updating", local_path);
+                    ctx.counters.busy(local_path);
+                    check_error_thresholds(ctx, false)?;
+                    return Ok(());
+                }
+                (None, TooRecentPolicy::Snapshot) => metadata.modified()?,
+            };
             let modified_datetime = DateTimeUtc::from(modified);
             let unix_mode = unix_mode(&metadata);
 
-            let maybe_changed = db_data.as_ref().map_or(true, |db_data| {
-                db_data.kind != kind || {
-                    db_data.content.as_ref().map_or(true, |content| {
-                        content.modified_at != modified_datetime || content.unix_mode != unix_mode
-                    })
-                }
-            });
+            let maybe_changed = file_maybe_changed(
+                db_data.as_ref(),
+                modified_datetime,
+                unix_mode,
+                ctx.config.mtime_precision,
+            );
 
             if maybe_changed {
-                let file_data =
-                    block_in_place(|| encryption::encrypt_file(local_path, &ctx.cipher))?;
+                let db_content = db_data
+                    .as_ref()
+                    .filter(|db_data| db_data.kind == kind)
+                    .and_then(|db_data| db_data.content.as_ref());
+                // A prefetched hash is used if it's enough to tell that the content is unchanged.
+                // With streaming uploads, content is always encrypted later, if it needs to be uploaded.
+                let prefetched = prefetched.filter(|prefetched| {
+                    prefetched.modified == modified
+                        && (ctx.config.stream_uploads
+                            || db_content.is_some_and(|content| content.hash == prefetched.hash))
+                });
+                // Held until the content is read and uploaded, including the encrypted
+                // temporary file if content is not streamed.
+                let _bytes_permit = if prefetched.is_some() && !ctx.config.stream_uploads {
+                    None
+                } else {
+                    Some(ctx.hash_pool.reserve_bytes(metadata.len()).await?)
+                };
+                let (hash, original_size, encrypted_file) = if let Some(prefetched) = prefetched {
+                    (prefetched.hash, prefetched.original_size, None)
+                } else if ctx.config.stream_uploads {
+                    let (hash, original_size) = ctx
+                        .counters
+                        .timings
+                        .hash
+                        .measure(|| block_in_place(|| encryption::hash_file(local_path)))
+                        .unreadable_path(local_path)?;
+                    (hash, original_size, None)
+                } else {
+                    let file_data = ctx
+                        .counters
+                        .timings
+                        .hash
+                        .measure(|| {
+                            block_in_place(|| encryption::encrypt_file(local_path, &ctx.cipher))
+                        })
+                        .unreadable_path(local_path)?;
+                    (
+                        file_data.hash,
+                        file_data.original_size,
+                        Some((file_data.file, file_data.encrypted_size)),
+                    )
+                };
 
-                let final_modified = fs::symlink_metadata(local_path)?.modified()?;
+                let final_modified = fs::symlink_metadata(local_path)
+                    .unreadable_path(local_path)?
+                    .modified()?;
                 if final_modified != modified {
-                    bail!(
-                        "file {:?} was updated while it was being processed",
-                        local_path
-                    );
+                    match ctx.config.too_recent_policy {
+                        TooRecentPolicy::Wait => bail!(
+                            "file {:?} was updated while it was being processed",
+                            local_path
+                        ),
+                        TooRecentPolicy::Skip => {
+                            warn!(
+                                "skipping file {} because it was updated while it was being processed",
+                                local_path
+                            );
+                            ctx.counters.busy(local_path);
+                            check_error_thresholds(ctx, false)?;
+                            return Ok(());
+                        }
+                        TooRecentPolicy::Snapshot => warn!(
+                            "file {} was updated while it was being processed, \
+                            uploaded content may be inconsistent",
+                            local_path
+                        ),
+                    }
                 }
 
-                let current_content = DecryptedFileContent {
-                    modified_at: modified_datetime,
-                    original_size: file_data.original_size,
-                    encrypted_size: file_data.encrypted_size,
-                    hash: file_data.hash,
-                    unix_mode,
-                };
-
-                changed = db_data.as_ref().map_or(true, |db_data| {
-                    db_data.kind != kind || {
-                        db_data.content.as_ref().map_or(true, |content| {
-                            content.hash != current_content.hash
-                                || content.unix_mode != current_content.unix_mode
-                        })
-                    }
+                changed = db_content.map_or(true, |content| {
+                    content.hash != hash || content.unix_mode != unix_mode
                 });
 
-                let encrypted_hash = encrypt_content_hash(&current_content.hash, &ctx.cipher)?;
-                if changed
+                if let Some((_, encrypted_size)) = &encrypted_file {
+                    // Checked before uploading to avoid sending content that will be rejected.
+                    if changed && exceeds_max_file_size(ctx, local_path, *encrypted_size).await {
+                        return Ok(());
+                    }
+                }
+                let encrypted_hash = encrypt_content_hash(&hash, &ctx.cipher)?;
+                let encrypted_size = if let Some((file, encrypted_size)) = encrypted_file {
+                    if changed
+                        && batch.uploads_content()
+                        && !ctx
+                            .client
+                            .request(&ContentHashExists(encrypted_hash.clone()))
+                            .await?
+                    {
+                        uploaded_size = encrypted_size;
+                        ctx.with_path_context(
+                            "Content upload",
+                            archive_path,
+                            ctx.counters
+                                .timings
+                                .upload
+                                .measure_async(ctx.client.upload(&encrypted_hash, file)),
+                        )
+                        .await?;
+                    }
+                    encrypted_size
+                } else if let Some(content) = db_content.filter(|content| content.hash == hash) {
+                    content.encrypted_size
+                } else if batch.uploads_content()
                     && !ctx
                         .client
                         .request(&ContentHashExists(encrypted_hash.clone()))
                         .await?
                 {
-                    ctx.client.upload(&encrypted_hash, file_data.file).await?;
-                }
+                    // Checked before streaming to avoid sending content that will be rejected.
+                    // The estimate assumes that content doesn't compress, so the exact size
+                    // is only calculated if the estimate exceeds the limit.
+                    let max_file_size = ctx.server_limits().await.max_file_size;
+                    if max_file_size.is_some_and(|max_file_size| {
+                        encryption::estimate_encrypted_size(original_size) > max_file_size
+                    }) {
+                        let encrypted_size = ctx
+                            .counters
+                            .timings
+                            .hash
+                            .measure(|| {
+                                block_in_place(|| encryption::encrypted_file_size(local_path))
+                            })
+                            .unreadable_path(local_path)?;
+                        if exceeds_max_file_size(ctx, local_path, encrypted_size).await {
+                            return Ok(());
+                        }
+                    }
+                    let encrypted_size = ctx
+                        .with_path_context(
+                            "Content upload",
+                            archive_path,
+                            ctx.counters
+                                .timings
+                                .upload
+                                .measure_async(ctx.client.upload_stream(
+                                    &hash,
+                                    local_path.as_path(),
+                                    &ctx.cipher,
+                                )),
+                        )
+                        .await
+                        .unreadable_path(local_path)?;
+                    uploaded_size = encrypted_size;
+                    encrypted_size
+                } else {
+                    // Content is already on the server (or is not uploaded in a dry run
+                    // or in a metadata-only mount point),
+                    // but its encrypted size is still needed.
+                    ctx.counters
+                        .timings
+                        .hash
+                        .measure(|| block_in_place(|| encryption::encrypted_file_size(local_path)))
+                        .unreadable_path(local_path)?
+                };
 
+                if changed && exceeds_max_file_size(ctx, local_path, encrypted_size).await {
+                    return Ok(());
+                }
+                let current_content = DecryptedFileContent {
+                    modified_at: modified_datetime,
+                    original_size,
+                    encrypted_size,
+                    hash,
+                    unix_mode,
+                };
                 content = Some(current_content);
             } else {
                 changed = false;
@@ -218,38 +1027,42 @@ pub fn upload<'a>(
             }
         };
 
-        if changed {
+        if let Some(estimate) = &mut batch.estimate {
+            // Files are counted before their content is read.
+            if changed {
+                estimate.new_directories += 1;
+            }
+        } else if changed {
             let add_version = AddVersion {
-                path: encrypt_path(archive_path, &ctx.cipher)?,
+                path: ctx.encrypt_path(archive_path)?,
                 record_trigger: RecordTrigger::Upload,
                 kind: Some(kind),
-                content: if let Some(content) = &content {
-                    Some(FileContent {
-                        modified_at: content.modified_at,
-                        original_size: encrypt_size(content.original_size, &ctx.cipher)?,
-                        encrypted_size: content.encrypted_size,
-                        hash: encrypt_content_hash(&content.hash, &ctx.cipher)?,
-                        unix_mode: content.unix_mode,
-                    })
-                } else {
-                    None
-                },
+                content: content
+                    .as_ref()
+                    .map(|content| file_content(ctx, content))
+                    .transpose()?,
             };
-            ctx.counters.sent_to_server.fetch_add(1, Ordering::Relaxed);
-            if ctx.client.request(&add_version).await?.added {
-                ctx.counters
-                    .updated_on_server
-                    .fetch_add(1, Ordering::Relaxed);
-                info!("Uploaded {}", local_path);
-            }
-            if is_mount {
-                ctx.db
-                    .set_local_entry(local_path, &LocalEntryInfo { kind, content })?;
+            if !batch.dry_run {
+                ctx.counters.sent_to_server.fetch_add(1, Ordering::Relaxed);
             }
+            let pending = PendingVersion {
+                local_path: local_path.clone(),
+                archive_path: archive_path.clone(),
+                change: PendingChange::Upload {
+                    old_kind: db_data.as_ref().map(|db_data| db_data.kind),
+                    local_entry: LocalEntryInfo { kind, content },
+                    is_mount,
+                },
+            };
+            batch.add(ctx, add_version, pending, uploaded_size).await?;
         }
         if is_dir {
-            for entry in fs::read_dir(local_path)? {
-                let entry = entry?;
+            rules
+                .load_ignore_file(local_path)
+                .unreadable_path(local_path)?;
+            let mut children = Vec::new();
+            for entry in fs::read_dir(local_path).unreadable_path(local_path)? {
+                let entry = entry.unreadable_path(local_path)?;
                 let file_name = entry.file_name();
                 let file_name_str = file_name
                     .to_str()
@@ -262,18 +1075,74 @@ pub fn upload<'a>(
                         err
                     )
                 })?;
-                upload(
+                children.push((entry.path(), entry_local_path, entry_archive_path));
+            }
+            // Files that may have changed are hashed in parallel ahead of being processed.
+            let candidates = children
+                .iter()
+                .map(|(_, entry_local_path, _)| {
+                    if batch.estimate.is_some() {
+                        return None;
+                    }
+                    prefetch_candidate(ctx, entry_local_path, rules)
+                        .map(|(modified, size)| (entry_local_path.clone(), modified, size))
+                })
+                .collect_vec();
+            let prefetched = stream::iter(candidates)
+                .map(|candidate| async move {
+                    let (local_path, modified, size) = candidate?;
+                    prefetch_hash(ctx, local_path, modified, size).await
+                })
+                .buffered(ctx.config.hash_threads.max(1));
+            tokio::pin!(prefetched);
+            for (path, entry_local_path, entry_archive_path) in &children {
+                let prefetched = prefetched.next().await.flatten();
+                upload_entry(
                     ctx,
-                    &entry_local_path,
-                    &entry_archive_path,
+                    entry_local_path,
+                    entry_archive_path,
                     rules,
                     is_mount,
                     existing_paths,
+                    batch,
+                    prefetched,
                 )
                 .await
-                .map_err(|err| anyhow!("Failed to process {:?}: {:?}", entry.path(), err))?;
+                .with_context(|| format!("Failed to process {:?}", path))?;
             }
         }
         Ok(())
     })
 }
+
+#[test]
+fn error_thresholds() {
+    assert!(thresholds_exceeded(None, None, 100, 0, true).is_ok());
+    assert!(thresholds_exceeded(Some(2), None, 2, 0, false).is_ok());
+    assert!(thresholds_exceeded(Some(2), None, 3, 0, false).is_err());
+
+    // The rate is only checked once scanning is finished.
+    assert!(thresholds_exceeded(None, Some(0.1), 5, 5, false).is_ok());
+    assert!(thresholds_exceeded(None, Some(0.1), 5, 5, true).is_err());
+    // Skipped paths are counted as scanned.
+    assert!(thresholds_exceeded(None, Some(0.1), 1, 9, true).is_ok());
+    assert!(thresholds_exceeded(None, Some(0.1), 2, 9, true).is_err());
+    assert!(thresholds_exceeded(None, Some(0.0), 0, 0, true).is_ok());
+}
+
+#[test]
+fn unreadable_path_errors() {
+    let path = SanitizedLocalPath::new("/tmp/unreadable").unwrap();
+    let mark = |err: io::Error| Err::<(), _>(err).unreadable_path(&path).unwrap_err();
+
+    let denied = mark(io::ErrorKind::PermissionDenied.into());
+    assert!(is_unreadable_path(&denied));
+    // Context added later doesn't hide the error.
+    assert!(is_unreadable_path(&denied.context("Failed to process")));
+    let removed = anyhow::Error::from(io::Error::from(io::ErrorKind::NotFound)).context("reading");
+    assert!(is_unreadable_path(
+        &Err::<(), _>(removed).unreadable_path(&path).unwrap_err()
+    ));
+    // ENOSPC from a temporary file is not a problem of the path.
+    assert!(!is_unreadable_path(&mark(io::Error::from_raw_os_error(28))));
+}
@@ -0,0 +1,153 @@
+//! Transport compression of responses.
+//!
+//! The client lists supported encodings in the `Accept-Encoding` header,
+//! and the server chooses one of them and reports it in `Content-Encoding`.
+//! Compression is only used for endpoints that return repetitive metadata
+//! (see `COMPRESS_RESPONSE` of the endpoint traits). File content is already
+//! compressed and encrypted by the client, so it's always sent as is.
+//! Each chunk of the response is flushed separately, so the client can
+//! decode items without waiting for the end of the stream.
+
+use std::io::Write;
+
+use anyhow::Result;
+use flate2::Compression;
+
+/// Value of the `Accept-Encoding` header sent by the client.
+pub const ACCEPT_ENCODING: &str = "zstd, gzip";
+
+const ZSTD_LEVEL: i32 = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    Gzip,
+    Zstd,
+}
+
+impl Encoding {
+    /// All supported encodings, in order of preference.
+    pub const ALL: [Encoding; 2] = [Encoding::Zstd, Encoding::Gzip];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Encoding::Gzip => "gzip",
+            Encoding::Zstd => "zstd",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.trim() {
+            "gzip" => Some(Encoding::Gzip),
+            "zstd" => Some(Encoding::Zstd),
+            _ => None,
+        }
+    }
+
+    /// Chooses the preferred encoding from the value of the `Accept-Encoding` header.
+    pub fn choose(accept_encoding: &str) -> Option<Self> {
+        let mut available = accept_encoding
+            .split(',')
+            .filter_map(|item| Self::from_name(item.split(';').next().unwrap_or_default()));
+        if available.clone().any(|encoding| encoding == Encoding::Zstd) {
+            Some(Encoding::Zstd)
+        } else {
+            available.next()
+        }
+    }
+}
+
+pub enum Encoder {
+    Gzip(flate2::write::GzEncoder<Vec<u8>>),
+    Zstd(zstd::stream::write::Encoder<'static, Vec<u8>>),
+}
+
+impl Encoder {
+    pub fn new(encoding: Encoding) -> Result<Self> {
+        Ok(match encoding {
+            Encoding::Gzip => Encoder::Gzip(flate2::write::GzEncoder::new(
+                Vec::new(),
+                Compression::default(),
+            )),
+            Encoding::Zstd => {
+                Encoder::Zstd(zstd::stream::write::Encoder::new(Vec::new(), ZSTD_LEVEL)?)
+            }
+        })
+    }
+
+    /// Compresses `data` and returns all output that is available so far.
+    pub fn encode(&mut self, data: &[u8]) -> Result<Vec<u8>> {
+        Ok(match self {
+            Encoder::Gzip(encoder) => {
+                encoder.write_all(data)?;
+                encoder.flush()?;
+                std::mem::take(encoder.get_mut())
+            }
+            Encoder::Zstd(encoder) => {
+                encoder.write_all(data)?;
+                encoder.flush()?;
+                std::mem::take(encoder.get_mut())
+            }
+        })
+    }
+
+    /// Returns the remaining output.
+    pub fn finish(self) -> Result<Vec<u8>> {
+        Ok(match self {
+            Encoder::Gzip(encoder) => encoder.finish()?,
+            Encoder::Zstd(encoder) => encoder.finish()?,
+        })
+    }
+}
+
+pub enum Decoder {
+    Gzip(flate2::write::GzDecoder<Vec<u8>>),
+    Zstd(zstd::stream::write::Decoder<'static, Vec<u8>>),
+}
+
+impl Decoder {
+    pub fn new(encoding: Encoding) -> Result<Self> {
+        Ok(match encoding {
+            Encoding::Gzip => Decoder::Gzip(flate2::write::GzDecoder::new(Vec::new())),
+            Encoding::Zstd => Decoder::Zstd(zstd::stream::write::Decoder::new(Vec::new())?),
+        })
+    }
+
+    /// Decompresses `data` and appends all output that is available so far to `out`.
+    pub fn decode(&mut self, data: &[u8], out: &mut Vec<u8>) -> Result<()> {
+        match self {
+            Decoder::Gzip(decoder) => {
+                decoder.write_all(data)?;
+                decoder.flush()?;
+                out.append(decoder.get_mut());
+            }
+            Decoder::Zstd(decoder) => {
+                decoder.write_all(data)?;
+                decoder.flush()?;
+                out.append(decoder.get_mut());
+            }
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn roundtrip() {
+    for encoding in [Encoding::Gzip, Encoding::Zstd] {
+        let mut encoder = Encoder::new(encoding).unwrap();
+        let mut decoder = Decoder::new(encoding).unwrap();
+        let mut out = Vec::new();
+        for i in 0..10 {
+            let chunk = format!("chunk {i} ").repeat(100);
+            let encoded = encoder.encode(chunk.as_bytes()).unwrap();
+            decoder.decode(&encoded, &mut out).unwrap();
+            // Each chunk must be available without waiting for the end of the stream.
+            assert!(out.ends_with(chunk.as_bytes()));
+        }
+        decoder
+            .decode(&encoder.finish().unwrap(), &mut out)
+            .unwrap();
+    }
+    assert_eq!(Encoding::choose("gzip, deflate"), Some(Encoding::Gzip));
+    assert_eq!(Encoding::choose("gzip;q=1.0, zstd"), Some(Encoding::Zstd));
+    assert_eq!(Encoding::choose("br"), None);
+}
@@ -1,3 +1,5 @@
+use std::{fmt, time::Duration};
+
 use serde::{Deserialize, Serialize};
 
 use crate::{
@@ -8,12 +10,18 @@ use crate::{
 pub trait RequestToResponse {
     type Response;
     const PATH: &'static str;
+    /// Whether the server may apply transport compression to the response.
+    const COMPRESS_RESPONSE: bool;
 }
 macro_rules! response_type {
     ($request:ty, $response:ty) => {
+        response_type!($request, $response, compress = false);
+    };
+    ($request:ty, $response:ty, compress = $compress:expr) => {
         impl RequestToResponse for $request {
             type Response = $response;
             const PATH: &'static str = concat!("/api/v1/", stringify!($request));
+            const COMPRESS_RESPONSE: bool = $compress;
         }
     };
 }
@@ -21,12 +29,18 @@ macro_rules! response_type {
 pub trait RequestToStreamingResponse {
     type ResponseItem;
     const PATH: &'static str;
+    /// Whether the server may apply transport compression to the response.
+    const COMPRESS_RESPONSE: bool;
 }
 macro_rules! streaming_response_type {
     ($request:ty, $response:ty) => {
+        streaming_response_type!($request, $response, compress = true);
+    };
+    ($request:ty, $response:ty, compress = $compress:expr) => {
         impl RequestToStreamingResponse for $request {
             type ResponseItem = $response;
             const PATH: &'static str = concat!("/api/v1/", stringify!($request));
+            const COMPRESS_RESPONSE: bool = $compress;
         }
     };
 }
@@ -43,12 +57,55 @@ pub struct GetNewEntries {
 }
 streaming_response_type!(GetNewEntries, Entry);
 
-/// Returns all entries that are direct children of the specified path.
-/// Results are ordered by path.
+/// Returns the largest update number of existing entries, or 0 if there are no entries.
 #[derive(Debug, Serialize, Deserialize)]
-pub struct GetDirectChildEntries(pub EncryptedArchivePath);
+pub struct GetLastUpdateNumber;
+response_type!(GetLastUpdateNumber, EntryUpdateNumber);
+
+/// Returns the current state of all entries with paths greater than `after_path`
+/// (or of all entries if it's not specified), ordered by path.
+///
+/// Used to initialize the local database of a new client. Entries updated after
+/// `GetLastUpdateNumber` was requested must be requested with `GetNewEntries` afterwards.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct GetBootstrapEntries {
+    pub after_path: Option<EncryptedArchivePath>,
+}
+streaming_response_type!(GetBootstrapEntries, Entry);
+
+/// Returns up to `limit` entries that are direct children of `path`.
+/// Results are ordered by kind (directories first, then files, then deleted entries)
+/// and then by path. If `after` is specified, only entries that come after it
+/// in this order are returned, so the next page can be requested
+/// using the last returned entry.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct GetDirectChildEntries {
+    pub path: EncryptedArchivePath,
+    pub after: Option<DirectChildEntriesCursor>,
+    pub limit: u32,
+}
 streaming_response_type!(GetDirectChildEntries, Entry);
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DirectChildEntriesCursor {
+    pub kind: Option<EntryKind>,
+    pub path: EncryptedArchivePath,
+}
+
+/// Returns the current state of each of the specified paths, in the same order.
+/// `None` is returned for paths that have never been recorded on the server.
+/// At most `MAX_GET_ENTRIES_PATHS` paths can be requested at once.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct GetEntries(pub Vec<EncryptedArchivePath>);
+response_type!(GetEntries, Vec<Option<Entry>>, compress = true);
+
+pub const MAX_GET_ENTRIES_PATHS: usize = 10_000;
+
+/// Returns all existing files with the specified content hash, ordered by path.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct GetEntriesByHash(pub EncryptedContentHash);
+streaming_response_type!(GetEntriesByHash, Entry, compress = false);
+
 /// Returns the version of the path corresponding to the specified time.
 /// If it's a directory, also returns the version of each child path
 /// at this time. Results are ordered by path.
@@ -91,6 +148,36 @@ pub struct AddVersionResponse {
     pub added: bool,
 }
 
+/// Adds multiple versions in the same way as `AddVersion`, in a single transaction:
+/// either all of them are recorded, or none. Versions are processed in order,
+/// and a response is returned for each of them.
+///
+/// If `dry_run` is true, the versions are checked in the same way, but the transaction
+/// is rolled back. Content missing from the storage is allowed in this mode.
+///
+/// If `metadata_only` is true, content of the versions doesn't have to be uploaded
+/// to the storage. Hashes of such content are remembered by the server, so that
+/// integrity checks don't report them as missing. Such content can't be downloaded.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AddVersions {
+    pub versions: Vec<AddVersion>,
+    pub dry_run: bool,
+    pub metadata_only: bool,
+}
+response_type!(AddVersions, Vec<AddVersionResponse>);
+
+/// Adds a new version of the specified path in the same way as `AddVersion`,
+/// but only if the current update number of the entry is equal to
+/// `expected_update_number`. If `expected_update_number` is `None`, the entry
+/// must not exist on the server. Returns an error if the entry has been
+/// changed since the client last saw it.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AddVersionIfUnchanged {
+    pub expected_update_number: Option<EntryUpdateNumber>,
+    pub version: AddVersion,
+}
+response_type!(AddVersionIfUnchanged, AddVersionResponse);
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct BulkActionStats {
     pub affected_paths: u64,
@@ -105,13 +192,49 @@ pub struct ResetVersion {
 }
 response_type!(ResetVersion, BulkActionStats);
 
+/// Permanently deletes all versions of the specified path recorded after `recorded_at`
+/// and restores the state it had at that time, as if the newer changes never happened.
+/// If a directory, rolls back all nested paths.
+/// Unlike `ResetVersion`, this removes history instead of adding new versions.
+/// Fails if a snapshot was made after `recorded_at`, because the exact state
+/// at that time may not be available anymore.
+/// If `dry_run` is true, only returns the number of paths that would be affected.
+/// Requires the server's admin token (see `ADMIN_TOKEN_HEADER`).
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Rollback {
+    pub path: EncryptedArchivePath,
+    pub recorded_at: DateTimeUtc,
+    pub dry_run: bool,
+}
+response_type!(Rollback, BulkActionStats);
+
+/// Removes all data recorded by a disabled source.
+/// Fails if the source was disabled less than `grace_period` ago.
+///
+/// Requires the server's admin token (see `ADMIN_TOKEN_HEADER`).
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PurgeSource {
+    pub name: String,
+    pub grace_period: Duration,
+}
+response_type!(PurgeSource, PurgeSourceStats);
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PurgeSourceStats {
+    pub deleted_entries: u64,
+    pub deleted_versions: u64,
+    pub removed_files: u64,
+}
+
 /// Records rename of `old_path` to `new_path`.
 /// `new_path` must not exist. If `old_path` is a directory,
 /// also renames all children.
+/// If `dry_run` is true, the rename is checked, but not recorded.
 #[derive(Debug, Serialize, Deserialize)]
 pub struct MovePath {
     pub old_path: EncryptedArchivePath,
     pub new_path: EncryptedArchivePath,
+    pub dry_run: bool,
 }
 response_type!(MovePath, BulkActionStats);
 
@@ -123,6 +246,67 @@ pub struct RemovePath {
 }
 response_type!(RemovePath, BulkActionStats);
 
+/// Marks the specified path as pinned. Versions of pinned paths are never removed
+/// when old history is pruned. If `recursive` is true, nested paths are pinned as well.
+/// Pinning an already pinned path replaces the `recursive` flag.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PinPath {
+    pub path: EncryptedArchivePath,
+    pub recursive: bool,
+}
+response_type!(PinPath, ());
+
+/// Removes the pin from the specified path. Returns false if the path was not pinned.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UnpinPath {
+    pub path: EncryptedArchivePath,
+}
+response_type!(UnpinPath, bool);
+
+/// Returns all pinned paths, ordered by path.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct GetPinnedPaths;
+response_type!(GetPinnedPaths, Vec<PinnedPath>, compress = true);
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PinnedPath {
+    pub path: EncryptedArchivePath,
+    pub recursive: bool,
+    pub pinned_at: DateTimeUtc,
+}
+
+/// Returns up to `limit` paths with the largest number of stored versions
+/// among `under` and all its nested paths, ordered by the number of versions (descending).
+#[derive(Debug, Serialize, Deserialize)]
+pub struct GetVersionHotspots {
+    pub under: EncryptedArchivePath,
+    pub limit: u32,
+}
+response_type!(GetVersionHotspots, Vec<VersionHotspot>, compress = true);
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct VersionHotspot {
+    pub path: EncryptedArchivePath,
+    pub versions: u64,
+}
+
+/// Returns statistics of the version of the path corresponding to the specified time,
+/// i.e. of the entries that `GetEntryVersionsAtTime` would return.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct GetVersionStats {
+    pub recorded_at: DateTimeUtc,
+    pub path: EncryptedArchivePath,
+}
+response_type!(GetVersionStats, VersionStats);
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct VersionStats {
+    pub files: u64,
+    pub directories: u64,
+    /// Total encrypted size of all files.
+    pub encrypted_size: u64,
+}
+
 /// Checks whether the specified content hash is stored on the server.
 #[derive(Debug, Serialize, Deserialize)]
 pub struct ContentHashExists(pub EncryptedContentHash);
@@ -136,20 +320,155 @@ response_type!(GetServerStatus, ServerStatus);
 #[derive(Debug, Serialize, Deserialize)]
 pub struct ServerStatus {
     pub available_space: u64,
+    /// Number of existing snapshots.
+    pub snapshots: u64,
+    /// Time of the latest snapshot. History before it is only available
+    /// at snapshot times.
+    pub last_snapshot_at: Option<DateTimeUtc>,
+    /// Time when the next snapshot is expected to be made, or `None` if there are
+    /// no entries yet. Snapshots are made by a periodic task, so it may happen
+    /// a bit later.
+    pub next_snapshot_at: Option<DateTimeUtc>,
+}
+
+/// Returns limits and policies enforced by the server, so that the client can
+/// check its changes before sending them.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct GetServerLimits;
+response_type!(GetServerLimits, ServerLimits);
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ServerLimits {
+    /// Maximum encrypted size of a single file, in bytes.
+    pub max_file_size: Option<u64>,
+    /// Maximum total encrypted size of file versions added by a single source
+    /// within the last 24 hours, in bytes.
+    pub max_daily_growth_per_source: Option<u64>,
+    /// Maximum number of existing entries (files and directories) in the archive.
+    pub max_entries: Option<u64>,
+    /// Minimum client version accepted by the server.
+    pub min_client_version: Option<String>,
+    /// Names of supported transport compression algorithms, in order of preference.
+    pub compression: Vec<String>,
+}
+
+/// A server limit that caused a request to be rejected.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum LimitExceeded {
+    /// Encrypted size of a file exceeds `max_file_size`.
+    FileSize { size: u64, max_file_size: u64 },
+    /// Versions added by the source within the last 24 hours would exceed
+    /// `max_daily_growth_per_source`.
+    DailyGrowth {
+        growth: u64,
+        max_daily_growth_per_source: u64,
+    },
+    /// Number of existing entries, including implicitly created parent directories,
+    /// would exceed `max_entries`.
+    Entries { max_entries: u64 },
+}
+
+impl fmt::Display for LimitExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LimitExceeded::FileSize {
+                size,
+                max_file_size,
+            } => write!(
+                f,
+                "limit exceeded: file size ({size} bytes) exceeds max_file_size \
+                ({max_file_size} bytes)"
+            ),
+            LimitExceeded::DailyGrowth {
+                growth,
+                max_daily_growth_per_source,
+            } => write!(
+                f,
+                "limit exceeded: daily growth for this source ({growth} bytes) \
+                would exceed max_daily_growth_per_source ({max_daily_growth_per_source} bytes)"
+            ),
+            LimitExceeded::Entries { max_entries } => write!(
+                f,
+                "limit exceeded: number of entries would exceed max_entries ({max_entries})"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for LimitExceeded {}
+
+/// Error sent by the server instead of a response.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ErrorResponse {
+    pub message: String,
+    /// Set if the request was rejected because of a server limit.
+    pub limit_exceeded: Option<LimitExceeded>,
+}
+
+impl ErrorResponse {
+    /// Converts the response to an error. If a limit was exceeded,
+    /// the error can be downcast to `LimitExceeded`.
+    pub fn into_error(self) -> anyhow::Error {
+        let message = format!("server error: {}", self.message);
+        match self.limit_exceeded {
+            Some(limit) => anyhow::Error::new(limit).context(message),
+            None => anyhow::Error::msg(message),
+        }
+    }
 }
 
+/// Returns current time according to the server's clock.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct GetServerTime;
+response_type!(GetServerTime, DateTimeUtc);
+
 /// Checks that file storage is consistent with database.
 #[derive(Debug, Serialize, Deserialize)]
 pub struct CheckIntegrity;
 response_type!(CheckIntegrity, ());
 
+/// Number of characters of the url-safe representation of content hashes
+/// that are used as prefixes in `GetContentDigests`.
+pub const CONTENT_DIGEST_DEPTH: usize = 3;
+
+/// Returns digests of content hashes referenced in the database and of content files
+/// present in the storage, for `prefix` and each of its children.
+///
+/// Content hashes are grouped by the first characters of their url-safe representation.
+/// Children of a prefix of `CONTENT_DIGEST_DEPTH` characters are individual hashes.
+/// Comparing the digests allows to find inconsistencies between the database and
+/// the storage without listing all hashes.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct GetContentDigests {
+    pub prefix: String,
+}
+response_type!(GetContentDigests, ContentDigests, compress = true);
+
+/// Digest of a set of content hashes and their sizes. Digest of an empty set is all zeros.
+pub type ContentDigest = [u8; 32];
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ContentDigests {
+    pub database: ContentDigest,
+    pub storage: ContentDigest,
+    /// Non-empty children of the prefix, ordered by prefix.
+    pub children: Vec<ChildContentDigests>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ChildContentDigests {
+    pub prefix: String,
+    pub database: ContentDigest,
+    pub storage: ContentDigest,
+}
+
 /// Returns id and name of all sources.
 #[derive(Debug, Serialize, Deserialize)]
 pub struct GetSources;
 
-response_type!(GetSources, Vec<SourceInfo>);
+response_type!(GetSources, Vec<SourceInfo>, compress = true);
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct SourceInfo {
     pub id: SourceId,
     pub name: String,
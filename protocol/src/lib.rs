@@ -1,5 +1,6 @@
 #![allow(clippy::collapsible_else_if)]
 
+pub mod compression;
 pub mod endpoints;
 mod path;
 pub mod util;
@@ -16,6 +17,17 @@ use std::fmt;
 
 pub type DateTimeUtc = chrono::DateTime<Utc>;
 
+/// HTTP header containing the version of the client that sent the request.
+pub const CLIENT_VERSION_HEADER: &str = "x-rammingen-client-version";
+
+/// HTTP header containing the server's admin token, required for administrative
+/// requests (e.g. `Rollback`) in addition to the source's access token.
+pub const ADMIN_TOKEN_HEADER: &str = "x-rammingen-admin-token";
+
+/// HTTP header containing the ID assigned to the request by the server.
+/// The same ID is included in the server's log messages about the request.
+pub const REQUEST_ID_HEADER: &str = "x-rammingen-request-id";
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize, From, Into)]
 pub struct SourceId(i32);
 
@@ -54,7 +66,7 @@ impl EntryId {
     }
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, Into)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize, Into)]
 pub struct ContentHash(Vec<u8>);
 
 impl ContentHash {
@@ -170,7 +182,7 @@ pub fn entry_kind_to_db(value: Option<EntryKind>) -> i32 {
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct EntryVersionData {
     pub path: EncryptedArchivePath,
     pub recorded_at: DateTimeUtc,
@@ -200,7 +212,7 @@ impl EntryVersionData {
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Entry {
     pub id: EntryId,
     pub update_number: EntryUpdateNumber,
@@ -71,6 +71,28 @@ impl ArchivePath {
         }
     }
 
+    /// Returns the path that `self` corresponds to when `self` is interpreted as relative to `base`
+    /// (e.g. `/a/b` nested under `/c` is `/c/a/b`).
+    pub fn nest_under(&self, base: &ArchivePath) -> ArchivePath {
+        if self.0 == "/" {
+            base.clone()
+        } else if base.0 == "/" {
+            self.clone()
+        } else {
+            Self(format!("{}{}", base.0, self.0))
+        }
+    }
+
+    /// Reverses `nest_under`. Returns `None` if `self` is not `base` or a path inside it.
+    pub fn unnest(&self, base: &ArchivePath) -> Option<ArchivePath> {
+        if self == base {
+            Some(Self("/".into()))
+        } else {
+            self.strip_prefix(base)
+                .map(|relative| Self(format!("/{relative}")))
+        }
+    }
+
     pub fn last_name(&self) -> Option<&str> {
         if self.0 == "/" {
             None
@@ -109,6 +131,22 @@ fn strip_prefix() {
     assert_eq!(p("/a/b/c/d").strip_prefix(&p("/")), Some("a/b/c/d"));
 }
 
+#[test]
+fn nest_under() {
+    fn p(s: &str) -> ArchivePath {
+        ArchivePath::from_str_without_prefix(s).unwrap()
+    }
+    assert_eq!(p("/a/b").nest_under(&p("/c/d")), p("/c/d/a/b"));
+    assert_eq!(p("/").nest_under(&p("/c/d")), p("/c/d"));
+    assert_eq!(p("/a/b").nest_under(&p("/")), p("/a/b"));
+
+    assert_eq!(p("/c/d/a/b").unnest(&p("/c/d")), Some(p("/a/b")));
+    assert_eq!(p("/c/d").unnest(&p("/c/d")), Some(p("/")));
+    assert_eq!(p("/a/b").unnest(&p("/")), Some(p("/a/b")));
+    assert_eq!(p("/c/de").unnest(&p("/c/d")), None);
+    assert_eq!(p("/c").unnest(&p("/c/d")), None);
+}
+
 impl<'de> Deserialize<'de> for ArchivePath {
     fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
     where
@@ -0,0 +1 @@
+ALTER TABLE sources ADD COLUMN disabled_at TIMESTAMP WITH TIME ZONE;
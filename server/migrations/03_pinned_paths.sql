@@ -0,0 +1,6 @@
+CREATE TABLE pinned_paths (
+    path VARCHAR PRIMARY KEY,
+    -- LIKE pattern matching nested paths, or NULL if nested paths are not pinned.
+    children_pattern VARCHAR,
+    pinned_at TIMESTAMP WITH TIME ZONE NOT NULL
+);
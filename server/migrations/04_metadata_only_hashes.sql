@@ -0,0 +1,4 @@
+-- Content hashes recorded by metadata-only mount points without uploading the content.
+CREATE TABLE metadata_only_hashes (
+    content_hash BYTEA PRIMARY KEY
+);
@@ -0,0 +1,2 @@
+-- Whether the source is allowed to record versions without uploading their content.
+ALTER TABLE sources ADD COLUMN allow_metadata_only BOOLEAN NOT NULL DEFAULT FALSE;
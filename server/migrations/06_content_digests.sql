@@ -0,0 +1,7 @@
+-- Digests of content hashes referenced by entry versions, maintained on each change.
+-- The digest of the empty prefix is NULL until the digests are computed for the first time.
+CREATE TABLE content_digests (
+    prefix VARCHAR PRIMARY KEY,
+    digest BYTEA
+);
+INSERT INTO content_digests (prefix, digest) VALUES ('', NULL);
@@ -0,0 +1,3 @@
+-- Allows paging through direct children in the order returned by `GetDirectChildEntries`.
+DROP INDEX idx_entries_parent_dir;
+CREATE INDEX idx_entries_parent_dir_kind_path ON entries (parent_dir, kind DESC, path);
@@ -0,0 +1,545 @@
+//! Adding new versions of entries.
+//!
+//! All entries affected by a batch of versions (the entries themselves and their parent
+//! directories) are loaded with a single query, the versions are applied in memory,
+//! and the changed rows are written with multi-row queries at the end.
+
+use std::collections::{HashMap, HashSet};
+
+use anyhow::{anyhow, bail, Result};
+use futures_util::TryStreamExt;
+use rammingen_protocol::{
+    endpoints::{AddVersion, AddVersionResponse, LimitExceeded},
+    entry_kind_from_db, entry_kind_to_db, EncryptedArchivePath, EncryptedContentHash,
+    EncryptedSize, EntryKind, EntryVersionData, FileContent,
+};
+use sqlx::{query, query_as, query_scalar, types::time::OffsetDateTime, Postgres, Transaction};
+
+use crate::{
+    digest::DigestChanges,
+    handler::{convert_version_data, Context, FromDb, ToDb},
+};
+
+/// A row of the `entries` table.
+#[derive(Debug, Clone)]
+struct EntryRow {
+    id: i64,
+    parent_dir: Option<i64>,
+    path: String,
+    /// Not written, `now()` is used instead.
+    recorded_at: OffsetDateTime,
+    source_id: i32,
+    record_trigger: i32,
+    kind: i32,
+    original_size: Option<Vec<u8>>,
+    encrypted_size: Option<i64>,
+    modified_at: Option<OffsetDateTime>,
+    content_hash: Option<Vec<u8>>,
+    unix_mode: Option<i64>,
+}
+
+impl EntryRow {
+    fn exists(&self) -> bool {
+        self.kind != EntryKind::NOT_EXISTS
+    }
+}
+
+struct Batch<'a> {
+    ctx: &'a Context,
+    /// Current state of all entries that may be affected by the batch, by path.
+    entries: HashMap<String, EntryRow>,
+    /// Paths of children of entries that may be deleted by the batch.
+    children: HashMap<i64, Vec<String>>,
+    /// Preallocated IDs for new entries.
+    new_ids: std::vec::IntoIter<i64>,
+    /// Total size of versions added by this source in the last day,
+    /// if `max_daily_growth_per_source` needs to be checked.
+    daily_growth: Option<u64>,
+    /// Number of existing entries, if `max_entries` needs to be checked.
+    existing_entries: Option<u64>,
+    /// Rows to be written, in order. Each group is written with a single query,
+    /// so it contains at most one row for each entry.
+    groups: Vec<Vec<EntryRow>>,
+    /// IDs of entries in the last group.
+    last_group_ids: HashSet<i64>,
+    /// Whether content missing from the storage is allowed.
+    allow_missing_content: bool,
+    /// Hashes of content that was missing from the storage.
+    missing_hashes: HashSet<Vec<u8>>,
+}
+
+impl<'a> Batch<'a> {
+    async fn load(
+        ctx: &'a Context,
+        versions: &[AddVersion],
+        allow_missing_content: bool,
+        tx: &mut Transaction<'_, Postgres>,
+    ) -> Result<Batch<'a>> {
+        let mut paths = HashSet::new();
+        for version in versions {
+            let mut path = Some(version.path.clone());
+            while let Some(current) = path {
+                if !paths.insert(current.to_str_without_prefix().to_owned()) {
+                    break;
+                }
+                path = current.parent();
+            }
+        }
+        let paths = paths.into_iter().collect::<Vec<_>>();
+        let mut entries = query_as!(
+            EntryRow,
+            "SELECT
+                id, parent_dir, path, recorded_at, source_id, record_trigger, kind,
+                original_size, encrypted_size, modified_at, content_hash, unix_mode
+            FROM entries WHERE path = ANY($1)",
+            &paths
+        )
+        .fetch(&mut *tx)
+        .map_ok(|row| (row.path.clone(), row))
+        .try_collect::<HashMap<_, _>>()
+        .await?;
+        let new_count = paths.iter().filter(|p| !entries.contains_key(*p)).count();
+
+        let deleted_ids = versions
+            .iter()
+            .filter(|version| version.kind.is_none())
+            .filter_map(|version| entries.get(version.path.to_str_without_prefix()))
+            .map(|entry| entry.id)
+            .collect::<Vec<_>>();
+        if !deleted_ids.is_empty() {
+            let mut rows = query_as!(
+                EntryRow,
+                "SELECT
+                    id, parent_dir, path, recorded_at, source_id, record_trigger, kind,
+                    original_size, encrypted_size, modified_at, content_hash, unix_mode
+                FROM entries WHERE kind != 0 AND parent_dir = ANY($1)",
+                &deleted_ids
+            )
+            .fetch(&mut *tx);
+            while let Some(row) = rows.try_next().await? {
+                entries.entry(row.path.clone()).or_insert(row);
+            }
+        }
+        let mut children = HashMap::<_, Vec<_>>::new();
+        for entry in entries.values() {
+            if let Some(parent_dir) = entry.parent_dir {
+                children
+                    .entry(parent_dir)
+                    .or_default()
+                    .push(entry.path.clone());
+            }
+        }
+
+        let new_ids = if new_count > 0 {
+            query_scalar!(
+                "SELECT nextval('entries_id_seq') FROM generate_series(1, $1::BIGINT)",
+                i64::try_from(new_count)?
+            )
+            .fetch_all(&mut *tx)
+            .await?
+            .into_iter()
+            .map(|id| id.ok_or_else(|| anyhow!("missing id in response")))
+            .collect::<Result<Vec<_>>>()?
+        } else {
+            Vec::new()
+        };
+
+        let daily_growth = if ctx.limits.max_daily_growth_per_source.is_some()
+            && versions.iter().any(|version| version.content.is_some())
+        {
+            let growth = query_scalar!(
+                "SELECT sum(encrypted_size)::BIGINT FROM entry_versions
+                WHERE source_id = $1 AND recorded_at > now() - interval '1 day'",
+                ctx.source_id.to_db()
+            )
+            .fetch_one(&mut *tx)
+            .await?
+            .unwrap_or_default();
+            Some(u64::try_from(growth)?)
+        } else {
+            None
+        };
+        let existing_entries = if ctx.limits.max_entries.is_some()
+            && versions.iter().any(|version| version.kind.is_some())
+        {
+            let count = query_scalar!("SELECT count(*) FROM entries WHERE kind != 0")
+                .fetch_one(&mut *tx)
+                .await?
+                .ok_or_else(|| anyhow!("missing row in response"))?;
+            Some(u64::try_from(count)?)
+        } else {
+            None
+        };
+
+        Ok(Self {
+            ctx,
+            entries,
+            children,
+            new_ids: new_ids.into_iter(),
+            daily_growth,
+            existing_entries,
+            groups: vec![Vec::new()],
+            last_group_ids: HashSet::new(),
+            allow_missing_content,
+            missing_hashes: HashSet::new(),
+        })
+    }
+
+    fn new_id(&mut self) -> Result<i64> {
+        self.new_ids
+            .next()
+            .ok_or_else(|| anyhow!("not enough preallocated ids"))
+    }
+
+    /// Checks size limits for the content of a new version. `max_entries` is checked
+    /// in `write`, so that implicitly created parent directories are also counted.
+    fn check_content_limits(&self, request: &AddVersion) -> Result<()> {
+        let limits = &self.ctx.limits;
+        if let Some(content) = &request.content {
+            if let Some(max_file_size) = limits.max_file_size {
+                if content.encrypted_size > max_file_size {
+                    return Err(LimitExceeded::FileSize {
+                        size: content.encrypted_size,
+                        max_file_size,
+                    }
+                    .into());
+                }
+            }
+            if let (Some(max_daily_growth_per_source), Some(growth)) =
+                (limits.max_daily_growth_per_source, self.daily_growth)
+            {
+                let growth = growth + content.encrypted_size;
+                if growth > max_daily_growth_per_source {
+                    return Err(LimitExceeded::DailyGrowth {
+                        growth,
+                        max_daily_growth_per_source,
+                    }
+                    .into());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn has_existing_children(&self, id: i64) -> bool {
+        self.children
+            .get(&id)
+            .is_some_and(|paths| paths.iter().any(|path| self.entries[path].exists()))
+    }
+
+    /// Queues a new state of an entry for writing.
+    fn write(&mut self, row: EntryRow) -> Result<()> {
+        let old = self.entries.get(&row.path);
+        if let Some(count) = &mut self.existing_entries {
+            match (old.is_some_and(EntryRow::exists), row.exists()) {
+                (false, true) => {
+                    *count += 1;
+                    if let Some(max_entries) = self.ctx.limits.max_entries {
+                        if *count > max_entries {
+                            return Err(LimitExceeded::Entries { max_entries }.into());
+                        }
+                    }
+                }
+                (true, false) => *count = count.saturating_sub(1),
+                _ => {}
+            }
+        }
+        if let Some(growth) = &mut self.daily_growth {
+            *growth += u64::try_from(row.encrypted_size.unwrap_or_default())?;
+        }
+        if old.is_none() {
+            if let Some(parent_dir) = row.parent_dir {
+                self.children
+                    .entry(parent_dir)
+                    .or_default()
+                    .push(row.path.clone());
+            }
+        }
+        if !self.last_group_ids.insert(row.id) {
+            // The same entry can't be changed twice in one query.
+            self.groups.push(Vec::new());
+            self.last_group_ids = HashSet::from([row.id]);
+        }
+        self.groups
+            .last_mut()
+            .ok_or_else(|| anyhow!("no groups"))?
+            .push(row.clone());
+        self.entries.insert(row.path.clone(), row);
+        Ok(())
+    }
+
+    /// Returns ID of the parent directory of `path`, creating the directory
+    /// or marking it as existing if necessary.
+    fn parent_dir(
+        &mut self,
+        path: &EncryptedArchivePath,
+        request: &AddVersion,
+    ) -> Result<Option<i64>> {
+        let Some(parent) = path.parent() else {
+            return Ok(None);
+        };
+        if let Some(entry) = self.entries.get(parent.to_str_without_prefix()).cloned() {
+            if entry.kind == EntryKind::File as i32 {
+                bail!("cannot save entry {} because {} is a file", path, parent);
+            }
+            if request.kind.is_some() && entry.kind == EntryKind::NOT_EXISTS {
+                // Make sure parent's parent is also marked as existing.
+                let _ = self.parent_dir(&parent, request)?;
+                self.write(EntryRow {
+                    kind: EntryKind::Directory as i32,
+                    source_id: self.ctx.source_id.to_db(),
+                    record_trigger: request.record_trigger as i32,
+                    ..entry.clone()
+                })?;
+            }
+            Ok(Some(entry.id))
+        } else {
+            let parent_of_parent = self.parent_dir(&parent, request)?;
+            let kind = if request.kind.is_some() {
+                EntryKind::Directory as i32
+            } else {
+                EntryKind::NOT_EXISTS
+            };
+            let id = self.new_id()?;
+            self.write(EntryRow {
+                id,
+                parent_dir: parent_of_parent,
+                path: parent.to_str_without_prefix().to_owned(),
+                recorded_at: OffsetDateTime::now_utc(),
+                source_id: self.ctx.source_id.to_db(),
+                record_trigger: request.record_trigger as i32,
+                kind,
+                original_size: None,
+                encrypted_size: None,
+                modified_at: None,
+                content_hash: None,
+                unix_mode: None,
+            })?;
+            Ok(Some(id))
+        }
+    }
+
+    fn add(&mut self, request: &AddVersion) -> Result<AddVersionResponse> {
+        if let Some(content) = &request.content {
+            if self.ctx.storage.exists(&content.hash)? {
+                let storage_size = self.ctx.storage.file_size(&content.hash)?;
+                if content.encrypted_size != storage_size {
+                    bail!(
+                        "cannot add version: size mismatch: {} in request, {} in storage",
+                        content.encrypted_size,
+                        storage_size
+                    );
+                }
+            } else if self.allow_missing_content {
+                self.missing_hashes.insert(content.hash.as_slice().to_vec());
+            } else {
+                bail!("cannot add version: hash not found in storage");
+            }
+        }
+        let path = request.path.to_str_without_prefix();
+        let mut unix_mode = request.content.as_ref().and_then(|c| c.unix_mode);
+        let (id, parent_dir) = if let Some(entry) = self.entries.get(path).cloned() {
+            let data = convert_version_data!(entry.clone());
+            if data.is_same(request) {
+                return Ok(AddVersionResponse { added: false });
+            }
+            self.check_content_limits(request)?;
+            if request.kind.is_none() && self.has_existing_children(entry.id) {
+                bail!(
+                    "cannot mark {} as deleted because it has existing children (request: {:?})",
+                    request.path,
+                    request,
+                );
+            }
+            if request.kind.is_some() && data.kind.is_none() {
+                // Make sure parent is marked as existing.
+                let _ = self.parent_dir(&request.path, request)?;
+            }
+            unix_mode = unix_mode.or_else(|| data.content.as_ref().and_then(|c| c.unix_mode));
+            (entry.id, entry.parent_dir)
+        } else {
+            self.check_content_limits(request)?;
+            let parent_dir = self.parent_dir(&request.path, request)?;
+            (self.new_id()?, parent_dir)
+        };
+        let content = request.content.as_ref();
+        self.write(EntryRow {
+            id,
+            parent_dir,
+            path: path.to_owned(),
+            recorded_at: OffsetDateTime::now_utc(),
+            source_id: self.ctx.source_id.to_db(),
+            record_trigger: request.record_trigger as i32,
+            kind: entry_kind_to_db(request.kind),
+            original_size: content.map(|c| c.original_size.as_slice().to_vec()),
+            encrypted_size: content
+                .map(|c| i64::try_from(c.encrypted_size))
+                .transpose()?,
+            modified_at: content.map(|c| c.modified_at.to_db()).transpose()?,
+            content_hash: content.map(|c| c.hash.as_slice().to_vec()),
+            unix_mode: unix_mode.map(i64::from),
+        })?;
+        Ok(AddVersionResponse { added: true })
+    }
+
+    async fn save(self, tx: &mut Transaction<'_, Postgres>) -> Result<()> {
+        let hashes = self
+            .missing_hashes
+            .iter()
+            .chain(
+                self.groups
+                    .iter()
+                    .flatten()
+                    .filter_map(|row| row.content_hash.as_ref()),
+            )
+            .cloned();
+        let digest_changes = DigestChanges::start(&mut *tx, hashes).await?;
+        if !self.missing_hashes.is_empty() {
+            let hashes = self.missing_hashes.into_iter().collect::<Vec<_>>();
+            query!(
+                "INSERT INTO metadata_only_hashes (content_hash)
+                SELECT * FROM UNNEST($1::BYTEA[])
+                ON CONFLICT DO NOTHING",
+                &hashes
+            )
+            .execute(&mut *tx)
+            .await?;
+        }
+        write_groups(self.groups, &mut *tx).await?;
+        digest_changes.finish(tx).await
+    }
+}
+
+/// Writes groups of rows to the `entries` table.
+async fn write_groups(
+    groups: Vec<Vec<EntryRow>>,
+    tx: &mut Transaction<'_, Postgres>,
+) -> Result<()> {
+    let count = groups.iter().map(Vec::len).sum::<usize>();
+    if count == 0 {
+        return Ok(());
+    }
+    let mut update_numbers = query_scalar!(
+        "SELECT nextval('entry_update_numbers') FROM generate_series(1, $1::BIGINT)",
+        i64::try_from(count)?
+    )
+    .fetch_all(&mut *tx)
+    .await?
+    .into_iter()
+    .map(|number| number.ok_or_else(|| anyhow!("missing update number in response")))
+    .collect::<Result<Vec<_>>>()?;
+    update_numbers.sort_unstable();
+    let mut update_numbers = update_numbers.into_iter();
+
+    for group in groups {
+        if group.is_empty() {
+            continue;
+        }
+        let mut ids = Vec::new();
+        let mut group_update_numbers = Vec::new();
+        let mut parent_dirs = Vec::new();
+        let mut paths = Vec::new();
+        let mut source_ids = Vec::new();
+        let mut record_triggers = Vec::new();
+        let mut kinds = Vec::new();
+        let mut original_sizes = Vec::new();
+        let mut encrypted_sizes = Vec::new();
+        let mut modified_ats = Vec::new();
+        let mut content_hashes = Vec::new();
+        let mut unix_modes = Vec::new();
+        for row in group {
+            ids.push(row.id);
+            group_update_numbers.push(
+                update_numbers
+                    .next()
+                    .ok_or_else(|| anyhow!("not enough update numbers"))?,
+            );
+            parent_dirs.push(row.parent_dir);
+            paths.push(row.path);
+            source_ids.push(row.source_id);
+            record_triggers.push(row.record_trigger);
+            kinds.push(row.kind);
+            original_sizes.push(row.original_size);
+            encrypted_sizes.push(row.encrypted_size);
+            modified_ats.push(row.modified_at);
+            content_hashes.push(row.content_hash);
+            unix_modes.push(row.unix_mode);
+        }
+        // Rows are inserted in order, so that the trigger records
+        // versions in the same order as they were added.
+        query!(
+            "INSERT INTO entries (
+                    id,
+                    update_number,
+                    recorded_at,
+                    parent_dir,
+                    path,
+                    source_id,
+                    record_trigger,
+                    kind,
+                    original_size,
+                    encrypted_size,
+                    modified_at,
+                    content_hash,
+                    unix_mode
+                )
+                SELECT
+                    id, update_number, now(), parent_dir, path, source_id, record_trigger, kind,
+                    original_size, encrypted_size, modified_at, content_hash, unix_mode
+                FROM UNNEST(
+                    $1::BIGINT[], $2::BIGINT[], $3::BIGINT[], $4::VARCHAR[],
+                    $5::INT[], $6::INT[], $7::INT[], $8::BYTEA[],
+                    $9::BIGINT[], $10::TIMESTAMPTZ[], $11::BYTEA[], $12::BIGINT[]
+                ) WITH ORDINALITY AS u(
+                    id, update_number, parent_dir, path, source_id, record_trigger, kind,
+                    original_size, encrypted_size, modified_at, content_hash, unix_mode,
+                    position
+                )
+                ORDER BY position
+                ON CONFLICT (id) DO UPDATE SET
+                    update_number = excluded.update_number,
+                    recorded_at = excluded.recorded_at,
+                    source_id = excluded.source_id,
+                    record_trigger = excluded.record_trigger,
+                    kind = excluded.kind,
+                    original_size = excluded.original_size,
+                    encrypted_size = excluded.encrypted_size,
+                    modified_at = excluded.modified_at,
+                    content_hash = excluded.content_hash,
+                    unix_mode = excluded.unix_mode",
+            &ids,
+            &group_update_numbers,
+            &parent_dirs as _,
+            &paths,
+            &source_ids,
+            &record_triggers,
+            &kinds,
+            &original_sizes as _,
+            &encrypted_sizes as _,
+            &modified_ats as _,
+            &content_hashes as _,
+            &unix_modes as _,
+        )
+        .execute(&mut *tx)
+        .await?;
+    }
+    Ok(())
+}
+
+/// Adds versions in order, with the same result as adding each of them separately.
+/// If `allow_missing_content` is true, versions with content missing from the storage
+/// are accepted, and their hashes are recorded as metadata-only.
+pub async fn add_versions(
+    ctx: &Context,
+    versions: &[AddVersion],
+    allow_missing_content: bool,
+    tx: &mut Transaction<'_, Postgres>,
+) -> Result<Vec<AddVersionResponse>> {
+    let mut batch = Batch::load(ctx, versions, allow_missing_content, tx).await?;
+    let responses = versions
+        .iter()
+        .map(|version| batch.add(version))
+        .collect::<Result<Vec<_>>>()?;
+    batch.save(tx).await?;
+    Ok(responses)
+}
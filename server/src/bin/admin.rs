@@ -3,8 +3,11 @@ use std::path::PathBuf;
 use clap::{Parser, Subcommand};
 use rammingen_server::{
     config_path,
-    util::{add_source, generate_access_token, set_access_token, sources},
-    Config,
+    util::{
+        add_source, generate_access_token, set_access_token, set_source_allow_metadata_only,
+        set_source_disabled, sources,
+    },
+    Config, StorageKey,
 };
 use sqlx::PgPool;
 
@@ -35,13 +38,29 @@ pub enum Command {
     AddSource { name: String },
     /// Changes access token of an existing source.
     UpdateAccessToken { name: String },
+    /// Disables a source. Requests from a disabled source are rejected,
+    /// but its data is kept until it's purged with the client's `purge-source` command.
+    DisableSource { name: String },
+    /// Enables a previously disabled source.
+    EnableSource { name: String },
+    /// Allows a source to record versions from metadata-only mount points,
+    /// without uploading their content.
+    AllowMetadataOnly { name: String },
+    /// Disallows a source to record versions without uploading their content.
+    DisallowMetadataOnly { name: String },
     /// Intializes or updates database structure.
     Migrate,
+    /// Generates a new key for `storage_encryption_key` config option.
+    GenerateStorageKey,
 }
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     let cli = Cli::parse();
+    if cli.command == Command::GenerateStorageKey {
+        println!("{}", StorageKey::generate());
+        return Ok(());
+    }
     let config_path = config_path(cli.config)?;
     let config = Config::parse(&config_path)?;
     let pool = PgPool::connect(&config.database_url).await?;
@@ -52,7 +71,18 @@ async fn main() -> anyhow::Result<()> {
                 println!("No configured sources.");
             }
             for source in sources {
-                println!("{source}");
+                let mut notes = Vec::new();
+                if let Some(disabled_at) = source.disabled_at {
+                    notes.push(format!("disabled at {disabled_at}"));
+                }
+                if source.allow_metadata_only {
+                    notes.push("metadata-only allowed".into());
+                }
+                if notes.is_empty() {
+                    println!("{}", source.name);
+                } else {
+                    println!("{} ({})", source.name, notes.join(", "));
+                }
             }
         }
         Command::AddSource { name } => {
@@ -65,11 +95,28 @@ async fn main() -> anyhow::Result<()> {
             set_access_token(&pool, &name, &token).await?;
             println!("Successfully updated access token. New access token:\n{token}");
         }
+        Command::DisableSource { name } => {
+            set_source_disabled(&pool, &name, true).await?;
+            println!("Successfully disabled source.");
+        }
+        Command::EnableSource { name } => {
+            set_source_disabled(&pool, &name, false).await?;
+            println!("Successfully enabled source.");
+        }
+        Command::AllowMetadataOnly { name } => {
+            set_source_allow_metadata_only(&pool, &name, true).await?;
+            println!("Successfully allowed metadata-only uploads.");
+        }
+        Command::DisallowMetadataOnly { name } => {
+            set_source_allow_metadata_only(&pool, &name, false).await?;
+            println!("Successfully disallowed metadata-only uploads.");
+        }
         Command::Migrate => {
             println!("Running migrations...");
             rammingen_server::util::migrate(&pool).await?;
             println!("Done");
         }
+        Command::GenerateStorageKey => unreachable!(),
     };
     Ok(())
 }
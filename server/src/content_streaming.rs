@@ -1,41 +1,75 @@
-use std::{convert::Infallible, io::Write};
+use std::{
+    convert::Infallible,
+    io::{Seek, SeekFrom, Write},
+};
 
 use futures_util::StreamExt;
 use http_body_util::{combinators::BoxBody, BodyExt, Empty, StreamBody};
 use hyper::{
     body::{self, Bytes, Frame},
-    header::CONTENT_LENGTH,
+    header::{HeaderValue, ACCEPT_RANGES, CONTENT_LENGTH, CONTENT_RANGE, RANGE},
     Request, Response, StatusCode,
 };
 use rammingen_protocol::{util::stream_file, EncryptedContentHash};
+use sqlx::query;
 use tokio::task::block_in_place;
 use tracing::warn;
 
-use crate::handler;
+use crate::{digest::DigestChanges, handler};
 
 pub async fn upload(
     ctx: handler::Context,
     mut request: Request<body::Incoming>,
     hash: &EncryptedContentHash,
 ) -> Result<Response<BoxBody<Bytes, Infallible>>, StatusCode> {
-    let content_length: u64 = request
-        .headers()
-        .get(CONTENT_LENGTH)
-        .ok_or_else(|| {
-            warn!("missing content length in request");
-            StatusCode::BAD_REQUEST
-        })?
-        .to_str()
-        .map_err(|err| {
-            warn!(?err, "invalid content length in request");
-            StatusCode::BAD_REQUEST
-        })?
-        .parse()
-        .map_err(|err| {
-            warn!(?err, "invalid content length in request");
-            StatusCode::BAD_REQUEST
-        })?;
+    // Content length is unknown if the client encrypts content while uploading it.
+    let content_length = if let Some(value) = request.headers().get(CONTENT_LENGTH) {
+        let content_length: u64 = value
+            .to_str()
+            .map_err(|err| {
+                warn!(?err, "invalid content length in request");
+                StatusCode::BAD_REQUEST
+            })?
+            .parse()
+            .map_err(|err| {
+                warn!(?err, "invalid content length in request");
+                StatusCode::BAD_REQUEST
+            })?;
+        Some(content_length)
+    } else {
+        None
+    };
+    let check_size = |size: u64| {
+        if let Some(max_file_size) = ctx.limits.max_file_size {
+            if size > max_file_size {
+                warn!(size, max_file_size, "content exceeds max file size");
+                return Err(StatusCode::PAYLOAD_TOO_LARGE);
+            }
+        }
+        Ok(())
+    };
+    if let Some(content_length) = content_length {
+        check_size(content_length)?;
+    }
+    let check_free_space = || {
+        if let Some(min_free_space) = ctx.limits.min_free_space {
+            let available_space = ctx.storage.available_space().map_err(|err| {
+                warn!(?err, "failed to get available space");
+                StatusCode::INTERNAL_SERVER_ERROR
+            })?;
+            if available_space < min_free_space {
+                warn!(
+                    available_space,
+                    min_free_space, "not enough free space in storage"
+                );
+                return Err(StatusCode::INSUFFICIENT_STORAGE);
+            }
+        }
+        Ok(())
+    };
 
+    let mut pending = ctx.storage.reserve(content_length.unwrap_or(0));
+    check_free_space()?;
     let mut file = block_in_place(|| ctx.storage.create_file()).map_err(|err| {
         warn!(?err, "failed to create file");
         StatusCode::INTERNAL_SERVER_ERROR
@@ -52,44 +86,104 @@ pub async fn upload(
             StatusCode::BAD_REQUEST
         })?;
         received_length += data.len() as u64;
+        if content_length.is_none() {
+            check_size(received_length)?;
+        }
         block_in_place(|| file.write_all(data)).map_err(|err| {
             warn!(?err, "failed to write to content file");
             StatusCode::INTERNAL_SERVER_ERROR
         })?;
+        pending.written(data.len() as u64);
+        check_free_space()?;
     }
 
-    if content_length != received_length {
-        warn!(content_length, received_length, "content length mismatch");
-        return Err(StatusCode::BAD_REQUEST);
+    if let Some(content_length) = content_length {
+        if content_length != received_length {
+            warn!(content_length, received_length, "content length mismatch");
+            return Err(StatusCode::BAD_REQUEST);
+        }
     }
 
     block_in_place(|| ctx.storage.commit_file(file, hash)).map_err(|err| {
         warn!(?err, "failed to commit content file");
         StatusCode::INTERNAL_SERVER_ERROR
     })?;
+    // The content is no longer metadata-only if it was recorded as such before.
+    remove_metadata_only_hash(&ctx, hash).await.map_err(|err| {
+        warn!(?err, "failed to update metadata-only hashes");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
 
     Ok(Response::new(BodyExt::boxed(Empty::new())))
 }
 
+async fn remove_metadata_only_hash(
+    ctx: &handler::Context,
+    hash: &EncryptedContentHash,
+) -> anyhow::Result<()> {
+    let mut tx = ctx.db_pool.begin().await?;
+    let digest_changes = DigestChanges::start(&mut tx, [hash.as_slice().to_vec()]).await?;
+    query!(
+        "DELETE FROM metadata_only_hashes WHERE content_hash = $1",
+        hash.as_slice()
+    )
+    .execute(&mut tx)
+    .await?;
+    digest_changes.finish(&mut tx).await?;
+    tx.commit().await?;
+    Ok(())
+}
+
 pub async fn download(
     ctx: handler::Context,
+    request: &Request<body::Incoming>,
     hash: &EncryptedContentHash,
 ) -> Result<Response<BoxBody<Bytes, Infallible>>, StatusCode> {
-    let file = block_in_place(|| ctx.storage.open_file(hash)).map_err(|err| {
+    let mut file = block_in_place(|| ctx.storage.open_file(hash)).map_err(|err| {
         warn!(?err, "couldn't open content file");
         StatusCode::NOT_FOUND
     })?;
-    let len = file
-        .metadata()
-        .map_err(|err| {
-            warn!(?err, "couldn't get metadata for content file");
+    let len = file.len();
+    // Content is compressed and encrypted by the client, so transport compression
+    // is never applied here.
+    let mut response = Response::builder().header(ACCEPT_RANGES, "bytes");
+    if let Some(range) = request.headers().get(RANGE) {
+        let start = range_start(range).ok_or_else(|| {
+            warn!(?range, "unsupported range");
+            StatusCode::RANGE_NOT_SATISFIABLE
+        })?;
+        if start >= len {
+            warn!(start, len, "range start is out of bounds");
+            return Err(StatusCode::RANGE_NOT_SATISFIABLE);
+        }
+        block_in_place(|| file.seek(SeekFrom::Start(start))).map_err(|err| {
+            warn!(?err, "couldn't seek content file");
             StatusCode::INTERNAL_SERVER_ERROR
-        })?
-        .len();
-    Ok(Response::builder()
-        .header(CONTENT_LENGTH, len)
+        })?;
+        response = response
+            .status(StatusCode::PARTIAL_CONTENT)
+            .header(
+                CONTENT_RANGE,
+                format!("bytes {}-{}/{}", start, len - 1, len),
+            )
+            .header(CONTENT_LENGTH, len - start);
+    } else {
+        response = response.header(CONTENT_LENGTH, len);
+    }
+    Ok(response
         .body(BodyExt::boxed(StreamBody::new(
             stream_file(file).map(|bytes| Ok(Frame::data(bytes))),
         )))
         .expect("response builder failed"))
 }
+
+/// Parses a range in `bytes=<start>-` form, which is the only form requested by the client.
+fn range_start(value: &HeaderValue) -> Option<u64> {
+    value
+        .to_str()
+        .ok()?
+        .strip_prefix("bytes=")?
+        .strip_suffix('-')?
+        .parse()
+        .ok()
+}
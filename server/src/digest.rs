@@ -0,0 +1,401 @@
+//! Digests of sets of content hashes, used to compare the database with the storage
+//! without listing all hashes.
+//!
+//! A digest of a set is the XOR of SHA-256 digests of its items (a content hash and its size),
+//! so it can be updated when an item is added or removed without recomputing it.
+//!
+//! Database digests are stored in the `content_digests` table. Any transaction that can change
+//! whether a content hash is included in the digests must use `DigestChanges` to update them.
+
+use std::collections::{BTreeMap, HashMap};
+
+use anyhow::{anyhow, bail, Result};
+use futures_util::TryStreamExt;
+use rammingen_protocol::{
+    endpoints::{ContentDigest, CONTENT_DIGEST_DEPTH},
+    EncryptedContentHash,
+};
+use sha2::{Digest, Sha256};
+use sqlx::{query, query_scalar, PgPool, Postgres, Transaction};
+
+const URL_SAFE_ALPHABET: &[u8; 64] =
+    b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-_";
+
+fn item_digest(hash: &EncryptedContentHash, size: u64) -> ContentDigest {
+    let mut hasher = Sha256::new();
+    hasher.update(hash.as_slice());
+    hasher.update(size.to_le_bytes());
+    hasher.finalize().into()
+}
+
+/// Adds an item to the digest or removes it from the digest.
+pub fn toggle(digest: &mut ContentDigest, hash: &EncryptedContentHash, size: u64) {
+    for (a, b) in digest.iter_mut().zip(item_digest(hash, size)) {
+        *a ^= b;
+    }
+}
+
+/// Adds an item to the digests of all prefixes it belongs to, or removes it from them.
+pub fn toggle_prefixes(
+    digests: &mut HashMap<String, ContentDigest>,
+    hash: &EncryptedContentHash,
+    size: u64,
+) {
+    for prefix in prefixes(&hash.to_url_safe()) {
+        toggle(digests.entry(prefix.into()).or_default(), hash, size);
+    }
+}
+
+/// Returns all prefixes that the hash belongs to, from the shortest (empty) one
+/// to the longest one.
+pub fn prefixes(hash: &str) -> impl Iterator<Item = &str> {
+    (0..=CONTENT_DIGEST_DEPTH.min(hash.len())).map(|len| &hash[..len])
+}
+
+/// Returns the child of `prefix` that the hash belongs to. Children of the longest
+/// prefixes are the hashes themselves.
+pub fn child<'a>(prefix: &str, hash: &'a str) -> &'a str {
+    if prefix.len() < CONTENT_DIGEST_DEPTH {
+        &hash[..=prefix.len()]
+    } else {
+        hash
+    }
+}
+
+/// Returns all possible children of a prefix that is shorter than `CONTENT_DIGEST_DEPTH`.
+pub fn child_prefixes(prefix: &str) -> impl Iterator<Item = String> + '_ {
+    URL_SAFE_ALPHABET
+        .iter()
+        .map(move |&c| format!("{prefix}{}", char::from(c)))
+}
+
+/// Returns the range of binary hashes (start inclusive, end exclusive) with the url-safe
+/// representation starting with `prefix`. There is no end if the range includes
+/// the largest hashes.
+pub fn prefix_range(prefix: &str) -> Result<(Vec<u8>, Option<Vec<u8>>)> {
+    if prefix.len() > CONTENT_DIGEST_DEPTH {
+        bail!("prefix is too long: {:?}", prefix);
+    }
+    let mut value = 0u32;
+    for c in prefix.bytes() {
+        let Some(index) = URL_SAFE_ALPHABET.iter().position(|&x| x == c) else {
+            bail!("invalid character in prefix: {:?}", prefix);
+        };
+        value = (value << 6) | index as u32;
+    }
+    // Prefixes are packed into the first 3 bytes.
+    let shift = 24 - 6 * prefix.len() as u32;
+    let bytes = |value: u32| (value << shift).to_be_bytes()[1..].to_vec();
+    let end = value + 1;
+    let end = if end >> (6 * prefix.len()) == 0 {
+        Some(bytes(end))
+    } else {
+        None
+    };
+    Ok((bytes(value), end))
+}
+
+/// Digests of the items of a prefix and of each of its children.
+#[derive(Debug, Default)]
+pub struct PrefixDigests {
+    pub digest: ContentDigest,
+    pub children: BTreeMap<String, ContentDigest>,
+}
+
+impl PrefixDigests {
+    /// Adds an item that belongs to `prefix`.
+    pub fn add(&mut self, prefix: &str, hash: &EncryptedContentHash, size: u64) {
+        toggle(&mut self.digest, hash, size);
+        let child = child(prefix, &hash.to_url_safe()).to_owned();
+        toggle(self.children.entry(child).or_default(), hash, size);
+    }
+}
+
+/// Takes transaction-level locks on `hashes`. Hashes must be sorted to avoid deadlocks.
+pub async fn lock_hashes(tx: &mut Transaction<'_, Postgres>, hashes: &[Vec<u8>]) -> Result<()> {
+    if !hashes.is_empty() {
+        query_scalar!(
+            r#"SELECT 1 AS "locked!"
+            FROM UNNEST($1::BYTEA[]) AS hashes(content_hash),
+                LATERAL pg_advisory_xact_lock(hashtext(encode(content_hash, 'hex')))"#,
+            hashes
+        )
+        .fetch_all(&mut *tx)
+        .await?;
+    }
+    Ok(())
+}
+
+/// Returns the hashes from `hashes` that are included in the database digests, with their sizes.
+/// A hash is included if it's referenced by a version and its content is not metadata-only.
+async fn included_hashes(
+    tx: &mut Transaction<'_, Postgres>,
+    hashes: &[Vec<u8>],
+) -> Result<HashMap<Vec<u8>, u64>> {
+    let mut output = HashMap::new();
+    let mut rows = query!(
+        r#"SELECT content_hash AS "content_hash!", (
+            SELECT encrypted_size FROM entry_versions
+            WHERE entry_versions.content_hash = hashes.content_hash
+            LIMIT 1
+        )
+        FROM UNNEST($1::BYTEA[]) AS hashes(content_hash)
+        WHERE content_hash NOT IN (SELECT content_hash FROM metadata_only_hashes)"#,
+        hashes
+    )
+    .fetch(&mut *tx);
+    while let Some(row) = rows.try_next().await? {
+        if let Some(size) = row.encrypted_size {
+            output.insert(row.content_hash, size.try_into()?);
+        }
+    }
+    Ok(output)
+}
+
+/// Tracks changes of the set of content hashes included in the database digests
+/// within a transaction.
+pub struct DigestChanges {
+    hashes: Vec<Vec<u8>>,
+    included: HashMap<Vec<u8>, u64>,
+}
+
+impl DigestChanges {
+    /// Locks `hashes`, so that other transactions can't change whether they're included,
+    /// and remembers which of them are included now.
+    pub async fn start(
+        tx: &mut Transaction<'_, Postgres>,
+        hashes: impl IntoIterator<Item = Vec<u8>>,
+    ) -> Result<Self> {
+        let mut hashes = hashes.into_iter().collect::<Vec<_>>();
+        hashes.sort_unstable();
+        hashes.dedup();
+        lock_hashes(tx, &hashes).await?;
+        let included = included_hashes(tx, &hashes).await?;
+        Ok(Self { hashes, included })
+    }
+
+    /// Updates the database digests according to the changes made since `start`.
+    pub async fn finish(self, tx: &mut Transaction<'_, Postgres>) -> Result<()> {
+        let included = included_hashes(tx, &self.hashes).await?;
+        let mut changes = Vec::new();
+        for hash in self.hashes {
+            let old = self.included.get(&hash);
+            let new = included.get(&hash);
+            if old != new {
+                let hash = EncryptedContentHash::from_encrypted(hash);
+                changes.extend(old.map(|size| (hash.clone(), *size)));
+                changes.extend(new.map(|size| (hash, *size)));
+            }
+        }
+        update_database_digests(tx, &changes).await
+    }
+}
+
+/// Adds items to the database digests or removes them from the digests.
+pub async fn update_database_digests(
+    tx: &mut Transaction<'_, Postgres>,
+    changes: &[(EncryptedContentHash, u64)],
+) -> Result<()> {
+    if changes.is_empty() {
+        return Ok(());
+    }
+    // The root row serializes all updates of the digests.
+    let root = query_scalar!("SELECT digest FROM content_digests WHERE prefix = '' FOR UPDATE")
+        .fetch_one(&mut *tx)
+        .await?;
+    if root.is_none() {
+        // Digests will be computed from scratch on first use.
+        return Ok(());
+    }
+    let mut digests = HashMap::new();
+    for (hash, size) in changes {
+        toggle_prefixes(&mut digests, hash, *size);
+    }
+    let prefixes = digests.keys().cloned().collect::<Vec<_>>();
+    let mut rows = query!(
+        "SELECT prefix, digest FROM content_digests WHERE prefix = ANY($1)",
+        &prefixes
+    )
+    .fetch(&mut *tx);
+    while let Some(row) = rows.try_next().await? {
+        if let Some(old) = row.digest {
+            let old = parse_digest(old)?;
+            let digest = digests.entry(row.prefix).or_default();
+            for (a, b) in digest.iter_mut().zip(old) {
+                *a ^= b;
+            }
+        }
+    }
+    drop(rows);
+    save_database_digests(tx, digests).await
+}
+
+async fn save_database_digests(
+    tx: &mut Transaction<'_, Postgres>,
+    digests: HashMap<String, ContentDigest>,
+) -> Result<()> {
+    let (prefixes, digests): (Vec<_>, Vec<_>) = digests
+        .into_iter()
+        .map(|(prefix, digest)| (prefix, digest.to_vec()))
+        .unzip();
+    query!(
+        "INSERT INTO content_digests (prefix, digest)
+        SELECT * FROM UNNEST($1::VARCHAR[], $2::BYTEA[])
+        ON CONFLICT (prefix) DO UPDATE SET digest = excluded.digest",
+        &prefixes,
+        &digests,
+    )
+    .execute(&mut *tx)
+    .await?;
+    Ok(())
+}
+
+fn parse_digest(digest: Vec<u8>) -> Result<ContentDigest> {
+    digest
+        .try_into()
+        .map_err(|digest| anyhow!("invalid digest in database: {:?}", digest))
+}
+
+/// Computes the database digests from scratch if they haven't been computed yet.
+async fn init_database_digests(db_pool: &PgPool) -> Result<()> {
+    let root = query_scalar!("SELECT digest FROM content_digests WHERE prefix = ''")
+        .fetch_one(db_pool)
+        .await?;
+    if root.is_some() {
+        return Ok(());
+    }
+    let mut tx = db_pool.begin().await?;
+    let root = query_scalar!("SELECT digest FROM content_digests WHERE prefix = '' FOR UPDATE")
+        .fetch_one(&mut tx)
+        .await?;
+    if root.is_some() {
+        return Ok(());
+    }
+    let mut digests = HashMap::new();
+    let mut rows = query!(
+        "SELECT DISTINCT content_hash, encrypted_size FROM entry_versions
+        WHERE content_hash IS NOT NULL
+            AND content_hash NOT IN (SELECT content_hash FROM metadata_only_hashes)"
+    )
+    .fetch(&mut tx);
+    while let Some(row) = rows.try_next().await? {
+        let hash = EncryptedContentHash::from_encrypted(
+            row.content_hash
+                .ok_or_else(|| anyhow!("expected hash to exist in query output"))?,
+        );
+        let size: u64 = row
+            .encrypted_size
+            .ok_or_else(|| anyhow!("expected size to exist in query output"))?
+            .try_into()?;
+        toggle_prefixes(&mut digests, &hash, size);
+    }
+    drop(rows);
+    digests.entry(String::new()).or_default();
+    save_database_digests(&mut tx, digests).await?;
+    tx.commit().await?;
+    Ok(())
+}
+
+/// Returns digests of content hashes referenced by entry versions.
+pub async fn database_digests(db_pool: &PgPool, prefix: &str) -> Result<PrefixDigests> {
+    let mut output = PrefixDigests::default();
+    if prefix.len() == CONTENT_DIGEST_DEPTH {
+        // Digests of individual hashes are not stored.
+        let (start, end) = prefix_range(prefix)?;
+        let mut rows = query!(
+            "SELECT DISTINCT content_hash, encrypted_size FROM entry_versions
+            WHERE content_hash >= $1 AND ($2::bytea IS NULL OR content_hash < $2)
+                AND content_hash NOT IN (SELECT content_hash FROM metadata_only_hashes)",
+            start,
+            end,
+        )
+        .fetch(db_pool);
+        while let Some(row) = rows.try_next().await? {
+            let hash = EncryptedContentHash::from_encrypted(
+                row.content_hash
+                    .ok_or_else(|| anyhow!("expected hash to exist in query output"))?,
+            );
+            let size: u64 = row
+                .encrypted_size
+                .ok_or_else(|| anyhow!("expected size to exist in query output"))?
+                .try_into()?;
+            output.add(prefix, &hash, size);
+        }
+        return Ok(output);
+    }
+
+    prefix_range(prefix)?;
+    init_database_digests(db_pool).await?;
+    let mut prefixes = child_prefixes(prefix).collect::<Vec<_>>();
+    prefixes.push(prefix.to_owned());
+    let mut rows = query!(
+        "SELECT prefix, digest FROM content_digests WHERE prefix = ANY($1)",
+        &prefixes
+    )
+    .fetch(db_pool);
+    while let Some(row) = rows.try_next().await? {
+        let Some(digest) = row.digest else {
+            continue;
+        };
+        let digest = parse_digest(digest)?;
+        if row.prefix == prefix {
+            output.digest = digest;
+        } else if digest != ContentDigest::default() {
+            output.children.insert(row.prefix, digest);
+        }
+    }
+    Ok(output)
+}
+
+#[test]
+fn ranges() {
+    assert_eq!(prefix_range("").unwrap(), (vec![0, 0, 0], None));
+    assert_eq!(
+        prefix_range("A").unwrap(),
+        (vec![0, 0, 0], Some(vec![0b0000_0100, 0, 0]))
+    );
+    assert_eq!(prefix_range("_").unwrap(), (vec![0b1111_1100, 0, 0], None));
+    assert_eq!(
+        prefix_range("AB_").unwrap(),
+        (
+            vec![0, 0b0001_1111, 0b1100_0000],
+            Some(vec![0, 0b0010_0000, 0])
+        )
+    );
+    assert!(prefix_range("ABCD").is_err());
+    assert!(prefix_range("A+").is_err());
+
+    for bytes in [
+        vec![0, 0, 0, 0],
+        vec![1, 2, 3, 4],
+        vec![255; 64],
+        vec![170; 64],
+    ] {
+        let hash = EncryptedContentHash::from_encrypted(bytes.clone());
+        for prefix in prefixes(&hash.to_url_safe()) {
+            let (start, end) = prefix_range(prefix).unwrap();
+            assert!(bytes >= start);
+            assert!(end.map_or(true, |end| bytes < end));
+        }
+    }
+}
+
+#[test]
+fn toggling() {
+    let hash1 = EncryptedContentHash::from_encrypted(vec![1; 64]);
+    let hash2 = EncryptedContentHash::from_encrypted(vec![2; 64]);
+    let mut a = ContentDigest::default();
+    toggle(&mut a, &hash1, 10);
+    toggle(&mut a, &hash2, 20);
+    let mut b = ContentDigest::default();
+    toggle(&mut b, &hash2, 20);
+    assert_ne!(a, b);
+    toggle(&mut b, &hash1, 10);
+    assert_eq!(a, b);
+    toggle(&mut b, &hash1, 11);
+    assert_ne!(a, b);
+    toggle(&mut b, &hash1, 11);
+    toggle(&mut b, &hash1, 10);
+    toggle(&mut b, &hash2, 20);
+    assert_eq!(b, ContentDigest::default());
+}
@@ -1,30 +1,53 @@
 use std::collections::HashMap;
-use std::{collections::HashSet, sync::Arc};
+use std::{
+    cmp::max,
+    collections::{BTreeSet, HashSet},
+    sync::Arc,
+    time::Duration,
+};
 
 use anyhow::{anyhow, bail, Result};
 use chrono::{TimeZone, Utc};
-use futures_util::{future::BoxFuture, Stream, TryStreamExt};
+use futures_util::{Stream, TryStreamExt};
 use rammingen_protocol::endpoints::{
-    AddVersion, AddVersionResponse, BulkActionStats, CheckIntegrity, ContentHashExists,
-    GetAllEntryVersions, GetDirectChildEntries, GetEntryVersionsAtTime, GetNewEntries,
-    GetServerStatus, GetSources, MovePath, RemovePath, ResetVersion, Response, ServerStatus,
-    SourceInfo, StreamingResponseItem,
+    AddVersion, AddVersionIfUnchanged, AddVersions, BulkActionStats, CheckIntegrity,
+    ChildContentDigests, ContentDigests, ContentHashExists, GetAllEntryVersions,
+    GetBootstrapEntries, GetContentDigests, GetDirectChildEntries, GetEntries, GetEntriesByHash,
+    GetEntryVersionsAtTime, GetLastUpdateNumber, GetNewEntries, GetPinnedPaths, GetServerLimits,
+    GetServerStatus, GetServerTime, GetSources, GetVersionHotspots, GetVersionStats, MovePath,
+    PinPath, PinnedPath, PurgeSource, RemovePath, ResetVersion, Response, Rollback, ServerLimits,
+    ServerStatus, SourceInfo, StreamingResponseItem, UnpinPath, VersionHotspot, VersionStats,
+    MAX_GET_ENTRIES_PATHS,
 };
 use rammingen_protocol::{
-    entry_kind_from_db, entry_kind_to_db, DateTimeUtc, EncryptedArchivePath, EncryptedContentHash,
-    EncryptedSize, Entry, EntryKind, EntryVersion, EntryVersionData, FileContent, RecordTrigger,
-    SourceId,
+    compression::Encoding, entry_kind_from_db, entry_kind_to_db, DateTimeUtc, EncryptedArchivePath,
+    EncryptedContentHash, EncryptedSize, Entry, EntryKind, EntryUpdateNumber, EntryVersion,
+    EntryVersionData, FileContent, RecordTrigger, SourceId,
 };
 use sqlx::{query, query_scalar, types::time::OffsetDateTime, PgPool, Postgres, Transaction};
-use tokio::sync::mpsc::Sender;
+use tokio::{sync::mpsc::Sender, task::block_in_place};
 
-use crate::storage::Storage;
+use crate::{
+    add_versions, digest,
+    snapshot::{next_snapshot_timestamp, remove_files, unused_hashes},
+    storage::Storage,
+    util, Limits,
+};
 
 #[derive(Debug, Clone)]
 pub struct Context {
     pub db_pool: PgPool,
+    /// Pool used by read-only streaming handlers. Same as `db_pool`
+    /// if no read replica is configured.
+    pub read_db_pool: PgPool,
     pub storage: Arc<Storage>,
     pub source_id: SourceId,
+    pub limits: Limits,
+    pub snapshot_interval: Duration,
+    pub retain_detailed_history_for: Duration,
+    pub min_client_version: Option<String>,
+    /// ID of the request being handled, included in error responses.
+    pub request_id: String,
 }
 
 macro_rules! convert_entry {
@@ -87,232 +110,75 @@ macro_rules! convert_version_data {
         }
     }};
 }
-pub(crate) use {convert_entry_version, convert_version_data};
+pub(crate) use convert_version_data;
 
-fn get_parent_dir<'a>(
-    ctx: &'a Context,
-    path: &'a EncryptedArchivePath,
-    tx: &'a mut Transaction<'_, Postgres>,
-    request: &'a AddVersion,
-) -> BoxFuture<'a, Result<Option<i64>>> {
-    Box::pin(async move {
-        let Some(parent) = path.parent() else { return Ok(None) };
-        let entry = query!(
-            "SELECT id, kind FROM entries WHERE path = $1",
-            parent.to_str_without_prefix()
-        )
-        .fetch_optional(&mut *tx)
-        .await?;
-        let entry_id = if let Some(entry) = entry {
-            if entry.kind == EntryKind::File as i32 {
-                bail!("cannot save entry {} because {} is a file", path, parent);
-            }
-            if request.kind.is_some() && entry.kind == EntryKind::NOT_EXISTS {
-                // Make sure parent's parent is also marked as existing.
-                let _ = get_parent_dir(ctx, &parent, &mut *tx, request).await?;
-
-                query!(
-                    "UPDATE entries SET
-                        update_number = nextval('entry_update_numbers'),
-                        recorded_at = now(),
-                        kind = $1,
-                        source_id = $2,
-                        record_trigger = $3
-                    WHERE id = $4",
-                    EntryKind::Directory as i32,
-                    ctx.source_id.to_db(),
-                    request.record_trigger as i32,
-                    entry.id,
-                )
-                .execute(&mut *tx)
-                .await?;
-                entry.id
-            } else {
-                return Ok(Some(entry.id));
-            }
-        } else {
-            let parent_of_parent = get_parent_dir(ctx, &parent, &mut *tx, request).await?;
-            let kind = if request.kind.is_some() {
-                EntryKind::Directory as i32
-            } else {
-                EntryKind::NOT_EXISTS
-            };
-            query_scalar!(
-                "INSERT INTO entries (
-                    update_number,
-                    recorded_at,
-
-                    kind,
-                    parent_dir,
-                    path,
-                    source_id,
-                    record_trigger,
-
-                    original_size,
-                    encrypted_size,
-                    modified_at,
-                    content_hash,
-                    unix_mode
-                ) VALUES (
-                    nextval('entry_update_numbers'),
-                    now(),
-                    $1, $2, $3, $4, $5,
-                    NULL, NULL, NULL, NULL, NULL
-                ) RETURNING id",
-                kind,
-                parent_of_parent,
-                parent.to_str_without_prefix(),
-                ctx.source_id.to_db(),
-                request.record_trigger as i32,
-            )
-            .fetch_one(&mut *tx)
-            .await?
-        };
-
-        Ok(Some(entry_id))
-    })
+pub async fn add_version(ctx: Context, request: AddVersion) -> Result<Response<AddVersion>> {
+    let mut tx = ctx.db_pool.begin().await?;
+    let mut r = add_versions::add_versions(&ctx, &[request], false, &mut tx).await?;
+    tx.commit().await?;
+    Ok(r.remove(0))
 }
 
-async fn add_version_inner<'a>(
-    ctx: &'a Context,
-    request: AddVersion,
-    tx: &'a mut Transaction<'_, Postgres>,
-) -> Result<Response<AddVersion>> {
-    if let Some(content) = &request.content {
-        if !ctx.storage.exists(&content.hash)? {
-            bail!("cannot add version: hash not found in storage");
-        }
-        let storage_size = ctx.storage.file_size(&content.hash)?;
-        if content.encrypted_size != storage_size {
-            bail!(
-                "cannot add version: size mismatch: {} in request, {} in storage",
-                content.encrypted_size,
-                storage_size
-            );
+pub async fn add_versions(ctx: Context, request: AddVersions) -> Result<Response<AddVersions>> {
+    let mut tx = ctx.db_pool.begin().await?;
+    if request.metadata_only {
+        let allowed = query_scalar!(
+            "SELECT allow_metadata_only FROM sources WHERE id = $1",
+            ctx.source_id.to_db()
+        )
+        .fetch_one(&mut tx)
+        .await?;
+        if !allowed {
+            bail!("this source is not allowed to record versions without uploading content");
         }
     }
-    let entry = query!(
-        "SELECT * FROM entries WHERE path = $1",
-        request.path.to_str_without_prefix()
+    let responses = add_versions::add_versions(
+        &ctx,
+        &request.versions,
+        request.dry_run || request.metadata_only,
+        &mut tx,
     )
-    .fetch_optional(&mut *tx)
     .await?;
-    let original_size_db = request.content.as_ref().map(|c| c.original_size.as_slice());
-    let encrypted_size_db = request
-        .content
-        .as_ref()
-        .map(|c| i64::try_from(c.encrypted_size))
-        .transpose()?;
-    let modified_at_db = request
-        .content
-        .as_ref()
-        .map(|c| c.modified_at.to_db())
-        .transpose()?;
-    let content_hash_db = request.content.as_ref().map(|c| c.hash.as_slice());
-    if let Some(entry) = entry {
-        let entry = convert_entry!(entry);
-        if entry.data.is_same(&request) {
-            return Ok(AddVersionResponse { added: false });
-        }
-        if request.kind.is_none() {
-            let child_count = query_scalar!(
-                "SELECT count(*) FROM entries
-                WHERE kind != 0 AND parent_dir = $1",
-                entry.id.to_db()
-            )
-            .fetch_one(&mut *tx)
-            .await?
-            .ok_or_else(|| anyhow!("missing row in response"))?;
-            if child_count > 0 {
-                bail!(
-                    "cannot mark {} as deleted because it has existing children (request: {:?})",
-                    request.path,
-                    request,
-                );
-            }
-        }
-        if request.kind.is_some() && entry.data.kind.is_none() {
-            // Make sure parent is marked as existing.
-            let _ = get_parent_dir(ctx, &request.path, &mut *tx, &request).await?;
-        }
-        let unix_mode_db = request
-            .content
-            .as_ref()
-            .and_then(|c| c.unix_mode)
-            .or_else(|| entry.data.content.as_ref().and_then(|ec| ec.unix_mode))
-            .map(i64::from);
-        query!(
-            "UPDATE entries
-            SET update_number = nextval('entry_update_numbers'),
-                recorded_at = now(),
-                source_id = $1,
-                record_trigger = $2,
-                kind = $3,
-                original_size = $4,
-                encrypted_size = $5,
-                modified_at = $6,
-                content_hash = $7,
-                unix_mode = $8
-            WHERE id = $9",
-            ctx.source_id.to_db(),
-            request.record_trigger as i32,
-            entry_kind_to_db(request.kind),
-            original_size_db,
-            encrypted_size_db,
-            modified_at_db,
-            content_hash_db,
-            unix_mode_db,
-            entry.id.to_db(),
-        )
-        .execute(&mut *tx)
-        .await?;
+    if request.dry_run {
+        tx.rollback().await?;
     } else {
-        let unix_mode_db = request
-            .content
-            .as_ref()
-            .and_then(|c| c.unix_mode)
-            .map(i64::from);
-        let parent = get_parent_dir(ctx, &request.path, &mut *tx, &request).await?;
-        query_scalar!(
-            "INSERT INTO entries (
-                update_number,
-                recorded_at,
-                parent_dir,
-                path,
-                source_id,
-                record_trigger,
-                kind,
-                original_size,
-                encrypted_size,
-                modified_at,
-                content_hash,
-                unix_mode
-            ) VALUES (
-                nextval('entry_update_numbers'), now(),
-                $1, $2, $3, $4, $5, $6, $7, $8, $9, $10
-            ) RETURNING id",
-            parent,
-            request.path.to_str_without_prefix(),
-            ctx.source_id.to_db(),
-            request.record_trigger as i32,
-            entry_kind_to_db(request.kind),
-            original_size_db,
-            encrypted_size_db,
-            modified_at_db,
-            content_hash_db,
-            unix_mode_db,
-        )
-        .fetch_one(&mut *tx)
-        .await?;
-    };
-    Ok(AddVersionResponse { added: true })
+        tx.commit().await?;
+    }
+    Ok(responses)
 }
 
-pub async fn add_version(ctx: Context, request: AddVersion) -> Result<Response<AddVersion>> {
+pub async fn add_version_if_unchanged(
+    ctx: Context,
+    request: AddVersionIfUnchanged,
+) -> Result<Response<AddVersionIfUnchanged>> {
     let mut tx = ctx.db_pool.begin().await?;
-    let r = add_version_inner(&ctx, request, &mut tx).await?;
+    // `FOR UPDATE` doesn't lock rows that don't exist yet, so concurrent requests
+    // creating the same path are serialized by the lock on the path.
+    query!(
+        "SELECT 1 AS locked FROM pg_advisory_xact_lock(hashtext($1))",
+        request.version.path.to_str_without_prefix()
+    )
+    .fetch_one(&mut tx)
+    .await?;
+    let update_number = query_scalar!(
+        "SELECT update_number FROM entries WHERE path = $1 FOR UPDATE",
+        request.version.path.to_str_without_prefix()
+    )
+    .fetch_optional(&mut *tx)
+    .await?
+    .map(EntryUpdateNumber::from);
+    if update_number != request.expected_update_number {
+        bail!(
+            "conflict: {} has been changed concurrently (expected update number: {:?}, \
+            actual update number: {:?})",
+            request.version.path,
+            request.expected_update_number,
+            update_number
+        );
+    }
+    let mut r = add_versions::add_versions(&ctx, &[request.version], false, &mut tx).await?;
     tx.commit().await?;
-    Ok(r)
+    Ok(r.remove(0))
 }
 
 pub async fn get_new_entries(
@@ -324,7 +190,38 @@ pub async fn get_new_entries(
         "SELECT * FROM entries WHERE update_number > $1 ORDER BY update_number",
         request.last_update_number.to_db()
     )
-    .fetch(&ctx.db_pool);
+    .fetch(&ctx.read_db_pool);
+    while let Some(row) = rows.try_next().await? {
+        tx.send(Ok(convert_entry!(row))).await?;
+    }
+    Ok(())
+}
+
+pub async fn get_last_update_number(
+    ctx: Context,
+    _request: GetLastUpdateNumber,
+) -> Result<Response<GetLastUpdateNumber>> {
+    // The same pool as in `get_bootstrap_entries` must be used, so that the entries
+    // returned there are not older than the returned update number.
+    let update_number = query_scalar!("SELECT max(update_number) FROM entries")
+        .fetch_one(&ctx.read_db_pool)
+        .await?;
+    Ok(update_number.unwrap_or(0).into())
+}
+
+pub async fn get_bootstrap_entries(
+    ctx: Context,
+    request: GetBootstrapEntries,
+    tx: Sender<Result<StreamingResponseItem<GetBootstrapEntries>>>,
+) -> Result<()> {
+    let mut rows = query!(
+        "SELECT * FROM entries WHERE $1::text IS NULL OR path > $1 ORDER BY path",
+        request
+            .after_path
+            .as_ref()
+            .map(|path| path.to_str_without_prefix()),
+    )
+    .fetch(&ctx.read_db_pool);
     while let Some(row) = rows.try_next().await? {
         tx.send(Ok(convert_entry!(row))).await?;
     }
@@ -338,17 +235,73 @@ pub async fn get_direct_child_entries(
 ) -> Result<()> {
     let main_entry_id = query_scalar!(
         "SELECT id FROM entries WHERE path = $1",
-        request.0.to_str_without_prefix()
+        request.path.to_str_without_prefix()
     )
-    .fetch_optional(&ctx.db_pool)
+    .fetch_optional(&ctx.read_db_pool)
     .await?
     .ok_or_else(|| anyhow!("entry not found"))?;
 
+    // Directories (kind = 2) go first, then files (kind = 1), then deleted entries (kind = 0).
     let mut rows = query!(
-        "SELECT * FROM entries WHERE parent_dir = $1 ORDER BY path",
-        main_entry_id
+        "SELECT * FROM entries
+        WHERE parent_dir = $1
+            AND ($2::INTEGER IS NULL OR kind < $2 OR (kind = $2 AND path > $3))
+        ORDER BY kind DESC, path
+        LIMIT $4",
+        main_entry_id,
+        request
+            .after
+            .as_ref()
+            .map(|after| entry_kind_to_db(after.kind)),
+        request
+            .after
+            .as_ref()
+            .map(|after| after.path.to_str_without_prefix()),
+        i64::from(request.limit),
     )
-    .fetch(&ctx.db_pool);
+    .fetch(&ctx.read_db_pool);
+    while let Some(row) = rows.try_next().await? {
+        tx.send(Ok(convert_entry!(row))).await?;
+    }
+    Ok(())
+}
+
+pub async fn get_entries(ctx: Context, request: GetEntries) -> Result<Response<GetEntries>> {
+    if request.0.len() > MAX_GET_ENTRIES_PATHS {
+        bail!(
+            "too many paths requested: {} (max: {})",
+            request.0.len(),
+            MAX_GET_ENTRIES_PATHS
+        );
+    }
+    let paths = request
+        .0
+        .iter()
+        .map(|path| path.to_str_without_prefix().to_owned())
+        .collect::<Vec<_>>();
+    let mut entries = HashMap::new();
+    let mut rows =
+        query!("SELECT * FROM entries WHERE path = ANY($1)", &paths).fetch(&ctx.read_db_pool);
+    while let Some(row) = rows.try_next().await? {
+        entries.insert(row.path.clone(), convert_entry!(row));
+    }
+    Ok(paths
+        .iter()
+        .map(|path| entries.get(path).cloned())
+        .collect())
+}
+
+pub async fn get_entries_by_hash(
+    ctx: Context,
+    request: GetEntriesByHash,
+    tx: Sender<Result<StreamingResponseItem<GetEntriesByHash>>>,
+) -> Result<()> {
+    let mut rows = query!(
+        "SELECT * FROM entries WHERE content_hash = $1 AND kind = $2 ORDER BY path",
+        request.0.as_slice(),
+        EntryKind::File as i32,
+    )
+    .fetch(&ctx.read_db_pool);
     while let Some(row) = rows.try_next().await? {
         tx.send(Ok(convert_entry!(row))).await?;
     }
@@ -380,7 +333,7 @@ pub async fn get_entry_versions_at_time(
     request: GetEntryVersionsAtTime,
     sender: Sender<Result<StreamingResponseItem<GetEntryVersionsAtTime>>>,
 ) -> Result<()> {
-    let mut tx = ctx.db_pool.begin().await?;
+    let mut tx = ctx.read_db_pool.begin().await?;
     let entries = get_versions_inner(request.recorded_at, &request.path, &mut tx).await?;
     tokio::pin!(entries);
 
@@ -392,6 +345,34 @@ pub async fn get_entry_versions_at_time(
     Ok(())
 }
 
+pub async fn get_version_stats(
+    ctx: Context,
+    request: GetVersionStats,
+) -> Result<Response<GetVersionStats>> {
+    let mut tx = ctx.read_db_pool.begin().await?;
+    let entries = get_versions_inner(request.recorded_at, &request.path, &mut tx).await?;
+    tokio::pin!(entries);
+
+    let mut stats = VersionStats {
+        files: 0,
+        directories: 0,
+        encrypted_size: 0,
+    };
+    while let Some(entry) = entries.try_next().await? {
+        match entry.data.kind {
+            Some(EntryKind::File) => {
+                stats.files += 1;
+                if let Some(content) = &entry.data.content {
+                    stats.encrypted_size += content.encrypted_size;
+                }
+            }
+            Some(EntryKind::Directory) => stats.directories += 1,
+            None => {}
+        }
+    }
+    Ok(stats)
+}
+
 pub async fn get_all_entry_versions(
     ctx: Context,
     request: GetAllEntryVersions,
@@ -405,7 +386,7 @@ pub async fn get_all_entry_versions(
             request.path.to_str_without_prefix(),
             starts_with(&request.path)
         )
-        .fetch(&ctx.db_pool);
+        .fetch(&ctx.read_db_pool);
         while let Some(row) = rows.try_next().await? {
             tx.send(Ok(convert_entry_version!(row))).await?;
         }
@@ -414,7 +395,7 @@ pub async fn get_all_entry_versions(
             "SELECT * FROM entry_versions WHERE path = $1 ORDER BY id",
             request.path.to_str_without_prefix()
         )
-        .fetch(&ctx.db_pool);
+        .fetch(&ctx.read_db_pool);
         while let Some(row) = rows.try_next().await? {
             tx.send(Ok(convert_entry_version!(row))).await?;
         }
@@ -497,6 +478,7 @@ pub async fn move_path(ctx: Context, request: MovePath) -> Result<Response<MoveP
     remove_entries_in_dir(&ctx, &request.old_path, RecordTrigger::Move, &mut tx).await?;
 
     let affected_paths = old_entries.len().try_into()?;
+    let mut versions = Vec::with_capacity(old_entries.len());
     for entry in old_entries {
         let new_path = if entry.data.path == request.old_path {
             request.new_path.clone()
@@ -505,19 +487,23 @@ pub async fn move_path(ctx: Context, request: MovePath) -> Result<Response<MoveP
         } else {
             bail!("strip_prefix failed while processing entry: {:?}", entry);
         };
-        let add_version = AddVersion {
+        versions.push(AddVersion {
             path: new_path,
             record_trigger: RecordTrigger::Move,
             kind: entry.data.kind,
             content: entry.data.content,
-        };
-        let result = add_version_inner(&ctx, add_version, &mut tx).await?;
-        if !result.added {
-            bail!("unexpected added = false while moving path");
-        }
+        });
+    }
+    let results = add_versions::add_versions(&ctx, &versions, false, &mut tx).await?;
+    if results.iter().any(|result| !result.added) {
+        bail!("unexpected added = false while moving path");
     }
 
-    tx.commit().await?;
+    if request.dry_run {
+        tx.rollback().await?;
+    } else {
+        tx.commit().await?;
+    }
     Ok(BulkActionStats { affected_paths })
 }
 
@@ -580,36 +566,247 @@ pub async fn reset_version(ctx: Context, request: ResetVersion) -> Result<Respon
         }
     }
 
+    let mut versions = Vec::new();
     for entry in entries {
         if entry.data.kind.is_some() {
             tracing::debug!("reset_version: updating {:?}", entry);
-            let r = add_version_inner(
-                &ctx,
-                AddVersion {
-                    path: entry.data.path,
-                    record_trigger: RecordTrigger::Reset,
-                    kind: entry.data.kind,
-                    content: entry.data.content,
-                },
-                &mut tx,
+            versions.push(AddVersion {
+                path: entry.data.path,
+                record_trigger: RecordTrigger::Reset,
+                kind: entry.data.kind,
+                content: entry.data.content,
+            });
+        }
+    }
+    let results = add_versions::add_versions(&ctx, &versions, false, &mut tx).await?;
+    affected_paths += results.iter().filter(|result| result.added).count() as u64;
+    tx.commit().await?;
+    Ok(BulkActionStats { affected_paths })
+}
+
+pub async fn rollback(ctx: Context, request: Rollback) -> Result<Response<Rollback>> {
+    let mut tx = ctx.db_pool.begin().await?;
+    let recorded_at = request.recorded_at.to_db()?;
+
+    let snapshot_exists = query_scalar!(
+        "SELECT 1 FROM snapshots WHERE timestamp > $1 LIMIT 1",
+        recorded_at
+    )
+    .fetch_optional(&mut tx)
+    .await?
+    .is_some();
+    if snapshot_exists {
+        bail!(
+            "cannot roll back to {}: a snapshot was made after this time",
+            request.recorded_at
+        );
+    }
+
+    let entry_ids = query_scalar!(
+        "SELECT id FROM entries
+        WHERE (path = $1 OR path LIKE $2) AND recorded_at > $3
+        ORDER BY path
+        FOR UPDATE",
+        request.path.to_str_without_prefix(),
+        starts_with(&request.path),
+        recorded_at,
+    )
+    .fetch_all(&mut tx)
+    .await?;
+    let affected_paths = entry_ids.len().try_into()?;
+    if request.dry_run {
+        return Ok(BulkActionStats { affected_paths });
+    }
+
+    let mut hashes_to_check = HashMap::new();
+    for id in entry_ids {
+        {
+            let mut deleted_rows = query!(
+                "DELETE FROM entry_versions
+                WHERE entry_id = $1 AND recorded_at > $2
+                RETURNING content_hash, encrypted_size",
+                id,
+                recorded_at,
+            )
+            .fetch(&mut tx);
+            while let Some(row) = deleted_rows.try_next().await? {
+                if let (Some(hash), Some(size)) = (row.content_hash, row.encrypted_size) {
+                    hashes_to_check.insert(EncryptedContentHash::from_encrypted(hash), size);
+                }
+            }
+        }
+        let version = query!(
+            "SELECT * FROM entry_versions
+            WHERE entry_id = $1
+            ORDER BY recorded_at DESC
+            LIMIT 1",
+            id
+        )
+        .fetch_optional(&mut tx)
+        .await?;
+        if let Some(version) = version {
+            tracing::debug!("rollback: restoring {:?}", version);
+            // A new update number is required for clients to receive the change.
+            query!(
+                "UPDATE entries
+                SET update_number = nextval('entry_update_numbers'),
+                    recorded_at = $1,
+                    source_id = $2,
+                    record_trigger = $3,
+                    kind = $4,
+                    original_size = $5,
+                    encrypted_size = $6,
+                    modified_at = $7,
+                    content_hash = $8,
+                    unix_mode = $9
+                WHERE id = $10",
+                version.recorded_at,
+                version.source_id,
+                version.record_trigger,
+                version.kind,
+                version.original_size,
+                version.encrypted_size,
+                version.modified_at,
+                version.content_hash,
+                version.unix_mode,
+                id,
             )
+            .execute(&mut tx)
             .await?;
-            if r.added {
-                affected_paths += 1;
+            // The update trigger has recorded a copy of this version with the new
+            // update number, so the original one is redundant unless a snapshot uses it.
+            if version.snapshot_id.is_none() {
+                query!("DELETE FROM entry_versions WHERE id = $1", version.id)
+                    .execute(&mut tx)
+                    .await?;
             }
+        } else {
+            tracing::debug!("rollback: deleting {:?}", id);
+            // The entry didn't exist at that time.
+            query!(
+                "UPDATE entries
+                SET update_number = nextval('entry_update_numbers'),
+                    recorded_at = now(),
+                    source_id = $1,
+                    record_trigger = $2,
+                    kind = $3,
+                    original_size = NULL,
+                    encrypted_size = NULL,
+                    modified_at = NULL,
+                    content_hash = NULL,
+                    unix_mode = NULL
+                WHERE id = $4",
+                ctx.source_id.to_db(),
+                RecordTrigger::Reset as i32,
+                EntryKind::NOT_EXISTS,
+                id,
+            )
+            .execute(&mut tx)
+            .await?;
         }
     }
+    let hashes_to_remove = unused_hashes(&mut tx, hashes_to_check).await?;
     tx.commit().await?;
+
+    let removed_files = remove_files(&ctx.storage, hashes_to_remove);
+    tracing::info!(?affected_paths, ?removed_files, "rollback completed");
     Ok(BulkActionStats { affected_paths })
 }
 
+pub async fn purge_source(ctx: Context, request: PurgeSource) -> Result<Response<PurgeSource>> {
+    let stats = util::purge_source(
+        &ctx.storage,
+        &ctx.db_pool,
+        &request.name,
+        request.grace_period,
+    )
+    .await?;
+    tracing::info!(name = request.name, ?stats, "source purged");
+    Ok(stats)
+}
+
+pub async fn pin_path(ctx: Context, request: PinPath) -> Result<Response<PinPath>> {
+    let children_pattern = request.recursive.then(|| starts_with(&request.path));
+    query!(
+        "INSERT INTO pinned_paths (path, children_pattern, pinned_at)
+        VALUES ($1, $2, now())
+        ON CONFLICT (path) DO UPDATE SET children_pattern = $2",
+        request.path.to_str_without_prefix(),
+        children_pattern,
+    )
+    .execute(&ctx.db_pool)
+    .await?;
+    Ok(())
+}
+
+pub async fn unpin_path(ctx: Context, request: UnpinPath) -> Result<Response<UnpinPath>> {
+    let r = query!(
+        "DELETE FROM pinned_paths WHERE path = $1",
+        request.path.to_str_without_prefix(),
+    )
+    .execute(&ctx.db_pool)
+    .await?;
+    Ok(r.rows_affected() > 0)
+}
+
+pub async fn get_pinned_paths(
+    ctx: Context,
+    _request: GetPinnedPaths,
+) -> Result<Response<GetPinnedPaths>> {
+    let mut paths = Vec::new();
+    let mut rows = query!("SELECT * FROM pinned_paths ORDER BY path").fetch(&ctx.db_pool);
+    while let Some(row) = rows.try_next().await? {
+        paths.push(PinnedPath {
+            path: EncryptedArchivePath::from_encrypted_without_prefix(&row.path)?,
+            recursive: row.children_pattern.is_some(),
+            pinned_at: row.pinned_at.from_db(),
+        });
+    }
+    Ok(paths)
+}
+
+pub async fn get_version_hotspots(
+    ctx: Context,
+    request: GetVersionHotspots,
+) -> Result<Response<GetVersionHotspots>> {
+    let mut hotspots = Vec::new();
+    let mut rows = query!(
+        r#"SELECT path, count(*) AS "versions!" FROM entry_versions
+        WHERE path = $1 OR path LIKE $2
+        GROUP BY path
+        ORDER BY count(*) DESC, path
+        LIMIT $3"#,
+        request.under.to_str_without_prefix(),
+        starts_with(&request.under),
+        i64::from(request.limit),
+    )
+    .fetch(&ctx.read_db_pool);
+    while let Some(row) = rows.try_next().await? {
+        hotspots.push(VersionHotspot {
+            path: EncryptedArchivePath::from_encrypted_without_prefix(&row.path)?,
+            versions: row.versions.try_into()?,
+        });
+    }
+    Ok(hotspots)
+}
+
+pub async fn get_server_time(
+    _ctx: Context,
+    _request: GetServerTime,
+) -> Result<Response<GetServerTime>> {
+    Ok(Utc::now())
+}
+
 pub async fn check_integrity(
     ctx: Context,
     _request: CheckIntegrity,
 ) -> Result<Response<CheckIntegrity>> {
     let mut db_hashes = HashMap::new();
+    // Content of metadata-only versions is not expected to be in the storage.
     let mut rows = query!(
-        "SELECT encrypted_size, content_hash FROM entry_versions WHERE content_hash IS NOT NULL"
+        "SELECT encrypted_size, content_hash FROM entry_versions
+        WHERE content_hash IS NOT NULL
+            AND content_hash NOT IN (SELECT content_hash FROM metadata_only_hashes)"
     )
     .fetch(&ctx.db_pool);
     while let Some(row) = rows.try_next().await? {
@@ -648,6 +845,31 @@ pub async fn check_integrity(
     Ok(())
 }
 
+pub async fn get_content_digests(
+    ctx: Context,
+    request: GetContentDigests,
+) -> Result<Response<GetContentDigests>> {
+    let database = digest::database_digests(&ctx.db_pool, &request.prefix).await?;
+    let storage = block_in_place(|| ctx.storage.content_digests(&request.prefix))?;
+    let children = database
+        .children
+        .keys()
+        .chain(storage.children.keys())
+        .collect::<BTreeSet<_>>()
+        .into_iter()
+        .map(|prefix| ChildContentDigests {
+            prefix: prefix.clone(),
+            database: database.children.get(prefix).copied().unwrap_or_default(),
+            storage: storage.children.get(prefix).copied().unwrap_or_default(),
+        })
+        .collect();
+    Ok(ContentDigests {
+        database: database.digest,
+        storage: storage.digest,
+        children,
+    })
+}
+
 pub async fn get_sources(ctx: Context, _request: GetSources) -> Result<Response<GetSources>> {
     let mut sources = Vec::new();
     let mut rows = query!("SELECT id, name FROM sources ORDER BY id").fetch(&ctx.db_pool);
@@ -697,11 +919,44 @@ pub async fn content_hash_exists(
     ctx.storage.exists(&request.0)
 }
 
+pub async fn get_server_limits(
+    ctx: Context,
+    _request: GetServerLimits,
+) -> Result<Response<GetServerLimits>> {
+    Ok(ServerLimits {
+        max_file_size: ctx.limits.max_file_size,
+        max_daily_growth_per_source: ctx.limits.max_daily_growth_per_source,
+        max_entries: ctx.limits.max_entries,
+        min_client_version: ctx.min_client_version,
+        compression: Encoding::ALL
+            .iter()
+            .map(|encoding| encoding.name().to_owned())
+            .collect(),
+    })
+}
+
 pub async fn get_server_status(
     ctx: Context,
     _request: GetServerStatus,
 ) -> Result<Response<GetServerStatus>> {
+    let mut tx = ctx.read_db_pool.begin().await?;
+    let snapshots = query!(r#"SELECT count(*) AS "count!", max(timestamp) AS last FROM snapshots"#)
+        .fetch_one(&mut tx)
+        .await?;
+    // A snapshot is made when its timestamp is older than `retain_detailed_history_for`.
+    let next_snapshot_at = next_snapshot_timestamp(&mut tx, ctx.snapshot_interval)
+        .await?
+        .map(|timestamp| {
+            anyhow::Ok(max(
+                timestamp + chrono::Duration::from_std(ctx.retain_detailed_history_for)?,
+                Utc::now(),
+            ))
+        })
+        .transpose()?;
     Ok(ServerStatus {
         available_space: ctx.storage.available_space()?,
+        snapshots: snapshots.count.try_into()?,
+        last_snapshot_at: snapshots.last.map(|last| last.from_db()),
+        next_snapshot_at,
     })
 }
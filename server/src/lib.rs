@@ -1,7 +1,10 @@
 #![allow(clippy::collapsible_else_if)]
 
+mod add_versions;
 mod content_streaming;
+mod digest;
 mod handler;
+pub mod log_tail;
 mod snapshot;
 mod storage;
 pub mod util;
@@ -10,59 +13,91 @@ use std::{
     cmp::min,
     collections::HashMap,
     convert::Infallible,
+    io,
     net::SocketAddr,
+    num::NonZeroU32,
     path::{Path, PathBuf},
     sync::Arc,
     time::{Duration, Instant},
 };
 
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, bail, Result};
 use bytes::{BufMut, BytesMut};
-use futures_util::{Future, StreamExt, TryStreamExt};
+use futures_util::{future::select_all, Future, StreamExt, TryStreamExt};
 use http_body_util::{combinators::BoxBody, BodyExt, Full, StreamBody};
 use humantime_serde::re::humantime::parse_duration;
 use hyper::{
     body::{self, Bytes, Frame},
-    header::AUTHORIZATION,
+    header::{HeaderValue, ACCEPT_ENCODING, AUTHORIZATION, CONTENT_ENCODING, CONTENT_TYPE},
     server::conn::http1,
     service::service_fn,
     Method, Request, Response, StatusCode,
 };
 use rammingen_protocol::{
+    compression::{Encoder, Encoding},
     endpoints::{
-        AddVersion, CheckIntegrity, ContentHashExists, GetAllEntryVersions, GetDirectChildEntries,
-        GetEntryVersionsAtTime, GetNewEntries, GetServerStatus, GetSources, MovePath, RemovePath,
-        RequestToResponse, RequestToStreamingResponse, ResetVersion, StreamingResponseItem,
+        AddVersion, AddVersionIfUnchanged, AddVersions, CheckIntegrity, ContentHashExists,
+        ErrorResponse, GetAllEntryVersions, GetBootstrapEntries, GetContentDigests,
+        GetDirectChildEntries, GetEntries, GetEntriesByHash, GetEntryVersionsAtTime,
+        GetLastUpdateNumber, GetNewEntries, GetPinnedPaths, GetServerLimits, GetServerStatus,
+        GetServerTime, GetSources, GetVersionHotspots, GetVersionStats, LimitExceeded, MovePath,
+        PinPath, PurgeSource, RemovePath, RequestToResponse, RequestToStreamingResponse,
+        ResetVersion, Rollback, StreamingResponseItem, UnpinPath,
     },
-    EncryptedContentHash, SourceId,
+    EncryptedContentHash, SourceId, ADMIN_TOKEN_HEADER, CLIENT_VERSION_HEADER, REQUEST_ID_HEADER,
 };
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use socket2::{Domain, Protocol, Socket, Type};
 use sqlx::{query, PgPool};
 use storage::Storage;
+pub use storage::StorageKey;
 use stream_generator::{generate_stream, Yielder};
+use subtle::ConstantTimeEq;
 use tokio::{
-    net::TcpListener,
+    net::{TcpListener, TcpStream},
     select,
     signal::ctrl_c,
     sync::{
+        broadcast::error::RecvError,
         mpsc::{self, Sender},
         Mutex,
     },
     task,
     time::interval,
 };
-use tracing::{error, info, warn};
-use util::default_config_dir;
+use tracing::{error, info, info_span, warn, Instrument, Span};
+use util::{default_config_dir, generate_request_id, parse_version};
 
-use crate::snapshot::make_snapshot;
+use crate::{
+    log_tail::log_tail,
+    snapshot::{make_snapshot, remove_excess_versions},
+};
 
 const SOURCES_CACHE_INTERVAL: Duration = Duration::from_secs(10);
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Config {
     pub database_url: String,
+    /// Database used by read-only requests (e.g. a read replica).
+    /// If omitted, `database_url` is used for all requests.
+    ///
+    /// Note that changes may become visible to clients with a delay
+    /// if the replica lags behind the primary database.
+    #[serde(default)]
+    pub read_database_url: Option<String>,
     pub storage_path: PathBuf,
-    pub bind_addr: SocketAddr,
+    /// If set, content files are encrypted with this key before they're written
+    /// to the storage, in addition to the encryption done by clients.
+    /// Files written before the key was set remain readable. Names and sizes of
+    /// content files are not hidden.
+    ///
+    /// Use `rammingen-admin generate-storage-key` to generate a key.
+    #[serde(default)]
+    pub storage_encryption_key: Option<StorageKey>,
+    /// Address to listen on, or a list of addresses
+    /// (e.g. `["0.0.0.0:8007", "[::]:8007"]` to serve both IPv4 and IPv6).
+    /// IPv6 addresses only accept IPv6 connections.
+    pub bind_addr: BindAddr,
     #[serde(default)]
     pub log_file: Option<PathBuf>,
     #[serde(default = "default_log_filter")]
@@ -75,6 +110,71 @@ pub struct Config {
         default = "default_retain_detailed_history_for"
     )]
     pub retain_detailed_history_for: Duration,
+    /// If set, only this number of the most recent versions is kept for each path
+    /// (in addition to versions included in snapshots), regardless of their age.
+    #[serde(default)]
+    pub max_versions_per_path: Option<NonZeroU32>,
+    /// Access token for administrative endpoints. If omitted, these endpoints are disabled.
+    ///
+    /// Available endpoints:
+    ///
+    /// - `GET /admin/log` - streams recent server log lines and follows new ones.
+    /// - `Rollback` requests. The token is sent in the `x-rammingen-admin-token` header
+    ///   in addition to the source's access token.
+    #[serde(default)]
+    pub admin_token: Option<String>,
+    /// Global limits applied to all sources.
+    #[serde(default)]
+    pub limits: Limits,
+    /// If set, requests from clients older than this version (e.g. `"0.2.0"`)
+    /// are rejected with 426 Upgrade Required. Clients that don't report
+    /// their version are rejected as well.
+    #[serde(default)]
+    pub min_client_version: Option<String>,
+}
+
+/// Limits enforced by the server regardless of client config.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct Limits {
+    /// Maximum encrypted size of a single file, in bytes.
+    #[serde(default)]
+    pub max_file_size: Option<u64>,
+    /// Maximum total encrypted size of file versions added by a single source
+    /// within the last 24 hours, in bytes.
+    #[serde(default)]
+    pub max_daily_growth_per_source: Option<u64>,
+    /// Maximum number of existing entries (files and directories) in the archive,
+    /// including parent directories created implicitly.
+    #[serde(default)]
+    pub max_entries: Option<u64>,
+    /// Minimum free space that must remain in the storage, in bytes.
+    /// Uploads are aborted with 507 Insufficient Storage as soon as
+    /// free space drops below this value.
+    #[serde(default)]
+    pub min_free_space: Option<u64>,
+}
+
+/// One or more socket addresses.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum BindAddr {
+    One(SocketAddr),
+    Many(Vec<SocketAddr>),
+}
+
+impl BindAddr {
+    pub fn addrs(&self) -> &[SocketAddr] {
+        match self {
+            BindAddr::One(addr) => std::slice::from_ref(addr),
+            BindAddr::Many(addrs) => addrs,
+        }
+    }
+}
+
+impl From<SocketAddr> for BindAddr {
+    fn from(addr: SocketAddr) -> Self {
+        BindAddr::One(addr)
+    }
 }
 
 fn default_snapshot_interval() -> Duration {
@@ -98,8 +198,10 @@ fn default_log_filter() -> String {
 #[derive(Debug, Clone)]
 pub struct Context {
     db_pool: PgPool,
+    read_db_pool: PgPool,
     storage: Arc<Storage>,
     sources: Arc<Mutex<CachedSources>>,
+    min_client_version: Option<(u64, u64, u64)>,
     config: Config,
 }
 
@@ -110,7 +212,7 @@ struct CachedSources {
 }
 
 async fn load_sources(db_pool: &PgPool) -> Result<HashMap<String, SourceId>> {
-    query!("SELECT id, access_token FROM sources")
+    query!("SELECT id, access_token FROM sources WHERE disabled_at IS NULL")
         .fetch(db_pool)
         .map_ok(|row| (row.access_token, row.id.into()))
         .try_collect()
@@ -121,19 +223,40 @@ async fn load_sources(db_pool: &PgPool) -> Result<HashMap<String, SourceId>> {
 pub async fn run(config: Config) -> Result<()> {
     info!("Connecting to database...");
     let db_pool = PgPool::connect(&config.database_url).await?;
+    let read_db_pool = if let Some(url) = &config.read_database_url {
+        PgPool::connect(url).await?
+    } else {
+        db_pool.clone()
+    };
     info!("Connected to database.");
+    let min_client_version = config
+        .min_client_version
+        .as_deref()
+        .map(parse_version)
+        .transpose()?;
     let ctx = Context {
         config: config.clone(),
-        storage: Arc::new(Storage::new(config.storage_path)?),
+        min_client_version,
+        storage: Arc::new(Storage::new(
+            config.storage_path,
+            config.storage_encryption_key,
+        )?),
         sources: Arc::new(Mutex::new(CachedSources {
             sources: load_sources(&db_pool).await?,
             updated_at: Instant::now(),
         })),
         db_pool,
+        read_db_pool,
     };
 
-    let listener = TcpListener::bind(&config.bind_addr).await?;
-    info!("Listening on {}", config.bind_addr);
+    if config.bind_addr.addrs().is_empty() {
+        bail!("bind_addr must contain at least one address");
+    }
+    let mut listeners = Vec::new();
+    for addr in config.bind_addr.addrs() {
+        listeners.push(bind(*addr)?);
+        info!("Listening on {}", addr);
+    }
 
     let snapshot_check_interval = min(config.snapshot_interval / 2, Duration::from_secs(60));
     let ctx2 = ctx.clone();
@@ -144,6 +267,11 @@ pub async fn run(config: Config) -> Result<()> {
             if let Err(err) = make_snapshot(&ctx2).await {
                 error!(?err, "error while making snapshot");
             }
+            if let Some(max_versions) = ctx2.config.max_versions_per_path {
+                if let Err(err) = remove_excess_versions(&ctx2, max_versions).await {
+                    error!(?err, "error while removing excess versions");
+                }
+            }
         }
     });
 
@@ -161,7 +289,7 @@ pub async fn run(config: Config) -> Result<()> {
                 info!("Got interrupt signal, shutting down.");
                 break;
             }
-            r = listener.accept() => match r {
+            r = accept(&listeners) => match r {
                 Ok((stream, _)) => {
                     let ctx = ctx.clone();
                     tokio::spawn(async move {
@@ -184,6 +312,29 @@ pub async fn run(config: Config) -> Result<()> {
     Ok(())
 }
 
+/// Creates a listener. IPv6 listeners don't accept IPv4 connections, so that
+/// an IPv4 listener can use the same port.
+fn bind(addr: SocketAddr) -> io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(true)?;
+    }
+    // Same as `TcpListener::bind`.
+    #[cfg(unix)]
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+    TcpListener::from_std(socket.into())
+}
+
+/// Accepts a connection on any of `listeners`.
+async fn accept(listeners: &[TcpListener]) -> io::Result<(TcpStream, SocketAddr)> {
+    let (result, _, _) =
+        select_all(listeners.iter().map(|listener| Box::pin(listener.accept()))).await;
+    result
+}
+
 #[cfg(target_family = "unix")]
 fn sigterm() -> Result<impl Future<Output = ()>> {
     use tokio::signal::unix::{signal, SignalKind};
@@ -202,27 +353,61 @@ async fn handle_request(
     ctx: Context,
     request: Request<body::Incoming>,
 ) -> Result<Response<BoxBody<Bytes, Infallible>>, Infallible> {
-    try_handle_request(ctx, request).await.or_else(|code| {
-        Ok(Response::builder()
-            .status(code)
-            .body(Full::new(Bytes::from(code.as_str().to_string())).boxed())
-            .expect("response builder failed"))
-    })
+    let request_id = generate_request_id();
+    let span = info_span!("request", id = %request_id);
+    let mut response = try_handle_request(ctx, request, request_id.clone())
+        .instrument(span)
+        .await
+        .unwrap_or_else(|code| {
+            Response::builder()
+                .status(code)
+                .body(Full::new(Bytes::from(code.as_str().to_string())).boxed())
+                .expect("response builder failed")
+        });
+    if let Ok(value) = HeaderValue::from_str(&request_id) {
+        response.headers_mut().insert(REQUEST_ID_HEADER, value);
+    }
+    Ok(response)
 }
 
 async fn try_handle_request(
     ctx: Context,
     request: Request<body::Incoming>,
+    request_id: String,
 ) -> Result<Response<BoxBody<Bytes, Infallible>>, StatusCode> {
+    if request.uri().path() == ADMIN_LOG_PATH {
+        return admin_log(&ctx, &request);
+    }
+    if let Err(message) = check_client_version(&ctx, &request) {
+        warn!(message, "rejected outdated client");
+        return Ok(Response::builder()
+            .status(StatusCode::UPGRADE_REQUIRED)
+            .body(Full::new(Bytes::from(message)).boxed())
+            .expect("response builder failed"));
+    }
+
     let source_id = auth(&ctx, &request).await.map_err(|err| {
         warn!(?err, "auth error");
         StatusCode::UNAUTHORIZED
     })?;
+    let is_admin = is_admin_token(
+        &ctx,
+        request
+            .headers()
+            .get(ADMIN_TOKEN_HEADER)
+            .and_then(|value| value.to_str().ok()),
+    );
 
     let ctx = handler::Context {
         db_pool: ctx.db_pool,
+        read_db_pool: ctx.read_db_pool,
         storage: ctx.storage,
         source_id,
+        limits: ctx.config.limits,
+        snapshot_interval: ctx.config.snapshot_interval,
+        retain_detailed_history_for: ctx.config.retain_detailed_history_for,
+        min_client_version: ctx.config.min_client_version.clone(),
+        request_id,
     };
 
     let path = request.uri().path();
@@ -234,7 +419,7 @@ async fn try_handle_request(
         if request.method() == Method::PUT {
             content_streaming::upload(ctx, request, &hash).await
         } else if request.method() == Method::GET {
-            content_streaming::download(ctx, &hash).await
+            content_streaming::download(ctx, &request, &hash).await
         } else {
             Err(StatusCode::NOT_FOUND)
         }
@@ -242,24 +427,64 @@ async fn try_handle_request(
         Err(StatusCode::NOT_FOUND)
     } else if path == GetNewEntries::PATH {
         wrap_stream(ctx, request, handler::get_new_entries).await
+    } else if path == GetBootstrapEntries::PATH {
+        wrap_stream(ctx, request, handler::get_bootstrap_entries).await
+    } else if path == GetLastUpdateNumber::PATH {
+        wrap_request(ctx, request, handler::get_last_update_number).await
     } else if path == GetDirectChildEntries::PATH {
         wrap_stream(ctx, request, handler::get_direct_child_entries).await
+    } else if path == GetEntries::PATH {
+        wrap_request(ctx, request, handler::get_entries).await
+    } else if path == GetEntriesByHash::PATH {
+        wrap_stream(ctx, request, handler::get_entries_by_hash).await
     } else if path == GetEntryVersionsAtTime::PATH {
         wrap_stream(ctx, request, handler::get_entry_versions_at_time).await
     } else if path == GetAllEntryVersions::PATH {
         wrap_stream(ctx, request, handler::get_all_entry_versions).await
     } else if path == AddVersion::PATH {
         wrap_request(ctx, request, handler::add_version).await
+    } else if path == AddVersions::PATH {
+        wrap_request(ctx, request, handler::add_versions).await
+    } else if path == AddVersionIfUnchanged::PATH {
+        wrap_request(ctx, request, handler::add_version_if_unchanged).await
     } else if path == MovePath::PATH {
         wrap_request(ctx, request, handler::move_path).await
     } else if path == RemovePath::PATH {
         wrap_request(ctx, request, handler::remove_path).await
     } else if path == ResetVersion::PATH {
         wrap_request(ctx, request, handler::reset_version).await
+    } else if path == Rollback::PATH {
+        if !is_admin {
+            warn!("admin auth error");
+            return Err(StatusCode::FORBIDDEN);
+        }
+        wrap_request(ctx, request, handler::rollback).await
+    } else if path == PurgeSource::PATH {
+        if !is_admin {
+            warn!("admin auth error");
+            return Err(StatusCode::FORBIDDEN);
+        }
+        wrap_request(ctx, request, handler::purge_source).await
+    } else if path == PinPath::PATH {
+        wrap_request(ctx, request, handler::pin_path).await
+    } else if path == UnpinPath::PATH {
+        wrap_request(ctx, request, handler::unpin_path).await
+    } else if path == GetPinnedPaths::PATH {
+        wrap_request(ctx, request, handler::get_pinned_paths).await
+    } else if path == GetVersionStats::PATH {
+        wrap_request(ctx, request, handler::get_version_stats).await
+    } else if path == GetVersionHotspots::PATH {
+        wrap_request(ctx, request, handler::get_version_hotspots).await
     } else if path == ContentHashExists::PATH {
         wrap_request(ctx, request, handler::content_hash_exists).await
+    } else if path == GetServerTime::PATH {
+        wrap_request(ctx, request, handler::get_server_time).await
+    } else if path == GetServerLimits::PATH {
+        wrap_request(ctx, request, handler::get_server_limits).await
     } else if path == GetServerStatus::PATH {
         wrap_request(ctx, request, handler::get_server_status).await
+    } else if path == GetContentDigests::PATH {
+        wrap_request(ctx, request, handler::get_content_digests).await
     } else if path == CheckIntegrity::PATH {
         wrap_request(ctx, request, handler::check_integrity).await
     } else if path == GetSources::PATH {
@@ -269,6 +494,65 @@ async fn try_handle_request(
     }
 }
 
+const ADMIN_LOG_PATH: &str = "/admin/log";
+
+fn admin_auth(ctx: &Context, request: &Request<body::Incoming>) -> Result<(), StatusCode> {
+    if ctx.config.admin_token.is_none() {
+        return Err(StatusCode::NOT_FOUND);
+    }
+    let access_token = request
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    if !is_admin_token(ctx, access_token) {
+        warn!("admin auth error");
+        return Err(StatusCode::UNAUTHORIZED);
+    }
+    Ok(())
+}
+
+/// Returns true if `token` is the configured admin token. The comparison takes
+/// constant time so that the token can't be guessed from response timings.
+fn is_admin_token(ctx: &Context, token: Option<&str>) -> bool {
+    match (ctx.config.admin_token.as_deref(), token) {
+        (Some(admin_token), Some(token)) => admin_token.as_bytes().ct_eq(token.as_bytes()).into(),
+        _ => false,
+    }
+}
+
+/// Streams recent log lines and then follows new lines until the client disconnects.
+fn admin_log(
+    ctx: &Context,
+    request: &Request<body::Incoming>,
+) -> Result<Response<BoxBody<Bytes, Infallible>>, StatusCode> {
+    admin_auth(ctx, request)?;
+    if request.method() != Method::GET {
+        return Err(StatusCode::NOT_FOUND);
+    }
+    let (recent, mut receiver) = log_tail().subscribe();
+    let body_stream = generate_stream(move |mut y| async move {
+        for line in recent {
+            y.send(line.into()).await;
+        }
+        loop {
+            match receiver.recv().await {
+                Ok(line) => y.send(line.into()).await,
+                Err(RecvError::Lagged(count)) => {
+                    y.send(format!("... skipped {count} lines\n").into()).await
+                }
+                Err(RecvError::Closed) => break,
+            }
+        }
+    });
+    Ok(Response::builder()
+        .header(CONTENT_TYPE, "text/plain; charset=utf-8")
+        .body(BodyExt::boxed(StreamBody::new(
+            body_stream.map(|bytes: Bytes| Ok(Frame::data(bytes))),
+        )))
+        .expect("response builder failed"))
+}
+
 async fn wrap_request<T, F, Fut>(
     ctx: handler::Context,
     request: Request<body::Incoming>,
@@ -280,11 +564,47 @@ where
     F: FnOnce(handler::Context, T) -> Fut,
     Fut: Future<Output = Result<<T as RequestToResponse>::Response>>,
 {
+    let encoding = response_encoding(&request, T::COMPRESS_RESPONSE);
     let request = parse_request(request).await?;
+    let request_id = ctx.request_id.clone();
     let response = f(ctx, request).await;
-    Ok(Response::new(BodyExt::boxed(Full::new(
-        serialize_response(response),
-    ))))
+    let bytes = serialize_response(response, &request_id);
+    // Compression of short responses doesn't save anything noticeable.
+    if let Some(encoding) = encoding.filter(|_| bytes.len() >= MIN_COMPRESSED_RESPONSE_SIZE) {
+        let encoded = task::block_in_place(|| {
+            Encoder::new(encoding).and_then(|mut encoder| {
+                let mut encoded = encoder.encode(&bytes)?;
+                encoded.extend(encoder.finish()?);
+                Ok(encoded)
+            })
+        });
+        match encoded {
+            Ok(encoded) => {
+                let mut response = Response::new(BodyExt::boxed(Full::new(encoded.into())));
+                response
+                    .headers_mut()
+                    .insert(CONTENT_ENCODING, HeaderValue::from_static(encoding.name()));
+                return Ok(response);
+            }
+            Err(err) => warn!(?err, "failed to encode response"),
+        }
+    }
+    Ok(Response::new(BodyExt::boxed(Full::new(bytes))))
+}
+
+const MIN_COMPRESSED_RESPONSE_SIZE: usize = 1024;
+
+/// Chooses transport compression for the response based on the `Accept-Encoding` header.
+/// Returns `None` if the endpoint's response is not worth compressing.
+fn response_encoding(request: &Request<body::Incoming>, compress: bool) -> Option<Encoding> {
+    if !compress {
+        return None;
+    }
+    request
+        .headers()
+        .get(ACCEPT_ENCODING)
+        .and_then(|value| value.to_str().ok())
+        .and_then(Encoding::choose)
 }
 
 const ITEMS_PER_CHUNK: usize = 1024;
@@ -303,47 +623,98 @@ where
     Fut: Future<Output = Result<()>> + Send,
 {
     let (tx, mut rx) = mpsc::channel(5);
+    let encoding = response_encoding(&request, T::COMPRESS_RESPONSE);
     let request = parse_request::<T>(request).await?;
-    tokio::spawn(async move {
-        if let Err(err) = f(ctx, request, tx.clone()).await {
-            let _ = tx.send(Err(err)).await;
+    let request_id = ctx.request_id.clone();
+    tokio::spawn(
+        async move {
+            if let Err(err) = f(ctx, request, tx.clone()).await {
+                let _ = tx.send(Err(err)).await;
+            }
         }
-    });
+        .in_current_span(),
+    );
 
-    let body_stream = generate_stream(move |mut y| async move {
-        async fn send<T>(y: &mut Yielder<Bytes>, data: Result<Option<&[StreamingResponseItem<T>]>>)
-        where
-            T: RequestToStreamingResponse,
-            StreamingResponseItem<T>: Serialize,
-        {
-            y.send(serialize_response_with_length(data)).await;
-        }
+    // `Mutex` makes the body `Sync`.
+    let encoder = encoding
+        .map(Encoder::new)
+        .transpose()
+        .map_err(|err| {
+            warn!(?err, "failed to create encoder");
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?
+        .map(std::sync::Mutex::new);
+    // The body is polled after the request handler returns, so errors
+    // logged while sending it need the request span too.
+    let span = Span::current();
+    let body_stream = generate_stream(move |mut y| {
+        async move {
+            async fn send<T>(
+                y: &mut Yielder<Bytes>,
+                encoder: Option<&std::sync::Mutex<Encoder>>,
+                data: Result<Option<&[StreamingResponseItem<T>]>>,
+                request_id: &str,
+            ) where
+                T: RequestToStreamingResponse,
+                StreamingResponseItem<T>: Serialize,
+            {
+                let bytes = serialize_response_with_length(data, request_id);
+                if let Some(encoder) = encoder {
+                    let encoded =
+                        task::block_in_place(|| encoder.lock().expect("poisoned").encode(&bytes));
+                    match encoded {
+                        Ok(encoded) => y.send(encoded.into()).await,
+                        Err(err) => warn!(?err, "failed to encode response"),
+                    }
+                } else {
+                    y.send(bytes).await;
+                }
+            }
 
-        let mut buf = Vec::new();
-        while let Some(item) = rx.recv().await {
-            match item {
-                Ok(item) => {
-                    buf.push(item);
-                    if buf.len() >= ITEMS_PER_CHUNK {
-                        send::<T>(&mut y, Ok(Some(&buf))).await;
-                        buf.clear();
+            let mut buf = Vec::new();
+            let mut error = None;
+            while let Some(item) = rx.recv().await {
+                match item {
+                    Ok(item) => {
+                        buf.push(item);
+                        if buf.len() >= ITEMS_PER_CHUNK {
+                            send::<T>(&mut y, encoder.as_ref(), Ok(Some(&buf)), &request_id).await;
+                            buf.clear();
+                        }
+                    }
+                    Err(err) => {
+                        error = Some(err);
+                        break;
                     }
                 }
-                Err(err) => {
-                    send::<T>(&mut y, Err(err)).await;
-                    return;
+            }
+            if let Some(err) = error {
+                send::<T>(&mut y, encoder.as_ref(), Err(err), &request_id).await;
+            } else {
+                if !buf.is_empty() {
+                    send::<T>(&mut y, encoder.as_ref(), Ok(Some(&buf)), &request_id).await;
+                }
+                send::<T>(&mut y, encoder.as_ref(), Ok(None), &request_id).await;
+            }
+            if let Some(encoder) = encoder {
+                match task::block_in_place(|| encoder.into_inner().expect("poisoned").finish()) {
+                    Ok(encoded) => y.send(encoded.into()).await,
+                    Err(err) => warn!(?err, "failed to encode response"),
                 }
             }
         }
-        if !buf.is_empty() {
-            send::<T>(&mut y, Ok(Some(&buf))).await;
-        }
-        send::<T>(&mut y, Ok(None)).await;
+        .instrument(span)
     });
 
-    Ok(Response::new(BodyExt::boxed(StreamBody::new(
+    let mut response = Response::new(BodyExt::boxed(StreamBody::new(
         body_stream.map(|bytes| Ok(Frame::data(bytes))),
-    ))))
+    )));
+    if let Some(encoding) = encoding {
+        response
+            .headers_mut()
+            .insert(CONTENT_ENCODING, HeaderValue::from_static(encoding.name()));
+    }
+    Ok(response)
 }
 
 async fn parse_request<T: DeserializeOwned>(
@@ -364,23 +735,30 @@ async fn parse_request<T: DeserializeOwned>(
     })
 }
 
-fn serialize_response<T: Serialize>(data: Result<T>) -> Bytes {
-    bincode::serialize(&data.map_err(|err| {
-        warn!(?err, "handler error");
-        format!("{err:?}")
-    }))
-    .expect("bincode serialization failed")
-    .into()
+/// Converts a handler error to the error sent to the client.
+/// The message includes the request ID, so that it can be found in the server log.
+fn error_response(err: anyhow::Error, request_id: &str) -> ErrorResponse {
+    warn!(?err, "handler error");
+    ErrorResponse {
+        message: format!("[request {request_id}] {err:?}"),
+        limit_exceeded: err
+            .chain()
+            .find_map(|cause| cause.downcast_ref::<LimitExceeded>())
+            .cloned(),
+    }
+}
+
+fn serialize_response<T: Serialize>(data: Result<T>, request_id: &str) -> Bytes {
+    bincode::serialize(&data.map_err(|err| error_response(err, request_id)))
+        .expect("bincode serialization failed")
+        .into()
 }
 
-fn serialize_response_with_length<T: Serialize>(data: Result<T>) -> Bytes {
+fn serialize_response_with_length<T: Serialize>(data: Result<T>, request_id: &str) -> Bytes {
     let mut buf = BytesMut::zeroed(4);
     bincode::serialize_into(
         (&mut buf).writer(),
-        &data.map_err(|err| {
-            warn!(?err, "handler error");
-            format!("{err:?}")
-        }),
+        &data.map_err(|err| error_response(err, request_id)),
     )
     .expect("bincode serialization failed");
     let len = (buf.len() - 4) as u32;
@@ -388,6 +766,32 @@ fn serialize_response_with_length<T: Serialize>(data: Result<T>) -> Bytes {
     buf.freeze()
 }
 
+/// Checks that the client version reported in the request is not older than
+/// `min_client_version`. Returns the message for the client otherwise.
+fn check_client_version(ctx: &Context, request: &Request<body::Incoming>) -> Result<(), String> {
+    let Some(min_version) = ctx.min_client_version else {
+        return Ok(());
+    };
+    let min_version_str = ctx.config.min_client_version.as_deref().unwrap_or_default();
+    let version = request
+        .headers()
+        .get(CLIENT_VERSION_HEADER)
+        .and_then(|value| value.to_str().ok());
+    let Some(version) = version else {
+        return Err(format!(
+            "client version is unknown, \
+            please upgrade rammingen to version {min_version_str} or newer"
+        ));
+    };
+    match parse_version(version) {
+        Ok(version) if version >= min_version => Ok(()),
+        _ => Err(format!(
+            "client version {version} is not supported, \
+            please upgrade rammingen to version {min_version_str} or newer"
+        )),
+    }
+}
+
 async fn auth(ctx: &Context, request: &Request<body::Incoming>) -> Result<SourceId> {
     let auth = request
         .headers()
@@ -416,3 +820,10 @@ pub fn config_path(config: Option<PathBuf>) -> Result<PathBuf> {
         Ok(default_config_dir()?.join("rammingen-server.conf"))
     }
 }
+
+#[tokio::test]
+async fn ipv4_and_ipv6_on_same_port() {
+    let ipv4 = bind("0.0.0.0:0".parse().unwrap()).unwrap();
+    let port = ipv4.local_addr().unwrap().port();
+    bind(format!("[::]:{port}").parse().unwrap()).unwrap();
+}
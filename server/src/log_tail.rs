@@ -0,0 +1,71 @@
+//! Keeps recent server log lines in memory so that they can be streamed
+//! to an administrator through the `/admin/log` endpoint.
+
+use std::{collections::VecDeque, io, sync::Mutex};
+
+use once_cell::sync::Lazy;
+use tokio::sync::broadcast;
+use tracing_subscriber::fmt::MakeWriter;
+
+/// Number of recent lines sent to a client before following new lines.
+const MAX_RECENT_LINES: usize = 1000;
+
+/// Capacity of the channel for new lines. If a client is too slow to receive lines,
+/// some of them are skipped.
+const CHANNEL_CAPACITY: usize = 1000;
+
+static LOG_TAIL: Lazy<LogTail> = Lazy::new(|| LogTail {
+    recent: Mutex::new(VecDeque::new()),
+    sender: broadcast::channel(CHANNEL_CAPACITY).0,
+});
+
+pub struct LogTail {
+    recent: Mutex<VecDeque<String>>,
+    sender: broadcast::Sender<String>,
+}
+
+/// Returns the global log tail. It can be used as a writer of a `tracing_subscriber` layer.
+pub fn log_tail() -> &'static LogTail {
+    &LOG_TAIL
+}
+
+impl LogTail {
+    fn push(&self, line: String) {
+        let mut recent = self.recent.lock().expect("poisoned");
+        if recent.len() >= MAX_RECENT_LINES {
+            recent.pop_front();
+        }
+        recent.push_back(line.clone());
+        // An error only means that there are no subscribers.
+        let _ = self.sender.send(line);
+    }
+
+    /// Returns recent lines and a receiver of all lines added after them.
+    pub fn subscribe(&self) -> (Vec<String>, broadcast::Receiver<String>) {
+        let recent = self.recent.lock().expect("poisoned");
+        (recent.iter().cloned().collect(), self.sender.subscribe())
+    }
+}
+
+pub struct LogTailWriter(&'static LogTail);
+
+impl io::Write for LogTailWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        for line in String::from_utf8_lossy(buf).lines() {
+            self.0.push(format!("{line}\n"));
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<'a> MakeWriter<'a> for &'static LogTail {
+    type Writer = LogTailWriter;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        LogTailWriter(self)
+    }
+}
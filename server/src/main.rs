@@ -1,9 +1,16 @@
-use anyhow::Result;
-use clap::Parser;
+use anyhow::{bail, Result};
+use clap::{Parser, Subcommand};
 use rammingen_protocol::util::log_writer;
-use rammingen_server::{config_path, Config};
+use rammingen_server::{
+    config_path,
+    log_tail::log_tail,
+    util::{check_database, storage_status},
+    Config,
+};
 use std::{path::PathBuf, sync::Mutex};
-use tracing_subscriber::{util::SubscriberInitExt, EnvFilter};
+use tracing_subscriber::{
+    prelude::__tracing_subscriber_SubscriberExt, util::SubscriberInitExt, EnvFilter,
+};
 
 #[derive(Debug, Parser)]
 #[command(version = env!("CARGO_PKG_VERSION"))]
@@ -20,6 +27,54 @@ pub struct Cli {
     /// - %APPDATA%\rammingen-server.conf on Windows
     #[clap(long)]
     pub config: Option<PathBuf>,
+    /// If omitted, the server is started.
+    #[clap(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand, PartialEq, Eq)]
+pub enum Command {
+    /// Shows storage usage and checks the database connection
+    /// without starting the server.
+    Status,
+}
+
+async fn status(config: &Config) -> Result<()> {
+    let mut ok = true;
+    println!("Storage path: {}", config.storage_path.display());
+    match storage_status(config) {
+        Ok(status) => {
+            println!(
+                "Content files: {} ({} bytes)",
+                status.content_files, status.content_size
+            );
+            println!(
+                "Disk space: {} bytes available of {} bytes",
+                status.available_space, status.total_space
+            );
+        }
+        Err(err) => {
+            println!("Storage error: {err:#}");
+            ok = false;
+        }
+    }
+    let mut databases = vec![("Database", &config.database_url)];
+    if let Some(url) = &config.read_database_url {
+        databases.push(("Read database", url));
+    }
+    for (name, url) in databases {
+        match check_database(url).await {
+            Ok(()) => println!("{name}: connected"),
+            Err(err) => {
+                println!("{name}: connection failed: {err:#}");
+                ok = false;
+            }
+        }
+    }
+    if !ok {
+        bail!("server status check failed");
+    }
+    Ok(())
 }
 
 #[tokio::main]
@@ -27,11 +82,21 @@ async fn main() -> Result<()> {
     let cli = Cli::parse();
     let config_path = config_path(cli.config)?;
     let config = Config::parse(config_path)?;
+    if cli.command == Some(Command::Status) {
+        return status(&config).await;
+    }
 
-    tracing_subscriber::fmt()
-        .with_writer(Mutex::new(log_writer(config.log_file.as_deref())?))
-        .with_env_filter(EnvFilter::try_new(&config.log_filter)?)
-        .finish()
+    tracing_subscriber::registry()
+        .with(
+            tracing_subscriber::fmt::layer()
+                .with_writer(Mutex::new(log_writer(config.log_file.as_deref())?)),
+        )
+        .with(
+            tracing_subscriber::fmt::layer()
+                .with_ansi(false)
+                .with_writer(log_tail()),
+        )
+        .with(EnvFilter::try_new(&config.log_filter)?)
         .init();
     rammingen_server::run(config).await?;
     Ok(())
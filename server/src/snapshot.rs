@@ -1,35 +1,49 @@
-use std::collections::HashSet;
+use std::{collections::HashMap, num::NonZeroU32, time::Duration};
 
 use crate::handler::{FromDb, ToDb};
 use anyhow::Result;
 use chrono::Utc;
 use futures_util::TryStreamExt;
-use rammingen_protocol::EncryptedContentHash;
-use sqlx::{query, query_scalar};
+use rammingen_protocol::{DateTimeUtc, EncryptedContentHash};
+use sqlx::{query, query_scalar, Postgres, Transaction};
 use tracing::{info, warn};
 
-use crate::Context;
-
-pub async fn make_snapshot(ctx: &Context) -> Result<()> {
-    let mut tx = ctx.db_pool.begin().await?;
+use crate::{digest, storage::Storage, Context};
 
+/// Returns the timestamp of the next snapshot, or `None` if there are no entries.
+/// The snapshot is made once the timestamp is older than `retain_detailed_history_for`.
+pub async fn next_snapshot_timestamp(
+    tx: &mut Transaction<'_, Postgres>,
+    snapshot_interval: Duration,
+) -> Result<Option<DateTimeUtc>> {
     let previous_snapshot_timestamp = if let Some(ts) =
         query_scalar!("SELECT max(timestamp) FROM snapshots")
-            .fetch_one(&mut tx)
+            .fetch_one(&mut *tx)
             .await?
     {
         ts
     } else if let Some(ts) = query_scalar!("SELECT min(recorded_at) FROM entry_versions")
-        .fetch_one(&mut tx)
+        .fetch_one(&mut *tx)
         .await?
     {
         ts
     } else {
+        return Ok(None);
+    };
+    Ok(Some(
+        previous_snapshot_timestamp.from_db() + chrono::Duration::from_std(snapshot_interval)?,
+    ))
+}
+
+pub async fn make_snapshot(ctx: &Context) -> Result<()> {
+    let mut tx = ctx.db_pool.begin().await?;
+
+    let Some(next_snapshot_timestamp) =
+        next_snapshot_timestamp(&mut tx, ctx.config.snapshot_interval).await?
+    else {
         // There are no entries, so there is no need for a snapshot.
         return Ok(());
     };
-    let next_snapshot_timestamp = previous_snapshot_timestamp.from_db()
-        + chrono::Duration::from_std(ctx.config.snapshot_interval)?;
     let latest_allowed_snapshot =
         Utc::now() - chrono::Duration::from_std(ctx.config.retain_detailed_history_for)?;
     if next_snapshot_timestamp > latest_allowed_snapshot {
@@ -37,10 +51,16 @@ pub async fn make_snapshot(ctx: &Context) -> Result<()> {
     }
     let next_snapshot_timestamp_db = next_snapshot_timestamp.to_db()?;
 
+    // Pinned paths keep all of their versions, so they are not included in snapshots.
     let versions: Vec<_> = query!(
         "SELECT DISTINCT ON (path) *
         FROM entry_versions
         WHERE recorded_at <= $1 AND snapshot_id IS NULL
+        AND NOT EXISTS (
+            SELECT 1 FROM pinned_paths
+            WHERE pinned_paths.path = entry_versions.path
+                OR entry_versions.path LIKE pinned_paths.children_pattern
+        )
         ORDER BY path, recorded_at DESC",
         next_snapshot_timestamp_db,
     )
@@ -50,20 +70,25 @@ pub async fn make_snapshot(ctx: &Context) -> Result<()> {
     .await?;
     let num_added = versions.len();
 
-    let mut hashes_to_check = HashSet::new();
+    let mut hashes_to_check = HashMap::new();
     let mut num_deleted = 0;
     {
-        let mut deleted_rows = query_scalar!(
+        let mut deleted_rows = query!(
             "DELETE FROM entry_versions
             WHERE recorded_at <= $1 AND snapshot_id IS NULL
-            RETURNING content_hash",
+            AND NOT EXISTS (
+                SELECT 1 FROM pinned_paths
+                WHERE pinned_paths.path = entry_versions.path
+                    OR entry_versions.path LIKE pinned_paths.children_pattern
+            )
+            RETURNING content_hash, encrypted_size",
             next_snapshot_timestamp_db,
         )
         .fetch(&mut tx);
-        while let Some(hash) = deleted_rows.try_next().await? {
+        while let Some(row) = deleted_rows.try_next().await? {
             num_deleted += 1;
-            if let Some(hash) = hash {
-                hashes_to_check.insert(EncryptedContentHash::from_encrypted(hash));
+            if let (Some(hash), Some(size)) = (row.content_hash, row.encrypted_size) {
+                hashes_to_check.insert(EncryptedContentHash::from_encrypted(hash), size);
             }
         }
     }
@@ -75,7 +100,6 @@ pub async fn make_snapshot(ctx: &Context) -> Result<()> {
     .fetch_one(&mut tx)
     .await?;
 
-    let mut hashes_to_remove = Vec::new();
     for version in versions {
         query!("
             INSERT INTO entry_versions (
@@ -99,39 +123,130 @@ pub async fn make_snapshot(ctx: &Context) -> Result<()> {
             version.unix_mode,
         ).execute(&mut tx)
         .await?;
-        if let Some(hash) = version.content_hash {
-            hashes_to_check.insert(EncryptedContentHash::from_encrypted(hash));
+        if let (Some(hash), Some(size)) = (version.content_hash, version.encrypted_size) {
+            hashes_to_check.insert(EncryptedContentHash::from_encrypted(hash), size);
         }
     }
-    for hash in hashes_to_check {
+    let hashes_to_remove = unused_hashes(&mut tx, hashes_to_check).await?;
+
+    tx.commit().await?;
+
+    let num_removed_files = remove_files(&ctx.storage, hashes_to_remove);
+
+    info!(
+        "created new snapshot for {} (deleted {} versions, added {} versions, removed {} files)",
+        next_snapshot_timestamp, num_deleted, num_added, num_removed_files,
+    );
+
+    Ok(())
+}
+
+/// Removes versions beyond `max_versions` most recent versions of each path.
+/// Versions that belong to snapshots are always kept and are not counted.
+/// Versions of pinned paths are always kept.
+pub async fn remove_excess_versions(ctx: &Context, max_versions: NonZeroU32) -> Result<()> {
+    let mut tx = ctx.db_pool.begin().await?;
+
+    let mut hashes_to_check = HashMap::new();
+    let mut num_deleted = 0;
+    {
+        let mut deleted_rows = query!(
+            "DELETE FROM entry_versions
+            WHERE id IN (
+                SELECT id FROM (
+                    SELECT id, row_number() OVER (
+                        PARTITION BY path ORDER BY recorded_at DESC, id DESC
+                    ) AS position
+                    FROM entry_versions
+                    WHERE snapshot_id IS NULL
+                    AND NOT EXISTS (
+                        SELECT 1 FROM pinned_paths
+                        WHERE pinned_paths.path = entry_versions.path
+                            OR entry_versions.path LIKE pinned_paths.children_pattern
+                    )
+                ) AS versions
+                WHERE position > $1
+            )
+            RETURNING content_hash, encrypted_size",
+            i64::from(max_versions.get()),
+        )
+        .fetch(&mut tx);
+        while let Some(row) = deleted_rows.try_next().await? {
+            num_deleted += 1;
+            if let (Some(hash), Some(size)) = (row.content_hash, row.encrypted_size) {
+                hashes_to_check.insert(EncryptedContentHash::from_encrypted(hash), size);
+            }
+        }
+    }
+    if num_deleted == 0 {
+        return Ok(());
+    }
+    let hashes_to_remove = unused_hashes(&mut tx, hashes_to_check).await?;
+
+    tx.commit().await?;
+
+    let num_removed_files = remove_files(&ctx.storage, hashes_to_remove);
+
+    info!(
+        "removed excess versions (deleted {} versions, removed {} files)",
+        num_deleted, num_removed_files,
+    );
+    Ok(())
+}
+
+/// Returns hashes from `hashes` that are no longer referenced by any version
+/// and have a file in the storage, and removes them from the database digests.
+/// `hashes` must contain the encrypted size of each hash.
+pub async fn unused_hashes(
+    tx: &mut Transaction<'_, Postgres>,
+    hashes: HashMap<EncryptedContentHash, i64>,
+) -> Result<Vec<EncryptedContentHash>> {
+    let mut hashes = hashes.into_iter().collect::<Vec<_>>();
+    hashes.sort_unstable_by(|a, b| a.0.as_slice().cmp(b.0.as_slice()));
+    let locked = hashes
+        .iter()
+        .map(|(hash, _)| hash.as_slice().to_vec())
+        .collect::<Vec<_>>();
+    digest::lock_hashes(tx, &locked).await?;
+
+    let mut unused = Vec::new();
+    let mut digest_changes = Vec::new();
+    for (hash, size) in hashes {
         let exists = query_scalar!(
             "SELECT 1 FROM entry_versions WHERE content_hash = $1 LIMIT 1",
             hash.as_slice()
         )
-        .fetch_optional(&mut tx)
+        .fetch_optional(&mut *tx)
         .await?
         .is_some();
         if !exists {
-            hashes_to_remove.push(hash);
+            // Content of metadata-only versions has no file in the storage.
+            let metadata_only = query_scalar!(
+                "DELETE FROM metadata_only_hashes WHERE content_hash = $1 RETURNING 1",
+                hash.as_slice()
+            )
+            .fetch_optional(&mut *tx)
+            .await?
+            .is_some();
+            if !metadata_only {
+                digest_changes.push((hash.clone(), size.try_into()?));
+                unused.push(hash);
+            }
         }
     }
+    digest::update_database_digests(tx, &digest_changes).await?;
+    Ok(unused)
+}
 
-    tx.commit().await?;
-
+pub fn remove_files(storage: &Storage, hashes: Vec<EncryptedContentHash>) -> usize {
     let mut num_removed_files = 0;
-    for hash in hashes_to_remove {
-        match ctx.storage.remove_file(&hash) {
+    for hash in hashes {
+        match storage.remove_file(&hash) {
             Ok(()) => num_removed_files += 1,
             Err(err) => {
                 warn!(?err, "failed to remove content file");
             }
         }
     }
-
-    info!(
-        "created new snapshot for {} (deleted {} versions, added {} versions, removed {} files)",
-        next_snapshot_timestamp, num_deleted, num_added, num_removed_files,
-    );
-
-    Ok(())
+    num_removed_files
 }
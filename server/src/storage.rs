@@ -1,18 +1,225 @@
 use anyhow::{anyhow, bail, Result};
+use base64::{prelude::BASE64_URL_SAFE_NO_PAD, Engine};
+use chacha20::{
+    cipher::{KeyIvInit, StreamCipher, StreamCipherSeek},
+    XChaCha20,
+};
 use fs2::available_space;
 use fs_err::{create_dir_all, read_dir, remove_file, rename, symlink_metadata, File};
-use rammingen_protocol::{util::try_exists, EncryptedContentHash};
+use parking_lot::{Mutex, MutexGuard, RwLock};
+use rammingen_protocol::{
+    endpoints::{ContentDigest, CONTENT_DIGEST_DEPTH},
+    util::try_exists,
+    EncryptedContentHash,
+};
+use rand::{rngs::OsRng, RngCore};
+use serde::{de::Error, Deserialize, Serialize};
 use std::{
     collections::HashMap,
-    io::Write,
+    fmt,
+    io::{self, Read, Seek, SeekFrom, Write},
     path::{Path, PathBuf},
+    sync::atomic::{AtomicU64, Ordering},
 };
 use tempfile::NamedTempFile;
 
+use crate::digest::{self, PrefixDigests};
+
+/// Marks content files encrypted with the storage key. Files written without the key
+/// don't have this header, so both kinds of files can be present in the same storage.
+const ENCRYPTED_FILE_MAGIC: &[u8; 8] = b"rmgenc01";
+const NONCE_LEN: usize = 24;
+const ENCRYPTED_HEADER_LEN: u64 = (ENCRYPTED_FILE_MAGIC.len() + NONCE_LEN) as u64;
+
+/// Key used by the server to encrypt content files at rest.
+#[derive(Clone)]
+pub struct StorageKey([u8; 32]);
+
+impl StorageKey {
+    pub fn generate() -> Self {
+        let mut key = [0; 32];
+        OsRng.fill_bytes(&mut key);
+        Self(key)
+    }
+}
+
+impl fmt::Debug for StorageKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("StorageKey").finish()
+    }
+}
+
+impl fmt::Display for StorageKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", BASE64_URL_SAFE_NO_PAD.encode(self.0))
+    }
+}
+
+impl<'de> Deserialize<'de> for StorageKey {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let string = String::deserialize(deserializer)?;
+        let binary = BASE64_URL_SAFE_NO_PAD
+            .decode(string)
+            .map_err(D::Error::custom)?;
+        let array = <[u8; 32]>::try_from(binary).map_err(|vec| {
+            D::Error::custom(format!(
+                "invalid storage key length, expected 32, got {}",
+                vec.len()
+            ))
+        })?;
+        Ok(Self(array))
+    }
+}
+
+impl Serialize for StorageKey {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        self.to_string().serialize(serializer)
+    }
+}
+
 #[derive(Debug)]
 pub struct Storage {
     root: PathBuf,
     tmp: PathBuf,
+    key: Option<StorageKey>,
+    /// Number of bytes that in-flight uploads are yet to write.
+    pending_size: AtomicU64,
+    /// Digests of content files for each prefix of their hashes (see `digest` module).
+    /// Computed on first use and updated when files are added or removed.
+    digests: Mutex<Option<HashMap<String, ContentDigest>>>,
+    /// Held for reading while a file is added or removed, and for writing while `digests`
+    /// are computed, so that the computed digests don't miss any changes.
+    files_lock: RwLock<()>,
+    /// Serialize adding and removing files with the same hash, so that `digests`
+    /// are updated according to the actual changes.
+    hash_locks: Vec<Mutex<()>>,
+}
+
+const NUM_HASH_LOCKS: usize = 64;
+
+/// New content file that is not yet committed to the storage.
+/// Content is encrypted while it's written if the storage key is configured.
+pub struct NewFile {
+    file: NamedTempFile,
+    cipher: Option<XChaCha20>,
+    buf: Vec<u8>,
+}
+
+impl Write for NewFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if let Some(cipher) = &mut self.cipher {
+            self.buf.clear();
+            self.buf.extend_from_slice(buf);
+            cipher.apply_keystream(&mut self.buf);
+            self.file.write_all(&self.buf)?;
+            Ok(buf.len())
+        } else {
+            self.file.write(buf)
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+/// Content file opened for reading. Content is decrypted while it's read
+/// if the file was encrypted with the storage key.
+pub struct StoredFile {
+    file: File,
+    cipher: Option<XChaCha20>,
+    len: u64,
+}
+
+impl StoredFile {
+    /// Size of the content, excluding the encryption header.
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    fn header_len(&self) -> u64 {
+        if self.cipher.is_some() {
+            ENCRYPTED_HEADER_LEN
+        } else {
+            0
+        }
+    }
+}
+
+impl Read for StoredFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.file.read(buf)?;
+        if let Some(cipher) = &mut self.cipher {
+            cipher.apply_keystream(&mut buf[..len]);
+        }
+        Ok(len)
+    }
+}
+
+impl Seek for StoredFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let header_len = self.header_len();
+        let pos = match pos {
+            SeekFrom::Start(pos) => SeekFrom::Start(pos + header_len),
+            pos => pos,
+        };
+        let pos = self
+            .file
+            .seek(pos)?
+            .checked_sub(header_len)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "seek before start"))?;
+        if let Some(cipher) = &mut self.cipher {
+            cipher.seek(pos);
+        }
+        Ok(pos)
+    }
+}
+
+/// Reads the encryption header of a content file. Returns the nonce
+/// if the file was encrypted with the storage key.
+fn read_header(file: &mut File) -> Result<Option<[u8; NONCE_LEN]>> {
+    let mut magic = [0; ENCRYPTED_FILE_MAGIC.len()];
+    let is_encrypted = match file.read_exact(&mut magic) {
+        Ok(()) => &magic == ENCRYPTED_FILE_MAGIC,
+        Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => false,
+        Err(err) => return Err(err.into()),
+    };
+    if !is_encrypted {
+        file.rewind()?;
+        return Ok(None);
+    }
+    let mut nonce = [0; NONCE_LEN];
+    file.read_exact(&mut nonce)?;
+    Ok(Some(nonce))
+}
+
+/// Reservation of space for an in-flight upload. Released when dropped.
+pub struct PendingUpload<'a> {
+    storage: &'a Storage,
+    remaining: u64,
+}
+
+impl PendingUpload<'_> {
+    /// Marks `size` bytes of the upload as written to the storage.
+    pub fn written(&mut self, size: u64) {
+        let size = size.min(self.remaining);
+        self.remaining -= size;
+        self.storage.pending_size.fetch_sub(size, Ordering::Relaxed);
+    }
+}
+
+impl Drop for PendingUpload<'_> {
+    fn drop(&mut self) {
+        self.storage
+            .pending_size
+            .fetch_sub(self.remaining, Ordering::Relaxed);
+    }
 }
 
 fn storage_paths(root: &Path, hash: &EncryptedContentHash) -> (PathBuf, PathBuf) {
@@ -26,7 +233,8 @@ fn storage_paths(root: &Path, hash: &EncryptedContentHash) -> (PathBuf, PathBuf)
 }
 
 impl Storage {
-    pub fn new(root: PathBuf) -> Result<Self> {
+    /// Opens the storage. If `key` is specified, new content files are encrypted with it.
+    pub fn new(root: PathBuf, key: Option<StorageKey>) -> Result<Self> {
         if !try_exists(&root)? {
             bail!("storage root doesn't exist");
         }
@@ -34,33 +242,121 @@ impl Storage {
         let tmp = root.join("tmp");
         create_dir_all(&tmp)?;
 
-        Ok(Self { root, tmp })
+        Ok(Self {
+            root,
+            tmp,
+            key,
+            pending_size: AtomicU64::new(0),
+            digests: Mutex::new(None),
+            files_lock: RwLock::new(()),
+            hash_locks: (0..NUM_HASH_LOCKS).map(|_| Mutex::new(())).collect(),
+        })
     }
 
-    pub fn create_file(&self) -> Result<NamedTempFile> {
-        Ok(NamedTempFile::new_in(&self.tmp)?)
+    /// Reserves `size` bytes for an upload that is about to start.
+    /// Reserved bytes are not reported as available until they are written
+    /// or the returned value is dropped.
+    pub fn reserve(&self, size: u64) -> PendingUpload<'_> {
+        self.pending_size.fetch_add(size, Ordering::Relaxed);
+        PendingUpload {
+            storage: self,
+            remaining: size,
+        }
     }
 
-    pub fn commit_file(&self, mut file: NamedTempFile, hash: &EncryptedContentHash) -> Result<()> {
+    pub fn create_file(&self) -> Result<NewFile> {
+        let mut file = NamedTempFile::new_in(&self.tmp)?;
+        let cipher = if let Some(key) = &self.key {
+            let mut nonce = [0; NONCE_LEN];
+            OsRng.fill_bytes(&mut nonce);
+            file.write_all(ENCRYPTED_FILE_MAGIC)?;
+            file.write_all(&nonce)?;
+            Some(XChaCha20::new(&key.0.into(), &nonce.into()))
+        } else {
+            None
+        };
+        Ok(NewFile {
+            file,
+            cipher,
+            buf: Vec::new(),
+        })
+    }
+
+    pub fn commit_file(&self, file: NewFile, hash: &EncryptedContentHash) -> Result<()> {
+        let mut file = file.file;
         file.flush()?;
         let (dir, new_file_path) = storage_paths(&self.root, hash);
         create_dir_all(dir)?;
+        let _files_guard = self.files_lock.read();
+        let _hash_guard = self.hash_lock(hash);
+        let tracked = self.digests.lock().is_some();
+        // The file may be uploaded again if it was committed after the client checked
+        // that it doesn't exist.
+        let old_size = if tracked && try_exists(&new_file_path)? {
+            Some(self.file_size(hash)?)
+        } else {
+            None
+        };
         let (_, old_path) = file.keep()?;
-        if let Err(err) = rename(&old_path, new_file_path) {
+        if let Err(err) = rename(&old_path, &new_file_path) {
             let _ = remove_file(&old_path);
             return Err(err.into());
         }
+        if tracked {
+            let new_size = self.file_size(hash)?;
+            if let Some(digests) = &mut *self.digests.lock() {
+                if let Some(old_size) = old_size {
+                    digest::toggle_prefixes(digests, hash, old_size);
+                }
+                digest::toggle_prefixes(digests, hash, new_size);
+            }
+        }
         Ok(())
     }
 
-    pub fn open_file(&self, hash: &EncryptedContentHash) -> Result<File> {
+    pub fn open_file(&self, hash: &EncryptedContentHash) -> Result<StoredFile> {
         let (_, path) = storage_paths(&self.root, hash);
-        Ok(File::open(path)?)
+        let mut file = File::open(path)?;
+        let file_len = file.metadata()?.len();
+        let cipher = if let Some(nonce) = read_header(&mut file)? {
+            let key = self
+                .key
+                .as_ref()
+                .ok_or_else(|| anyhow!("content file is encrypted, but storage key is not set"))?;
+            Some(XChaCha20::new(&key.0.into(), &nonce.into()))
+        } else {
+            None
+        };
+        let len = if cipher.is_some() {
+            file_len - ENCRYPTED_HEADER_LEN
+        } else {
+            file_len
+        };
+        Ok(StoredFile { file, cipher, len })
     }
 
     pub fn remove_file(&self, hash: &EncryptedContentHash) -> Result<()> {
         let (_, path) = storage_paths(&self.root, hash);
-        Ok(remove_file(path)?)
+        let _files_guard = self.files_lock.read();
+        let _hash_guard = self.hash_lock(hash);
+        let tracked = self.digests.lock().is_some();
+        let size = if tracked {
+            Some(self.file_size(hash)?)
+        } else {
+            None
+        };
+        remove_file(path)?;
+        if let Some(size) = size {
+            if let Some(digests) = &mut *self.digests.lock() {
+                digest::toggle_prefixes(digests, hash, size);
+            }
+        }
+        Ok(())
+    }
+
+    fn hash_lock(&self, hash: &EncryptedContentHash) -> MutexGuard<'_, ()> {
+        let index = hash.as_slice().first().copied().unwrap_or_default();
+        self.hash_locks[usize::from(index) % NUM_HASH_LOCKS].lock()
     }
 
     pub fn exists(&self, hash: &EncryptedContentHash) -> Result<bool> {
@@ -68,13 +364,64 @@ impl Storage {
         try_exists(path)
     }
 
+    /// Returns size of the content, excluding the encryption header.
     pub fn file_size(&self, hash: &EncryptedContentHash) -> Result<u64> {
         let (_, path) = storage_paths(&self.root, hash);
-        Ok(symlink_metadata(path)?.len())
+        content_size(&path, symlink_metadata(&path)?.len())
     }
 
+    /// Returns free space in the storage, excluding space reserved for in-flight uploads.
     pub fn available_space(&self) -> Result<u64> {
-        Ok(available_space(&self.root)?)
+        Ok(available_space(&self.root)?.saturating_sub(self.pending_size.load(Ordering::Relaxed)))
+    }
+
+    /// Returns digests of content files with hashes starting with `prefix` and
+    /// of each of its children.
+    ///
+    /// Digests are computed when this function is called for the first time, which
+    /// requires a scan of the whole storage. For the longest prefixes, the corresponding
+    /// directory is always read again.
+    pub fn content_digests(&self, prefix: &str) -> Result<PrefixDigests> {
+        if prefix.len() == CONTENT_DIGEST_DEPTH {
+            let mut output = PrefixDigests::default();
+            let dir = self
+                .root
+                .join(prefix.chars().map(String::from).collect::<PathBuf>());
+            if try_exists(&dir)? {
+                let mut map = HashMap::new();
+                self.add_hashes_and_sizes(&dir, &mut map)?;
+                for (hash, size) in map {
+                    output.add(prefix, &hash, size);
+                }
+            }
+            return Ok(output);
+        }
+
+        if self.digests.lock().is_none() {
+            let _files_guard = self.files_lock.write();
+            if self.digests.lock().is_none() {
+                let mut new_digests = HashMap::new();
+                for (hash, size) in self.all_hashes_and_sizes()? {
+                    digest::toggle_prefixes(&mut new_digests, &hash, size);
+                }
+                *self.digests.lock() = Some(new_digests);
+            }
+        }
+        let digests = self.digests.lock();
+        let Some(digests) = &*digests else {
+            bail!("content digests are not computed");
+        };
+        let get = |prefix: &str| digests.get(prefix).copied().unwrap_or_default();
+        Ok(PrefixDigests {
+            digest: get(prefix),
+            children: digest::child_prefixes(prefix)
+                .map(|child| {
+                    let digest = get(&child);
+                    (child, digest)
+                })
+                .filter(|(_, digest)| *digest != ContentDigest::default())
+                .collect(),
+        })
     }
 
     pub fn all_hashes_and_sizes(&self) -> Result<HashMap<EncryptedContentHash, u64>> {
@@ -106,7 +453,7 @@ impl Storage {
                     .to_str()
                     .ok_or_else(|| anyhow!("invalid file name: {:?}", path))?;
                 let hash = EncryptedContentHash::from_url_safe(name)?;
-                let size = meta.len();
+                let size = content_size(&path, meta.len())?;
                 out.insert(hash, size);
             }
         }
@@ -114,13 +461,21 @@ impl Storage {
     }
 }
 
+fn content_size(path: &Path, file_len: u64) -> Result<u64> {
+    if read_header(&mut File::open(path)?)?.is_some() {
+        Ok(file_len - ENCRYPTED_HEADER_LEN)
+    } else {
+        Ok(file_len)
+    }
+}
+
 #[test]
 fn basic() {
     use std::io::Read;
     use tempfile::TempDir;
 
     let dir = TempDir::new().unwrap();
-    let storage = Storage::new(dir.path().into()).unwrap();
+    let storage = Storage::new(dir.path().into(), None).unwrap();
     let hash = EncryptedContentHash::from_encrypted((0..64).collect());
     let mut file = storage.create_file().unwrap();
     writeln!(file, "ok").unwrap();
@@ -131,3 +486,113 @@ fn basic() {
     file2.read_to_string(&mut buf).unwrap();
     assert_eq!(buf, "ok\n");
 }
+
+#[test]
+fn pending_uploads() {
+    use tempfile::TempDir;
+
+    let dir = TempDir::new().unwrap();
+    let storage = Storage::new(dir.path().into(), None).unwrap();
+    let mut upload = storage.reserve(100);
+    assert_eq!(storage.pending_size.load(Ordering::Relaxed), 100);
+    upload.written(30);
+    assert_eq!(storage.pending_size.load(Ordering::Relaxed), 70);
+    let upload2 = storage.reserve(5);
+    drop(upload);
+    assert_eq!(storage.pending_size.load(Ordering::Relaxed), 5);
+    drop(upload2);
+    assert_eq!(storage.pending_size.load(Ordering::Relaxed), 0);
+}
+
+#[test]
+fn encrypted() {
+    use tempfile::TempDir;
+
+    let dir = TempDir::new().unwrap();
+    let plain_storage = Storage::new(dir.path().into(), None).unwrap();
+    let plain_hash = EncryptedContentHash::from_encrypted((0..64).collect());
+    let mut file = plain_storage.create_file().unwrap();
+    write!(file, "plain").unwrap();
+    plain_storage.commit_file(file, &plain_hash).unwrap();
+
+    let storage = Storage::new(dir.path().into(), Some(StorageKey::generate())).unwrap();
+    let hash = EncryptedContentHash::from_encrypted((1..65).collect());
+    let mut file = storage.create_file().unwrap();
+    write!(file, "secret content").unwrap();
+    storage.commit_file(file, &hash).unwrap();
+
+    let (_, path) = storage_paths(dir.path(), &hash);
+    let raw = fs_err::read(path).unwrap();
+    assert_eq!(raw.len() as u64, ENCRYPTED_HEADER_LEN + 14);
+    assert!(!raw.windows(6).any(|w| w == b"secret"));
+    assert_eq!(storage.file_size(&hash).unwrap(), 14);
+
+    let mut file = storage.open_file(&hash).unwrap();
+    assert_eq!(file.len(), 14);
+    let mut buf = String::new();
+    file.read_to_string(&mut buf).unwrap();
+    assert_eq!(buf, "secret content");
+    file.seek(SeekFrom::Start(7)).unwrap();
+    buf.clear();
+    file.read_to_string(&mut buf).unwrap();
+    assert_eq!(buf, "content");
+
+    // Files written without the key are still readable.
+    let mut file = storage.open_file(&plain_hash).unwrap();
+    buf.clear();
+    file.read_to_string(&mut buf).unwrap();
+    assert_eq!(buf, "plain");
+
+    assert!(plain_storage.open_file(&hash).is_err());
+    let sizes = storage.all_hashes_and_sizes().unwrap();
+    assert_eq!(sizes[&hash], 14);
+    assert_eq!(sizes[&plain_hash], 5);
+}
+
+#[test]
+fn digests() {
+    use tempfile::TempDir;
+
+    let dir = TempDir::new().unwrap();
+    let storage = Storage::new(dir.path().into(), None).unwrap();
+    let add = |bytes: Vec<u8>, content: &str| {
+        let hash = EncryptedContentHash::from_encrypted(bytes);
+        let mut file = storage.create_file().unwrap();
+        write!(file, "{content}").unwrap();
+        storage.commit_file(file, &hash).unwrap();
+        hash
+    };
+    let hash1 = add(vec![1; 64], "a");
+    let root = storage.content_digests("").unwrap();
+    assert_ne!(root.digest, ContentDigest::default());
+    assert_eq!(root.children.len(), 1);
+
+    let hash2 = add(vec![2; 64], "bb");
+    add(vec![2; 64], "ccc");
+    add(vec![3; 64], "dd");
+    storage
+        .remove_file(&EncryptedContentHash::from_encrypted(vec![3; 64]))
+        .unwrap();
+
+    // Incrementally updated digests must match the ones computed from scratch.
+    let fresh = Storage::new(dir.path().into(), None).unwrap();
+    for prefix in ["", &hash1.to_url_safe()[..1], &hash2.to_url_safe()[..2]] {
+        let digests = storage.content_digests(prefix).unwrap();
+        let fresh_digests = fresh.content_digests(prefix).unwrap();
+        assert_eq!(digests.digest, fresh_digests.digest);
+        assert_eq!(digests.children, fresh_digests.children);
+    }
+    let leaf = &hash2.to_url_safe()[..CONTENT_DIGEST_DEPTH];
+    let digests = storage.content_digests(leaf).unwrap();
+    assert_eq!(
+        digests.children.keys().collect::<Vec<_>>(),
+        [&hash2.to_url_safe()]
+    );
+    assert_eq!(
+        digests.digest,
+        storage
+            .content_digests(&hash2.to_url_safe()[..2])
+            .unwrap()
+            .children[leaf]
+    );
+}
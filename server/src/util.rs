@@ -1,11 +1,35 @@
-use anyhow::{bail, Result};
+use anyhow::{anyhow, bail, Result};
+use chrono::Utc;
+use futures_util::TryStreamExt;
+use rammingen_protocol::{
+    endpoints::PurgeSourceStats, DateTimeUtc, EncryptedContentHash, RecordTrigger,
+};
 use rand::{distributions::Alphanumeric, distributions::DistString, rngs::OsRng};
 use sqlx::{query, query_scalar, PgPool};
-use std::path::PathBuf;
+use std::{collections::HashMap, path::PathBuf, time::Duration};
 
-pub async fn sources(db: &PgPool) -> Result<Vec<String>> {
-    query_scalar!("SELECT name FROM sources ORDER BY name")
-        .fetch_all(db)
+use crate::{
+    handler::FromDb,
+    snapshot::{remove_files, unused_hashes},
+    storage::Storage,
+    Config,
+};
+
+pub struct SourceInfo {
+    pub name: String,
+    pub disabled_at: Option<DateTimeUtc>,
+    pub allow_metadata_only: bool,
+}
+
+pub async fn sources(db: &PgPool) -> Result<Vec<SourceInfo>> {
+    query!("SELECT name, disabled_at, allow_metadata_only FROM sources ORDER BY name")
+        .fetch(db)
+        .map_ok(|row| SourceInfo {
+            name: row.name,
+            disabled_at: row.disabled_at.map(|t| t.from_db()),
+            allow_metadata_only: row.allow_metadata_only,
+        })
+        .try_collect()
         .await
         .map_err(Into::into)
 }
@@ -37,10 +61,209 @@ pub async fn set_access_token(db: &PgPool, name: &str, access_token: &str) -> Re
     Ok(())
 }
 
+/// Disables or enables a source. Requests from a disabled source are rejected,
+/// but its data is kept.
+pub async fn set_source_disabled(db: &PgPool, name: &str, disabled: bool) -> Result<()> {
+    let rows = if disabled {
+        query!(
+            "UPDATE sources SET disabled_at = now() WHERE name = $1 AND disabled_at IS NULL",
+            name,
+        )
+        .execute(db)
+        .await?
+        .rows_affected()
+    } else {
+        query!(
+            "UPDATE sources SET disabled_at = NULL WHERE name = $1 AND disabled_at IS NOT NULL",
+            name,
+        )
+        .execute(db)
+        .await?
+        .rows_affected()
+    };
+    if rows == 0 {
+        let exists = query_scalar!("SELECT 1 FROM sources WHERE name = $1", name)
+            .fetch_optional(db)
+            .await?
+            .is_some();
+        if !exists {
+            bail!("source not found");
+        }
+        if disabled {
+            bail!("source is already disabled");
+        } else {
+            bail!("source is not disabled");
+        }
+    }
+    Ok(())
+}
+
+/// Allows or disallows the source to record versions from metadata-only mount points,
+/// without uploading their content.
+pub async fn set_source_allow_metadata_only(db: &PgPool, name: &str, allow: bool) -> Result<()> {
+    let rows = query!(
+        "UPDATE sources SET allow_metadata_only = $1 WHERE name = $2",
+        allow,
+        name,
+    )
+    .execute(db)
+    .await?
+    .rows_affected();
+    if rows == 0 {
+        bail!("source not found");
+    }
+    Ok(())
+}
+
+/// Removes all data recorded by a source.
+///
+/// The source must have been disabled for at least `grace_period`.
+/// Existing entries last recorded by the source are marked as deleted (except
+/// directories that still contain entries from other sources), all other versions
+/// recorded by the source are deleted, and content files that are no longer
+/// referenced are removed from storage.
+///
+/// Versions that belong to snapshots are kept, so that the archive can still be
+/// downloaded as it was at the time of each snapshot.
+///
+/// Files must be removed through the running server's storage, so that its content
+/// digests stay up to date.
+pub async fn purge_source(
+    storage: &Storage,
+    db: &PgPool,
+    name: &str,
+    grace_period: Duration,
+) -> Result<PurgeSourceStats> {
+    let mut tx = db.begin().await?;
+    let source = query!(
+        "SELECT id, disabled_at FROM sources WHERE name = $1 FOR UPDATE",
+        name
+    )
+    .fetch_optional(&mut tx)
+    .await?
+    .ok_or_else(|| anyhow!("source not found"))?;
+    let Some(disabled_at) = source.disabled_at else {
+        bail!("source must be disabled before it can be purged");
+    };
+    let purge_allowed_at = disabled_at.from_db() + chrono::Duration::from_std(grace_period)?;
+    if purge_allowed_at > Utc::now() {
+        bail!(
+            "source was disabled at {}, it can't be purged until {}",
+            disabled_at.from_db(),
+            purge_allowed_at
+        );
+    }
+
+    // Children are processed before their parents, so a directory that only
+    // contains entries of this source is deleted as well.
+    let entries = query!(
+        "SELECT id FROM entries WHERE source_id = $1 AND kind != 0 ORDER BY path DESC",
+        source.id
+    )
+    .fetch_all(&mut tx)
+    .await?;
+    let mut deleted_entries = 0;
+    for entry in entries {
+        let has_children = query_scalar!(
+            "SELECT 1 FROM entries WHERE kind != 0 AND parent_dir = $1 LIMIT 1",
+            entry.id
+        )
+        .fetch_optional(&mut tx)
+        .await?
+        .is_some();
+        if has_children {
+            continue;
+        }
+        query!(
+            "UPDATE entries
+            SET update_number = nextval('entry_update_numbers'),
+                recorded_at = now(),
+                record_trigger = $1,
+                kind = 0,
+                original_size = NULL,
+                encrypted_size = NULL,
+                modified_at = NULL,
+                content_hash = NULL,
+                unix_mode = NULL
+            WHERE id = $2",
+            RecordTrigger::Remove as i32,
+            entry.id,
+        )
+        .execute(&mut tx)
+        .await?;
+        deleted_entries += 1;
+    }
+
+    let mut hashes_to_check = HashMap::new();
+    let mut deleted_versions = 0;
+    {
+        // Current versions of entries and versions used by snapshots are kept.
+        let mut deleted_rows = query!(
+            "DELETE FROM entry_versions
+            WHERE source_id = $1 AND snapshot_id IS NULL AND NOT EXISTS (
+                SELECT 1 FROM entries WHERE entries.update_number = entry_versions.update_number
+            )
+            RETURNING content_hash, encrypted_size",
+            source.id
+        )
+        .fetch(&mut tx);
+        while let Some(row) = deleted_rows.try_next().await? {
+            deleted_versions += 1;
+            if let (Some(hash), Some(size)) = (row.content_hash, row.encrypted_size) {
+                hashes_to_check.insert(EncryptedContentHash::from_encrypted(hash), size);
+            }
+        }
+    }
+    let hashes_to_remove = unused_hashes(&mut tx, hashes_to_check).await?;
+    tx.commit().await?;
+
+    let removed_files = remove_files(storage, hashes_to_remove);
+    Ok(PurgeSourceStats {
+        deleted_entries,
+        deleted_versions,
+        removed_files: removed_files.try_into()?,
+    })
+}
+
+pub struct StorageStatus {
+    pub content_files: u64,
+    pub content_size: u64,
+    pub total_space: u64,
+    pub available_space: u64,
+}
+
+/// Collects statistics of the storage by scanning all content files.
+pub fn storage_status(config: &Config) -> Result<StorageStatus> {
+    let storage = Storage::new(
+        config.storage_path.clone(),
+        config.storage_encryption_key.clone(),
+    )?;
+    let hashes = storage.all_hashes_and_sizes()?;
+    Ok(StorageStatus {
+        content_files: hashes.len().try_into()?,
+        content_size: hashes.values().sum(),
+        total_space: fs2::total_space(&config.storage_path)?,
+        available_space: storage.available_space()?,
+    })
+}
+
+/// Checks that a connection to the database can be established.
+pub async fn check_database(url: &str) -> Result<()> {
+    let pool = PgPool::connect(url).await?;
+    query_scalar!("SELECT 1").fetch_one(&pool).await?;
+    pool.close().await;
+    Ok(())
+}
+
 pub fn generate_access_token() -> String {
     Alphanumeric.sample_string(&mut OsRng, 64)
 }
 
+/// Generates an ID for correlating a request with server log messages.
+pub fn generate_request_id() -> String {
+    Alphanumeric.sample_string(&mut OsRng, 12)
+}
+
 pub async fn migrate(db: &PgPool) -> Result<()> {
     sqlx::migrate!().run(db).await?;
     Ok(())
@@ -57,3 +280,29 @@ pub fn default_config_dir() -> Result<PathBuf> {
 pub fn default_config_dir() -> Result<PathBuf> {
     dirs::config_dir().ok_or_else(|| anyhow::anyhow!("failed to get config dir"))
 }
+
+/// Parses a version in `major.minor.patch` form. Pre-release and build metadata suffixes
+/// are ignored.
+pub fn parse_version(version: &str) -> Result<(u64, u64, u64)> {
+    let numbers = version
+        .split(['-', '+'])
+        .next()
+        .unwrap_or_default()
+        .split('.')
+        .map(|number| number.parse::<u64>())
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|err| anyhow!("invalid version {:?}: {}", version, err))?;
+    let [major, minor, patch] = numbers[..] else {
+        bail!("invalid version {:?}: expected major.minor.patch", version);
+    };
+    Ok((major, minor, patch))
+}
+
+#[test]
+fn versions() {
+    assert_eq!(parse_version("0.1.0").unwrap(), (0, 1, 0));
+    assert_eq!(parse_version("1.12.3-beta.1+abc").unwrap(), (1, 12, 3));
+    assert!(parse_version("1.2").is_err());
+    assert!(parse_version("1.2.x").is_err());
+    assert!(parse_version("0.9.10").unwrap() < parse_version("0.10.0").unwrap());
+}
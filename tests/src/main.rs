@@ -2,33 +2,56 @@ mod diff;
 mod shuffle;
 
 use std::{
+    collections::HashSet,
+    fs::Permissions,
+    future::Future,
+    io::Write,
     net::SocketAddr,
+    os::unix::{fs::PermissionsExt, net::UnixListener},
     path::{Path, PathBuf},
-    time::Duration,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
 };
 
-use anyhow::{bail, Result};
-use chrono::{DateTime, FixedOffset, Utc};
+use anyhow::{bail, ensure, Result};
+use chrono::{DateTime, FixedOffset, Local, Utc};
 use clap::{Parser, Subcommand};
 use diff::{diff, diff_ignored, is_leftover_dir_with_ignored_files};
 use fs_err::{
-    copy, create_dir, create_dir_all, read_dir, remove_dir_all, remove_file, rename, write,
+    copy, create_dir, create_dir_all, read_dir, read_to_string, remove_dir_all, remove_file,
+    rename, set_permissions, write,
 };
-use futures::future::pending;
+use futures::future::{join, join_all, pending};
 use portpicker::pick_unused_port;
 use rammingen::{
-    config::{EncryptionKey, MountPoint},
+    config::{EncryptionKey, MountPoint, TooRecentPolicy},
     path::SanitizedLocalPath,
     rules::Rule,
     setup_logger,
     term::clear_status,
 };
-use rammingen_protocol::{util::native_to_archive_relative_path, ArchivePath, DateTimeUtc};
-use rammingen_server::util::{add_source, migrate};
+use rammingen_protocol::{
+    endpoints::{
+        AddVersion, AddVersionIfUnchanged, AddVersions, ErrorResponse, GetEntries, GetServerTime,
+        LimitExceeded, RequestToResponse, MAX_GET_ENTRIES_PATHS,
+    },
+    util::native_to_archive_relative_path,
+    ArchivePath, DateTimeUtc, EncryptedArchivePath, EncryptedContentHash, EncryptedSize, EntryKind,
+    FileContent, RecordTrigger, CLIENT_VERSION_HEADER,
+};
+use rammingen_server::{
+    util::{add_source, migrate, set_source_allow_metadata_only, set_source_disabled},
+    StorageKey,
+};
 use rand::{seq::SliceRandom, thread_rng, Rng};
 use reqwest::Url;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use shuffle::{choose_path, random_content, random_name, shuffle};
-use sqlx::PgPool;
+use sqlx::{query_scalar, PgPool};
 use tempfile::TempDir;
 use tokio::time::{interval, sleep};
 use tracing::{debug, error, info};
@@ -69,6 +92,12 @@ pub struct Cli {
 pub enum Command {
     Random,
     Snapshot,
+    LargeDir,
+    KindChange,
+    Resync,
+    MetadataOnly,
+    MountOptions,
+    Protocol,
     ServerOnly,
 }
 
@@ -82,9 +111,10 @@ async fn try_main() -> Result<()> {
         "info,sqlx=warn,rammingen_server=debug".into(),
     )?;
 
+    let mut db_pool = None;
     let server_url = if let Some(database_url) = cli.database_url {
-        let db_pool = PgPool::connect(&database_url).await?;
-        migrate(&db_pool).await?;
+        let pool = db_pool.insert(PgPool::connect(&database_url).await?);
+        migrate(pool).await?;
 
         debug!("dir: {}", dir.display());
         let storage_path = dir.join("storage");
@@ -97,19 +127,44 @@ async fn try_main() -> Result<()> {
             SocketAddr::new("127.0.0.1".parse()?, port)
         };
         let server_config = rammingen_server::Config {
-            bind_addr,
+            bind_addr: bind_addr.into(),
             database_url: database_url.clone(),
+            read_database_url: None,
             storage_path,
+            storage_encryption_key: Some(StorageKey::generate()),
             log_file: None,
             log_filter: String::new(),
             retain_detailed_history_for: match &cli.command {
-                Command::Random | Command::ServerOnly => Duration::from_secs(3600),
+                Command::Random
+                | Command::LargeDir
+                | Command::KindChange
+                | Command::Resync
+                | Command::MetadataOnly
+                | Command::MountOptions
+                | Command::Protocol
+                | Command::ServerOnly => Duration::from_secs(3600),
                 Command::Snapshot => Duration::from_secs(10),
             },
             snapshot_interval: match &cli.command {
-                Command::Random | Command::ServerOnly => Duration::from_secs(3600),
+                Command::Random
+                | Command::LargeDir
+                | Command::KindChange
+                | Command::Resync
+                | Command::MetadataOnly
+                | Command::MountOptions
+                | Command::Protocol
+                | Command::ServerOnly => Duration::from_secs(3600),
                 Command::Snapshot => Duration::from_secs(5),
             },
+            max_versions_per_path: None,
+            admin_token: Some(ADMIN_TOKEN.into()),
+            limits: rammingen_server::Limits {
+                max_file_size: Some(MAX_FILE_SIZE),
+                max_entries: matches!(cli.command, Command::Protocol)
+                    .then_some(PROTOCOL_MAX_ENTRIES),
+                ..Default::default()
+            },
+            min_client_version: Some("0.1.0".into()),
         };
         write(
             &dir.join("rammingen-server.conf"),
@@ -117,7 +172,7 @@ async fn try_main() -> Result<()> {
         )?;
         for client_index in 0..3 {
             add_source(
-                &db_pool,
+                pool,
                 &format!("client{client_index}"),
                 &access_token(client_index),
             )
@@ -153,11 +208,41 @@ async fn try_main() -> Result<()> {
                 local_path: mount_dir.to_str().unwrap().parse()?,
                 archive_path: archive_mount_path.clone(),
                 exclude: vec![],
+                exclude_hidden: false,
+                priority: 0,
+                transactional: client_index == 1,
+                one_file_system: false,
+                exclude_filesystems: vec![],
+                download_trigger_filter: vec![],
+                metadata_only: false,
             }],
-            encryption_key: encryption_key.clone(),
+            encryption_key: Some(encryption_key.clone()),
+            encryption_passphrase: None,
+            encryption_salt: None,
             server_url: server_url.clone(),
             access_token: access_token(client_index),
+            admin_token: Some(ADMIN_TOKEN.into()),
+            archive_prefix: None,
+            mtime_precision: Default::default(),
+            stream_uploads: client_index == 1,
+            log_decrypted_paths: client_index == 0,
+            skip_unreadable: false,
+            max_errors: None,
+            max_error_rate: None,
+            on_start: None,
+            on_success: None,
+            on_failure: None,
+            compact_db_interval: (client_index == 2).then(|| Duration::from_secs(0)),
+            retain_deleted_files: None,
+            sources_cache_ttl: Duration::from_secs(3600),
+            too_recent_policy: Default::default(),
+            add_versions_batch_size: if client_index == 0 { 4 } else { 128 },
+            add_versions_batch_bytes: if client_index == 0 { 10_000 } else { 1 << 26 },
+            hash_threads: 4,
+            max_bytes_in_flight: (client_index == 1).then_some(100_000),
             local_db_path: Some(client_dir.join("db")),
+            compress_local_db: client_index == 2,
+            manifest_path: (client_index == 1).then(|| client_dir.join("manifest.json")),
             log_file: None,
             log_filter: String::new(),
         };
@@ -170,10 +255,17 @@ async fn try_main() -> Result<()> {
         clients,
         dir,
         archive_mount_path,
+        db_pool,
     };
     match cli.command {
         Command::Random => test_random(ctx).await,
         Command::Snapshot => test_snapshot(ctx).await,
+        Command::LargeDir => test_large_dir(ctx).await,
+        Command::KindChange => test_kind_change(ctx).await,
+        Command::Resync => test_resync(ctx).await,
+        Command::MetadataOnly => test_metadata_only(ctx).await,
+        Command::MountOptions => test_mount_options(ctx).await,
+        Command::Protocol => test_protocol(ctx).await,
         Command::ServerOnly => {
             info!("started server at {server_url}");
             pending().await
@@ -181,6 +273,15 @@ async fn try_main() -> Result<()> {
     }
 }
 
+/// Max file size allowed by the test server.
+const MAX_FILE_SIZE: u64 = 1 << 20;
+
+/// Max number of entries allowed by the test server in the protocol test.
+const PROTOCOL_MAX_ENTRIES: u64 = 20;
+
+/// Admin token of the test server.
+const ADMIN_TOKEN: &str = "admin_token";
+
 fn access_token(index: usize) -> String {
     format!("access_token{index}")
 }
@@ -189,6 +290,8 @@ struct Context {
     clients: Vec<ClientData>,
     dir: PathBuf,
     archive_mount_path: ArchivePath,
+    /// Only available if the server is started by the test.
+    db_pool: Option<PgPool>,
 }
 
 async fn test_random(ctx: Context) -> Result<()> {
@@ -267,9 +370,12 @@ async fn test_random(ctx: Context) -> Result<()> {
                     let archive_path =
                         archive_subpath(&ctx.archive_mount_path, &expected, &path_in_expected)?;
                     debug!("Checking upload ({archive_path})");
+                    let local_path = SanitizedLocalPath::new(&path_for_upload)?;
+                    // A dry run must not change anything.
                     client1
-                        .upload(SanitizedLocalPath::new(&path_for_upload)?, archive_path)
+                        .upload(local_path.clone(), archive_path.clone(), true)
                         .await?;
+                    client1.upload(local_path, archive_path, false).await?;
                 }
                 2 => {
                     // move path
@@ -286,7 +392,13 @@ async fn test_random(ctx: Context) -> Result<()> {
                     let new_archive_path =
                         archive_subpath(&ctx.archive_mount_path, &expected, &path2)?;
                     debug!("Checking mv ({archive_path} -> {new_archive_path})");
-                    client1.move_path(archive_path, new_archive_path).await?;
+                    // A dry run must not change anything, otherwise the move would fail.
+                    client1
+                        .move_path(archive_path.clone(), new_archive_path.clone(), true)
+                        .await?;
+                    client1
+                        .move_path(archive_path, new_archive_path, false)
+                        .await?;
                 }
                 3 => {
                     // remove path
@@ -389,6 +501,13 @@ async fn test_random(ctx: Context) -> Result<()> {
             &ctx.clients.choose(&mut thread_rng()).unwrap().mount_dir,
         )
         .await?;
+        check_checksum_download(
+            &ctx.dir,
+            &ctx.archive_mount_path,
+            &ctx.clients,
+            &ctx.clients.choose(&mut thread_rng()).unwrap().mount_dir,
+        )
+        .await?;
         if thread_rng().gen_bool(0.3) {
             if let Some(snapshot_time_value) = snapshot_time {
                 check_download(
@@ -512,6 +631,1228 @@ async fn test_snapshot(ctx: Context) -> Result<()> {
             Some(29),
         ]
     );
+    let (last_snapshot, last_time) = snapshots.last().unwrap();
+    check_restore_into(&ctx, index, last_snapshot, *last_time).await?;
+    check_rollback(&ctx, index, snapshots[0].1).await?;
+    check_pinned(&ctx, index).await?;
+    Ok(())
+}
+
+/// Checks that `download --version <time> --into <dir>` restores the version
+/// into a new directory and records it in a manifest.
+async fn check_restore_into(
+    ctx: &Context,
+    index: usize,
+    snapshot: &Path,
+    time: DateTimeUtc,
+) -> Result<()> {
+    let into = ctx.dir.join("restore_into");
+    // The version argument has a precision of one second, and nothing
+    // is changed after `time`.
+    let version = (time + chrono::Duration::seconds(1))
+        .with_timezone(&Local)
+        .format("%Y-%m-%d_%H:%M:%S")
+        .to_string();
+    let restore = || {
+        rammingen::cli::Cli::try_parse_from([
+            "rammingen",
+            "download",
+            &ctx.archive_mount_path.to_string(),
+            "--version",
+            &version,
+            "--into",
+            into.to_str().unwrap(),
+        ])
+    };
+    rammingen::run(restore()?, ctx.clients[index].config.clone()).await?;
+    let restored = into.join(ctx.archive_mount_path.last_name().unwrap());
+    diff(&restored, snapshot)?;
+    let manifest: serde_json::Value =
+        serde_json::from_str(&read_to_string(into.join("manifest.json"))?)?;
+    ensure!(
+        manifest["archive_path"] == serde_json::to_value(&ctx.archive_mount_path)?,
+        "unexpected archive path in manifest: {manifest}"
+    );
+    // The target directory must be empty.
+    ensure!(
+        rammingen::run(restore()?, ctx.clients[index].config.clone())
+            .await
+            .is_err(),
+        "restore into a non-empty directory should fail"
+    );
+    // `--into` replaces the local path.
+    ensure!(
+        rammingen::cli::Cli::try_parse_from([
+            "rammingen",
+            "download",
+            "ar:/my_files",
+            "local_path",
+            "--into",
+            into.to_str().unwrap(),
+        ])
+        .is_err(),
+        "--into should conflict with the local path"
+    );
+    Ok(())
+}
+
+/// Checks that all versions of a file in a pinned directory are kept
+/// while old versions of an unpinned file are removed by snapshots.
+async fn check_pinned(ctx: &Context, index: usize) -> Result<()> {
+    let pinned_archive_path: ArchivePath = "ar:/pinned".parse()?;
+    let unpinned_archive_path: ArchivePath = "ar:/unpinned".parse()?;
+    ctx.clients[index]
+        .pin(pinned_archive_path.clone(), true)
+        .await?;
+    let versioned_file_path = ctx.dir.join("versioned_file");
+    let mut times = Vec::new();
+    // Detailed history is kept for 10 seconds and snapshots are made every 5 seconds,
+    // so the first versions of the unpinned file are removed by the end of the loop.
+    let mut interval = interval(Duration::from_secs(1));
+    for i in 0..20 {
+        interval.tick().await;
+        write(&versioned_file_path, format!("version {i}"))?;
+        for archive_path in [&pinned_archive_path, &unpinned_archive_path] {
+            ctx.clients[index]
+                .upload(
+                    versioned_file_path.to_str().unwrap().parse()?,
+                    archive_path.join_one("file")?,
+                    false,
+                )
+                .await?;
+        }
+        times.push(Utc::now());
+    }
+
+    let download_path = ctx.dir.join("pinned_download");
+    for (i, time) in times.iter().enumerate() {
+        if download_path.exists() {
+            remove_file(&download_path)?;
+        }
+        ctx.clients[index]
+            .download(
+                pinned_archive_path.join_one("file")?,
+                download_path.to_str().unwrap().parse()?,
+                Some(*time),
+            )
+            .await?;
+        if read_to_string(&download_path)? != format!("version {i}") {
+            bail!("unexpected content of pinned file version {i}");
+        }
+    }
+    if download_path.exists() {
+        remove_file(&download_path)?;
+    }
+    if ctx.clients[index]
+        .download(
+            unpinned_archive_path.join_one("file")?,
+            download_path.to_str().unwrap().parse()?,
+            Some(times[0]),
+        )
+        .await
+        .is_ok()
+    {
+        bail!("old version of unpinned file should be removed");
+    }
+    info!("Pinned paths check passed");
+    Ok(())
+}
+
+/// Checks that rollback is refused if a snapshot was made after the target time
+/// and restores the exact previous state otherwise.
+async fn check_rollback(ctx: &Context, index: usize, old_time: DateTimeUtc) -> Result<()> {
+    let client = &ctx.clients[index];
+    if client
+        .rollback(ctx.archive_mount_path.clone(), old_time, true)
+        .await
+        .is_ok()
+    {
+        bail!("rollback to a time before a snapshot should fail");
+    }
+
+    let non_admin = ClientData {
+        mount_dir: client.mount_dir.clone(),
+        config: rammingen::config::Config {
+            admin_token: None,
+            ..client.config.clone()
+        },
+    };
+    if non_admin
+        .rollback(ctx.archive_mount_path.clone(), Utc::now(), false)
+        .await
+        .is_ok()
+    {
+        bail!("rollback without the admin token should fail");
+    }
+    let wrong_admin = ClientData {
+        mount_dir: client.mount_dir.clone(),
+        config: rammingen::config::Config {
+            admin_token: Some("wrong".into()),
+            ..client.config.clone()
+        },
+    };
+    if wrong_admin
+        .rollback(ctx.archive_mount_path.clone(), Utc::now(), false)
+        .await
+        .is_ok()
+    {
+        bail!("rollback with a wrong admin token should fail");
+    }
+
+    client.sync().await?;
+    let state_path = ctx.dir.join("before_rollback");
+    copy_dir_all(&client.mount_dir, &state_path)?;
+    let state_time = client.request(&GetServerTime).await?;
+    while diff(&state_path, &client.mount_dir).is_ok() {
+        shuffle(&client.mount_dir)?;
+    }
+    client.sync().await?;
+    client
+        .rollback(ctx.archive_mount_path.clone(), state_time, false)
+        .await?;
+    client.sync().await?;
+    if diff(&state_path, &client.mount_dir).is_ok() {
+        bail!("rollback without confirmation should not change anything");
+    }
+    client
+        .rollback(ctx.archive_mount_path.clone(), state_time, true)
+        .await?;
+    client.sync().await?;
+    diff(&state_path, &client.mount_dir)?;
+    client.check_integrity().await?;
+    info!("Rollback check passed");
+    Ok(())
+}
+
+/// Runs a client command and returns its output, taken from the test log.
+async fn command_output(
+    ctx: &Context,
+    command: impl Future<Output = Result<()>>,
+) -> Result<String> {
+    let log_path = ctx.dir.join("1.log");
+    let offset = usize::try_from(fs_err::metadata(&log_path)?.len())?;
+    command.await?;
+    Ok(String::from_utf8_lossy(&fs_err::read(&log_path)?[offset..]).into_owned())
+}
+
+/// Runs `ls` and returns names of the listed entries in the printed order,
+/// along with the whole output.
+async fn ls_output(
+    ctx: &Context,
+    client: &ClientData,
+    path: ArchivePath,
+    deleted: bool,
+) -> Result<(Vec<String>, String)> {
+    let output = command_output(ctx, client.ls(path, deleted)).await?;
+    let is_listed_name = |name: &str| {
+        ["file", "dir"].iter().any(|prefix| {
+            name.strip_prefix(prefix)
+                .is_some_and(|index| !index.is_empty() && index.bytes().all(|b| b.is_ascii_digit()))
+        })
+    };
+    let names = output
+        .lines()
+        .filter_map(|line| line.split_whitespace().last())
+        .filter(|name| is_listed_name(name))
+        .map(String::from)
+        .collect();
+    Ok((names, output))
+}
+
+/// Number of files in the directory created by `test_large_dir`.
+const LARGE_DIR_FILES: usize = 5_000;
+
+async fn test_large_dir(ctx: Context) -> Result<()> {
+    let large_dir = ctx.clients[0].mount_dir.join("large_dir");
+    create_dir(&large_dir)?;
+    for i in 0..LARGE_DIR_FILES {
+        write(
+            large_dir.join(format!("file{i}")),
+            format!("content {}", i % 10),
+        )?;
+    }
+    for i in 0..10 {
+        create_dir(large_dir.join(format!("dir{i}")))?;
+    }
+    ctx.clients[0].sync().await?;
+    // Make sure the listing also contains deleted entries.
+    for i in 0..100 {
+        remove_file(large_dir.join(format!("file{i}")))?;
+    }
+    ctx.clients[0].sync().await?;
+
+    let archive_path = ctx.archive_mount_path.join_one("large_dir")?;
+    let started = Instant::now();
+    let (listed, output) = ls_output(&ctx, &ctx.clients[1], archive_path.clone(), false).await?;
+    info!(
+        "Listed directory with {} entries in {:?}",
+        LARGE_DIR_FILES,
+        started.elapsed()
+    );
+    let dirs = (0..10).map(|i| format!("dir{i}")).collect::<HashSet<_>>();
+    let deleted = (0..100).map(|i| format!("file{i}")).collect::<HashSet<_>>();
+    let existing = (100..LARGE_DIR_FILES)
+        .map(|i| format!("file{i}"))
+        .chain(dirs.iter().cloned())
+        .collect::<HashSet<_>>();
+    ensure!(listed.len() == existing.len());
+    ensure!(listed.iter().cloned().collect::<HashSet<_>>() == existing);
+    // Directories are listed before files.
+    ensure!(listed[..dirs.len()].iter().all(|name| dirs.contains(name)));
+    ensure!(output.contains("100 deleted entries"));
+
+    let (listed, _) = ls_output(&ctx, &ctx.clients[1], archive_path, true).await?;
+    ensure!(listed.len() == existing.len() + deleted.len());
+    ensure!(listed[..dirs.len()].iter().all(|name| dirs.contains(name)));
+    // Deleted entries are listed after existing ones.
+    ensure!(listed[..existing.len()]
+        .iter()
+        .all(|name| existing.contains(name)));
+    ensure!(listed[existing.len()..]
+        .iter()
+        .all(|name| deleted.contains(name)));
+
+    // Only metadata is checked, so rewritten files are counted as changed.
+    for i in 100..103 {
+        write(large_dir.join(format!("file{i}")), "changed")?;
+    }
+    create_dir(large_dir.join("new_dir"))?;
+    let output = command_output(
+        &ctx,
+        ctx.clients[0].estimate(large_dir.to_str().unwrap().parse()?),
+    )
+    .await?;
+    let expected = format!(
+        "3 new or modified files ({} unchanged), 1 new directories",
+        LARGE_DIR_FILES - 103
+    );
+    ensure!(output.contains(&expected), "unexpected estimate: {output}");
+    ctx.clients[0].sync().await?;
+
+    ctx.clients[1].sync().await?;
+    diff(&ctx.clients[0].mount_dir, &ctx.clients[1].mount_dir)?;
+    info!("Large directory test passed");
+    Ok(())
+}
+
+/// Checks that paths that change from file to directory and vice versa
+/// are synced correctly in both directions.
+async fn test_kind_change(ctx: Context) -> Result<()> {
+    let [client0, client1, ..] = &ctx.clients[..] else {
+        bail!("not enough clients");
+    };
+    let a = |client: &ClientData| client.mount_dir.join("a");
+    let b = |client: &ClientData| client.mount_dir.join("b");
+
+    write(a(client0), "file a")?;
+    create_dir(b(client0))?;
+    write(b(client0).join("nested"), "nested in b")?;
+    client0.sync().await?;
+    client1.sync().await?;
+    diff(&client0.mount_dir, &client1.mount_dir)?;
+
+    // File -> directory and directory -> file, uploaded by client0.
+    remove_file(a(client0))?;
+    create_dir(a(client0))?;
+    write(a(client0).join("nested"), "nested in a")?;
+    remove_dir_all(b(client0))?;
+    write(b(client0), "file b")?;
+    client0.sync().await?;
+    client1.sync().await?;
+    diff(&client0.mount_dir, &client1.mount_dir)?;
+
+    // Back again, uploaded by client1.
+    remove_dir_all(a(client1))?;
+    write(a(client1), "file a again")?;
+    remove_file(b(client1))?;
+    create_dir(b(client1))?;
+    client1.sync().await?;
+    client0.sync().await?;
+    diff(&client0.mount_dir, &client1.mount_dir)?;
+
+    // Damaged files are restored by repair.
+    write(a(client0), "file A again")?;
+    remove_dir_all(b(client0))?;
+    client0
+        .repair(
+            ctx.archive_mount_path.clone(),
+            client0.mount_dir.to_str().unwrap().parse()?,
+        )
+        .await?;
+    diff(&client0.mount_dir, &client1.mount_dir)?;
+
+    info!("Kind change test passed");
+    Ok(())
+}
+
+/// Checks that `resync-mount` doesn't overwrite changes made by another client.
+async fn test_resync(ctx: Context) -> Result<()> {
+    let [client0, client1, ..] = &ctx.clients[..] else {
+        bail!("not enough clients");
+    };
+    let a = |client: &ClientData| client.mount_dir.join("a");
+
+    write(a(client0), "file a")?;
+    client0.sync().await?;
+    client1.sync().await?;
+    diff(&client0.mount_dir, &client1.mount_dir)?;
+
+    // Nothing to record if the server is up to date.
+    client0.resync_mount().await?;
+    client1.sync().await?;
+    diff(&client0.mount_dir, &client1.mount_dir)?;
+
+    // client0 hasn't synced the change made by client1, so the local database
+    // of client0 differs from the server, but the change must be kept.
+    write(a(client1), "changed by client1")?;
+    client1.sync().await?;
+    client0.resync_mount().await?;
+    client0.sync().await?;
+    ensure!(read_to_string(a(client0))? == "changed by client1");
+    diff(&client0.mount_dir, &client1.mount_dir)?;
+
+    check_no_local_db(&ctx).await?;
+    info!("Resync test passed");
+    Ok(())
+}
+
+/// Checks that commands run with `--no-local-db` don't create the local database,
+/// and that commands depending on the local state are rejected.
+async fn check_no_local_db(ctx: &Context) -> Result<()> {
+    let mut config = ctx.clients[0].config.clone();
+    let db_dir = ctx.dir.join("no_local_db_state");
+    config.local_db_path = Some(db_dir.join("rammingen.db"));
+    let run = |command| {
+        rammingen::run(
+            rammingen::cli::Cli {
+                no_local_db: true,
+                ..cli(command)
+            },
+            config.clone(),
+        )
+    };
+
+    let uploaded = ctx.dir.join("no_local_db_upload");
+    create_dir(&uploaded)?;
+    write(uploaded.join("file"), "uploaded without local db")?;
+    let archive_path: ArchivePath = "ar:/no_local_db".parse()?;
+    let upload = || rammingen::cli::Command::Upload {
+        local_path: uploaded.to_str().unwrap().parse().unwrap(),
+        archive_path: archive_path.clone(),
+        dry_run: false,
+        validate_server: false,
+    };
+    run(upload()).await?;
+    // Nothing is known about the previous run, so all files are checked again.
+    write(uploaded.join("file2"), "added later")?;
+    run(upload()).await?;
+
+    let downloaded = ctx.dir.join("no_local_db_download");
+    run(rammingen::cli::Command::Download {
+        archive_path: archive_path.clone(),
+        local_path: Some(downloaded.to_str().unwrap().parse()?),
+        version: None,
+        version_option: None,
+        into: None,
+        checksum: false,
+        metadata_only: false,
+        server_time: false,
+        repair: false,
+        pattern: None,
+    })
+    .await?;
+    diff(&uploaded, &downloaded)?;
+
+    ensure!(
+        run(rammingen::cli::Command::Sync { path: None })
+            .await
+            .is_err(),
+        "sync must be rejected with --no-local-db"
+    );
+    ensure!(
+        !db_dir.try_exists()?,
+        "local database must not be created with --no-local-db"
+    );
+    info!("No local database check passed");
+    Ok(())
+}
+
+/// Checks that metadata-only mount points can only be used by allowed sources,
+/// that their content is not reported as missing, and that it becomes regular
+/// content once it's uploaded by another mount point.
+async fn test_metadata_only(ctx: Context) -> Result<()> {
+    let Some(db_pool) = &ctx.db_pool else {
+        bail!("this test requires --database-url");
+    };
+    let client0 = &ctx.clients[0];
+    let metadata_only_dir = ctx.dir.join("metadata_only");
+    create_dir(&metadata_only_dir)?;
+    // Shares the local database with client0.
+    let mut config = client0.config.clone();
+    config.mount_points.push(MountPoint {
+        local_path: metadata_only_dir.to_str().unwrap().parse()?,
+        archive_path: "ar:/metadata_only".parse()?,
+        metadata_only: true,
+        ..config.mount_points[0].clone()
+    });
+    let client = ClientData {
+        config,
+        mount_dir: client0.mount_dir.clone(),
+    };
+    let num_metadata_only_hashes = || async {
+        query_scalar::<_, i64>("SELECT count(*) FROM metadata_only_hashes")
+            .fetch_one(db_pool)
+            .await
+    };
+
+    write(metadata_only_dir.join("large"), "large file")?;
+    ensure!(
+        client.sync().await.is_err(),
+        "metadata-only upload must be rejected for a source that is not allowed"
+    );
+    set_source_allow_metadata_only(db_pool, "client0", true).await?;
+    client.sync().await?;
+    ensure!(num_metadata_only_hashes().await? == 1);
+    client.check_integrity().await?;
+
+    write(client.mount_dir.join("copy"), "large file")?;
+    client.sync().await?;
+    ensure!(num_metadata_only_hashes().await? == 0);
+    client.check_integrity().await?;
+    ctx.clients[1].sync().await?;
+    diff(&client0.mount_dir, &ctx.clients[1].mount_dir)?;
+
+    check_purge_source(&ctx, db_pool).await?;
+    info!("Metadata-only test passed");
+    Ok(())
+}
+
+/// Checks that a disabled source can be purged through the server, and that
+/// the server's content digests stay valid after that.
+async fn check_purge_source(ctx: &Context, db_pool: &PgPool) -> Result<()> {
+    let client0 = &ctx.clients[0];
+    let client2 = &ctx.clients[2];
+    let dir = client2.mount_dir.join("purged");
+    create_dir(&dir)?;
+    write(dir.join("file"), "old content")?;
+    client2.sync().await?;
+    write(dir.join("file"), "new content")?;
+    client2.sync().await?;
+    client0.sync().await?;
+    ensure!(client0.mount_dir.join("purged/file").exists());
+    client0.check_integrity().await?;
+
+    let purge = |grace_period| async move {
+        rammingen::run(
+            cli(rammingen::cli::Command::PurgeSource {
+                name: "client2".into(),
+                grace_period,
+            }),
+            client0.config.clone(),
+        )
+        .await
+    };
+    ensure!(
+        purge(Duration::ZERO).await.is_err(),
+        "purging an enabled source must fail"
+    );
+    set_source_disabled(db_pool, "client2", true).await?;
+    ensure!(
+        purge(Duration::from_secs(3600)).await.is_err(),
+        "purging must fail until the grace period has passed"
+    );
+    purge(Duration::ZERO).await?;
+
+    client0.sync().await?;
+    ensure!(!client0.mount_dir.join("purged").exists());
+    client0.check_integrity().await?;
+    ctx.clients[1].sync().await?;
+    diff(&client0.mount_dir, &ctx.clients[1].mount_dir)?;
+    Ok(())
+}
+
+/// Checks behavior of mount point and upload options.
+async fn test_mount_options(ctx: Context) -> Result<()> {
+    check_download_trigger_filter(&ctx).await?;
+    check_max_file_size(&ctx).await?;
+    check_skip_unreadable(&ctx).await?;
+    check_too_recent_policy(&ctx).await?;
+    check_rejected_batch(&ctx).await?;
+    check_mount_priority(&ctx).await?;
+    check_sync_path(&ctx).await?;
+    check_manifest(&ctx).await?;
+    check_retain_deleted_files(&ctx).await?;
+    info!("Mount options test passed");
+    Ok(())
+}
+
+/// Checks that files deleted in the archive are retained with `retain_deleted_files`
+/// and reused instead of being downloaded again when they are restored.
+async fn check_retain_deleted_files(ctx: &Context) -> Result<()> {
+    let [client0, client1, ..] = &ctx.clients[..] else {
+        bail!("not enough clients");
+    };
+    let mut config = client1.config.clone();
+    config.retain_deleted_files = Some(Duration::from_secs(3600));
+    let retaining = ClientData {
+        config,
+        mount_dir: client1.mount_dir.clone(),
+    };
+    let deleted_dir = ctx.dir.join("client1/db.deleted");
+    let num_retained = || -> Result<usize> { Ok(read_dir(&deleted_dir)?.count()) };
+
+    write(client0.mount_dir.join("retained"), "retained content")?;
+    client0.sync().await?;
+    retaining.sync().await?;
+    remove_file(client0.mount_dir.join("retained"))?;
+    client0.sync().await?;
+    retaining.sync().await?;
+    ensure!(!retaining.mount_dir.join("retained").try_exists()?);
+    ensure!(num_retained()? == 1);
+
+    write(client0.mount_dir.join("retained"), "retained content")?;
+    client0.sync().await?;
+    let output = command_output(ctx, retaining.sync()).await?;
+    ensure!(
+        output.contains("Reused retained deleted file"),
+        "restored file was downloaded again: {output}"
+    );
+    ensure!(read_to_string(retaining.mount_dir.join("retained"))? == "retained content");
+    ensure!(num_retained()? == 0);
+
+    // Retained files are removed when retention is disabled.
+    remove_file(client0.mount_dir.join("retained"))?;
+    client0.sync().await?;
+    retaining.sync().await?;
+    ensure!(num_retained()? == 1);
+    client1.sync().await?;
+    ensure!(num_retained()? == 0);
+    info!("Retain deleted files check passed");
+    Ok(())
+}
+
+/// Checks that `download_trigger_filter` skips filtered entries
+/// but still downloads their unfiltered children.
+async fn check_download_trigger_filter(ctx: &Context) -> Result<()> {
+    let [client0, client1, ..] = &ctx.clients[..] else {
+        bail!("not enough clients");
+    };
+    let mut config = client1.config.clone();
+    config.mount_points[0].download_trigger_filter = vec![RecordTrigger::Move];
+    let filtered = ClientData {
+        config,
+        mount_dir: client1.mount_dir.clone(),
+    };
+
+    create_dir(client0.mount_dir.join("a"))?;
+    write(client0.mount_dir.join("a/f"), "moved")?;
+    client0.sync().await?;
+    client0
+        .move_path(
+            ctx.archive_mount_path.join_one("a")?,
+            ctx.archive_mount_path.join_one("moved")?,
+            false,
+        )
+        .await?;
+    client0.sync().await?;
+    // Recorded by sync inside a directory recorded by move.
+    write(client0.mount_dir.join("moved/g"), "synced")?;
+    client0.sync().await?;
+
+    filtered.sync().await?;
+    let dir = filtered.mount_dir.join("moved");
+    ensure!(read_to_string(dir.join("g"))? == "synced");
+    ensure!(!dir.join("f").try_exists()?);
+    // Nothing changes on the next sync.
+    filtered.sync().await?;
+    ensure!(!dir.join("f").try_exists()?);
+    client0.sync().await?;
+    ensure!(read_to_string(client0.mount_dir.join("moved/f"))? == "moved");
+    info!("Download trigger filter check passed");
+    Ok(())
+}
+
+/// Checks that files exceeding the server's max file size are skipped
+/// and reported without failing the sync, with and without streaming uploads.
+async fn check_max_file_size(ctx: &Context) -> Result<()> {
+    let [client0, client1, ..] = &ctx.clients[..] else {
+        bail!("not enough clients");
+    };
+    let oversized_count = ctx.dir.join("oversized_count");
+    for stream_uploads in [false, true] {
+        let mut config = client0.config.clone();
+        config.stream_uploads = stream_uploads;
+        config.on_success = Some(vec![
+            "sh".into(),
+            "-c".into(),
+            format!(
+                "echo $RAMMINGEN_OVERSIZED > '{}'",
+                oversized_count.display()
+            ),
+        ]);
+        let client = ClientData {
+            config,
+            mount_dir: client0.mount_dir.clone(),
+        };
+
+        let mut content = vec![0u8; 2 * MAX_FILE_SIZE as usize];
+        thread_rng().fill(&mut content[..]);
+        write(client.mount_dir.join("oversized"), content)?;
+        // Fits into the limit after compression.
+        write(
+            client.mount_dir.join("compressible"),
+            vec![0u8; 2 * MAX_FILE_SIZE as usize],
+        )?;
+        write(client.mount_dir.join("small"), "small")?;
+        client.sync().await?;
+        ensure!(read_to_string(&oversized_count)?.trim() == "1");
+
+        client1.sync().await?;
+        ensure!(read_to_string(client1.mount_dir.join("small"))? == "small");
+        ensure!(client1.mount_dir.join("compressible").try_exists()?);
+        ensure!(!client1.mount_dir.join("oversized").try_exists()?);
+        for name in ["oversized", "compressible", "small"] {
+            remove_file(client.mount_dir.join(name))?;
+        }
+        client.sync().await?;
+        ensure!(read_to_string(&oversized_count)?.trim() == "0");
+        client1.sync().await?;
+    }
+    info!("Max file size check passed");
+    Ok(())
+}
+
+/// Checks that unreadable paths fail the sync unless `skip_unreadable` is set,
+/// in which case they're skipped and reported.
+async fn check_skip_unreadable(ctx: &Context) -> Result<()> {
+    let [client0, _, client2, ..] = &ctx.clients[..] else {
+        bail!("not enough clients");
+    };
+    let unreadable_count = ctx.dir.join("unreadable_count");
+    let mut config = client2.config.clone();
+    config.skip_unreadable = true;
+    config.on_success = Some(vec![
+        "sh".into(),
+        "-c".into(),
+        format!(
+            "echo $RAMMINGEN_UNREADABLE > '{}'",
+            unreadable_count.display()
+        ),
+    ]);
+    let skipping = ClientData {
+        config,
+        mount_dir: client2.mount_dir.clone(),
+    };
+
+    let nested = client2.mount_dir.join("unreadable_nested");
+    let locked = nested.join("locked");
+    create_dir_all(&locked)?;
+    write(locked.join("file"), "locked")?;
+    skipping.sync().await?;
+
+    set_permissions(&locked, Permissions::from_mode(0o000))?;
+    // Permissions are not enforced if the test runs as root.
+    let permissions_enforced = read_dir(&locked).is_err();
+    // Opening a socket as a file fails even if permissions are not enforced.
+    let socket_path = nested.join("socket");
+    let listener = UnixListener::bind(&socket_path)?;
+    write(nested.join("readable"), "readable")?;
+    ensure!(
+        client2.sync().await.is_err(),
+        "sync must fail on unreadable paths without skip_unreadable"
+    );
+    skipping.sync().await?;
+    let expected_count = 1 + usize::from(permissions_enforced);
+    ensure!(read_to_string(&unreadable_count)?.trim() == expected_count.to_string());
+    client0.sync().await?;
+    let client0_nested = client0.mount_dir.join("unreadable_nested");
+    ensure!(read_to_string(client0_nested.join("readable"))? == "readable");
+    ensure!(!client0_nested.join("socket").try_exists()?);
+    // Content of the skipped directory is kept on the server.
+    ensure!(read_to_string(client0_nested.join("locked/file"))? == "locked");
+
+    set_permissions(&locked, Permissions::from_mode(0o755))?;
+    drop(listener);
+    remove_file(&socket_path)?;
+    skipping.sync().await?;
+    ensure!(read_to_string(&unreadable_count)?.trim() == "0");
+    info!("Skip unreadable check passed");
+    Ok(())
+}
+
+/// Checks how files that keep being modified during upload are handled
+/// with each `too_recent_policy`.
+async fn check_too_recent_policy(ctx: &Context) -> Result<()> {
+    let [client0, _, client2, ..] = &ctx.clients[..] else {
+        bail!("not enough clients");
+    };
+    let busy_count = ctx.dir.join("busy_count");
+    let client = |policy| {
+        let mut config = client2.config.clone();
+        config.too_recent_policy = policy;
+        config.on_success = Some(vec![
+            "sh".into(),
+            "-c".into(),
+            format!("echo $RAMMINGEN_BUSY > '{}'", busy_count.display()),
+        ]);
+        ClientData {
+            config,
+            mount_dir: client2.mount_dir.clone(),
+        }
+    };
+    let path = client2.mount_dir.join("busy");
+    let stop = Arc::new(AtomicBool::new(false));
+    let writer = std::thread::spawn({
+        let path = path.clone();
+        let stop = stop.clone();
+        move || {
+            while !stop.load(Ordering::Relaxed) {
+                write(&path, "busy")?;
+                std::thread::sleep(Duration::from_millis(10));
+            }
+            anyhow::Ok(())
+        }
+    });
+    write(client2.mount_dir.join("not_busy"), "not busy")?;
+
+    ensure!(
+        client(TooRecentPolicy::Wait).sync().await.is_err(),
+        "sync must fail on a busy file with the wait policy"
+    );
+    client(TooRecentPolicy::Skip).sync().await?;
+    ensure!(read_to_string(&busy_count)?.trim() == "1");
+    client0.sync().await?;
+    ensure!(read_to_string(client0.mount_dir.join("not_busy"))? == "not busy");
+    ensure!(!client0.mount_dir.join("busy").try_exists()?);
+
+    client(TooRecentPolicy::Snapshot).sync().await?;
+    ensure!(read_to_string(&busy_count)?.trim() == "0");
+    stop.store(true, Ordering::Relaxed);
+    writer.join().expect("writer panicked")?;
+    client0.sync().await?;
+    ensure!(read_to_string(client0.mount_dir.join("busy"))? == "busy");
+    info!("Too recent policy check passed");
+    Ok(())
+}
+
+/// Checks that changes of a batch rejected by the server are detected again
+/// on the next sync.
+async fn check_rejected_batch(ctx: &Context) -> Result<()> {
+    let client0 = &ctx.clients[0];
+    let client2 = &ctx.clients[2];
+    let metadata_only_dir = ctx.dir.join("rejected_metadata_only");
+    create_dir(&metadata_only_dir)?;
+    write(metadata_only_dir.join("large"), "large file")?;
+    // Metadata-only versions are rejected because the source is not allowed to record them.
+    let mut config = client2.config.clone();
+    config.mount_points.push(MountPoint {
+        local_path: metadata_only_dir.to_str().unwrap().parse()?,
+        archive_path: "ar:/rejected_metadata_only".parse()?,
+        metadata_only: true,
+        ..config.mount_points[0].clone()
+    });
+    let rejecting = ClientData {
+        config,
+        mount_dir: client2.mount_dir.clone(),
+    };
+
+    client2.sync().await?;
+    write(client2.mount_dir.join("in_rejected_batch"), "rejected")?;
+    ensure!(
+        rejecting.sync().await.is_err(),
+        "metadata-only upload must be rejected for a source that is not allowed"
+    );
+    client0.sync().await?;
+    ensure!(!client0.mount_dir.join("in_rejected_batch").try_exists()?);
+
+    client2.sync().await?;
+    client0.sync().await?;
+    ensure!(read_to_string(client0.mount_dir.join("in_rejected_batch"))? == "rejected");
+    info!("Rejected batch check passed");
+    Ok(())
+}
+
+/// Checks that the part of a mount point overridden by a mount point with a higher
+/// priority is not uploaded and receives content of the other mount point.
+async fn check_mount_priority(ctx: &Context) -> Result<()> {
+    let [client0, client1, ..] = &ctx.clients[..] else {
+        bail!("not enough clients");
+    };
+    let override_dir = ctx.dir.join("override");
+    create_dir(&override_dir)?;
+    let mut config = client0.config.clone();
+    config.mount_points.push(MountPoint {
+        local_path: override_dir.to_str().unwrap().parse()?,
+        archive_path: ctx.archive_mount_path.join_one("sub")?,
+        priority: 1,
+        ..config.mount_points[0].clone()
+    });
+    let client = ClientData {
+        config,
+        mount_dir: client0.mount_dir.clone(),
+    };
+    let overridden = client.mount_dir.join("sub");
+
+    write(override_dir.join("x"), "from override")?;
+    client.sync().await?;
+    ensure!(read_to_string(overridden.join("x"))? == "from override");
+
+    // Local changes in the overridden part are not uploaded.
+    write(overridden.join("x"), "local change")?;
+    write(overridden.join("y"), "local file")?;
+    client.sync().await?;
+    client1.sync().await?;
+    ensure!(read_to_string(client1.mount_dir.join("sub/x"))? == "from override");
+    ensure!(!client1.mount_dir.join("sub/y").try_exists()?);
+
+    // Changes in the mount point with the higher priority are uploaded
+    // and replace local files of the overridden part.
+    write(override_dir.join("x"), "changed in override")?;
+    client.sync().await?;
+    ensure!(read_to_string(overridden.join("x"))? == "changed in override");
+    client1.sync().await?;
+    ensure!(read_to_string(client1.mount_dir.join("sub/x"))? == "changed in override");
+
+    remove_file(overridden.join("y"))?;
+    info!("Mount priority check passed");
+    Ok(())
+}
+
+/// Checks that `sync --path` only uploads changes and deletions inside the path,
+/// including mount points nested in it, and leaves other mount points alone.
+async fn check_sync_path(ctx: &Context) -> Result<()> {
+    let [client0, client1, ..] = &ctx.clients[..] else {
+        bail!("not enough clients");
+    };
+    let scope = client0.mount_dir.join("scope");
+    let nested = scope.join("nested");
+    let other = ctx.dir.join("other_mount");
+    create_dir_all(&nested)?;
+    create_dir(&other)?;
+    let mut config = client0.config.clone();
+    config.mount_points[0]
+        .exclude
+        .push(Rule::PathEquals(nested.to_str().unwrap().parse()?));
+    for (local_path, archive_path) in [(&nested, "ar:/nested_mount"), (&other, "ar:/other_mount")] {
+        config.mount_points.push(MountPoint {
+            local_path: local_path.to_str().unwrap().parse()?,
+            archive_path: archive_path.parse()?,
+            exclude: vec![],
+            ..config.mount_points[0].clone()
+        });
+    }
+    let client = ClientData {
+        config,
+        mount_dir: client0.mount_dir.clone(),
+    };
+
+    write(scope.join("removed"), "removed")?;
+    write(client.mount_dir.join("outside"), "outside")?;
+    write(nested.join("removed"), "removed")?;
+    write(other.join("old"), "old")?;
+    client.sync().await?;
+
+    write(scope.join("added"), "added")?;
+    remove_file(scope.join("removed"))?;
+    write(client.mount_dir.join("added_outside"), "added")?;
+    remove_file(client.mount_dir.join("outside"))?;
+    write(nested.join("added"), "added")?;
+    remove_file(nested.join("removed"))?;
+    write(other.join("new"), "new")?;
+    client.sync_path(scope.to_str().unwrap().parse()?).await?;
+
+    client1.sync().await?;
+    let synced = client1.mount_dir.join("scope");
+    ensure!(read_to_string(synced.join("added"))? == "added");
+    ensure!(!synced.join("removed").try_exists()?);
+    ensure!(!synced.join("nested").try_exists()?);
+    ensure!(read_to_string(client1.mount_dir.join("outside"))? == "outside");
+    ensure!(!client1.mount_dir.join("added_outside").try_exists()?);
+
+    let downloaded = ctx.dir.join("sync_path_download");
+    client1
+        .download(
+            "ar:/nested_mount".parse()?,
+            downloaded.to_str().unwrap().parse()?,
+            None,
+        )
+        .await?;
+    ensure!(read_to_string(downloaded.join("added"))? == "added");
+    ensure!(!downloaded.join("removed").try_exists()?);
+    remove_dir_all(&downloaded)?;
+    client1
+        .download(
+            "ar:/other_mount".parse()?,
+            downloaded.to_str().unwrap().parse()?,
+            None,
+        )
+        .await?;
+    ensure!(read_to_string(downloaded.join("old"))? == "old");
+    ensure!(!downloaded.join("new").try_exists()?);
+
+    client.sync().await?;
+    client1.sync().await?;
+    ensure!(!client1.mount_dir.join("outside").try_exists()?);
+    ensure!(read_to_string(client1.mount_dir.join("added_outside"))? == "added");
+    info!("Sync path check passed");
+    Ok(())
+}
+
+/// A file listed in the manifest written after sync.
+#[derive(Debug, Deserialize)]
+struct ManifestFile {
+    local_path: PathBuf,
+    archive_path: ArchivePath,
+    hash: String,
+    size: u64,
+}
+
+#[derive(Debug, Deserialize)]
+struct SyncManifest {
+    files: Vec<ManifestFile>,
+}
+
+/// Checks that the manifest lists all synced files with their archive paths,
+/// choosing the mount point with the highest priority for nested mount points.
+async fn check_manifest(ctx: &Context) -> Result<()> {
+    let client1 = &ctx.clients[1];
+    let Some(manifest_path) = &client1.config.manifest_path else {
+        bail!("client1 must have manifest_path");
+    };
+    let nested = client1.mount_dir.join("manifest_nested");
+    create_dir(&nested)?;
+    let mut config = client1.config.clone();
+    let nested_mount_point = MountPoint {
+        local_path: nested.to_str().unwrap().parse()?,
+        archive_path: "ar:/manifest_nested".parse()?,
+        priority: 1,
+        ..config.mount_points[0].clone()
+    };
+    config.mount_points.push(nested_mount_point);
+    let client = ClientData {
+        config,
+        mount_dir: client1.mount_dir.clone(),
+    };
+    write(nested.join("file"), "nested file")?;
+    client.sync().await?;
+
+    let manifest: SyncManifest = serde_json::from_str(&read_to_string(manifest_path)?)?;
+    let mut expected = Vec::new();
+    find_files(&client.mount_dir, &mut expected)?;
+    let mut listed = manifest
+        .files
+        .iter()
+        .map(|file| file.local_path.clone())
+        .collect::<Vec<_>>();
+    listed.sort();
+    expected.sort();
+    ensure!(
+        listed == expected,
+        "manifest lists {listed:?}, expected {expected:?}"
+    );
+    for file in &manifest.files {
+        let content = fs_err::read(&file.local_path)?;
+        ensure!(file.size == content.len() as u64);
+        ensure!(file.hash == format!("{:x}", Sha256::digest(&content)));
+        let relative = file.local_path.strip_prefix(&client.mount_dir)?;
+        let expected_archive_path = if let Ok(relative) = file.local_path.strip_prefix(&nested) {
+            format!("ar:/manifest_nested/{}", relative.to_str().unwrap()).parse()?
+        } else {
+            ctx.archive_mount_path
+                .join_multiple(&native_to_archive_relative_path(relative)?)?
+        };
+        ensure!(
+            file.archive_path == expected_archive_path,
+            "unexpected archive path for {:?}: {}",
+            file.local_path,
+            file.archive_path
+        );
+    }
+    info!("Manifest check passed");
+    Ok(())
+}
+
+/// Adds paths of all files in `dir` to `output`.
+fn find_files(dir: &Path, output: &mut Vec<PathBuf>) -> Result<()> {
+    for entry in read_dir(dir)? {
+        let path = entry?.path();
+        if fs_err::symlink_metadata(&path)?.is_dir() {
+            find_files(&path, output)?;
+        } else {
+            output.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// Checks server behavior that can't be triggered by client commands
+/// by sending requests directly.
+async fn test_protocol(ctx: Context) -> Result<()> {
+    check_concurrent_create(&ctx).await?;
+    check_get_entries(&ctx).await?;
+    check_server_limits(&ctx).await?;
+    info!("Protocol test passed");
+    Ok(())
+}
+
+/// Checks that `GetEntries` returns entries in the requested order, including
+/// missing and duplicated paths.
+async fn check_get_entries(ctx: &Context) -> Result<()> {
+    let client = &ctx.clients[0];
+    let path = |path| EncryptedArchivePath::from_encrypted_without_prefix(path);
+    client
+        .request(&AddVersionIfUnchanged {
+            expected_update_number: None,
+            version: AddVersion {
+                path: path("/entries")?,
+                record_trigger: RecordTrigger::Upload,
+                kind: Some(EntryKind::Directory),
+                content: None,
+            },
+        })
+        .await?;
+    let entries = client
+        .request(&GetEntries(vec![
+            path("/entries")?,
+            path("/missing")?,
+            path("/")?,
+            path("/entries")?,
+        ]))
+        .await?;
+    let kinds = entries
+        .iter()
+        .map(|entry| {
+            entry
+                .as_ref()
+                .map(|entry| (entry.data.path.to_str_without_prefix(), entry.data.kind))
+        })
+        .collect::<Vec<_>>();
+    ensure!(
+        kinds
+            == [
+                Some(("/entries", Some(EntryKind::Directory))),
+                None,
+                Some(("/", Some(EntryKind::Directory))),
+                Some(("/entries", Some(EntryKind::Directory))),
+            ],
+        "unexpected entries: {kinds:?}"
+    );
+
+    let too_many = vec![path("/entries")?; MAX_GET_ENTRIES_PATHS + 1];
+    ensure!(
+        client.request(&GetEntries(too_many)).await.is_err(),
+        "expected too many paths to be rejected"
+    );
+    Ok(())
+}
+
+/// Checks that versions exceeding server limits are rejected with errors that identify
+/// the limit, and that implicitly created parent directories count towards `max_entries`.
+async fn check_server_limits(ctx: &Context) -> Result<()> {
+    let Some(db_pool) = &ctx.db_pool else {
+        bail!("this test requires --database-url");
+    };
+    let client = &ctx.clients[0];
+    let limit_exceeded = |result: Result<Vec<_>>| {
+        result
+            .err()
+            .and_then(|err| err.downcast_ref::<LimitExceeded>().cloned())
+    };
+
+    let large_file = AddVersion {
+        path: EncryptedArchivePath::from_encrypted_without_prefix("/large")?,
+        record_trigger: RecordTrigger::Upload,
+        kind: Some(EntryKind::File),
+        content: Some(FileContent {
+            modified_at: Utc::now(),
+            original_size: EncryptedSize::from_encrypted(vec![0; 16]),
+            encrypted_size: MAX_FILE_SIZE + 1,
+            hash: EncryptedContentHash::from_encrypted(vec![0; 32]),
+            unix_mode: None,
+        }),
+    };
+    let result = client
+        .request(&AddVersions {
+            versions: vec![large_file],
+            dry_run: true,
+            metadata_only: false,
+        })
+        .await;
+    ensure!(
+        limit_exceeded(result)
+            == Some(LimitExceeded::FileSize {
+                size: MAX_FILE_SIZE + 1,
+                max_file_size: MAX_FILE_SIZE,
+            })
+    );
+
+    let num_entries = query_scalar::<_, i64>("SELECT count(*) FROM entries WHERE kind != 0")
+        .fetch_one(db_pool)
+        .await?;
+    let remaining = PROTOCOL_MAX_ENTRIES - u64::try_from(num_entries)?;
+    // A single version that creates `depth` entries, including its parent directories.
+    let nested_dir = |depth: u64| -> Result<AddVersions> {
+        let path = (0..depth).map(|i| format!("/limit{i}")).collect::<String>();
+        Ok(AddVersions {
+            versions: vec![AddVersion {
+                path: EncryptedArchivePath::from_encrypted_without_prefix(&path)?,
+                record_trigger: RecordTrigger::Upload,
+                kind: Some(EntryKind::Directory),
+                content: None,
+            }],
+            dry_run: false,
+            metadata_only: false,
+        })
+    };
+    let result = client.request(&nested_dir(remaining + 1)?).await;
+    ensure!(
+        limit_exceeded(result)
+            == Some(LimitExceeded::Entries {
+                max_entries: PROTOCOL_MAX_ENTRIES,
+            })
+    );
+    client.request(&nested_dir(remaining)?).await?;
+    Ok(())
+}
+
+/// Checks that only one of concurrent requests creating the same path succeeds.
+async fn check_concurrent_create(ctx: &Context) -> Result<()> {
+    let Some(db_pool) = &ctx.db_pool else {
+        bail!("this test requires --database-url");
+    };
+    let path = EncryptedArchivePath::from_encrypted_without_prefix("/created")?;
+    let request = AddVersionIfUnchanged {
+        expected_update_number: None,
+        version: AddVersion {
+            path: path.clone(),
+            record_trigger: RecordTrigger::Upload,
+            kind: Some(EntryKind::Directory),
+            content: None,
+        },
+    };
+    // Blocks writes to entries but not reads, so that all requests check
+    // that the entry doesn't exist before any of them creates it.
+    let mut lock = db_pool.begin().await?;
+    sqlx::query("LOCK TABLE entries IN SHARE MODE")
+        .execute(&mut lock)
+        .await?;
+    let requests = join_all(ctx.clients.iter().map(|client| client.request(&request)));
+    let unlock = async {
+        sleep(Duration::from_millis(500)).await;
+        lock.commit().await
+    };
+    let (results, unlock_result) = join(requests, unlock).await;
+    unlock_result?;
+    let num_succeeded = results.iter().filter(|result| result.is_ok()).count();
+    ensure!(
+        num_succeeded == 1,
+        "expected 1 successful request, got {num_succeeded}"
+    );
+    let num_entries = query_scalar::<_, i64>("SELECT count(*) FROM entries WHERE path = $1")
+        .bind(path.to_str_without_prefix())
+        .fetch_one(db_pool)
+        .await?;
+    ensure!(num_entries == 1, "expected 1 entry, got {num_entries}");
     Ok(())
 }
 
@@ -521,12 +1862,42 @@ struct ClientData {
 }
 
 impl ClientData {
+    /// Sends a request to the server directly, without the client.
+    async fn request<R>(&self, request: &R) -> Result<R::Response>
+    where
+        R: RequestToResponse + Serialize,
+        R::Response: DeserializeOwned,
+    {
+        let response = reqwest::Client::new()
+            .post(self.config.server_url.join(R::PATH)?)
+            .bearer_auth(&self.config.access_token)
+            .header(CLIENT_VERSION_HEADER, env!("CARGO_PKG_VERSION"))
+            .body(bincode::serialize(request)?)
+            .send()
+            .await?
+            .error_for_status()?;
+        bincode::deserialize::<Result<R::Response, ErrorResponse>>(&response.bytes().await?)?
+            .map_err(ErrorResponse::into_error)
+    }
     async fn sync(&self) -> Result<()> {
         rammingen::run(
-            rammingen::cli::Cli {
-                config: None,
-                command: rammingen::cli::Command::Sync,
-            },
+            cli(rammingen::cli::Command::Sync { path: None }),
+            self.config.clone(),
+        )
+        .await
+    }
+    async fn sync_path(&self, path: SanitizedLocalPath) -> Result<()> {
+        rammingen::run(
+            cli(rammingen::cli::Command::Sync { path: Some(path) }),
+            self.config.clone(),
+        )
+        .await
+    }
+    async fn resync_mount(&self) -> Result<()> {
+        rammingen::run(
+            cli(rammingen::cli::Command::ResyncMount {
+                path: self.mount_dir.to_str().unwrap().parse()?,
+            }),
             self.config.clone(),
         )
         .await
@@ -536,16 +1907,60 @@ impl ClientData {
         archive_path: ArchivePath,
         local_path: SanitizedLocalPath,
         version: Option<DateTimeUtc>,
+    ) -> Result<()> {
+        self.download_inner(archive_path, local_path, version, false)
+            .await
+    }
+    async fn download_with_checksum(
+        &self,
+        archive_path: ArchivePath,
+        local_path: SanitizedLocalPath,
+    ) -> Result<()> {
+        self.download_inner(archive_path, local_path, None, true)
+            .await
+    }
+    async fn download_inner(
+        &self,
+        archive_path: ArchivePath,
+        local_path: SanitizedLocalPath,
+        version: Option<DateTimeUtc>,
+        checksum: bool,
     ) -> Result<()> {
         rammingen::run(
-            rammingen::cli::Cli {
-                config: None,
-                command: rammingen::cli::Command::Download {
-                    archive_path,
-                    local_path,
-                    version: version.map(Into::into),
-                },
-            },
+            cli(rammingen::cli::Command::Download {
+                archive_path,
+                local_path: Some(local_path),
+                version: version.map(Into::into),
+                version_option: None,
+                into: None,
+                checksum,
+                metadata_only: false,
+                server_time: false,
+                repair: false,
+                pattern: None,
+            }),
+            self.config.clone(),
+        )
+        .await
+    }
+    async fn repair(
+        &self,
+        archive_path: ArchivePath,
+        local_path: SanitizedLocalPath,
+    ) -> Result<()> {
+        rammingen::run(
+            cli(rammingen::cli::Command::Download {
+                archive_path,
+                local_path: Some(local_path),
+                version: None,
+                version_option: None,
+                into: None,
+                checksum: false,
+                metadata_only: false,
+                server_time: false,
+                repair: true,
+                pattern: None,
+            }),
             self.config.clone(),
         )
         .await
@@ -554,15 +1969,15 @@ impl ClientData {
         &self,
         local_path: SanitizedLocalPath,
         archive_path: ArchivePath,
+        dry_run: bool,
     ) -> Result<()> {
         rammingen::run(
-            rammingen::cli::Cli {
-                config: None,
-                command: rammingen::cli::Command::Upload {
-                    local_path,
-                    archive_path,
-                },
-            },
+            cli(rammingen::cli::Command::Upload {
+                local_path,
+                archive_path,
+                dry_run,
+                validate_server: dry_run,
+            }),
             self.config.clone(),
         )
         .await
@@ -571,52 +1986,102 @@ impl ClientData {
         &self,
         archive_path: ArchivePath,
         new_archive_path: ArchivePath,
+        dry_run: bool,
     ) -> Result<()> {
         rammingen::run(
-            rammingen::cli::Cli {
-                config: None,
-                command: rammingen::cli::Command::Move {
-                    old_path: archive_path,
-                    new_path: new_archive_path,
-                },
-            },
+            cli(rammingen::cli::Command::Move {
+                old_path: archive_path,
+                new_path: new_archive_path,
+                dry_run,
+            }),
             self.config.clone(),
         )
         .await
     }
     async fn remove_path(&self, archive_path: ArchivePath) -> Result<()> {
         rammingen::run(
-            rammingen::cli::Cli {
-                config: None,
-                command: rammingen::cli::Command::Remove { archive_path },
-            },
+            cli(rammingen::cli::Command::Remove { archive_path }),
             self.config.clone(),
         )
         .await
     }
     async fn reset(&self, archive_path: ArchivePath, version: DateTime<FixedOffset>) -> Result<()> {
         rammingen::run(
-            rammingen::cli::Cli {
-                config: None,
-                command: rammingen::cli::Command::Reset {
-                    archive_path,
-                    version,
-                },
-            },
+            cli(rammingen::cli::Command::Reset {
+                archive_path,
+                version,
+                server_time: false,
+            }),
             self.config.clone(),
         )
         .await
     }
-    async fn check_integrity(&self) -> Result<()> {
+    async fn estimate(&self, path: SanitizedLocalPath) -> Result<()> {
         rammingen::run(
-            rammingen::cli::Cli {
-                config: None,
-                command: rammingen::cli::Command::CheckIntegrity,
-            },
+            cli(rammingen::cli::Command::Estimate { path }),
+            self.config.clone(),
+        )
+        .await
+    }
+    async fn ls(&self, path: ArchivePath, deleted: bool) -> Result<()> {
+        rammingen::run(
+            cli(rammingen::cli::Command::Ls {
+                path,
+                deleted,
+                refresh_sources: false,
+            }),
+            self.config.clone(),
+        )
+        .await
+    }
+    async fn rollback(
+        &self,
+        archive_path: ArchivePath,
+        to: DateTimeUtc,
+        confirm: bool,
+    ) -> Result<()> {
+        rammingen::run(
+            cli(rammingen::cli::Command::Rollback {
+                archive_path,
+                to: to.into(),
+                confirm,
+            }),
+            self.config.clone(),
+        )
+        .await
+    }
+    async fn pin(&self, archive_path: ArchivePath, recursive: bool) -> Result<()> {
+        rammingen::run(
+            cli(rammingen::cli::Command::Pin {
+                archive_path,
+                recursive,
+            }),
             self.config.clone(),
         )
         .await
     }
+    async fn check_integrity(&self) -> Result<()> {
+        for full in [false, true] {
+            rammingen::run(
+                cli(rammingen::cli::Command::CheckIntegrity { full }),
+                self.config.clone(),
+            )
+            .await?;
+        }
+        Ok(())
+    }
+}
+
+/// Returns the client CLI arguments for running `command` with default global options.
+fn cli(command: rammingen::cli::Command) -> rammingen::cli::Cli {
+    rammingen::cli::Cli {
+        config: None,
+        wait: false,
+        timings: false,
+        repair_db: false,
+        no_local_db: false,
+        command,
+    }
 }
 
 fn archive_subpath(
@@ -661,6 +2126,42 @@ async fn check_download(
     Ok(())
 }
 
+/// Checks that a download with `--checksum` repairs a damaged local copy.
+async fn check_checksum_download(
+    dir: &Path,
+    archive_mount_path: &ArchivePath,
+    clients: &[ClientData],
+    source_dir: &Path,
+) -> Result<()> {
+    let local_path = choose_path(source_dir, true, false, false, false)?;
+    let Some(local_path) = local_path else {
+        return Ok(());
+    };
+    let archive_path = archive_subpath(archive_mount_path, source_dir, &local_path)?;
+    info!("Checking checksum download: {}", archive_path);
+    let client2 = clients.choose(&mut thread_rng()).unwrap();
+    let destination = dir.join("tmp_checksum_download");
+    if destination.exists() {
+        remove_dir_all_or_file(&destination)?;
+    }
+    client2
+        .download(
+            archive_path.clone(),
+            destination.to_str().unwrap().parse()?,
+            None,
+        )
+        .await?;
+    fs_err::OpenOptions::new()
+        .append(true)
+        .open(&destination)?
+        .write_all(b"damaged")?;
+    client2
+        .download_with_checksum(archive_path, destination.to_str().unwrap().parse()?)
+        .await?;
+    diff(&local_path, &destination)?;
+    Ok(())
+}
+
 fn is_ignored(path: &Path) -> bool {
     let name = path.file_name().unwrap().to_str().unwrap();
     name == "target" || name.starts_with("build_")